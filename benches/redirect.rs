@@ -0,0 +1,69 @@
+//! Cost of `RouterState::redirect`, with and without precomputed redirect urls.
+use criterion::{criterion_group, criterion_main, Criterion};
+use survey_redirect::{
+    config::Config,
+    state::{RedirectParams, Route, RouterState},
+};
+use tokio::runtime::Runtime;
+
+const ROUTES: usize = 10_000;
+
+fn setup(rt: &Runtime) -> (RouterState, String, tempfile::TempDir) {
+    let dir = tempfile::tempdir().unwrap();
+    let yaml = format!(
+        "server_binding: 127.0.0.1:0\n\
+         base_url: https://redirect.example\n\
+         admin_token: \"00000000000000000000\"\n\
+         storage_root: {}\n\
+         log_file: {}\n",
+        dir.path().join("db").display(),
+        dir.path().join("survey_redirect.log").display(),
+    );
+    let config = Config::from_yaml(&yaml).unwrap();
+    let state = RouterState::init(&config).unwrap();
+    let table: Vec<Route> = serde_json::from_value(
+        (0..ROUTES)
+            .map(|i| {
+                serde_json::json!({
+                    "uid": format!("p{i}"),
+                    "url": format!("https://survey.example/s?wave=1&group={i}"),
+                })
+            })
+            .collect(),
+    )
+    .unwrap();
+    rt.block_on(state.put_routing_table(table, None)).unwrap();
+    let query = {
+        let code_table = rt.block_on(state.code_table.lock());
+        let code = code_table.values().next().unwrap();
+        format!("code={code}")
+    };
+    (state, query, dir)
+}
+
+fn redirect(c: &mut Criterion) {
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let (state, query, _dir) = setup(&rt);
+    let mut group = c.benchmark_group("redirect");
+    let mut bench = |name: &str| {
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                let params = RedirectParams::from_query(Some(&query)).unwrap();
+                rt.block_on(state.redirect(params, None, None)).unwrap()
+            })
+        });
+    };
+    bench("precomputed");
+    // the per request path taken before urls were precomputed
+    for (_, entry) in rt.block_on(state.router_table.write()).iter_mut() {
+        entry.redirect_url = None;
+    }
+    bench("per_request");
+    group.finish();
+}
+
+criterion_group!(benches, redirect);
+criterion_main!(benches);
@@ -0,0 +1,90 @@
+//! Cost of table updates at a million routes.
+//!
+//! Both PUT and PATCH include writing the snapshot, `router_table` measures
+//! the in-memory update of a PATCH alone.
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use survey_redirect::{
+    config::Config,
+    state::{ConflictResolution, PatchMode, Route, RouterState},
+};
+use tokio::runtime::Runtime;
+
+const ROUTES: usize = 1_000_000;
+const PATCHED: usize = 10;
+
+fn routes(ids: impl Iterator<Item = usize>, wave: u32) -> Vec<Route> {
+    serde_json::from_value(
+        ids.map(|i| {
+            serde_json::json!({
+                "uid": format!("p{i}"),
+                "url": format!("https://survey.example/s?wave={wave}&group={i}"),
+            })
+        })
+        .collect(),
+    )
+    .unwrap()
+}
+
+fn state(dir: &tempfile::TempDir) -> RouterState {
+    let yaml = format!(
+        "server_binding: 127.0.0.1:0\n\
+         base_url: https://redirect.example\n\
+         admin_token: \"00000000000000000000\"\n\
+         storage_root: {}\n\
+         log_file: {}\n",
+        dir.path().join("db").display(),
+        dir.path().join("survey_redirect.log").display(),
+    );
+    RouterState::init(&Config::from_yaml(&yaml).unwrap()).unwrap()
+}
+
+fn routing_table(c: &mut Criterion) {
+    let rt: Runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let state = state(&dir);
+
+    let mut group = c.benchmark_group("routing_table");
+    group.sample_size(10);
+    group.bench_function("put_1m", |b| {
+        b.iter_batched(
+            || routes(0..ROUTES, 1),
+            |table| rt.block_on(state.put_routing_table(table, None)).unwrap(),
+            BatchSize::PerIteration,
+        )
+    });
+    group.bench_function("patch_10_into_1m", |b| {
+        b.iter_batched(
+            || routes(0..PATCHED, 2),
+            |patch| {
+                rt.block_on(state.patch_routing_table(
+                    patch,
+                    ConflictResolution::Overwrite,
+                    PatchMode::Upsert,
+                    true,
+                    None,
+                ))
+                .unwrap()
+            },
+            BatchSize::PerIteration,
+        )
+    });
+    group.finish();
+
+    let router_table = rt.block_on(state.router_table.read()).clone();
+    let entries: Vec<_> = router_table.iter().take(PATCHED).collect();
+    c.bench_function("router_table/update_10_of_1m", |b| {
+        b.iter(|| {
+            let mut tmp = router_table.clone();
+            for (code, entry) in &entries {
+                tmp.insert((*code).clone(), (*entry).clone());
+            }
+            tmp
+        })
+    });
+}
+
+criterion_group!(benches, routing_table);
+criterion_main!(benches);
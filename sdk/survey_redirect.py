@@ -1,4 +1,5 @@
-from typing import Dict as _Dict, List as _List, Tuple as _Tuple, Callable as _Callable
+from typing import Dict as _Dict, List as _List, Tuple as _Tuple, Callable as _Callable, Optional as _Optional, Any as _Any, \
+    Iterator as _Iterator
 import requests as _requests
 import json as _json
 from urllib import parse as _parse
@@ -13,31 +14,82 @@ __all__ = ["Route", "ServeyRedirectSdk"]
 
 _CHUNK_SIZE = 32 * 1024
 TIMEOUT = 30
+_ADMIN = "/v1/admin"
 
 
 @_dataclass
 class Route:
     uid: str
     url: str
+    mobile_url: _Optional[str]
+    round_robin_urls: _Optional[_List[str]]
+    geo_urls: _Optional[_Dict[str, str]]
+    redirect_mode: _Optional[str]
+    request_timeout_secs: _Optional[int]
+    description: _Optional[str]
+    notes: _Optional[str]
+    participant: _Optional[str]
+    wave: _Optional[str]
+    expires_at: _Optional[str]
 
-    def __init__(self, uid: str, url: str, params: _Dict[str, str]):
+    def __init__(self, uid: str, url: str, params: _Dict[str, str],
+                 description: _Optional[str] = None, notes: _Optional[str] = None,
+                 mobile_url: _Optional[str] = None,
+                 round_robin_urls: _Optional[_List[str]] = None,
+                 request_timeout_secs: _Optional[int] = None,
+                 geo_urls: _Optional[_Dict[str, str]] = None,
+                 redirect_mode: _Optional[str] = None,
+                 participant: _Optional[str] = None,
+                 wave: _Optional[str] = None,
+                 expires_at: _Optional[str] = None):
         self.uid = uid
-        # parse url
-        url_parts = _parse.urlparse(url)
-        # parse params
-        this_params = _parse.parse_qsl(url_parts.query)
-        # add params
-        for key, value in params.items():
-            this_params.append((key, value))
-        # rebuild url
-        self.url = _parse.urlunparse((
-            url_parts.scheme,
-            url_parts.netloc,
-            url_parts.path,
-            url_parts.params,
-            _parse.urlencode(this_params),
-            url_parts.fragment
-        ))
+        self.url = _with_params(url, params)
+        self.mobile_url = None if mobile_url is None else _with_params(mobile_url, params)
+        self.round_robin_urls = None if round_robin_urls is None else [
+            _with_params(u, params) for u in round_robin_urls
+        ]
+        # keyed by country code ("DE") or "EU", used with `geoip_database`
+        self.geo_urls = None if geo_urls is None else {
+            key: _with_params(u, params) for key, u in geo_urls.items()
+        }
+        # "http" or "html", overrides `redirect_mode` of the server config
+        self.redirect_mode = redirect_mode
+        self.request_timeout_secs = request_timeout_secs
+        self.description = description
+        self.notes = notes
+        # the routes of later waves of a participant name them and their wave
+        self.participant = participant
+        self.wave = wave
+        # RFC 3339, e.g. "2026-12-31T00:00:00Z", the link answers 410 from then on
+        self.expires_at = expires_at
+
+
+def _with_params(url: str, params: _Dict[str, str]) -> str:
+    # parse url
+    url_parts = _parse.urlparse(url)
+    # parse params
+    this_params = _parse.parse_qsl(url_parts.query)
+    # add params
+    for key, value in params.items():
+        this_params.append((key, value))
+    # rebuild url
+    return _parse.urlunparse((
+        url_parts.scheme,
+        url_parts.netloc,
+        url_parts.path,
+        url_parts.params,
+        _parse.urlencode(this_params),
+        url_parts.fragment
+    ))
+
+
+def _data(response: _requests.Response) -> _Any:
+    """`data` of an admin response envelope, `{"status": "ok", "ts": ..., "data": ...}`.
+
+    Error responses (`{"status": "error", "code": ..., "message": ...}`) raise `HTTPError`.
+    """
+    response.raise_for_status()
+    return response.json()["data"]
 
 
 class _ReaderWrapper(object):
@@ -71,7 +123,7 @@ class ServeyRedirectSdk:
         Returns:
             Dict[str, str]: A mapping from user ID to their survey links.
         """
-        url = self.server_url + "/admin/get_links"
+        url = self.server_url + _ADMIN + "/get_links"
         headers = {
             "Authorization": "Bearer " + self.admin_token,
             "Accept-Encoding": "gzip",
@@ -85,7 +137,26 @@ class ServeyRedirectSdk:
                     data.extend(chunk)
                     t.update(len(chunk))
         response.raise_for_status()
-        return _json.loads(data)
+        return {uid: item["link"] for uid, item in _json.loads(data)["data"].items()}
+
+    def iter_links(self, **kwargs) -> _Iterator[_Tuple[str, str]]:
+        """Get links from server one at a time, without holding all of them in memory.
+
+        Returns:
+            Iterator[Tuple[str, str]]: user IDs and their survey links.
+        """
+        url = self.server_url + _ADMIN + "/get_links"
+        headers = {
+            "Authorization": "Bearer " + self.admin_token,
+            "Accept": "application/x-ndjson",
+            "Accept-Encoding": "gzip",
+        }
+        with _requests.get(url, stream=True, headers=headers, timeout=TIMEOUT, **kwargs) as response:
+            response.raise_for_status()
+            for line in response.iter_lines():
+                if line:
+                    link = _json.loads(line)
+                    yield (link["id"], link["url"])
 
     def get_codes(self, **kwargs) -> _Dict[str, str]:
         """Get links from server.
@@ -93,7 +164,7 @@ class ServeyRedirectSdk:
         Returns:
             Dict[str, str]: A mapping from user ID to their codes.
         """
-        url = self.server_url + "/admin/get_codes"
+        url = self.server_url + _ADMIN + "/get_codes"
         headers = {
             "Authorization": "Bearer " + self.admin_token,
             "Accept-Encoding": "gzip",
@@ -107,9 +178,275 @@ class ServeyRedirectSdk:
                     data.extend(chunk)
                     t.update(len(chunk))
         response.raise_for_status()
-        return _json.loads(data)
+        return _json.loads(data)["data"]
+
+    def get_routing_table(self, **kwargs) -> _List[_Dict[str, _Any]]:
+        """Get the routing table as uploaded, to compare with the source of truth.
+
+        Returns:
+            List[Dict[str, Any]]: Routes with `uid`, `url` and the optional fields.
+                Routes whose code has no id have `uid` None and `orphaned` True.
+        """
+        url = self.server_url + _ADMIN + "/routing_table"
+        headers = {
+            "Authorization": "Bearer " + self.admin_token,
+            "Accept-Encoding": "gzip",
+        }
+        response = _requests.get(url, headers=headers, timeout=TIMEOUT, **kwargs)
+        return _data(response)
+
+    def get_completions(self, **kwargs) -> _Dict[str, _Dict[str, _Any]]:
+        """Get the survey completions reported to `/api/complete`.
+
+        Returns:
+            Dict[str, Dict[str, Any]]: A mapping from user ID to `completed_at` (first completion) and `count`.
+        """
+        url = self.server_url + _ADMIN + "/completions"
+        headers = {
+            "Authorization": "Bearer " + self.admin_token,
+            "Accept-Encoding": "gzip",
+        }
+        response = _requests.get(url, headers=headers, timeout=TIMEOUT, **kwargs)
+        return _data(response)
+
+    def get_route_stats(self, **kwargs) -> _Dict[str, int]:
+        """Get redirect hit counts from server.
+
+        Returns:
+            Dict[str, int]: A mapping from user ID to their number of redirects.
+        """
+        url = self.server_url + _ADMIN + "/route_stats"
+        headers = {
+            "Authorization": "Bearer " + self.admin_token,
+            "Accept-Encoding": "gzip",
+        }
+        response = _requests.get(url, headers=headers, timeout=TIMEOUT, **kwargs)
+        return _data(response)
+
+    def get_route_history(self, uid: str, **kwargs) -> _List[_Dict[str, str]]:
+        """Get the survey URL of a user ID in every stored routing table, newest first.
+
+        Returns:
+            List[Dict[str, str]]: `ts` (snapshot time) and `url` of each snapshot.
+        """
+        url = self.server_url + _ADMIN + "/route_history"
+        headers = {
+            "Authorization": "Bearer " + self.admin_token,
+            "Accept-Encoding": "gzip",
+        }
+        response = _requests.get(url, params={"id": uid}, headers=headers, timeout=TIMEOUT, **kwargs)
+        return _data(response)
+
+    def get_participant_links(self, uid: str, **kwargs) -> _Dict[str, _Dict[str, _Any]]:
+        """Get the links of a participant, keyed by wave (`"default"` for routes without one).
+
+        These are the route of the user ID and the routes with it as `participant`.
+        Raises `HTTPError` 404 for unknown user IDs.
+
+        Returns:
+            Dict[str, Dict[str, Any]]: `link`, `url`, `status` ("active", "expired" or "disabled"),
+            `hit_count`, and `expires_at`, `description` and `notes` if set.
+        """
+        url = self.server_url + _ADMIN + "/participant/" + _parse.quote(uid, safe="") + "/links"
+        headers = {
+            "Authorization": "Bearer " + self.admin_token,
+        }
+        response = _requests.get(url, headers=headers, timeout=TIMEOUT, **kwargs)
+        return _data(response)
+
+    def get_storage_stats(self, **kwargs) -> _Dict[str, _Any]:
+        """Get the number of snapshots and the disk usage of the server storage.
+
+        Returns:
+            Dict[str, Any]: `storage_root`, `snapshot_count`, `total_size_bytes`,
+            `latest_snapshot_ts` and `oldest_snapshot_ts`.
+        """
+        url = self.server_url + _ADMIN + "/storage_stats"
+        headers = {
+            "Authorization": "Bearer " + self.admin_token,
+        }
+        response = _requests.get(url, headers=headers, timeout=TIMEOUT, **kwargs)
+        return _data(response)
+
+    def get_runtime_info(self, **kwargs) -> _Dict[str, _Any]:
+        """Get request and connection counters of the server.
+
+        Returns:
+            Dict[str, Any]: `requests_total`, `last_request_at_iso`, `redirect_errors_total`, etc.
+        """
+        url = self.server_url + _ADMIN + "/runtime_info"
+        headers = {
+            "Authorization": "Bearer " + self.admin_token,
+            "Accept-Encoding": "gzip",
+        }
+        response = _requests.get(url, headers=headers, timeout=TIMEOUT, **kwargs)
+        return _data(response)
+
+    def get_metrics(self, **kwargs) -> _Dict[str, _Any]:
+        """Get table sizes, snapshots on disk and redirect counters, for simple monitors.
+
+        Returns:
+            Dict[str, Any]: `entries` and `approx_heap_bytes` of `router_table` and `code_table`,
+            `snapshot_count`, `snapshot_size_bytes`, `redirects` by outcome and `too_many_requests_total`.
+        """
+        url = self.server_url + _ADMIN + "/metrics.json"
+        headers = {
+            "Authorization": "Bearer " + self.admin_token,
+        }
+        response = _requests.get(url, headers=headers, timeout=TIMEOUT, **kwargs)
+        return _data(response)
+
+    def reload(self, **kwargs) -> _Dict[str, _Any]:
+        """Replace the server tables with those in its storage, e.g. after restoring a backup.
+
+        Raises `HTTPError` 409 while a table update is running.
+
+        Returns:
+            Dict[str, Any]: `snapshot_ts` of the loaded router table, number of `routes` and `codes`.
+        """
+        url = self.server_url + _ADMIN + "/reload"
+        headers = {
+            "Authorization": "Bearer " + self.admin_token,
+        }
+        response = _requests.post(url, headers=headers, timeout=TIMEOUT, **kwargs)
+        return _data(response)
+
+    def validate(self, **kwargs) -> _Dict[str, _Any]:
+        """Check the consistency of the server tables, as at startup.
+
+        Returns:
+            Dict[str, Any]: numbers of `routes` and `codes`, the `deactivated` count, and for
+            `routes_without_id`, `ids_without_route`, `duplicate_codes` and `invalid_routes`
+            the `count` of offenders with the first 100 as `samples`.
+        """
+        url = self.server_url + _ADMIN + "/validate"
+        headers = {
+            "Authorization": "Bearer " + self.admin_token,
+        }
+        response = _requests.get(url, headers=headers, timeout=TIMEOUT, **kwargs)
+        return _data(response)
+
+    def reload_cert(self, **kwargs) -> None:
+        """Reload the TLS certificates from their files, as when the files change.
+
+        The reload happens in the background. Raises `HTTPError` 409 if the server does not use TLS.
+        """
+        url = self.server_url + _ADMIN + "/reload_cert"
+        headers = {
+            "Authorization": "Bearer " + self.admin_token,
+        }
+        response = _requests.post(url, headers=headers, timeout=TIMEOUT, **kwargs)
+        response.raise_for_status()
+
+    def rotate_admin_token(self, new_token: str, **kwargs) -> None:
+        """Replace the admin token of the server, and use it for the following requests.
+
+        The token must be at least 32 visible ASCII characters, with at least 8 different ones.
+        Raises `HTTPError` 422 if it is too weak, the old token stays valid then.
+        """
+        url = self.server_url + _ADMIN + "/admin_token"
+        headers = {
+            "Authorization": "Bearer " + self.admin_token,
+        }
+        response = _requests.patch(url, json={"new_token": new_token}, headers=headers, timeout=TIMEOUT, **kwargs)
+        response.raise_for_status()
+        self.admin_token = new_token
+
+    def activate_codes(self, ids: _List[str], **kwargs) -> _Dict[str, _List[str]]:
+        """Resume redirecting the given user IDs.
+
+        Returns:
+            Dict[str, List[str]]: `succeeded` and `not_found` user IDs.
+        """
+        return self.__set_deactivated(_ADMIN + "/activate_codes", ids, **kwargs)
+
+    def deactivate_codes(self, ids: _List[str], **kwargs) -> _Dict[str, _List[str]]:
+        """Suspend the links of the given user IDs, they answer 410 until activated again.
+
+        Returns:
+            Dict[str, List[str]]: `succeeded` and `not_found` user IDs.
+        """
+        return self.__set_deactivated(_ADMIN + "/deactivate_codes", ids, **kwargs)
+
+    def delete_routes(self, ids: _List[str], **kwargs) -> _Dict[str, _List[str]]:
+        """Remove the routes of the given user IDs. They keep their links if routes are added again.
+
+        Returns:
+            Dict[str, List[str]]: `removed` and `not_found` user IDs.
+        """
+        url = self.server_url + _ADMIN + "/routing_table"
+        headers = {
+            "Authorization": "Bearer " + self.admin_token,
+        }
+        response = _requests.delete(url, json={"ids": ids}, headers=headers, timeout=TIMEOUT, **kwargs)
+        return _data(response)
+
+    def rekey(self, renames: _Dict[str, str], **kwargs) -> None:
+        """Change user IDs, keeping their links. Applied in order, all or none.
+
+        Args:
+            renames (Dict[str, str]): new user IDs by old user ID.
+
+        Raises `HTTPError` 404 if an old ID has no link, 409 if a new ID has one already.
+        """
+        url = self.server_url + _ADMIN + "/rekey"
+        headers = {
+            "Authorization": "Bearer " + self.admin_token,
+        }
+        body = [{"old_id": old_id, "new_id": new_id} for old_id, new_id in renames.items()]
+        response = _requests.post(url, json=body, headers=headers, timeout=TIMEOUT, **kwargs)
+        response.raise_for_status()
+
+    def get_codes_without_routes(self, **kwargs) -> _List[str]:
+        """Get the user IDs that keep a code but have no route, e.g. after their routes were deleted.
+
+        Returns:
+            List[str]: the user IDs, sorted.
+        """
+        url = self.server_url + _ADMIN + "/codes_without_routes"
+        headers = {
+            "Authorization": "Bearer " + self.admin_token,
+        }
+        response = _requests.get(url, headers=headers, timeout=TIMEOUT, **kwargs)
+        return _data(response)["orphaned"]
+
+    def purge_orphaned_codes(self, **kwargs) -> _List[str]:
+        """Forget the codes of user IDs without route. Their old links stay invalid,
+        and they get new links if they are added again.
+
+        Returns:
+            List[str]: the purged user IDs, sorted.
+        """
+        url = self.server_url + _ADMIN + "/purge_orphaned_codes"
+        headers = {
+            "Authorization": "Bearer " + self.admin_token,
+        }
+        response = _requests.post(url, headers=headers, timeout=TIMEOUT, **kwargs)
+        return _data(response)["orphaned"]
 
-    def put_redirect_tables(self, table: _List[Route], **kwargs) -> _Tuple[int, str]:
+    def __set_deactivated(self, path: str, ids: _List[str], **kwargs) -> _Dict[str, _List[str]]:
+        url = self.server_url + path
+        headers = {
+            "Authorization": "Bearer " + self.admin_token,
+        }
+        response = _requests.post(url, json={"ids": ids}, headers=headers, timeout=TIMEOUT, **kwargs)
+        return _data(response)
+
+    def get_table_version(self, **kwargs) -> int:
+        """Get the version of the routing table, for `if_match` of uploads.
+
+        Returns:
+            int: The version, raised by every change of the table.
+        """
+        url = self.server_url + _ADMIN + "/storage_stats"
+        headers = {
+            "Authorization": "Bearer " + self.admin_token,
+        }
+        response = _requests.get(url, headers=headers, timeout=TIMEOUT, **kwargs)
+        return _data(response)["table_version"]
+
+    def put_redirect_tables(self, table: _List[Route], if_match: _Optional[int] = None, return_links: bool = False,
+                            **kwargs) -> _Tuple[int, str]:
         """Put redirect table to server.
 
         Replaces the existing redirect table with the given one
@@ -117,29 +454,37 @@ class ServeyRedirectSdk:
 
         Args:
             table (List[Route]): The redirect table to be put.
+            if_match (Optional[int]): Fail with 412 if the table version is no longer this one.
+            return_links (bool): Answer with the links of the uploaded users, as `get_links` does.
 
         Returns:
             Tuple[int, str]: The status code and response text.
-            (200, "success") if success. Raise exception otherwise.
+            (200, '{"status": "ok", "ts": ..., "data": null}') if success, with `"data": {"links": {...}}`
+            if `return_links`. Raise exception otherwise.
         """
         # Check input
         self.__check_table(table)
 
         # Send request
-        url = self.server_url + "/admin/routing_table"
+        url = self.server_url + _ADMIN + "/routing_table"
         headers = {
             "Content-Type": "application/json",
             "Content-Encoding": "gzip",
             "Authorization": "Bearer " + self.admin_token
         }
+        if if_match is not None:
+            headers["If-Match"] = str(if_match)
         data = _gzip.compress(_json.dumps([_asdict(dat) for dat in table]).encode("utf-8"))
         with self.__progress_bar(desc="Uploading", total=len(data)) as t:
             reader_wrapper = _ReaderWrapper(t.update, _BytesIO(data), len(data))
-            response = _requests.put(url, headers=headers, data=reader_wrapper, timeout=TIMEOUT, **kwargs)
+            response = _requests.put(url, headers=headers, data=reader_wrapper,
+                                     params={"return_links": str(return_links).lower()}, timeout=TIMEOUT, **kwargs)
             response.raise_for_status()
             return (response.status_code, response.text)
 
-    def patch_redirect_tables(self, table: _List[Route], **kwargs) -> _Tuple[int, str]:
+    def patch_redirect_tables(self, table: _List[Route], conflict: str = "overwrite", mode: str = "upsert",
+                              if_match: _Optional[int] = None, return_links: bool = False,
+                              durable: bool = False, **kwargs) -> _Tuple[int, str]:
         """Patch redirect table of server.
 
         Partially update redirect table with the given one
@@ -147,28 +492,65 @@ class ServeyRedirectSdk:
 
         Args:
             table (List[Route]): The redirect table to be put.
+            conflict (str): What to do with users that already have a route:
+                "overwrite" (default), "skip", or "error" (reject the whole patch).
+            mode (str): What to do with users without a link: "upsert" (default) creates them,
+                "update_only" rejects the whole patch with 422, e.g. to catch misspelled IDs.
+            if_match (Optional[int]): Fail with 412 if the table version is no longer this one.
+            return_links (bool): Add the `links` of the patched users to the response.
+            durable (bool): Write the table to disk before returning, instead of a few seconds later.
 
         Returns:
             Tuple[int, str]: The status code and response text.
-            (200, "success") if success. Raise exception otherwise.
+            (200, '{"status": "ok", "ts": ..., "data": {"mode": "upsert", "updated": N, "overwritten": [...], "skipped": [...],
+            "errors": []}}') if success. Raise exception otherwise.
         """
         # Check input
         self.__check_table(table)
 
         # Send request
-        url = self.server_url + "/admin/routing_table"
+        url = self.server_url + _ADMIN + "/routing_table"
         headers = {
             "Content-Type": "application/json",
             "Content-Encoding": "gzip",
             "Authorization": "Bearer " + self.admin_token
         }
+        if if_match is not None:
+            headers["If-Match"] = str(if_match)
         data = _gzip.compress(_json.dumps([_asdict(dat) for dat in table]).encode("utf-8"))
         with self.__progress_bar(desc="Uploading", total=len(data)) as t:
             reader_wrapper = _ReaderWrapper(t.update, _BytesIO(data), len(data))
-            response = _requests.patch(url, headers=headers, data=reader_wrapper, timeout=TIMEOUT, **kwargs)
+            response = _requests.patch(url, headers=headers, data=reader_wrapper,
+                                       params={"conflict": conflict, "mode": mode,
+                                               "return_links": str(return_links).lower(),
+                                               "durable": str(durable).lower()}, timeout=TIMEOUT, **kwargs)
             response.raise_for_status()
             return (response.status_code, response.text)
 
+    def merge_patch_redirect_tables(self, patch: _Dict[str, _Optional[str]], if_match: _Optional[int] = None,
+                                    durable: bool = False, **kwargs) -> _Dict[str, int]:
+        """Patch redirect table of server with a JSON Merge Patch (RFC 7396).
+
+        Args:
+            patch (Dict[str, Optional[str]]): New urls by user ID, `None` removes the route of a user.
+                Users keep the other options of their routes, and their links.
+            if_match (Optional[int]): Fail with 412 if the table version is no longer this one.
+            durable (bool): Write the table to disk before returning, instead of a few seconds later.
+
+        Returns:
+            Dict[str, int]: number of `updated` and `removed` routes.
+        """
+        url = self.server_url + _ADMIN + "/routing_table"
+        headers = {
+            "Content-Type": "application/merge-patch+json",
+            "Authorization": "Bearer " + self.admin_token,
+        }
+        if if_match is not None:
+            headers["If-Match"] = str(if_match)
+        response = _requests.patch(url, headers=headers, json=patch, params={"durable": str(durable).lower()},
+                                   timeout=TIMEOUT, **kwargs)
+        return _data(response)
+
     def __check_table(self, table: _List[Route]):
         if not isinstance(table, list):
             raise Exception("Not a list")
@@ -0,0 +1,63 @@
+//! Per-request access log.
+use crate::client_ip::ClientIp;
+use axum::{
+    body::HttpBody as _,
+    extract::{Request, State},
+    http::{header::CONTENT_LENGTH, HeaderMap},
+    middleware::Next,
+    response::Response,
+};
+use std::{sync::Arc, time::Instant};
+
+/// tracing target of access log lines, see `Config::access_log_file`.
+pub const ACCESS_LOG_TARGET: &str = "survey_redirect::access";
+
+/// middleware emitting one log line per request.
+///
+/// Query strings are never logged since they carry participant codes.
+pub async fn access_log(
+    State(excluded_paths): State<Arc<[String]>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    if excluded_paths.iter().any(|p| p == req.uri().path()) {
+        return next.run(req).await;
+    }
+    let start = Instant::now();
+    let method = req.method().clone();
+    let path = req.uri().path().to_owned();
+    let client_ip = req.extensions().get::<ClientIp>().copied();
+    let req_bytes = body_len(req.headers(), req.body().size_hint().exact());
+
+    let rsp = next.run(req).await;
+
+    let resp_bytes = body_len(rsp.headers(), rsp.body().size_hint().exact());
+    tracing::info!(
+        target: ACCESS_LOG_TARGET,
+        %method,
+        %path,
+        status = rsp.status().as_u16(),
+        latency_ms = start.elapsed().as_secs_f64() * 1000.0,
+        req_bytes = %display_len(req_bytes),
+        resp_bytes = %display_len(resp_bytes),
+        client_ip = %display_ip(client_ip),
+    );
+    rsp
+}
+
+/// body length from `Content-Length`, or from the body itself if known.
+fn body_len(headers: &HeaderMap, exact: Option<u64>) -> Option<u64> {
+    headers
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+        .or(exact)
+}
+
+fn display_len(len: Option<u64>) -> String {
+    len.map_or_else(|| "-".to_owned(), |len| len.to_string())
+}
+
+fn display_ip(ip: Option<ClientIp>) -> String {
+    ip.map_or_else(|| "-".to_owned(), |ip| ip.to_string())
+}
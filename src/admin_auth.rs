@@ -0,0 +1,232 @@
+//! Bearer token check of the admin api, with a token that can be rotated at runtime,
+//! and the network allowlist checked before it.
+//!
+//! Clients guessing tokens are throttled: after `admin_auth_max_failures` failed
+//! checks they get 429 for a backoff doubling with every further failure.
+//! IPv6 clients are counted by /64 network, which is what a single host
+//! typically gets to pick its addresses from.
+use crate::{
+    client_ip::ClientIp,
+    handler::AdminResponse,
+    monitoring::{ADMIN_AUTH_FAILURES_TOTAL, ADMIN_AUTH_THROTTLED_TOTAL, ADMIN_DENIED_TOTAL},
+};
+use axum::{
+    extract::{Request, State},
+    http::{
+        header::{AUTHORIZATION, RETRY_AFTER},
+        HeaderValue, StatusCode,
+    },
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Extension,
+};
+use ipnet::IpNet;
+use lru::LruCache;
+use metrics::counter;
+use std::{
+    net::{IpAddr, Ipv6Addr},
+    num::NonZeroUsize,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
+};
+use subtle::ConstantTimeEq;
+
+/// shortest token accepted by `rotate_admin_token`.
+pub const MIN_ADMIN_TOKEN_LENGTH: usize = 32;
+/// fewest different characters of a rotated token, rejecting e.g. `aaaa...`.
+const MIN_ADMIN_TOKEN_DISTINCT_CHARS: usize = 8;
+
+/// first backoff of a throttled client, doubled with every further failure.
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(15 * 60);
+/// clients tracked at most, the least recently failing one is dropped for a new one.
+const MAX_TRACKED_CLIENTS: NonZeroUsize = match NonZeroUsize::new(10_000) {
+    Some(n) => n,
+    None => unreachable!(),
+};
+
+/// The current admin token, shared by the middleware and `RouterState`.
+pub type AdminToken = Arc<RwLock<String>>;
+
+/// State of [`require_admin_token`].
+#[derive(Clone)]
+pub struct AdminAuth {
+    token: AdminToken,
+    failures: Arc<AuthFailures>,
+}
+
+/// Failed token checks by client, see `client_key`.
+struct AuthFailures {
+    max_failures: u32,
+    window: Duration,
+    clients: Mutex<LruCache<IpAddr, ClientFailures>>,
+}
+
+struct ClientFailures {
+    count: u32,
+    first: Instant,
+    blocked_until: Option<Instant>,
+}
+
+impl AdminAuth {
+    /// throttle clients after `max_failures` failed checks within `window`.
+    pub fn new(token: AdminToken, max_failures: u32, window: Duration) -> Self {
+        Self {
+            token,
+            failures: Arc::new(AuthFailures {
+                max_failures,
+                window,
+                clients: Mutex::new(LruCache::new(MAX_TRACKED_CLIENTS)),
+            }),
+        }
+    }
+}
+
+/// the address failures are counted by: the /64 network of IPv6 clients,
+/// and the address itself otherwise.
+fn client_key(ip: IpAddr) -> IpAddr {
+    match ip.to_canonical() {
+        IpAddr::V6(ip) => IpAddr::V6(Ipv6Addr::from(u128::from(ip) & !u128::from(u64::MAX))),
+        ip => ip,
+    }
+}
+
+impl AuthFailures {
+    /// how long `ip` is still throttled.
+    fn blocked(&self, ip: IpAddr, now: Instant) -> Option<Duration> {
+        let clients = self.clients.lock().expect("poisoned");
+        let until = clients.peek(&client_key(ip))?.blocked_until?;
+        (until > now).then(|| until - now)
+    }
+
+    /// count a failure of `ip`, throttling it from `max_failures` failures on.
+    fn record(&self, ip: IpAddr, now: Instant) {
+        let mut clients = self.clients.lock().expect("poisoned");
+        let client = clients.get_or_insert_mut(client_key(ip), || ClientFailures {
+            count: 0,
+            first: now,
+            blocked_until: None,
+        });
+        if self.expired(client, now) {
+            *client = ClientFailures {
+                count: 0,
+                first: now,
+                blocked_until: None,
+            };
+        }
+        client.count += 1;
+        if client.count >= self.max_failures {
+            let doublings = (client.count - self.max_failures).min(16);
+            let backoff = (MIN_BACKOFF * 2u32.pow(doublings)).min(MAX_BACKOFF);
+            // logged once per block, not per request
+            tracing::warn!(
+                "admin auth from {ip} throttled for {}s after {} failures",
+                backoff.as_secs(),
+                client.count
+            );
+            client.blocked_until = Some(now + backoff);
+        }
+    }
+
+    /// failures to forget: older than the window, or a window after the block ended.
+    fn expired(&self, client: &ClientFailures, now: Instant) -> bool {
+        match client.blocked_until {
+            None => now.duration_since(client.first) > self.window,
+            Some(until) => now > until + self.window,
+        }
+    }
+
+    fn reset(&self, ip: IpAddr) {
+        self.clients.lock().expect("poisoned").pop(&client_key(ip));
+    }
+}
+
+/// middleware answering 401 to requests without `Authorization: Bearer <admin token>`,
+/// and 429 to clients throttled for failing too often.
+pub async fn require_admin_token(
+    State(auth): State<AdminAuth>,
+    Extension(ClientIp(ip)): Extension<ClientIp>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let now = Instant::now();
+    if let Some(remaining) = auth.failures.blocked(ip, now) {
+        counter!(ADMIN_AUTH_THROTTLED_TOTAL).increment(1);
+        // round up to whole seconds
+        let secs = remaining.as_secs() + u64::from(remaining.subsec_nanos() > 0);
+        return (
+            [(RETRY_AFTER, HeaderValue::from(secs))],
+            AdminResponse::error(
+                StatusCode::TOO_MANY_REQUESTS,
+                "TOO_MANY_AUTH_FAILURES",
+                "too many invalid admin tokens, try again later",
+            ),
+        )
+            .into_response();
+    }
+    let authorized = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|bearer| {
+            let token = auth.token.read().expect("poisoned");
+            bearer.as_bytes().ct_eq(token.as_bytes()).into()
+        });
+    if !authorized {
+        counter!(ADMIN_AUTH_FAILURES_TOTAL).increment(1);
+        auth.failures.record(ip, now);
+        return AdminResponse::error(
+            StatusCode::UNAUTHORIZED,
+            "UNAUTHORIZED",
+            "missing or invalid admin token",
+        )
+        .into_response();
+    }
+    auth.failures.reset(ip);
+    next.run(req).await
+}
+
+/// middleware answering 403 to clients outside of `admin_allowed_networks`.
+///
+/// IPv4-mapped IPv6 addresses are matched as IPv4 addresses.
+pub async fn require_allowed_network(
+    State(networks): State<Arc<[IpNet]>>,
+    Extension(ClientIp(ip)): Extension<ClientIp>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let ip = ip.to_canonical();
+    if !networks.iter().any(|net| net.contains(&ip)) {
+        tracing::warn!("admin request from {ip} denied, not in admin_allowed_networks");
+        counter!(ADMIN_DENIED_TOTAL).increment(1);
+        return AdminResponse::error(
+            StatusCode::FORBIDDEN,
+            "FORBIDDEN",
+            "admin api not allowed from this address",
+        )
+        .into_response();
+    }
+    next.run(req).await
+}
+
+/// check that a new admin token is long and varied enough, and fits in a header.
+pub fn validate_admin_token(token: &str) -> Result<(), String> {
+    if token.len() < MIN_ADMIN_TOKEN_LENGTH {
+        return Err(format!(
+            "admin token must be at least {MIN_ADMIN_TOKEN_LENGTH} characters long"
+        ));
+    }
+    if !token.bytes().all(|b| b.is_ascii_graphic()) {
+        return Err("admin token must consist of visible ascii characters".to_owned());
+    }
+    let mut distinct = token.as_bytes().to_vec();
+    distinct.sort_unstable();
+    distinct.dedup();
+    if distinct.len() < MIN_ADMIN_TOKEN_DISTINCT_CHARS {
+        return Err(format!(
+            "admin token must contain at least {MIN_ADMIN_TOKEN_DISTINCT_CHARS} different characters"
+        ));
+    }
+    Ok(())
+}
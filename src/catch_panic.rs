@@ -0,0 +1,27 @@
+//! Turn handler panics into 500 responses.
+use crate::{handler::internal_error, request_id::RequestId};
+use axum::{extract::Request, middleware::Next, response::Response, Extension};
+use futures::FutureExt;
+use std::{backtrace::Backtrace, panic::AssertUnwindSafe};
+
+/// log panics with their backtrace through `tracing`, instead of stderr.
+///
+/// The hook runs on the panicking thread, so the log line carries the request span.
+pub fn install_panic_hook() {
+    std::panic::set_hook(Box::new(|info| {
+        let backtrace = Backtrace::force_capture();
+        tracing::error!("panic: {info}\n{backtrace}");
+    }));
+}
+
+/// middleware answering 500 if the handler panics, rather than dropping the connection.
+pub async fn catch_panic(
+    Extension(request_id): Extension<RequestId>,
+    req: Request,
+    next: Next,
+) -> Response {
+    match AssertUnwindSafe(next.run(req)).catch_unwind().await {
+        Ok(rsp) => rsp,
+        Err(_) => internal_error("internal error", &request_id),
+    }
+}
@@ -1,52 +1,246 @@
 use crate::config::TlsConfig;
 use notify::Watcher as _;
+use ring::digest::{digest, SHA256};
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN},
+    rand::{SecureRandom, SystemRandom},
+};
 use rustls_pemfile::{certs, private_key};
-use std::{io::BufReader, path::Path, sync::Arc, time::Duration};
+use std::{
+    io::BufReader,
+    path::Path,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
 use tokio::runtime::Runtime;
 use tokio_rustls::{
-    rustls::{self},
+    rustls::{
+        self,
+        crypto::GetRandomFailed,
+        server::{ProducesTickets, ServerSessionMemoryCache, StoresServerSessions},
+        TicketSwitcher,
+    },
     TlsAcceptor,
 };
 
 const CERT_RETRY_TIMEOUT: Duration = Duration::from_millis(500);
+/// sessions kept for resumption without tickets, as rustls does by default.
+const SESSION_CACHE_SIZE: usize = 256;
+
+/// fingerprints of the certificates being served, see `cert_fingerprint`.
+static CERT_FINGERPRINT: RwLock<Option<String>> = RwLock::new(None);
+static ADMIN_CERT_FINGERPRINT: RwLock<Option<String>> = RwLock::new(None);
+
+/// Which listeners a certificate is served on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CertRole {
+    /// `server_tls`, also used by the admin listeners unless `admin_tls` is set.
+    Server,
+    /// `admin_tls`.
+    Admin,
+}
+
+impl CertRole {
+    fn fingerprint(self) -> &'static RwLock<Option<String>> {
+        match self {
+            CertRole::Server => &CERT_FINGERPRINT,
+            CertRole::Admin => &ADMIN_CERT_FINGERPRINT,
+        }
+    }
+}
+
+/// Why the certificates are reloaded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CertReload {
+    /// the watched files changed.
+    FileChange,
+    /// requested by `/admin/reload_cert`, e.g. when file events are missed.
+    Forced,
+}
+
+/// triggers a reload of the certificates of one role, see `RouterState::reload_cert`.
+pub type CertReloadSender = tokio::sync::watch::Sender<CertReload>;
+
+/// SHA-256 fingerprint of the last loaded certificate of `role`, `None` without tls.
+///
+/// Formatted like `openssl x509 -fingerprint -sha256`, e.g. `AB:01:...`.
+pub fn cert_fingerprint(role: CertRole) -> Option<String> {
+    role.fingerprint().read().expect("poisoned").clone()
+}
+
+/// colon-separated upper case hex of the SHA-256 of `der`.
+fn fingerprint(der: &[u8]) -> String {
+    digest(&SHA256, der)
+        .as_ref()
+        .iter()
+        .map(|b| format!("{b:02X}"))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// Session resumption settings of one certificate, shared by the configs
+/// built on every reload so that clients keep resuming their sessions.
+#[derive(Clone)]
+struct Resumption {
+    session_storage: Arc<dyn StoresServerSessions + Send + Sync>,
+    /// `None` without `session_tickets`.
+    ticketer: Option<Arc<dyn ProducesTickets>>,
+    max_early_data_size: u32,
+}
+
+impl Resumption {
+    fn new(tls_config: &TlsConfig) -> std::io::Result<Self> {
+        let ticketer = if tls_config.session_tickets {
+            let switcher = TicketSwitcher::new(tls_config.ticket_rotation_secs, new_ticket_key)
+                .map_err(|e| std::io::Error::other(format!("failed to create ticket key {e}")))?;
+            Some(Arc::new(switcher) as Arc<dyn ProducesTickets>)
+        } else {
+            None
+        };
+        if tls_config.max_early_data_size > 0 {
+            tracing::warn!(
+                "accepting up to {} bytes of tls early data, which can be replayed",
+                tls_config.max_early_data_size
+            );
+        }
+        Ok(Self {
+            session_storage: ServerSessionMemoryCache::new(SESSION_CACHE_SIZE),
+            ticketer,
+            max_early_data_size: tls_config.max_early_data_size,
+        })
+    }
+
+    fn apply(&self, server_config: &mut rustls::ServerConfig) {
+        server_config.session_storage = self.session_storage.clone();
+        if let Some(ticketer) = &self.ticketer {
+            server_config.ticketer = ticketer.clone();
+        }
+        server_config.max_early_data_size = self.max_early_data_size;
+    }
+}
+
+/// A session ticket key, rotated by `TicketSwitcher`. Tickets are the key
+/// name, a random nonce and the session sealed with ChaCha20-Poly1305.
+struct TicketKey {
+    name: [u8; 16],
+    key: LessSafeKey,
+}
+
+fn new_ticket_key() -> Result<Box<dyn ProducesTickets>, GetRandomFailed> {
+    let rng = SystemRandom::new();
+    let mut name = [0; 16];
+    let mut key = [0; 32];
+    rng.fill(&mut name).map_err(|_| GetRandomFailed)?;
+    rng.fill(&mut key).map_err(|_| GetRandomFailed)?;
+    let key = UnboundKey::new(&CHACHA20_POLY1305, &key).expect("32 byte key");
+    Ok(Box::new(TicketKey {
+        name,
+        key: LessSafeKey::new(key),
+    }))
+}
+
+impl std::fmt::Debug for TicketKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TicketKey").finish_non_exhaustive()
+    }
+}
+
+impl ProducesTickets for TicketKey {
+    fn enabled(&self) -> bool {
+        true
+    }
+
+    /// unused, `TicketSwitcher` reports the lifetime of its tickets.
+    fn lifetime(&self) -> u32 {
+        0
+    }
+
+    fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>> {
+        let mut nonce = [0; NONCE_LEN];
+        SystemRandom::new().fill(&mut nonce).ok()?;
+        let mut sealed = plain.to_vec();
+        self.key
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(self.name),
+                &mut sealed,
+            )
+            .ok()?;
+        Some([&self.name[..], &nonce, &sealed].concat())
+    }
+
+    fn decrypt(&self, ticket: &[u8]) -> Option<Vec<u8>> {
+        let ticket = ticket.strip_prefix(&self.name[..])?;
+        if ticket.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, sealed) = ticket.split_at(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(nonce).ok()?;
+        let mut plain = sealed.to_vec();
+        let len = self
+            .key
+            .open_in_place(nonce, Aad::from(self.name), &mut plain)
+            .ok()?
+            .len();
+        plain.truncate(len);
+        Some(plain)
+    }
+}
 
 /// Watch the files of the cert, and return a watcher receiver
-/// that sends new tls_acceptors when cert file is updated.
+/// that sends new tls_acceptors when cert file is updated,
+/// and a sender to reload the cert without a file change.
 /// (involves BLOCKING operations!!!)
 pub fn cert_provider_from_file<P: AsRef<Path>>(
     tls_config: Option<TlsConfig>,
+    role: CertRole,
     watch_cert_changes_path: &Option<P>,
     rt: &Runtime,
-) -> std::io::Result<Option<tokio::sync::watch::Receiver<TlsAcceptor>>> {
+) -> std::io::Result<Option<(tokio::sync::watch::Receiver<TlsAcceptor>, CertReloadSender)>> {
     let Some(tls_config) = tls_config else {
-        tracing::warn!("serving with insecured connection.");
+        if role == CertRole::Server {
+            tracing::warn!("serving with insecured connection.");
+        }
         return Ok(None);
     };
-    let (watcher, mut cert_update_signal_rx) =
-        watch_cert_changes(&tls_config, watch_cert_changes_path)?;
-    let init_cert = build_tls_acceptor_sync(&tls_config)?;
+    let (cert_update_signal_tx, mut cert_update_signal_rx) =
+        tokio::sync::watch::channel(CertReload::FileChange);
+    let watcher = watch_cert_changes(
+        &tls_config,
+        watch_cert_changes_path,
+        cert_update_signal_tx.clone(),
+    )?;
+    let resumption = Resumption::new(&tls_config)?;
+    let init_cert = build_tls_acceptor_sync(&tls_config, role, &resumption)?;
     let (tls_acceptor_tx, tls_acceptor_rx) = tokio::sync::watch::channel(init_cert);
     rt.spawn(async move {
         // need to keep watcher alive.
         let _watcher = watcher;
         while cert_update_signal_rx.changed().await.is_ok() {
-            tracing::info!("certs files change detected");
+            match *cert_update_signal_rx.borrow_and_update() {
+                CertReload::FileChange => tracing::info!("certs files change detected"),
+                CertReload::Forced => tracing::info!("forced certs reload requested"),
+            }
             // upon cert update signal, wait for some time
             // for cert update tasks to complete
             tokio::time::sleep(CERT_RETRY_TIMEOUT).await;
-            let tls_acceptor = build_tls_acceptor(&tls_config).await;
+            let tls_acceptor = build_tls_acceptor(&tls_config, role, &resumption).await;
             let _ = tls_acceptor_tx.send(tls_acceptor);
             cert_update_signal_rx.mark_unchanged();
         }
     });
-    Ok(Some(tls_acceptor_rx))
+    Ok(Some((tls_acceptor_rx, cert_update_signal_tx)))
 }
 
 /// Asynchronous function to load tls files, keep trying if failed.
-async fn build_tls_acceptor(tls_config: &TlsConfig) -> TlsAcceptor {
+async fn build_tls_acceptor(
+    tls_config: &TlsConfig,
+    role: CertRole,
+    resumption: &Resumption,
+) -> TlsAcceptor {
     // try to load tls config if any
     let server_config = loop {
-        match tokio::task::block_in_place(|| load_certs_key(tls_config)) {
+        match tokio::task::block_in_place(|| load_certs_key(tls_config, role, resumption)) {
             Ok(server_config) => break server_config,
             Err(e) => {
                 tracing::error!("failed to load certs {}, retrying...", e);
@@ -60,18 +254,27 @@ async fn build_tls_acceptor(tls_config: &TlsConfig) -> TlsAcceptor {
 /// Synchronous function to load tls files, return error if failed.
 /// Not to be used within tokio runtime, but only at the initial stage.
 /// (BLOCKING!!)
-fn build_tls_acceptor_sync(tls_config: &TlsConfig) -> std::io::Result<TlsAcceptor> {
+fn build_tls_acceptor_sync(
+    tls_config: &TlsConfig,
+    role: CertRole,
+    resumption: &Resumption,
+) -> std::io::Result<TlsAcceptor> {
     // try to load tls config if any
-    let tls_config = load_certs_key(tls_config)?;
+    let tls_config = load_certs_key(tls_config, role, resumption)?;
     Ok(TlsAcceptor::from(Arc::new(tls_config)))
 }
 
 /// load certificates and private keys from file (BLOCKING!!).
-fn load_certs_key(config: &TlsConfig) -> std::io::Result<rustls::ServerConfig> {
+fn load_certs_key(
+    config: &TlsConfig,
+    role: CertRole,
+    resumption: &Resumption,
+) -> std::io::Result<rustls::ServerConfig> {
     let mut cert = BufReader::new(std::fs::File::open(&config.cert)?);
     let mut key = BufReader::new(std::fs::File::open(&config.key)?);
 
     let cert_chain = certs(&mut cert).collect::<std::io::Result<Vec<_>>>()?;
+    let cert_fingerprint = cert_chain.first().map(|cert| fingerprint(cert));
     let key_der = private_key(&mut key)?.ok_or(std::io::Error::new(
         std::io::ErrorKind::NotFound,
         format!("private key not found in {}", config.key.display()),
@@ -88,52 +291,40 @@ fn load_certs_key(config: &TlsConfig) -> std::io::Result<rustls::ServerConfig> {
         })?;
 
     tls_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    resumption.apply(&mut tls_config);
+    if let Some(fingerprint) = &cert_fingerprint {
+        match role {
+            CertRole::Server => tracing::info!("loaded cert fingerprint: {fingerprint}"),
+            CertRole::Admin => tracing::info!("loaded admin cert fingerprint: {fingerprint}"),
+        }
+    }
+    *role.fingerprint().write().expect("poisoned") = cert_fingerprint;
     Ok(tls_config)
 }
 
-/// monitor certificate changes.
+/// monitor certificate changes, signalled on `cert_update_signal_tx`.
 fn watch_cert_changes<P: AsRef<Path>>(
     tls_config: &TlsConfig,
     watch_cert_changes_path: &Option<P>,
-) -> std::io::Result<(notify::RecommendedWatcher, tokio::sync::watch::Receiver<()>)> {
-    let (cert_update_signal_tx, cert_update_signal_rx) = tokio::sync::watch::channel(());
+    cert_update_signal_tx: CertReloadSender,
+) -> std::io::Result<notify::RecommendedWatcher> {
     let mut cert_watcher =
         notify::recommended_watcher(move |event: Result<notify::Event, notify::Error>| {
             if event.is_ok() {
-                let _ = cert_update_signal_tx.send(());
+                let _ = cert_update_signal_tx.send(CertReload::FileChange);
             }
         })
-        .map_err(|e| {
-            std::io::Error::new(
-                std::io::ErrorKind::Other,
-                format!("failed to init cert watcher {}", e),
-            )
-        })?;
+        .map_err(|e| std::io::Error::other(format!("failed to init cert watcher {}", e)))?;
     cert_watcher
         .watch(&tls_config.cert, notify::RecursiveMode::NonRecursive)
-        .map_err(|e| {
-            std::io::Error::new(
-                std::io::ErrorKind::Other,
-                format!("failed to watch cert {}", e),
-            )
-        })?;
+        .map_err(|e| std::io::Error::other(format!("failed to watch cert {}", e)))?;
     cert_watcher
         .watch(&tls_config.key, notify::RecursiveMode::NonRecursive)
-        .map_err(|e| {
-            std::io::Error::new(
-                std::io::ErrorKind::Other,
-                format!("failed to watch key {}", e),
-            )
-        })?;
+        .map_err(|e| std::io::Error::other(format!("failed to watch key {}", e)))?;
     if let Some(path) = watch_cert_changes_path {
         cert_watcher
             .watch(path.as_ref(), notify::RecursiveMode::Recursive)
-            .map_err(|e| {
-                std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    format!("failed to watch cert path {}", e),
-                )
-            })?;
+            .map_err(|e| std::io::Error::other(format!("failed to watch cert path {}", e)))?;
     }
-    Ok((cert_watcher, cert_update_signal_rx))
+    Ok(cert_watcher)
 }
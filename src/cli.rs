@@ -0,0 +1,211 @@
+//! Offline commands, working directly on a store without running the server.
+//!
+//! Codes are assigned and persisted by [`RouterState`], exactly as the server does,
+//! so that a store prepared offline can be served unchanged.
+use crate::{
+    config::{Config, StorageBackend},
+    state::{Id, Route, RouterState, StateError},
+    utility,
+};
+use clap::{Parser, Subcommand};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use url::Url;
+
+#[derive(Parser)]
+#[command(version, about, args_conflicts_with_subcommands = true)]
+pub struct Cli {
+    /// run the server (using `config.yaml`) if no command is given.
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    /// load `config.yaml` and the stored tables, print a summary as json
+    /// and exit, without binding any socket or changing the store.
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// replace the routing table of a store with a csv, and print the links.
+    Import {
+        /// csv with columns `uid,url` and optionally `mobile_url,description,notes`.
+        #[arg(long)]
+        csv: PathBuf,
+        /// storage directory, created if missing.
+        #[arg(long)]
+        store: PathBuf,
+        #[arg(long)]
+        base_url: Url,
+        /// write the `id,link` csv to this file instead of stdout.
+        #[arg(long)]
+        out: Option<PathBuf>,
+        /// length of newly generated codes.
+        #[arg(long)]
+        code_length: Option<usize>,
+        /// prefix of newly generated codes.
+        #[arg(long)]
+        code_prefix: Option<String>,
+    },
+    /// print the links of an existing store.
+    Links {
+        #[arg(long)]
+        store: PathBuf,
+        #[arg(long)]
+        base_url: Url,
+        /// write the `id,link` csv to this file instead of stdout.
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+}
+
+/// csv row of `import`.
+#[derive(Deserialize)]
+struct CsvRoute {
+    uid: Id,
+    url: Url,
+    #[serde(default)]
+    mobile_url: Option<Url>,
+    #[serde(default)]
+    description: Option<String>,
+    #[serde(default)]
+    notes: Option<String>,
+}
+
+impl From<CsvRoute> for Route {
+    fn from(row: CsvRoute) -> Self {
+        Route {
+            uid: row.uid,
+            url: row.url,
+            mobile_url: row.mobile_url,
+            round_robin_urls: None,
+            geo_urls: None,
+            redirect_mode: None,
+            request_timeout_secs: None,
+            description: row.description,
+            notes: row.notes,
+            participant: None,
+            wave: None,
+            expires_at: None,
+        }
+    }
+}
+
+/// csv row of the output.
+#[derive(Serialize)]
+struct LinkRecord {
+    id: Id,
+    link: Url,
+}
+
+/// `--dry-run` output.
+#[derive(Serialize)]
+pub struct DryRunSummary {
+    pub api_url: Url,
+    /// `None` for sled stores, see `dry_run`.
+    pub routes: Option<usize>,
+    pub codes: Option<usize>,
+}
+
+/// summarize the stored tables of `config` without changing the store, so
+/// that it is safe next to a running server (BLOCKING!!): nothing is created,
+/// replayed or repaired. Sled stores are not opened, since a running server
+/// holds the lock of the database.
+pub fn dry_run(config: &Config) -> std::io::Result<DryRunSummary> {
+    let (routes, codes) = match config.storage_backend {
+        StorageBackend::File if config.storage_root.is_dir() => {
+            let (routes, codes) = utility::stored_table_sizes(&config.storage_root)?;
+            (Some(routes), Some(codes))
+        }
+        StorageBackend::File => (Some(0), Some(0)),
+        StorageBackend::Sled => (None, None),
+    };
+    Ok(DryRunSummary {
+        api_url: config.api_url(),
+        routes,
+        codes,
+    })
+}
+
+/// run an offline command (BLOCKING!!).
+pub fn run(command: Command) -> std::io::Result<()> {
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+    match command {
+        Command::Import {
+            csv,
+            store,
+            base_url,
+            out,
+            code_length,
+            code_prefix,
+        } => {
+            let config = offline_config(&base_url, &store, code_length, code_prefix.as_deref())?;
+            let routes = read_routes(&csv)?;
+            let state = RouterState::init(&config).map_err(state_error)?;
+            rt.block_on(state.put_routing_table(routes, None))
+                .map_err(state_error)?;
+            write_links(&rt, &state, out.as_deref())
+        }
+        Command::Links {
+            store,
+            base_url,
+            out,
+        } => {
+            if !store.is_dir() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!("store {} not found", store.display()),
+                ));
+            }
+            let config = offline_config(&base_url, &store, None, None)?;
+            let state = RouterState::init(&config).map_err(state_error)?;
+            write_links(&rt, &state, out.as_deref())
+        }
+    }
+}
+
+fn offline_config(
+    base_url: &Url,
+    store: &Path,
+    code_length: Option<usize>,
+    code_prefix: Option<&str>,
+) -> std::io::Result<Config> {
+    Config::offline(base_url, store, code_length, code_prefix)
+        .map_err(|e| std::io::Error::other(format!("invalid settings: {e}")))
+}
+
+fn read_routes(path: &Path) -> std::io::Result<Vec<Route>> {
+    csv::Reader::from_path(path)?
+        .into_deserialize::<CsvRoute>()
+        .map(|row| row.map(Route::from))
+        .collect::<Result<_, _>>()
+        .map_err(|e| std::io::Error::other(format!("csv error in {}: {e}", path.display())))
+}
+
+fn write_links(
+    rt: &tokio::runtime::Runtime,
+    state: &RouterState,
+    out: Option<&Path>,
+) -> std::io::Result<()> {
+    let links = rt.block_on(state.links()).map_err(state_error)?;
+    let writer: Box<dyn std::io::Write> = match out {
+        Some(path) => Box::new(std::fs::File::create(path)?),
+        None => Box::new(std::io::stdout().lock()),
+    };
+    let mut writer = csv::Writer::from_writer(writer);
+    for (id, link) in links {
+        writer
+            .serialize(LinkRecord { id, link })
+            .map_err(|e| std::io::Error::other(format!("csv error: {e}")))?;
+    }
+    writer.flush()
+}
+
+fn state_error(e: StateError) -> std::io::Error {
+    match e {
+        StateError::StoreError(e) => e,
+        StateError::InvalidRoute(e) => std::io::Error::other(format!("invalid route: {e}")),
+        e => std::io::Error::other(format!("{e:?}")),
+    }
+}
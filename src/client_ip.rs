@@ -0,0 +1,342 @@
+//! Client IP attribution behind trusted reverse proxies.
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::HeaderMap,
+    middleware::Next,
+    response::Response,
+};
+use ipnet::IpNet;
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+const FORWARDED: &str = "forwarded";
+const X_REAL_IP: &str = "x-real-ip";
+
+/// The address of the client that originated the request,
+/// inserted as a request extension by [`client_ip`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ClientIp(pub IpAddr);
+
+impl fmt::Display for ClientIp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// middleware resolving the client ip of every request.
+///
+/// Forwarding headers are only honored when the peer is a trusted proxy.
+pub async fn client_ip(
+    State(trusted_proxies): State<Arc<[IpNet]>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    let ip = resolve_client_ip(peer.ip(), req.headers(), &trusted_proxies);
+    req.extensions_mut().insert(ClientIp(ip));
+    next.run(req).await
+}
+
+/// Resolve the real client ip from the peer address and forwarding headers.
+///
+/// The chain is taken from the first present header: `X-Forwarded-For`,
+/// then `Forwarded`, then `X-Real-IP` (a single hop, as set by nginx).
+/// It is walked from the right (closest hop) and the first address that
+/// is not a trusted proxy is returned. A malformed hop stops the walk at
+/// the last well-formed hop (or the peer), since anything left of it may
+/// have been written by the client.
+pub fn resolve_client_ip(peer: IpAddr, headers: &HeaderMap, trusted_proxies: &[IpNet]) -> IpAddr {
+    if !is_trusted(peer, trusted_proxies) {
+        return peer;
+    }
+    let chain = if headers.contains_key(X_FORWARDED_FOR) {
+        parse_x_forwarded_for(headers)
+    } else if headers.contains_key(FORWARDED) {
+        parse_forwarded(headers)
+    } else if headers.contains_key(X_REAL_IP) {
+        parse_x_real_ip(headers)
+    } else {
+        return peer;
+    };
+    let mut client = peer;
+    for hop in chain.into_iter().rev() {
+        let Some(hop) = hop else {
+            break;
+        };
+        client = hop;
+        if !is_trusted(hop, trusted_proxies) {
+            break;
+        }
+    }
+    client
+}
+
+#[inline]
+fn is_trusted(ip: IpAddr, trusted_proxies: &[IpNet]) -> bool {
+    trusted_proxies.iter().any(|net| net.contains(&ip))
+}
+
+/// parse all `X-Forwarded-For` headers into a list of hops (left to right).
+///
+/// malformed hops are `None`.
+fn parse_x_forwarded_for(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    let mut hops = Vec::new();
+    for value in headers.get_all(X_FORWARDED_FOR) {
+        let Ok(value) = value.to_str() else {
+            hops.push(None);
+            continue;
+        };
+        hops.extend(value.split(',').map(|hop| parse_node(hop.trim())));
+    }
+    hops
+}
+
+/// parse the `for=` parameters of all RFC 7239 `Forwarded` headers.
+///
+/// malformed, obfuscated or `unknown` nodes are `None`.
+fn parse_forwarded(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    let mut hops = Vec::new();
+    for value in headers.get_all(FORWARDED) {
+        let Ok(value) = value.to_str() else {
+            hops.push(None);
+            continue;
+        };
+        for element in value.split(',') {
+            for pair in element.split(';') {
+                let Some((key, node)) = pair.trim().split_once('=') else {
+                    continue;
+                };
+                if key.trim().eq_ignore_ascii_case("for") {
+                    let node = node.trim();
+                    let node = node
+                        .strip_prefix('"')
+                        .and_then(|n| n.strip_suffix('"'))
+                        .unwrap_or(node);
+                    hops.push(parse_node(node));
+                }
+            }
+        }
+    }
+    hops
+}
+
+/// parse a single `X-Real-IP` header as a one hop chain.
+///
+/// a repeated or malformed header is a single `None` hop.
+fn parse_x_real_ip(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    let mut values = headers.get_all(X_REAL_IP).iter();
+    let hop = match (values.next(), values.next()) {
+        (Some(value), None) => value.to_str().ok().and_then(|v| parse_node(v.trim())),
+        _ => None,
+    };
+    vec![hop]
+}
+
+/// parse `1.2.3.4`, `1.2.3.4:80`, `::1`, `[::1]` or `[::1]:80`.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    if let Ok(ip) = node.parse::<IpAddr>() {
+        return Some(ip);
+    }
+    if let Some(rest) = node.strip_prefix('[') {
+        let (ip, port) = rest.split_once(']')?;
+        if !(port.is_empty() || port.strip_prefix(':')?.parse::<u16>().is_ok()) {
+            return None;
+        }
+        return ip.parse().ok();
+    }
+    node.parse::<SocketAddr>().ok().map(|addr| addr.ip())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn resolve(peer: &str, headers: &[(&'static str, &'static str)]) -> IpAddr {
+        let trusted: Vec<IpNet> = vec!["10.0.0.0/8".parse().unwrap()];
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            map.append(*name, HeaderValue::from_static(value));
+        }
+        resolve_client_ip(peer.parse().unwrap(), &map, &trusted)
+    }
+
+    #[test]
+    fn x_real_ip_from_trusted_proxy() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        assert_eq!(
+            resolve("10.0.0.1", &[("x-real-ip", "203.0.113.7")]),
+            ip("203.0.113.7")
+        );
+        // not from a trusted proxy
+        assert_eq!(
+            resolve("198.51.100.1", &[("x-real-ip", "203.0.113.7")]),
+            ip("198.51.100.1")
+        );
+        // X-Forwarded-For wins
+        assert_eq!(
+            resolve(
+                "10.0.0.1",
+                &[
+                    ("x-real-ip", "203.0.113.7"),
+                    ("x-forwarded-for", "192.0.2.5")
+                ]
+            ),
+            ip("192.0.2.5")
+        );
+        // malformed or repeated
+        assert_eq!(
+            resolve("10.0.0.1", &[("x-real-ip", "not an ip")]),
+            ip("10.0.0.1")
+        );
+        assert_eq!(
+            resolve(
+                "10.0.0.1",
+                &[("x-real-ip", "203.0.113.7"), ("x-real-ip", "203.0.113.8")]
+            ),
+            ip("10.0.0.1")
+        );
+    }
+
+    #[test]
+    fn x_forwarded_for_chain() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        // trusted hops are skipped from the right
+        assert_eq!(
+            resolve(
+                "10.0.0.1",
+                &[("x-forwarded-for", "203.0.113.7, 10.0.0.2, 10.0.0.3")]
+            ),
+            ip("203.0.113.7")
+        );
+        // hops left of the first untrusted one may be spoofed
+        assert_eq!(
+            resolve(
+                "10.0.0.1",
+                &[("x-forwarded-for", "198.51.100.9, 203.0.113.7, 10.0.0.2")]
+            ),
+            ip("203.0.113.7")
+        );
+        // repeated headers form one chain
+        assert_eq!(
+            resolve(
+                "10.0.0.1",
+                &[
+                    ("x-forwarded-for", "203.0.113.7"),
+                    ("x-forwarded-for", "10.0.0.2")
+                ]
+            ),
+            ip("203.0.113.7")
+        );
+        // every hop trusted
+        assert_eq!(
+            resolve("10.0.0.1", &[("x-forwarded-for", "10.0.0.3, 10.0.0.2")]),
+            ip("10.0.0.3")
+        );
+        // with ports
+        assert_eq!(
+            resolve(
+                "10.0.0.1",
+                &[("x-forwarded-for", "[2001:db8::1]:4711, 10.0.0.2:80")]
+            ),
+            ip("2001:db8::1")
+        );
+    }
+
+    #[test]
+    fn forwarded_chain() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        assert_eq!(
+            resolve(
+                "10.0.0.1",
+                &[(
+                    "forwarded",
+                    r#"for=198.51.100.9;proto=https, for="[2001:db8::1]:4711", For=10.0.0.2;by=10.0.0.1"#
+                )]
+            ),
+            ip("2001:db8::1")
+        );
+        assert_eq!(
+            resolve(
+                "10.0.0.1",
+                &[
+                    ("forwarded", r#"for="203.0.113.7:47011""#),
+                    ("forwarded", "for=10.0.0.2")
+                ]
+            ),
+            ip("203.0.113.7")
+        );
+    }
+
+    #[test]
+    fn malformed_hops_stop_the_walk() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        for headers in [
+            &[("x-forwarded-for", "203.0.113.7, not an ip")][..],
+            &[("x-forwarded-for", "[2001:db8::1]:port")],
+            &[("forwarded", "for=unknown")],
+            &[("forwarded", r#"for="[2001:db8::1""#)],
+        ] {
+            assert_eq!(resolve("10.0.0.1", headers), ip("10.0.0.1"), "{headers:?}");
+        }
+        // the walk stops at the last well-formed hop
+        assert_eq!(
+            resolve("10.0.0.1", &[("x-forwarded-for", "203.0.113.7,,10.0.0.2")]),
+            ip("10.0.0.2")
+        );
+        assert_eq!(
+            resolve("10.0.0.1", &[("forwarded", "for=_hidden, for=10.0.0.2")]),
+            ip("10.0.0.2")
+        );
+    }
+
+    #[test]
+    fn malformed_header_does_not_fall_through() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        // nginx appends the real address to a client supplied `X-Forwarded-For: x`
+        assert_eq!(
+            resolve(
+                "10.0.0.1",
+                &[
+                    ("x-forwarded-for", "x, 203.0.113.7"),
+                    ("forwarded", "for=10.1.2.3")
+                ]
+            ),
+            ip("203.0.113.7")
+        );
+        assert_eq!(
+            resolve(
+                "10.0.0.1",
+                &[
+                    ("x-forwarded-for", "x"),
+                    ("forwarded", "for=10.1.2.3"),
+                    ("x-real-ip", "10.1.2.4")
+                ]
+            ),
+            ip("10.0.0.1")
+        );
+    }
+
+    #[test]
+    fn untrusted_peer_ignores_headers() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        for headers in [
+            &[("x-forwarded-for", "203.0.113.7")][..],
+            &[("forwarded", "for=203.0.113.7")],
+            &[
+                ("x-forwarded-for", "10.0.0.2"),
+                ("forwarded", "for=10.0.0.3"),
+            ],
+        ] {
+            assert_eq!(
+                resolve("198.51.100.1", headers),
+                ip("198.51.100.1"),
+                "{headers:?}"
+            );
+        }
+    }
+}
@@ -1,31 +1,750 @@
-use crate::CONFIG_FILE_NAME;
+use crate::{
+    redirect_page::URL_PLACEHOLDER, state::RedirectMode, utility::load_admin_token, API,
+    BODY_LIMIT, CODE_LENGTH, CONFIG_FILE_NAME, DEFAULT_TIMEOUT, EXTERNEL_ID,
+};
 use config::{Config as Conf, ConfigError};
-use serde::Deserialize;
-use std::{net::SocketAddr, path::PathBuf};
+use ipnet::IpNet;
+use serde::{Deserialize, Deserializer};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{atomic::AtomicU64, Arc},
+};
+use tower_http::CompressionLevel;
 use url::Url;
 
+pub(crate) const MIN_CODE_LENGTH: usize = 8;
+pub(crate) const MAX_CODE_LENGTH: usize = 64;
+const MAX_CODE_PREFIX_LENGTH: usize = 8;
+/// random characters left after `code_prefix`, so that codes stay unguessable
+/// and collisions rare.
+const MIN_RANDOM_CODE_LENGTH: usize = 8;
+
 #[derive(Deserialize)]
 pub struct Config {
-    pub server_binding: SocketAddr,
+    /// `ip:port`, or `unix:/path/to/socket` for a unix domain socket,
+    /// or a list of those.
+    #[serde(deserialize_with = "one_or_many")]
+    pub server_binding: Vec<Bind>,
+    /// serve the admin api on its own address(es) instead of `server_binding`.
+    #[serde(default, deserialize_with = "optional_one_or_many")]
+    pub admin_binding: Option<Vec<Bind>>,
+    /// serve `/metrics` without the admin token on its own address(es),
+    /// instead of `/admin/metrics`.
+    #[serde(default, deserialize_with = "optional_one_or_many")]
+    pub metrics_binding: Option<Vec<Bind>>,
+    /// permission bits of unix domain sockets, e.g. `0o660`.
+    /// Unix domain sockets are always served without tls.
+    pub unix_socket_mode: Option<u32>,
+    /// public address of the server, links are `<base_url>/<path_prefix>/api?code=...`.
+    /// A path is kept, e.g. for a reverse proxy that strips it.
     pub base_url: Url,
+    /// serve every route under this path (e.g. `/redirect`), for a reverse
+    /// proxy that passes its mount path through.
+    pub path_prefix: Option<String>,
+    /// where `/api/complete` redirects participants who finished their survey,
+    /// a built-in thank-you page is shown if unset.
+    pub thank_you_url: Option<Url>,
+    /// bearer token of the admin api, overridden by a token rotated
+    /// through `PATCH /admin/admin_token`.
     pub admin_token: String,
+    /// failed admin token checks of a client ip, within `admin_auth_failure_window_secs`,
+    /// after which it gets 429 for a backoff that doubles with every further failure.
+    #[serde(default = "default_admin_auth_max_failures")]
+    pub admin_auth_max_failures: u32,
+    #[serde(default = "default_admin_auth_failure_window_secs")]
+    pub admin_auth_failure_window_secs: u64,
     pub storage_root: PathBuf,
+    /// `file` (default, json snapshots) or `sled` (needs the `sled-storage` feature).
+    #[serde(default)]
+    pub storage_backend: StorageBackend,
+    /// append each router table to `wal.log` before its snapshot is persisted,
+    /// so that a crash in between does not lose it (file backend only).
+    #[serde(default)]
+    pub write_ahead_log: bool,
+    /// at load, give ids that share a code with another id new codes
+    /// (logged), instead of failing.
+    #[serde(default)]
+    pub repair_duplicate_codes: bool,
     pub log_file: PathBuf,
     pub watch_cert_changes: Option<PathBuf>,
+    /// required by tcp bindings, unless built with the `insecure` feature.
     pub server_tls: Option<TlsConfig>,
+    /// certificate of `admin_binding`, which uses `server_tls` if unset.
+    pub admin_tls: Option<TlsConfig>,
+    #[serde(default = "default_code_length")]
+    pub code_length: usize,
+    /// prepended to newly generated codes, counts towards `code_length`.
+    /// At least 8 random characters must be left after the prefix.
+    pub code_prefix: Option<String>,
+    /// `random` (default) or `sequential` (debug builds only, for tests).
+    #[serde(default)]
+    pub code_gen_mode: CodeGenMode,
+    /// how long connections may take to finish at shutdown before being aborted.
+    #[serde(default = "default_shutdown_timeout_secs")]
+    pub shutdown_timeout_secs: u64,
+    /// close http/1 connections that send no request for this long,
+    /// e.g. idle keep-alive connections. Unlimited if unset.
+    pub idle_connection_timeout_secs: Option<u64>,
+    /// pending connections queued by tcp listeners, for connection bursts.
+    /// Linux silently caps it at `net.core.somaxconn`.
+    #[serde(default = "default_tcp_backlog")]
+    pub tcp_backlog: u32,
+    /// how often hit counts are flushed to disk.
+    #[serde(default = "default_hit_flush_interval_secs")]
+    pub hit_flush_interval_secs: u64,
+    /// seconds PATCHes wait before writing a snapshot of the tables, pushed
+    /// back by every PATCH meanwhile, so that a burst of them writes one.
+    /// 0 writes immediately, as do PATCHes with `durable=true`.
+    #[serde(default = "default_snapshot_debounce_secs")]
+    pub snapshot_debounce_secs: u64,
+    /// seconds after the first PATCH of a snapshot that it is written at the
+    /// latest, however often PATCHes push it back.
+    #[serde(default = "default_snapshot_max_delay_secs")]
+    pub snapshot_max_delay_secs: u64,
+    /// `never` (default), `hourly`, `daily`, or `{size_mb: <n>}`.
+    #[serde(default)]
+    pub log_rotation: LogRotation,
+    /// name of the log file and prefix of rotated files, in the directory of
+    /// `log_file`. Defaults to the file name of `log_file`.
+    pub log_file_prefix: Option<String>,
+    /// log files kept at rotation, including the current one.
+    #[serde(default = "default_log_keep_files")]
+    pub log_keep_files: usize,
+    /// write access log lines to this file instead of `log_file`.
+    pub access_log_file: Option<PathBuf>,
+    /// write one line per redirect (code, outcome, target host) to this file.
+    pub click_log_file: Option<PathBuf>,
+    /// request paths excluded from the access log (e.g. health checks).
+    #[serde(default)]
+    pub access_log_exclude: Vec<String>,
+    /// send `Cache-Control: no-store` etc. with redirects, so that going back
+    /// to a link hits the server again.
+    #[serde(default = "default_true")]
+    pub disable_redirect_caching: bool,
+    /// `http` (default, 303 redirects) or `html` (a page with a meta refresh
+    /// and a link), for routes without their own `redirect_mode`.
+    #[serde(default)]
+    pub redirect_mode: RedirectMode,
+    /// html file replacing the built-in page of `redirect_mode: html`,
+    /// `{{url}}` is replaced by the escaped target url.
+    pub redirect_page_template: Option<PathBuf>,
+    /// directory of `404.html` (unknown codes) and `500.html` (unexpected
+    /// errors) replacing the plain text responses of `/api`, reread when
+    /// its files change. Missing files keep the plain text.
+    pub error_templates_dir: Option<PathBuf>,
+    /// admin requests handled at once, excess requests get 503.
+    #[serde(default = "default_admin_concurrency_limit")]
+    pub admin_concurrency_limit: usize,
+    /// `/api` requests handled at once, excess requests get 503.
+    #[serde(default = "default_api_concurrency_limit")]
+    pub api_concurrency_limit: usize,
+    /// send `X-Robots-Tag: noindex, nofollow` with `/api` responses.
+    #[serde(default = "default_true")]
+    pub robots_noindex: bool,
+    /// size limit of uploaded tables after decompression, answered with 413.
+    #[serde(default = "default_max_decoded_body_size")]
+    pub max_decoded_body_size: usize,
+    /// size limit of `PUT` and `PATCH` bodies of the routing table, answered
+    /// with 413. Other admin endpoints take small json bodies or none.
+    #[serde(default = "default_max_upload_bytes")]
+    pub max_upload_bytes: usize,
+    /// encodings of admin requests and responses.
+    #[serde(default)]
+    pub compression: CompressionConfig,
+    /// responses to `Idempotency-Key` uploads kept for replay.
+    #[serde(default = "default_idempotency_cache_size")]
+    pub idempotency_cache_size: usize,
+    /// how long an `Idempotency-Key` response is replayed.
+    #[serde(default = "default_idempotency_ttl_secs")]
+    pub idempotency_ttl_secs: u64,
+    /// body of `/robots.txt`.
+    #[serde(default = "default_robots_txt")]
+    pub robots_txt: String,
+    /// icon served at `/favicon.ico`, which answers 204 otherwise.
+    pub favicon: Option<PathBuf>,
+    /// also check that `storage_root` is writable in `/readyz`.
+    #[serde(default)]
+    pub readiness_probe_storage: bool,
+    /// admin api is served at `/{api_version}/admin`, with the legacy `/admin`
+    /// kept as deprecated. `null` serves only `/admin`.
+    #[serde(default = "default_api_version")]
+    pub api_version: Option<String>,
+    /// hosts routes must not redirect to, besides the host of `base_url`.
+    #[serde(default)]
+    pub denied_target_hosts: Vec<String>,
+    /// `/api` requests whose `Referer` matches any of these regexes get 204
+    /// instead of a redirect, and are not counted (e.g. link preview services).
+    #[serde(default)]
+    pub blocked_referrers: Vec<String>,
+    /// query parameters appended to every redirect target after `externalUserId`,
+    /// e.g. `utm_source`. Parameters already in the target are kept instead.
+    /// Also accepted as `global_append_params`.
+    #[serde(default, alias = "global_append_params")]
+    pub global_query_params: BTreeMap<String, String>,
+    /// MaxMind country database for `geo_urls` of routes, which are
+    /// ignored without it, also while the file is missing or broken.
+    /// Reread by `/admin/reload`.
+    pub geoip_database: Option<PathBuf>,
+    /// histogram buckets (seconds) of the redirect latency metrics.
+    #[serde(default = "default_metrics_latency_buckets")]
+    pub metrics_latency_buckets: Vec<f64>,
+    /// peers allowed to set `X-Forwarded-For` / `Forwarded` headers.
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpNet>,
+    /// only clients in these networks reach the admin api, checked before
+    /// the admin token. Any client if unset.
+    pub admin_allowed_networks: Option<Vec<IpNet>>,
+}
+
+/// Address of a listener.
+#[derive(Clone, Debug)]
+pub enum Bind {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+const UNIX_BIND_PREFIX: &str = "unix:";
+
+impl<'de> Deserialize<'de> for Bind {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let bind = String::deserialize(deserializer)?;
+        match bind.strip_prefix(UNIX_BIND_PREFIX) {
+            Some(path) if !path.is_empty() => Ok(Bind::Unix(PathBuf::from(path))),
+            Some(_) => Err(serde::de::Error::custom("empty unix socket path")),
+            None => bind
+                .parse()
+                .map(Bind::Tcp)
+                .map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+impl fmt::Display for Bind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Bind::Tcp(addr) => addr.fmt(f),
+            Bind::Unix(path) => write!(f, "{UNIX_BIND_PREFIX}{}", path.display()),
+        }
+    }
+}
+
+/// accept a single value or a list.
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Bind>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(Bind),
+        Many(Vec<Bind>),
+    }
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(bind) => vec![bind],
+        OneOrMany::Many(binds) => binds,
+    })
+}
+
+fn optional_one_or_many<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Vec<Bind>>, D::Error> {
+    one_or_many(deserializer).map(Some)
+}
+
+/// How new codes are generated.
+#[derive(Deserialize, Clone, Default)]
+#[serde(from = "CodeGenModeName")]
+pub enum CodeGenMode {
+    #[default]
+    Random,
+    /// zero-padded counter `0..01`, `0..02`, ... for reproducible tests.
+    Sequential(Arc<AtomicU64>),
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum CodeGenModeName {
+    Random,
+    Sequential,
+}
+
+impl From<CodeGenModeName> for CodeGenMode {
+    fn from(name: CodeGenModeName) -> Self {
+        match name {
+            CodeGenModeName::Random => CodeGenMode::Random,
+            CodeGenModeName::Sequential => CodeGenMode::Sequential(Arc::new(AtomicU64::new(0))),
+        }
+    }
+}
+
+/// Where the router and code tables are stored.
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageBackend {
+    #[default]
+    File,
+    Sled,
+}
+
+/// When the log file is rotated.
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum LogRotation {
+    #[default]
+    Never,
+    Hourly,
+    Daily,
+    /// once it exceeds this many MiB.
+    SizeMb(u64),
+}
+
+/// Encodings accepted in admin request bodies and offered in admin responses.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct CompressionConfig {
+    pub gzip: bool,
+    pub br: bool,
+    pub zstd: bool,
+    /// `fastest`, `default`, `best` or an encoder-specific level,
+    /// e.g. brotli 0-11, zstd 1-22.
+    pub level: CompressionQuality,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            gzip: true,
+            br: true,
+            zstd: true,
+            level: CompressionQuality::Named(NamedQuality::Default),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(untagged)]
+pub enum CompressionQuality {
+    Named(NamedQuality),
+    Precise(i32),
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum NamedQuality {
+    Fastest,
+    /// brotli defaults to 4, as its own default is too slow for large responses.
+    Default,
+    Best,
+}
+
+impl From<CompressionQuality> for CompressionLevel {
+    fn from(quality: CompressionQuality) -> Self {
+        match quality {
+            CompressionQuality::Named(NamedQuality::Fastest) => CompressionLevel::Fastest,
+            CompressionQuality::Named(NamedQuality::Default) => CompressionLevel::Default,
+            CompressionQuality::Named(NamedQuality::Best) => CompressionLevel::Best,
+            CompressionQuality::Precise(level) => CompressionLevel::Precise(level),
+        }
+    }
 }
 
 #[derive(Deserialize, Clone)]
 pub struct TlsConfig {
     pub key: PathBuf,
     pub cert: PathBuf,
+    /// resume sessions with stateless tickets, sparing returning clients a
+    /// full handshake. Otherwise sessions are resumed from a small cache.
+    /// Tickets and the cache survive certificate reloads.
+    #[serde(default = "default_true")]
+    pub session_tickets: bool,
+    /// seconds a ticket key issues tickets before it is rotated, tickets are
+    /// accepted for twice as long.
+    #[serde(default = "default_ticket_rotation_secs")]
+    pub ticket_rotation_secs: u32,
+    /// bytes of tls 1.3 early data (0-RTT) accepted from resumed clients,
+    /// 0 (default) disables it.
+    ///
+    /// Early data saves a round trip, but is not protected against replay:
+    /// whoever records a connection can send its first request again. A
+    /// replayed redirect counts another hit. Requests other than GET and
+    /// HEAD, and `/api/complete` which records a completion, get
+    /// `425 Too Early` when sent as early data, so that clients retry after
+    /// the handshake.
+    #[serde(default)]
+    pub max_early_data_size: u32,
 }
 
 impl Config {
     pub fn load() -> Result<Self, ConfigError> {
+        Self::from_source(config::File::with_name(CONFIG_FILE_NAME))
+    }
+
+    /// settings for offline commands, which only touch the store.
+    pub fn offline(
+        base_url: &Url,
+        storage_root: &Path,
+        code_length: Option<usize>,
+        code_prefix: Option<&str>,
+    ) -> Result<Self, ConfigError> {
         let config = Conf::builder()
-            .add_source(config::File::with_name(CONFIG_FILE_NAME))
+            .set_default("server_binding", "127.0.0.1:0")?
+            .set_default("admin_token", "")?
+            .set_default("log_file", "survey_redirect.log")?
+            .set_override("base_url", base_url.as_str())?
+            .set_override("storage_root", storage_root.to_string_lossy().as_ref())?
+            .set_override_option("code_length", code_length.map(|l| l as u64))?
+            .set_override_option("code_prefix", code_prefix)?
             .build()?;
-        config.try_deserialize()
+        let config: Self = config.try_deserialize()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// parse a yaml document, e.g. in tests.
+    pub fn from_yaml(yaml: &str) -> Result<Self, ConfigError> {
+        Self::from_source(config::File::from_str(yaml, config::FileFormat::Yaml))
+    }
+
+    /// the public url of `/api`, participant links add their code to it.
+    pub fn api_url(&self) -> Url {
+        let mut url = self.base_url.clone();
+        let path = format!(
+            "{}{}/{API}",
+            url.path().trim_end_matches('/'),
+            self.path_prefix.as_deref().unwrap_or_default()
+        );
+        url.set_path(&path);
+        url.set_query(None);
+        url.set_fragment(None);
+        url
     }
+
+    /// directory and name of the (current) log file.
+    pub fn log_file_location(&self) -> (PathBuf, String) {
+        let dir = match self.log_file.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_owned(),
+            _ => PathBuf::from("."),
+        };
+        let prefix = self.log_file_prefix.clone().unwrap_or_else(|| {
+            self.log_file
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default()
+        });
+        (dir, prefix)
+    }
+
+    fn from_source<S: config::Source + Send + Sync + 'static>(
+        source: S,
+    ) -> Result<Self, ConfigError> {
+        let config = Conf::builder().add_source(source).build()?;
+        let mut config: Self = config.try_deserialize()?;
+        // a rotated token replaces the one of the config file
+        if let Some(token) = load_admin_token(&config.storage_root).map_err(|e| {
+            ConfigError::Message(format!("failed to read the rotated admin token: {e}"))
+        })? {
+            config.admin_token = token;
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// check value ranges not expressible in serde.
+    fn validate(&self) -> Result<(), ConfigError> {
+        if !(MIN_CODE_LENGTH..=MAX_CODE_LENGTH).contains(&self.code_length) {
+            return Err(ConfigError::Message(format!(
+                "code_length must be between {MIN_CODE_LENGTH} and {MAX_CODE_LENGTH}, got {}",
+                self.code_length
+            )));
+        }
+        if matches!(self.code_gen_mode, CodeGenMode::Sequential(_)) && cfg!(not(debug_assertions)) {
+            // sequential codes are guessable
+            return Err(ConfigError::Message(
+                "code_gen_mode sequential is only available in debug builds".to_owned(),
+            ));
+        }
+        if let Some(prefix) = &self.code_prefix {
+            if prefix.len() > MAX_CODE_PREFIX_LENGTH
+                || !prefix.chars().all(|c| c.is_ascii_alphanumeric())
+            {
+                return Err(ConfigError::Message(format!(
+                    "code_prefix must be at most {MAX_CODE_PREFIX_LENGTH} alphanumeric characters, got {prefix:?}"
+                )));
+            }
+            if prefix.len() >= self.code_length {
+                return Err(ConfigError::Message(format!(
+                    "code_prefix {prefix:?} leaves no random characters for code_length {}",
+                    self.code_length
+                )));
+            }
+            if matches!(self.code_gen_mode, CodeGenMode::Random)
+                && self.code_length - prefix.len() < MIN_RANDOM_CODE_LENGTH
+            {
+                return Err(ConfigError::Message(format!(
+                    "code_prefix {prefix:?} leaves less than {MIN_RANDOM_CODE_LENGTH} random characters for code_length {}",
+                    self.code_length
+                )));
+            }
+        }
+        if self.server_binding.is_empty()
+            || self.admin_binding.as_ref().is_some_and(Vec::is_empty)
+            || self.metrics_binding.as_ref().is_some_and(Vec::is_empty)
+        {
+            return Err(ConfigError::Message(
+                "server_binding, admin_binding and metrics_binding must not be empty".to_owned(),
+            ));
+        }
+        // unix sockets of `admin_binding` and `metrics_binding` are served
+        // without the `server_tls` they fall back to
+        if self.server_tls.is_some()
+            && self
+                .server_binding
+                .iter()
+                .any(|bind| matches!(bind, Bind::Unix(_)))
+        {
+            return Err(ConfigError::Message(
+                "tls is not supported on unix domain sockets".to_owned(),
+            ));
+        }
+        if self
+            .server_tls
+            .iter()
+            .chain(&self.admin_tls)
+            .any(|tls| tls.ticket_rotation_secs == 0)
+        {
+            return Err(ConfigError::Message(
+                "ticket_rotation_secs must be positive".to_owned(),
+            ));
+        }
+        if self.admin_tls.is_some() {
+            let Some(admin_binding) = &self.admin_binding else {
+                return Err(ConfigError::Message(
+                    "admin_tls requires admin_binding".to_owned(),
+                ));
+            };
+            if admin_binding
+                .iter()
+                .any(|bind| matches!(bind, Bind::Unix(_)))
+            {
+                return Err(ConfigError::Message(
+                    "tls is not supported on unix domain sockets".to_owned(),
+                ));
+            }
+        }
+        if self.storage_backend == StorageBackend::Sled && !cfg!(feature = "sled-storage") {
+            return Err(ConfigError::Message(
+                "storage_backend sled requires the sled-storage feature".to_owned(),
+            ));
+        }
+        if self.max_upload_bytes == 0 {
+            return Err(ConfigError::Message(
+                "max_upload_bytes must be positive".to_owned(),
+            ));
+        }
+        if self.admin_auth_max_failures == 0 || self.admin_auth_failure_window_secs == 0 {
+            return Err(ConfigError::Message(
+                "admin_auth_max_failures and admin_auth_failure_window_secs must be positive"
+                    .to_owned(),
+            ));
+        }
+        if self.admin_concurrency_limit == 0 || self.api_concurrency_limit == 0 {
+            return Err(ConfigError::Message(
+                "admin_concurrency_limit and api_concurrency_limit must be positive".to_owned(),
+            ));
+        }
+        if let Some(prefix) = &self.path_prefix {
+            if prefix.len() < 2
+                || !prefix.starts_with('/')
+                || prefix.ends_with('/')
+                || prefix.contains(['?', '#'])
+            {
+                return Err(ConfigError::Message(format!(
+                    "path_prefix must start with / and not end with /, got {prefix:?}"
+                )));
+            }
+        }
+        if let Some(api_version) = &self.api_version {
+            if api_version.is_empty() || !api_version.chars().all(|c| c.is_ascii_alphanumeric()) {
+                return Err(ConfigError::Message(
+                    "api_version must be non-empty and alphanumeric".to_owned(),
+                ));
+            }
+        }
+        if let Some(favicon) = &self.favicon {
+            if !favicon.is_file() {
+                return Err(ConfigError::Message(format!(
+                    "favicon {} is not a file",
+                    favicon.display()
+                )));
+            }
+        }
+        if let Some(name) = self
+            .global_query_params
+            .keys()
+            .find(|name| name.is_empty() || name.eq_ignore_ascii_case(EXTERNEL_ID))
+        {
+            return Err(ConfigError::Message(format!(
+                "global_query_params must not set {name:?}"
+            )));
+        }
+        if let Some(template) = &self.redirect_page_template {
+            let content = std::fs::read_to_string(template).map_err(|e| {
+                ConfigError::Message(format!(
+                    "failed to read redirect_page_template {}: {e}",
+                    template.display()
+                ))
+            })?;
+            if !content.contains(URL_PLACEHOLDER) {
+                return Err(ConfigError::Message(format!(
+                    "redirect_page_template {} does not contain {URL_PLACEHOLDER}",
+                    template.display()
+                )));
+            }
+        }
+        if let Some(dir) = &self.error_templates_dir {
+            if !dir.is_dir() {
+                return Err(ConfigError::Message(format!(
+                    "error_templates_dir {} is not a directory",
+                    dir.display()
+                )));
+            }
+        }
+        if self
+            .admin_allowed_networks
+            .as_ref()
+            .is_some_and(|networks| networks.is_empty())
+        {
+            return Err(ConfigError::Message(
+                "admin_allowed_networks must not be empty, leave it unset to allow any client"
+                    .to_owned(),
+            ));
+        }
+        if self.tcp_backlog == 0 {
+            return Err(ConfigError::Message(
+                "tcp_backlog must be positive".to_owned(),
+            ));
+        }
+        if self.idle_connection_timeout_secs == Some(0) {
+            return Err(ConfigError::Message(
+                "idle_connection_timeout_secs must be positive".to_owned(),
+            ));
+        }
+        if self.log_keep_files == 0 {
+            return Err(ConfigError::Message(
+                "log_keep_files must be positive".to_owned(),
+            ));
+        }
+        if matches!(self.log_rotation, LogRotation::SizeMb(0)) {
+            return Err(ConfigError::Message(
+                "log_rotation size_mb must be positive".to_owned(),
+            ));
+        }
+        if self.idempotency_cache_size == 0 {
+            return Err(ConfigError::Message(
+                "idempotency_cache_size must be positive".to_owned(),
+            ));
+        }
+        if let Err(e) = regex::RegexSet::new(&self.blocked_referrers) {
+            return Err(ConfigError::Message(format!(
+                "invalid blocked_referrers: {e}"
+            )));
+        }
+        if self.metrics_latency_buckets.is_empty()
+            || !self
+                .metrics_latency_buckets
+                .iter()
+                .all(|b| b.is_finite() && *b > 0.0)
+            || !self.metrics_latency_buckets.windows(2).all(|w| w[0] < w[1])
+        {
+            return Err(ConfigError::Message(
+                "metrics_latency_buckets must be positive and increasing".to_owned(),
+            ));
+        }
+        if self.hit_flush_interval_secs == 0 {
+            return Err(ConfigError::Message(
+                "hit_flush_interval_secs must be positive".to_owned(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn default_admin_concurrency_limit() -> usize {
+    2
+}
+
+fn default_admin_auth_max_failures() -> u32 {
+    10
+}
+
+fn default_admin_auth_failure_window_secs() -> u64 {
+    60
+}
+
+fn default_api_concurrency_limit() -> usize {
+    1024
+}
+
+fn default_metrics_latency_buckets() -> Vec<f64> {
+    vec![0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0]
+}
+
+fn default_log_keep_files() -> usize {
+    7
+}
+
+fn default_max_decoded_body_size() -> usize {
+    BODY_LIMIT
+}
+
+fn default_max_upload_bytes() -> usize {
+    BODY_LIMIT
+}
+
+fn default_idempotency_cache_size() -> usize {
+    1000
+}
+
+fn default_idempotency_ttl_secs() -> u64 {
+    24 * 60 * 60
+}
+
+fn default_robots_txt() -> String {
+    "User-agent: *\nDisallow: /\n".to_owned()
+}
+
+fn default_api_version() -> Option<String> {
+    Some("v1".to_owned())
+}
+
+fn default_shutdown_timeout_secs() -> u64 {
+    DEFAULT_TIMEOUT.as_secs()
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_ticket_rotation_secs() -> u32 {
+    6 * 60 * 60
+}
+
+fn default_code_length() -> usize {
+    CODE_LENGTH
+}
+
+fn default_tcp_backlog() -> u32 {
+    1024
+}
+
+fn default_hit_flush_interval_secs() -> u64 {
+    60
+}
+
+fn default_snapshot_debounce_secs() -> u64 {
+    5
+}
+
+fn default_snapshot_max_delay_secs() -> u64 {
+    30
 }
@@ -0,0 +1,62 @@
+//! Html pages of failed redirects, read from `Config::error_templates_dir`.
+use crate::{handler::internal_error, request_id::RequestId};
+use axum::{
+    body::Bytes,
+    http::StatusCode,
+    response::{Html, IntoResponse, Response},
+};
+use std::path::Path;
+
+/// shown for unknown codes.
+pub const NOT_FOUND_PAGE: &str = "404.html";
+/// shown for unexpected errors.
+pub const INTERNAL_ERROR_PAGE: &str = "500.html";
+
+/// the pages of a template directory, plain text is sent for missing ones.
+#[derive(Default)]
+pub struct ErrorPages {
+    pub not_found: Option<Bytes>,
+    pub internal_error: Option<Bytes>,
+}
+
+impl ErrorPages {
+    /// read the pages in `dir`, which may be missing.
+    ///
+    /// This is a blocking function.
+    pub fn load(dir: &Path) -> Self {
+        Self {
+            not_found: read_page(&dir.join(NOT_FOUND_PAGE)),
+            internal_error: read_page(&dir.join(INTERNAL_ERROR_PAGE)),
+        }
+    }
+
+    /// 404 of an unknown code.
+    pub fn not_found(&self) -> Response {
+        match &self.not_found {
+            Some(page) => (StatusCode::NOT_FOUND, Html(page.clone())).into_response(),
+            None => (StatusCode::NOT_FOUND, "invalid code").into_response(),
+        }
+    }
+
+    /// 500 of an unexpected error, the plain text quotes the request id.
+    pub fn internal_error(&self, request_id: &RequestId) -> Response {
+        match &self.internal_error {
+            Some(page) => (StatusCode::INTERNAL_SERVER_ERROR, Html(page.clone())).into_response(),
+            None => internal_error("internal error", request_id),
+        }
+    }
+}
+
+fn read_page(path: &Path) -> Option<Bytes> {
+    match std::fs::read(path) {
+        Ok(page) => {
+            tracing::info!("error page loaded from {}", path.display());
+            Some(page.into())
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => {
+            tracing::warn!("failed to read {}: {e}", path.display());
+            None
+        }
+    }
+}
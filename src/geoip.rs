@@ -0,0 +1,54 @@
+//! Country of client ips from a MaxMind database, for `geo_urls` of routes.
+use maxminddb::{geoip2, MaxMindDBError, Reader};
+use std::{net::IpAddr, path::Path};
+
+/// `geo_urls` key matching the member states of the European Union.
+pub const EUROPEAN_UNION: &str = "EU";
+
+/// An opened GeoIP2 / GeoLite2 country (or city) database.
+pub struct GeoIp {
+    reader: Reader<Vec<u8>>,
+}
+
+/// Where a client ip is located, as far as `geo_urls` are concerned.
+pub struct GeoLocation {
+    /// ISO 3166-1 alpha-2 code, upper case.
+    pub country: Option<String>,
+    pub in_european_union: bool,
+}
+
+impl GeoIp {
+    /// read the mmdb file at `path` into memory.
+    ///
+    /// This is a blocking function.
+    pub fn open(path: &Path) -> Result<Self, MaxMindDBError> {
+        Ok(Self {
+            reader: Reader::open_readfile(path)?,
+        })
+    }
+
+    /// `None` if `ip` is not in the database, or the lookup fails.
+    pub fn lookup(&self, ip: IpAddr) -> Option<GeoLocation> {
+        let record: geoip2::Country = self.reader.lookup(ip).ok()?;
+        let country = record.country?;
+        Some(GeoLocation {
+            country: country.iso_code.map(str::to_ascii_uppercase),
+            in_european_union: country.is_in_european_union.unwrap_or(false),
+        })
+    }
+}
+
+impl GeoLocation {
+    /// the `geo_urls` keys matching this location, most specific first.
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        self.country
+            .as_deref()
+            .into_iter()
+            .chain(self.in_european_union.then_some(EUROPEAN_UNION))
+    }
+}
+
+/// whether `key` can match a location: an upper case country code, or `EU`.
+pub fn is_valid_key(key: &str) -> bool {
+    key.len() == 2 && key.bytes().all(|b| b.is_ascii_uppercase())
+}
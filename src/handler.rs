@@ -1,132 +1,1415 @@
-use crate::state::{RedirectParams, Route, RouterState, StateError};
+use crate::{
+    client_ip::ClientIp,
+    idempotency::idempotency_key,
+    monitoring::{
+        ADMIN_OPERATION_DURATION_SECONDS, BUSY_RESPONSES_TOTAL, REDIRECT_DURATION_SECONDS,
+        REDIRECT_RESOLUTION_DURATION_SECONDS,
+    },
+    redirect_page,
+    request_id::RequestId,
+    state::{
+        join_ids, parse_table, parse_xlsx, AdminTokenRotation, BulkIds, Code, Completion,
+        CompletionsFormat, CompletionsParams, ConflictResolution, Id, LinksFormat, LinksParams,
+        PatchMode, PatchParams, PutParams, RedirectMode, RedirectParams, RedirectTarget,
+        RekeyRequest, Route, RouteHistoryParams, RouterState, RoutingTableFormat,
+        RoutingTableParams, RuntimeInfo, SearchCodesParams, SearchRoutesParams, StateError,
+        TableFormat, TableRoute,
+    },
+    timeout::RequestTimeout,
+    trace_context,
+    utility::storage_statistics,
+    MERGE_PATCH_CONTENT_TYPE, XLSX_CONTENT_TYPE, X_SKIPPED_ROWS, X_SURVEY_TIMEOUT, X_TABLE_FORMAT,
+    X_TABLE_VERSION,
+};
 use axum::{
-    body::Body,
-    extract::{Query, State},
-    http::{Request, StatusCode},
-    response::{IntoResponse, Redirect, Response},
+    body::{Body, Bytes},
+    extract::{Path, Query, RawQuery, State},
+    http::{
+        header::{
+            ACCEPT, CACHE_CONTROL, CONTENT_TYPE, EXPIRES, IF_MATCH, PRAGMA, REFERER, RETRY_AFTER,
+            USER_AGENT,
+        },
+        HeaderMap, HeaderName, HeaderValue, Request, StatusCode,
+    },
+    response::{Html, IntoResponse, Redirect, Response},
+    BoxError, Extension, Json, RequestExt,
 };
+use chrono::{SecondsFormat, Utc};
 use futures::StreamExt;
-use tracing::{error, info, warn};
+use metrics::{counter, histogram};
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    sync::atomic::Ordering,
+    time::{Duration, Instant},
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, warn};
+use url::Url;
+
+/// tracing target of click log lines, see `Config::click_log_file`.
+pub const CLICK_LOG_TARGET: &str = "survey_redirect::clicks";
 
 pub async fn redirect(
     State(state): State<RouterState>,
-    Query(redirect_params): Query<RedirectParams>,
+    Extension(request_id): Extension<RequestId>,
+    Extension(client_ip): Extension<ClientIp>,
+    Extension(timeout): Extension<RequestTimeout>,
+    RawQuery(query): RawQuery,
+    headers: HeaderMap,
 ) -> Response {
-    match state.redirect(redirect_params).await {
-        Ok(url) => {
-            info!("redirect request to {url}");
-            Redirect::to(url.as_str()).into_response()
+    let start = Instant::now();
+    state.count_request();
+    let redirect_params = match RedirectParams::from_query(query.as_deref()) {
+        Ok(redirect_params) => redirect_params,
+        Err(e) => {
+            state.redirect_outcomes.count("malformed_query");
+            warn!("request from {client_ip} with malformed query: {e}");
+            return (StatusCode::BAD_REQUEST, e).into_response();
+        }
+    };
+    let code = redirect_params.code.clone();
+    if let Some(referer) = headers.get(REFERER).and_then(|v| v.to_str().ok()) {
+        if state.redirect_policy().is_blocked_referrer(referer) {
+            record_click(&state, &code, "blocked_referrer", None);
+            debug!("request from {client_ip} with blocked referrer {referer}");
+            return StatusCode::NO_CONTENT.into_response();
+        }
+    }
+    let user_agent = headers.get(USER_AGENT).and_then(|v| v.to_str().ok());
+    let result = state
+        .redirect(redirect_params, user_agent, Some(client_ip.0))
+        .await;
+    histogram!(REDIRECT_DURATION_SECONDS).record(start.elapsed().as_secs_f64());
+    match result {
+        Ok(RedirectTarget {
+            url,
+            request_timeout_secs,
+            mode,
+        }) => {
+            record_click(&state, &code, "success", Some(&*url));
+            // the full url may carry personal data, see `record_click`
+            info!(
+                traceparent = trace_context::traceparent(&headers),
+                "redirect request from {client_ip} to {}",
+                url.host_str().unwrap_or("-")
+            );
+            debug!("redirect to {url}");
+            if let Some(secs) = request_timeout_secs {
+                timeout.extend(Duration::from_secs(secs));
+            }
+            let mut rsp = match mode {
+                RedirectMode::Http => Redirect::to(url.as_str()).into_response(),
+                RedirectMode::Html => Html(redirect_page::render(
+                    &state.redirect_page_template,
+                    url.as_str(),
+                ))
+                .into_response(),
+            };
+            if let Some(secs) = request_timeout_secs {
+                rsp.headers_mut()
+                    .insert(X_SURVEY_TIMEOUT, HeaderValue::from(secs));
+            }
+            if state.disable_redirect_caching {
+                let headers = rsp.headers_mut();
+                headers.insert(
+                    CACHE_CONTROL,
+                    HeaderValue::from_static("no-store, no-cache"),
+                );
+                headers.insert(PRAGMA, HeaderValue::from_static("no-cache"));
+                headers.insert(EXPIRES, HeaderValue::from_static("0"));
+            }
+            histogram!(REDIRECT_RESOLUTION_DURATION_SECONDS).record(start.elapsed().as_secs_f64());
+            rsp
         }
         Err(StateError::InvalidCode) => {
-            warn!("request with invalid code");
-            (StatusCode::NOT_FOUND, "invalid code").into_response()
+            state.redirect_errors_total.fetch_add(1, Ordering::Relaxed);
+            record_click(&state, &code, "invalid_code", None);
+            warn!("request from {client_ip} with invalid code");
+            state.error_pages().not_found()
+        }
+        Err(StateError::Deactivated) => {
+            record_click(&state, &code, "deactivated", None);
+            info!("request from {client_ip} to deactivated link");
+            (StatusCode::GONE, "link deactivated").into_response()
+        }
+        Err(StateError::Expired) => {
+            record_click(&state, &code, "expired", None);
+            info!("request from {client_ip} to expired link");
+            (StatusCode::GONE, "link expired").into_response()
+        }
+        Err(StateError::BlockedTarget(e)) => {
+            record_click(&state, &code, "blocked_target", None);
+            warn!("request from {client_ip} to blocked target: {e}");
+            (StatusCode::FORBIDDEN, "redirect target blocked").into_response()
         }
         Err(e) => {
+            record_click(&state, &code, "error", None);
             error!("fatal, unknown error when redirecting: {:?}", e);
-            (StatusCode::INTERNAL_SERVER_ERROR, "internal error").into_response()
+            state.error_pages().internal_error(&request_id)
         }
     }
 }
 
-pub async fn put_routing_table(State(state): State<RouterState>, req: Request<Body>) -> Response {
-    let data = match decode_request(req).await {
-        Ok(data) => data,
+/// shown by `/api/complete` without `thank_you_url`.
+const THANK_YOU_PAGE: &str = "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\">\
+<title>Thank you</title></head><body><p>Thank you for completing the survey, \
+you may close this page.</p></body></html>\n";
+
+/// record a survey completion, then show the thank-you page.
+pub async fn complete(
+    State(state): State<RouterState>,
+    Extension(client_ip): Extension<ClientIp>,
+    RawQuery(query): RawQuery,
+) -> Response {
+    let params = match RedirectParams::from_query(query.as_deref()) {
+        Ok(params) => params,
+        Err(e) => {
+            warn!("completion from {client_ip} with malformed query: {e}");
+            return (StatusCode::BAD_REQUEST, e).into_response();
+        }
+    };
+    match state.complete(params).await {
+        Ok(()) => {
+            info!("completion from {client_ip}");
+            match &state.thank_you_url {
+                Some(url) => Redirect::to(url.as_str()).into_response(),
+                None => Html(THANK_YOU_PAGE).into_response(),
+            }
+        }
+        Err(_) => {
+            warn!("completion from {client_ip} with invalid code");
+            (StatusCode::NOT_FOUND, "invalid code").into_response()
+        }
+    }
+}
+
+/// count a redirect outcome, and log it to the click log.
+///
+/// Only the host of the target is logged, the full url may carry personal data.
+fn record_click(state: &RouterState, code: &Code, outcome: &'static str, url: Option<&Url>) {
+    state.redirect_outcomes.count(outcome);
+    info!(
+        target: CLICK_LOG_TARGET,
+        %code,
+        outcome,
+        target_host = url.and_then(Url::host_str).unwrap_or("-"),
+    );
+}
+
+/// liveness probe.
+pub async fn healthz() -> &'static str {
+    "ok"
+}
+
+/// reachability check of the admin listener, no authentication.
+pub async fn ping() -> AdminResponse<&'static str> {
+    AdminResponse::Ok("pong")
+}
+
+/// readiness probe, fails during graceful shutdown.
+pub async fn readyz(State(state): State<RouterState>) -> Response {
+    match state.readiness().await {
+        Ok(()) => (StatusCode::OK, "ready").into_response(),
+        Err(StateError::ShuttingDown) => {
+            (StatusCode::SERVICE_UNAVAILABLE, "shutting down").into_response()
+        }
+        Err(StateError::Draining) => (StatusCode::SERVICE_UNAVAILABLE, "draining").into_response(),
+        Err(e) => {
+            warn!("readiness probe failed: {:?}", e);
+            (StatusCode::SERVICE_UNAVAILABLE, "not ready").into_response()
+        }
+    }
+}
+
+pub async fn put_routing_table(
+    State(state): State<RouterState>,
+    Extension(request_id): Extension<RequestId>,
+    Query(params): Query<PutParams>,
+    req: Request<Body>,
+) -> Response {
+    let key = match idempotency_key(&req) {
+        Ok(key) => key,
+        Err(e) => return e.into_response(),
+    };
+    let idempotency = state.idempotency.clone();
+    idempotency
+        .run(key, put_routing_table_once(state, request_id, params, req))
+        .await
+}
+
+async fn put_routing_table_once(
+    state: RouterState,
+    request_id: RequestId,
+    params: PutParams,
+    req: Request<Body>,
+) -> Response {
+    let if_match = match if_match(req.headers()) {
+        Ok(if_match) => if_match,
+        Err(e) => return e.into_response(),
+    };
+    let table = match decode_request(req, state.max_decoded_body_size).await {
+        Ok(table) => table,
         Err(rsp) => return rsp,
     };
-    match state.put_routing_table(data).await {
-        Ok(_) => {
-            info!("put table success");
-            (StatusCode::OK, "success").into_response()
+    let headers = table.headers();
+    let ids = params.return_links.then(|| uploaded_ids(&table.routes));
+    let start = Instant::now();
+    // applied in a task, so that it is completed even if the client goes away,
+    // which cancels the update if it has not started applying yet
+    let cancelled = CancellationToken::new();
+    let _cancel_on_drop = cancelled.clone().drop_guard();
+    let result = tokio::spawn({
+        let state = state.clone();
+        async move {
+            state
+                .put_routing_table_cancellable(table.routes, if_match, &cancelled)
+                .await
+        }
+    })
+    .await
+    .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()));
+    histogram!(ADMIN_OPERATION_DURATION_SECONDS, "operation" => "put_routing_table")
+        .record(start.elapsed().as_secs_f64());
+    match result {
+        Ok(version) => {
+            info!(
+                "put table success (format={}, skipped_rows={}, version={version})",
+                table.format.as_str(),
+                table.skipped_rows
+            );
+            (
+                headers,
+                [(X_TABLE_VERSION, HeaderValue::from(version))],
+                with_links(&state, (), ids).await,
+            )
+                .into_response()
+        }
+        Err(StateError::VersionMismatch { expected, current }) => {
+            version_mismatch(expected, current)
+        }
+        Err(StateError::InvalidRoute(e)) => {
+            warn!("invalid route: {e}");
+            AdminResponse::error(
+                StatusCode::BAD_REQUEST,
+                "INVALID_ROUTE",
+                format!("invalid route: {e}"),
+            )
+            .into_response()
         }
         Err(StateError::StoreError(e)) => {
             error!("storage error: {e}");
-            (StatusCode::INTERNAL_SERVER_ERROR, "storage error").into_response()
-        }
-        Err(StateError::Busy) => {
-            warn!("put table api busy");
-            (StatusCode::TOO_MANY_REQUESTS, "busy, try again").into_response()
+            AdminResponse::internal_error("storage error", &request_id).into_response()
         }
+        Err(StateError::Busy) => busy("put_routing_table", &state),
         Err(e) => {
             error!("fatal, unknown error in put_routing_table: {:?}", e);
-            (StatusCode::INTERNAL_SERVER_ERROR, "unknown error").into_response()
+            AdminResponse::internal_error("unknown error", &request_id).into_response()
+        }
+    }
+}
+
+pub async fn patch_routing_table(
+    State(state): State<RouterState>,
+    Extension(request_id): Extension<RequestId>,
+    Query(params): Query<PatchParams>,
+    req: Request<Body>,
+) -> Response {
+    let key = match idempotency_key(&req) {
+        Ok(key) => key,
+        Err(e) => return e.into_response(),
+    };
+    let idempotency = state.idempotency.clone();
+    idempotency
+        .run(
+            key,
+            patch_routing_table_once(state, request_id, params, req),
+        )
+        .await
+}
+
+async fn patch_routing_table_once(
+    state: RouterState,
+    request_id: RequestId,
+    params: PatchParams,
+    req: Request<Body>,
+) -> Response {
+    let if_match = match if_match(req.headers()) {
+        Ok(if_match) => if_match,
+        Err(e) => return e.into_response(),
+    };
+    let is_merge_patch = req
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with(MERGE_PATCH_CONTENT_TYPE));
+    if is_merge_patch {
+        return merge_patch_routing_table(state, request_id, params, if_match, req).await;
+    }
+    let table = match decode_request(req, state.max_decoded_body_size).await {
+        Ok(table) => table,
+        Err(rsp) => return rsp,
+    };
+    let headers = table.headers();
+    let ids = params.return_links.then(|| uploaded_ids(&table.routes));
+    let start = Instant::now();
+    let result = state
+        .patch_routing_table(
+            table.routes,
+            params.conflict,
+            params.mode,
+            params.durable,
+            if_match,
+        )
+        .await;
+    histogram!(ADMIN_OPERATION_DURATION_SECONDS, "operation" => "patch_routing_table")
+        .record(start.elapsed().as_secs_f64());
+    match result {
+        Ok((summary, version)) => {
+            info!(
+                "patch table success (format={}, mode={:?}, skipped_rows={}, updated={}, skipped={}, version={version})",
+                table.format.as_str(),
+                summary.mode,
+                table.skipped_rows,
+                summary.updated,
+                summary.skipped.len()
+            );
+            (
+                headers,
+                [(X_TABLE_VERSION, HeaderValue::from(version))],
+                with_links(&state, summary, ids).await,
+            )
+                .into_response()
+        }
+        Err(StateError::VersionMismatch { expected, current }) => {
+            version_mismatch(expected, current)
+        }
+        Err(StateError::Conflict(summary)) => {
+            warn!("patch table conflicts: {}", summary.errors.len());
+            AdminResponse::error(StatusCode::CONFLICT, "CONFLICT", summary.errors.join("; "))
+                .into_response()
+        }
+        Err(StateError::UnknownIds(ids)) => {
+            warn!("patch table with {} unknown ids", ids.len());
+            AdminResponse::error(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "UNKNOWN_IDS",
+                format!(
+                    "update_only patch of {} unknown ids: {}",
+                    ids.len(),
+                    join_ids(&ids)
+                ),
+            )
+            .into_response()
+        }
+        Err(StateError::InvalidRoute(e)) => {
+            warn!("invalid route: {e}");
+            AdminResponse::error(
+                StatusCode::BAD_REQUEST,
+                "INVALID_ROUTE",
+                format!("invalid route: {e}"),
+            )
+            .into_response()
+        }
+        Err(StateError::StoreError(e)) => {
+            error!("storage error: {e}");
+            AdminResponse::internal_error("storage error", &request_id).into_response()
+        }
+        Err(StateError::Busy) => busy("patch_routing_table", &state),
+        Err(e) => {
+            error!("fatal, unknown error in patch_routing_table: {:?}", e);
+            AdminResponse::internal_error("unknown error", &request_id).into_response()
         }
     }
 }
 
-pub async fn patch_routing_table(State(state): State<RouterState>, req: Request<Body>) -> Response {
-    let data = match decode_request(req).await {
+/// PATCH with a JSON Merge Patch from ids to urls, `null` removes routes.
+///
+/// `mode` and `conflict` do not apply to merge patches, and are rejected.
+async fn merge_patch_routing_table(
+    state: RouterState,
+    request_id: RequestId,
+    params: PatchParams,
+    if_match: Option<u64>,
+    req: Request<Body>,
+) -> Response {
+    if params.mode != PatchMode::default() || params.conflict != ConflictResolution::default() {
+        warn!("merge patch with mode or conflict");
+        return AdminResponse::error(
+            StatusCode::BAD_REQUEST,
+            "INVALID_QUERY",
+            "mode and conflict are not supported with merge patches",
+        )
+        .into_response();
+    }
+    let data = match read_body(req, state.max_decoded_body_size).await {
         Ok(data) => data,
         Err(rsp) => return rsp,
     };
-    match state.patch_routing_table(data).await {
-        Ok(_) => {
-            info!("patch table success");
-            (StatusCode::OK, "success").into_response()
+    let patch: HashMap<Id, Option<Url>> = match serde_json::from_slice(&data) {
+        Ok(patch) => patch,
+        Err(e) => {
+            warn!("merge patch decode error: {e}");
+            return AdminResponse::error(
+                StatusCode::BAD_REQUEST,
+                "INVALID_TABLE",
+                format!("corrupt data: {e}"),
+            )
+            .into_response();
+        }
+    };
+    // removed routes have no links
+    let ids = params.return_links.then(|| {
+        patch
+            .iter()
+            .filter(|(_, url)| url.is_some())
+            .map(|(id, _)| id.clone())
+            .collect()
+    });
+    let start = Instant::now();
+    let result = state
+        .merge_patch_routing_table(patch, params.durable, if_match)
+        .await;
+    histogram!(ADMIN_OPERATION_DURATION_SECONDS, "operation" => "merge_patch_routing_table")
+        .record(start.elapsed().as_secs_f64());
+    match result {
+        Ok((summary, version)) => {
+            info!(
+                "merge patch table success (updated={}, removed={}, version={version})",
+                summary.updated, summary.removed
+            );
+            (
+                [(X_TABLE_VERSION, HeaderValue::from(version))],
+                with_links(&state, summary, ids).await,
+            )
+                .into_response()
+        }
+        Err(StateError::VersionMismatch { expected, current }) => {
+            version_mismatch(expected, current)
+        }
+        Err(StateError::InvalidRoute(e)) => {
+            warn!("invalid route: {e}");
+            AdminResponse::error(
+                StatusCode::BAD_REQUEST,
+                "INVALID_ROUTE",
+                format!("invalid route: {e}"),
+            )
+            .into_response()
+        }
+        Err(StateError::StoreError(e)) => {
+            error!("storage error: {e}");
+            AdminResponse::internal_error("storage error", &request_id).into_response()
         }
+        Err(StateError::Busy) => busy("patch_routing_table", &state),
+        Err(e) => {
+            error!("fatal, unknown error in merge_patch_routing_table: {:?}", e);
+            AdminResponse::internal_error("unknown error", &request_id).into_response()
+        }
+    }
+}
+
+/// `data` of uploads with `return_links=true`.
+#[derive(Serialize)]
+struct WithLinks<T> {
+    #[serde(flatten)]
+    summary: T,
+    links: HashMap<Id, Url>,
+}
+
+/// ids of an uploaded table, for `with_links`.
+fn uploaded_ids(routes: &[Route]) -> Vec<Id> {
+    routes.iter().map(|route| route.uid.clone()).collect()
+}
+
+/// the summary of an upload, with the links of its `ids` if they were asked for.
+async fn with_links<T: Serialize>(
+    state: &RouterState,
+    summary: T,
+    ids: Option<Vec<Id>>,
+) -> Response {
+    match ids {
+        Some(ids) => AdminResponse::Ok(WithLinks {
+            summary,
+            links: state.links_of(ids).await,
+        })
+        .into_response(),
+        None => AdminResponse::Ok(summary).into_response(),
+    }
+}
+
+pub async fn runtime_info(State(state): State<RouterState>) -> AdminResponse<RuntimeInfo> {
+    AdminResponse::Ok(state.runtime_info())
+}
+
+/// table sizes, snapshots and counters as plain json, for simple monitors.
+pub async fn json_metrics(
+    State(state): State<RouterState>,
+    Extension(request_id): Extension<RequestId>,
+) -> Response {
+    match state.json_metrics().await {
+        Ok(metrics) => AdminResponse::Ok(metrics).into_response(),
+        Err(StateError::Busy) => busy("metrics.json", &state),
+        Err(e) => {
+            error!("storage error in metrics.json: {:?}", e);
+            AdminResponse::internal_error("storage error", &request_id).into_response()
+        }
+    }
+}
+
+/// refuse new public connections, e.g. before a rolling restart.
+pub async fn drain(State(state): State<RouterState>) -> AdminResponse<&'static str> {
+    if !state.set_draining(true) {
+        info!("draining, new public connections get 503");
+    }
+    AdminResponse::Ok("draining")
+}
+
+/// accept new public connections again.
+pub async fn undrain(State(state): State<RouterState>) -> AdminResponse<&'static str> {
+    if state.set_draining(false) {
+        info!("stopped draining");
+    }
+    AdminResponse::Ok("ok")
+}
+
+/// 409 while a table update is running.
+pub async fn reload(
+    State(state): State<RouterState>,
+    Extension(request_id): Extension<RequestId>,
+) -> Response {
+    match state.reload().await {
+        Ok(summary) => {
+            info!(
+                "tables reloaded (snapshot={}, routes={}, codes={}, geoip={})",
+                summary.snapshot_ts.as_deref().unwrap_or("none"),
+                summary.routes,
+                summary.codes,
+                summary.geoip
+            );
+            AdminResponse::Ok(summary).into_response()
+        }
+        Err(StateError::InvalidRoute(e)) => {
+            warn!("invalid stored tables: {e}");
+            AdminResponse::error(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "INVALID_STORED_TABLES",
+                format!("invalid stored tables: {e}"),
+            )
+            .into_response()
+        }
+        Err(StateError::DuplicateCode { code, ids }) => AdminResponse::error(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "INVALID_STORED_TABLES",
+            format!(
+                "invalid stored tables: code {code} is used by ids {}",
+                join_ids(&ids)
+            ),
+        )
+        .into_response(),
         Err(StateError::StoreError(e)) => {
             error!("storage error: {e}");
-            (StatusCode::INTERNAL_SERVER_ERROR, "storage error").into_response()
+            AdminResponse::internal_error("storage error", &request_id).into_response()
         }
         Err(StateError::Busy) => {
-            warn!("patch table api busy");
-            (StatusCode::TOO_MANY_REQUESTS, "busy, try again").into_response()
+            warn!("reload refused, table update in progress");
+            AdminResponse::error(StatusCode::CONFLICT, "BUSY", "table update in progress")
+                .into_response()
+        }
+        Err(StateError::SnapshotPending) => {
+            warn!("reload refused, snapshot of PATCHes pending");
+            AdminResponse::error(
+                StatusCode::CONFLICT,
+                "SNAPSHOT_PENDING",
+                "PATCHes are not written yet, retry after snapshot_debounce_secs",
+            )
+            .into_response()
         }
         Err(e) => {
-            error!("fatal, unknown error in patch_routing_table: {:?}", e);
-            (StatusCode::INTERNAL_SERVER_ERROR, "unknown error").into_response()
+            error!("fatal, unknown error in reload: {:?}", e);
+            AdminResponse::internal_error("unknown error", &request_id).into_response()
+        }
+    }
+}
+
+/// reload the certificates in the background, without waiting for a file change.
+/// 202 once the reload is triggered, 409 if no listener serves tls.
+pub async fn reload_cert(State(state): State<RouterState>) -> Response {
+    match state.reload_cert() {
+        0 => {
+            AdminResponse::error(StatusCode::CONFLICT, "NO_TLS", "not serving tls").into_response()
+        }
+        certs => {
+            info!("forced reload of {certs} certs");
+            (StatusCode::ACCEPTED, AdminResponse::Ok(())).into_response()
+        }
+    }
+}
+
+/// 422 if the new token is too weak, the old token stays valid then.
+pub async fn rotate_admin_token(
+    State(state): State<RouterState>,
+    Extension(request_id): Extension<RequestId>,
+    Json(AdminTokenRotation { new_token }): Json<AdminTokenRotation>,
+) -> Response {
+    match state.rotate_admin_token(new_token) {
+        Ok(()) => {
+            info!("admin token rotated");
+            AdminResponse::Ok(()).into_response()
+        }
+        Err(StateError::InvalidToken(e)) => {
+            warn!("admin token rotation refused: {e}");
+            AdminResponse::error(StatusCode::UNPROCESSABLE_ENTITY, "INVALID_TOKEN", e)
+                .into_response()
+        }
+        Err(StateError::StoreError(e)) => {
+            error!("failed to save the admin token: {e}");
+            AdminResponse::internal_error("storage error", &request_id).into_response()
+        }
+        Err(e) => {
+            error!("fatal, unknown error in rotate_admin_token: {:?}", e);
+            AdminResponse::internal_error("unknown error", &request_id).into_response()
+        }
+    }
+}
+
+pub async fn activate_codes(
+    state: State<RouterState>,
+    request_id: Extension<RequestId>,
+    ids: Json<BulkIds>,
+) -> Response {
+    bulk_set_deactivated(state, request_id, ids, false).await
+}
+
+pub async fn deactivate_codes(
+    state: State<RouterState>,
+    request_id: Extension<RequestId>,
+    ids: Json<BulkIds>,
+) -> Response {
+    bulk_set_deactivated(state, request_id, ids, true).await
+}
+
+/// 207 if some ids have no route.
+async fn bulk_set_deactivated(
+    State(state): State<RouterState>,
+    Extension(request_id): Extension<RequestId>,
+    Json(BulkIds { ids }): Json<BulkIds>,
+    deactivated: bool,
+) -> Response {
+    match state.bulk_set_deactivated(ids, deactivated).await {
+        Ok(result) => {
+            info!(
+                "set deactivated={deactivated} (succeeded={}, not_found={})",
+                result.succeeded.len(),
+                result.not_found.len()
+            );
+            let status = if result.not_found.is_empty() {
+                StatusCode::OK
+            } else {
+                StatusCode::MULTI_STATUS
+            };
+            (status, AdminResponse::Ok(result)).into_response()
+        }
+        Err(StateError::StoreError(e)) => {
+            error!("storage error: {e}");
+            AdminResponse::internal_error("storage error", &request_id).into_response()
+        }
+        Err(StateError::Busy) => busy("bulk_set_deactivated", &state),
+        Err(e) => {
+            error!("fatal, unknown error in bulk_set_deactivated: {:?}", e);
+            AdminResponse::internal_error("unknown error", &request_id).into_response()
+        }
+    }
+}
+
+/// move the codes of ids to new ids, keeping their links.
+pub async fn rekey(
+    State(state): State<RouterState>,
+    Extension(request_id): Extension<RequestId>,
+    headers: HeaderMap,
+    Json(request): Json<RekeyRequest>,
+) -> Response {
+    let if_match = match if_match(&headers) {
+        Ok(if_match) => if_match,
+        Err(e) => return e.into_response(),
+    };
+    let rekeys = request.into_vec();
+    let count = rekeys.len();
+    match state.rekey(rekeys, if_match).await {
+        Ok(version) => {
+            info!("rekey (ids={count}, version={version})");
+            (
+                [(X_TABLE_VERSION, HeaderValue::from(version))],
+                AdminResponse::Ok(()),
+            )
+                .into_response()
+        }
+        Err(StateError::VersionMismatch { expected, current }) => {
+            version_mismatch(expected, current)
+        }
+        Err(StateError::UnknownOldId(id)) => AdminResponse::error(
+            StatusCode::NOT_FOUND,
+            "UNKNOWN_ID",
+            format!("unknown id {id}"),
+        )
+        .into_response(),
+        Err(StateError::IdTaken(id)) => AdminResponse::error(
+            StatusCode::CONFLICT,
+            "ID_TAKEN",
+            format!("id {id} has a code already"),
+        )
+        .into_response(),
+        Err(StateError::StoreError(e)) => {
+            error!("storage error: {e}");
+            AdminResponse::internal_error("storage error", &request_id).into_response()
+        }
+        Err(StateError::Busy) => busy("rekey", &state),
+        Err(e) => {
+            error!("fatal, unknown error in rekey: {:?}", e);
+            AdminResponse::internal_error("unknown error", &request_id).into_response()
+        }
+    }
+}
+
+/// ids with a code but no route.
+pub async fn codes_without_routes(
+    State(state): State<RouterState>,
+    Extension(request_id): Extension<RequestId>,
+) -> Response {
+    match state.codes_without_routes().await {
+        Ok(orphaned) => {
+            info!("codes without routes request ({} orphaned)", orphaned.count);
+            AdminResponse::Ok(orphaned).into_response()
+        }
+        Err(StateError::Busy) => busy("codes_without_routes", &state),
+        Err(e) => {
+            error!("fatal, unknown error in codes_without_routes: {:?}", e);
+            AdminResponse::internal_error("unknown error", &request_id).into_response()
         }
     }
 }
 
-pub async fn get_links(State(state): State<RouterState>) -> Response {
-    match state.get_links().await {
+/// forget the codes of ids without route.
+pub async fn purge_orphaned_codes(
+    State(state): State<RouterState>,
+    Extension(request_id): Extension<RequestId>,
+) -> Response {
+    match state.purge_orphaned_codes().await {
+        Ok((purged, version)) => {
+            info!(
+                "purged orphaned codes (ids={}, version={version})",
+                purged.count
+            );
+            (
+                [(X_TABLE_VERSION, HeaderValue::from(version))],
+                AdminResponse::Ok(purged),
+            )
+                .into_response()
+        }
+        Err(StateError::StoreError(e)) => {
+            error!("storage error: {e}");
+            AdminResponse::internal_error("storage error", &request_id).into_response()
+        }
+        Err(StateError::Busy) => busy("purge_orphaned_codes", &state),
+        Err(e) => {
+            error!("fatal, unknown error in purge_orphaned_codes: {:?}", e);
+            AdminResponse::internal_error("unknown error", &request_id).into_response()
+        }
+    }
+}
+
+/// remove the routes of several ids, 207 if some ids have no route.
+pub async fn delete_routes(
+    State(state): State<RouterState>,
+    Extension(request_id): Extension<RequestId>,
+    Json(BulkIds { ids }): Json<BulkIds>,
+) -> Response {
+    match state.bulk_remove_routes(ids).await {
+        Ok(result) => {
+            info!(
+                "delete routes (removed={}, not_found={})",
+                result.removed.len(),
+                result.not_found.len()
+            );
+            let status = if result.not_found.is_empty() {
+                StatusCode::OK
+            } else {
+                StatusCode::MULTI_STATUS
+            };
+            (status, AdminResponse::Ok(result)).into_response()
+        }
+        Err(StateError::StoreError(e)) => {
+            error!("storage error: {e}");
+            AdminResponse::internal_error("storage error", &request_id).into_response()
+        }
+        Err(StateError::Busy) => busy("delete_routes", &state),
+        Err(e) => {
+            error!("fatal, unknown error in delete_routes: {:?}", e);
+            AdminResponse::internal_error("unknown error", &request_id).into_response()
+        }
+    }
+}
+
+pub async fn get_links(
+    State(state): State<RouterState>,
+    Extension(request_id): Extension<RequestId>,
+    Query(mut params): Query<LinksParams>,
+    headers: HeaderMap,
+) -> Response {
+    // `format=json` is the default, so the query cannot override `Accept`
+    if params.format == LinksFormat::Json && accepts_ndjson(&headers) {
+        params.format = LinksFormat::Ndjson;
+    }
+    let format = params.format;
+    match state.get_links(params).await {
+        Ok(links) if format == LinksFormat::Json => {
+            info!("get links request");
+            let (parts, body) = links.into_parts();
+            Response::from_parts(parts, enveloped_body(body))
+        }
         Ok(links) => {
             info!("get links request");
             links
         }
-        Err(StateError::Busy) => {
-            warn!("get links api busy");
-            (StatusCode::TOO_MANY_REQUESTS, "busy, try again").into_response()
-        }
+        Err(StateError::Busy) => busy("get_links", &state),
         Err(e) => {
             error!("fatal, unknown error in get_links: {:?}", e);
-            (StatusCode::INTERNAL_SERVER_ERROR, "unknown error").into_response()
+            AdminResponse::internal_error("unknown error", &request_id).into_response()
+        }
+    }
+}
+
+/// consistency of the tables, see `RouterState::validate`.
+pub async fn validate(
+    State(state): State<RouterState>,
+    Extension(request_id): Extension<RequestId>,
+) -> Response {
+    match state.validate().await {
+        Ok(report) => {
+            info!("validate request (clean={})", report.is_clean());
+            AdminResponse::Ok(report).into_response()
+        }
+        Err(StateError::Busy) => busy("validate", &state),
+        Err(e) => {
+            error!("fatal, unknown error in validate: {:?}", e);
+            AdminResponse::internal_error("unknown error", &request_id).into_response()
+        }
+    }
+}
+
+/// whether `Accept` lists `application/x-ndjson`.
+fn accepts_ndjson(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(ACCEPT)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|media_type| {
+            let essence = media_type.split(';').next().unwrap_or_default();
+            essence.trim().eq_ignore_ascii_case("application/x-ndjson")
+        })
+}
+
+/// the routes as uploaded, json or csv.
+pub async fn get_routing_table(
+    State(state): State<RouterState>,
+    Extension(request_id): Extension<RequestId>,
+    Query(params): Query<RoutingTableParams>,
+) -> Response {
+    let (routes, version) = match state.get_routing_table().await {
+        Ok(table) => table,
+        Err(StateError::Busy) => return busy("get_routing_table", &state),
+        Err(e) => {
+            error!("fatal, unknown error in get_routing_table: {:?}", e);
+            return AdminResponse::internal_error("unknown error", &request_id).into_response();
         }
+    };
+    info!("get routing table request ({} routes)", routes.len());
+    let version = [(X_TABLE_VERSION, HeaderValue::from(version))];
+    match params.format {
+        RoutingTableFormat::Json => (version, AdminResponse::Ok(routes)).into_response(),
+        RoutingTableFormat::Csv => match routing_table_csv(&routes) {
+            Ok(body) => (version, [(CONTENT_TYPE, "text/csv")], body).into_response(),
+            Err(e) => {
+                error!("csv error in get_routing_table: {e}");
+                AdminResponse::internal_error("csv error", &request_id).into_response()
+            }
+        },
     }
 }
 
-pub async fn get_codes(State(state): State<RouterState>) -> Response {
+/// the columns of the offline import, other route options are left out.
+fn routing_table_csv(routes: &[TableRoute]) -> csv::Result<Vec<u8>> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer.write_record([
+        "uid",
+        "url",
+        "mobile_url",
+        "description",
+        "notes",
+        "orphaned",
+    ])?;
+    for route in routes {
+        writer.write_record([
+            route.uid.as_ref().map(Id::to_string).unwrap_or_default(),
+            route.url.to_string(),
+            route
+                .mobile_url
+                .as_ref()
+                .map(Url::to_string)
+                .unwrap_or_default(),
+            route.description.clone().unwrap_or_default(),
+            route.notes.clone().unwrap_or_default(),
+            route.orphaned.to_string(),
+        ])?;
+    }
+    writer.into_inner().map_err(|e| e.into_error().into())
+}
+
+pub async fn get_codes(
+    State(state): State<RouterState>,
+    Extension(request_id): Extension<RequestId>,
+) -> Response {
     match state.get_codes().await {
-        Ok(links) => {
+        Ok(codes) => {
             info!("get codes request");
-            links
-        }
-        Err(StateError::Busy) => {
-            warn!("get codes api busy");
-            (StatusCode::TOO_MANY_REQUESTS, "busy, try again").into_response()
+            AdminResponse::Ok(codes).into_response()
         }
+        Err(StateError::Busy) => busy("get_codes", &state),
         Err(e) => {
             error!("fatal, unknown error in get_codes: {:?}", e);
-            (StatusCode::INTERNAL_SERVER_ERROR, "unknown error").into_response()
+            AdminResponse::internal_error("unknown error", &request_id).into_response()
+        }
+    }
+}
+
+pub async fn search_routes(
+    State(state): State<RouterState>,
+    Extension(request_id): Extension<RequestId>,
+    Query(params): Query<SearchRoutesParams>,
+) -> Response {
+    match state.search_routes(params).await {
+        Ok(matches) => {
+            info!("search routes request ({} matches)", matches.len());
+            AdminResponse::Ok(matches).into_response()
+        }
+        Err(StateError::InvalidQuery(e)) => {
+            AdminResponse::error(StatusCode::BAD_REQUEST, "INVALID_QUERY", e).into_response()
+        }
+        Err(StateError::Busy) => busy("search_routes", &state),
+        Err(e) => {
+            error!("fatal, unknown error in search_routes: {:?}", e);
+            AdminResponse::internal_error("unknown error", &request_id).into_response()
+        }
+    }
+}
+
+pub async fn search_codes(
+    State(state): State<RouterState>,
+    Extension(request_id): Extension<RequestId>,
+    Query(params): Query<SearchCodesParams>,
+) -> Response {
+    match state.search_codes(params).await {
+        Ok(matches) => {
+            info!("search codes request ({} matches)", matches.len());
+            AdminResponse::Ok(matches).into_response()
+        }
+        Err(StateError::Busy) => busy("search_codes", &state),
+        Err(e) => {
+            error!("fatal, unknown error in search_codes: {:?}", e);
+            AdminResponse::internal_error("unknown error", &request_id).into_response()
+        }
+    }
+}
+
+pub async fn route_history(
+    State(state): State<RouterState>,
+    Extension(request_id): Extension<RequestId>,
+    Query(params): Query<RouteHistoryParams>,
+) -> Response {
+    match state.route_history(&params.id).await {
+        Ok(history) => {
+            info!("route history request ({} snapshots)", history.len());
+            AdminResponse::Ok(history).into_response()
+        }
+        Err(StateError::UnknownId) => {
+            AdminResponse::error(StatusCode::NOT_FOUND, "UNKNOWN_ID", "unknown id").into_response()
+        }
+        Err(StateError::StoreError(e)) => {
+            error!("storage error: {e}");
+            AdminResponse::internal_error("storage error", &request_id).into_response()
+        }
+        Err(StateError::Busy) => busy("route_history", &state),
+        Err(e) => {
+            error!("fatal, unknown error in route_history: {:?}", e);
+            AdminResponse::internal_error("unknown error", &request_id).into_response()
+        }
+    }
+}
+
+/// the links of one participant, keyed by survey.
+pub async fn participant_links(
+    State(state): State<RouterState>,
+    Extension(request_id): Extension<RequestId>,
+    Path(id): Path<Id>,
+) -> Response {
+    match state.participant_links(&id).await {
+        Ok(links) => {
+            info!("participant links request ({} links)", links.len());
+            AdminResponse::Ok(links).into_response()
+        }
+        Err(StateError::UnknownId) => {
+            AdminResponse::error(StatusCode::NOT_FOUND, "UNKNOWN_ID", "unknown id").into_response()
+        }
+        Err(StateError::Busy) => busy("participant_links", &state),
+        Err(e) => {
+            error!("fatal, unknown error in participant_links: {:?}", e);
+            AdminResponse::internal_error("unknown error", &request_id).into_response()
+        }
+    }
+}
+
+/// snapshot count and disk usage of `storage_root`.
+pub async fn storage_stats(
+    State(state): State<RouterState>,
+    Extension(request_id): Extension<RequestId>,
+) -> Response {
+    let dir = state.router_table_store.clone();
+    let table_version = state.table_version();
+    match tokio::task::spawn_blocking(move || storage_statistics(&dir, table_version)).await {
+        Ok(Ok(stats)) => {
+            info!("storage stats request");
+            AdminResponse::Ok(stats).into_response()
+        }
+        Ok(Err(e)) => {
+            error!("storage error: {e}");
+            AdminResponse::internal_error("storage error", &request_id).into_response()
+        }
+        Err(e) => {
+            error!("fatal, storage_stats task failed: {e}");
+            AdminResponse::internal_error("unknown error", &request_id).into_response()
+        }
+    }
+}
+
+/// completions by id, as json or csv.
+pub async fn completions(
+    State(state): State<RouterState>,
+    Extension(request_id): Extension<RequestId>,
+    Query(params): Query<CompletionsParams>,
+) -> Response {
+    let completions = match state.get_completions().await {
+        Ok(completions) => completions,
+        Err(StateError::Busy) => return busy("completions", &state),
+        Err(e) => {
+            error!("fatal, unknown error in completions: {:?}", e);
+            return AdminResponse::internal_error("unknown error", &request_id).into_response();
+        }
+    };
+    info!("completions request ({} completed)", completions.len());
+    match params.format {
+        CompletionsFormat::Json => {
+            AdminResponse::Ok(completions.into_iter().collect::<HashMap<_, _>>()).into_response()
+        }
+        CompletionsFormat::Csv => match completions_csv(&completions) {
+            Ok(body) => ([(CONTENT_TYPE, "text/csv")], body).into_response(),
+            Err(e) => {
+                error!("csv error in completions: {e}");
+                AdminResponse::internal_error("csv error", &request_id).into_response()
+            }
+        },
+    }
+}
+
+fn completions_csv(completions: &[(Id, Completion)]) -> csv::Result<Vec<u8>> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer.write_record(["id", "completed_at", "count"])?;
+    for (id, completion) in completions {
+        writer.write_record([
+            id.to_string(),
+            completion.completed_at.to_rfc3339(),
+            completion.count.to_string(),
+        ])?;
+    }
+    writer.into_inner().map_err(|e| e.into_error().into())
+}
+
+pub async fn get_route_stats(
+    State(state): State<RouterState>,
+    Extension(request_id): Extension<RequestId>,
+) -> Response {
+    match state.get_route_stats().await {
+        Ok(stats) => {
+            info!("get route stats request");
+            AdminResponse::Ok(stats).into_response()
         }
+        Err(StateError::Busy) => busy("get_route_stats", &state),
+        Err(e) => {
+            error!("fatal, unknown error in get_route_stats: {:?}", e);
+            AdminResponse::internal_error("unknown error", &request_id).into_response()
+        }
+    }
+}
+
+/// an uploaded table.
+struct DecodedTable {
+    routes: Vec<Route>,
+    format: TableFormat,
+    /// xlsx rows without id.
+    skipped_rows: usize,
+}
+
+impl DecodedTable {
+    /// response headers describing the upload.
+    fn headers(&self) -> [(HeaderName, HeaderValue); 2] {
+        [
+            (
+                X_TABLE_FORMAT,
+                HeaderValue::from_static(self.format.as_str()),
+            ),
+            (X_SKIPPED_ROWS, HeaderValue::from(self.skipped_rows)),
+        ]
     }
 }
 
-/// Decompress and parse json data
-async fn decode_request(req: Request<Body>) -> Result<Vec<Route>, Response> {
+/// read a body, decompressed by then, see `decode_request`.
+async fn read_body(req: Request<Body>, max_decoded_body_size: usize) -> Result<Vec<u8>, Response> {
     let mut data = Vec::new();
-    let mut data_stream = req.into_body().into_data_stream();
+    // limited to `max_upload_bytes` by the `DefaultBodyLimit` of the route
+    let mut data_stream = req.into_limited_body().into_data_stream();
     while let Some(bytes) = data_stream.next().await {
         match bytes {
+            Ok(bytes) if data.len() + bytes.len() > max_decoded_body_size => {
+                warn!("decoded body exceeds {max_decoded_body_size} bytes");
+                return Err(AdminResponse::error(
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    "BODY_TOO_LARGE",
+                    "body too large",
+                )
+                .into_response());
+            }
             Ok(bytes) => data.extend(bytes),
+            Err(e) if is_length_limit_error(&e) => {
+                warn!("body exceeds max_upload_bytes");
+                return Err(AdminResponse::error(
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    "BODY_TOO_LARGE",
+                    "body too large",
+                )
+                .into_response());
+            }
             Err(e) => {
                 error!("error reading data: {e}");
-                return Err((StatusCode::BAD_REQUEST, "corrupt data").into_response());
+                return Err(AdminResponse::error(
+                    StatusCode::BAD_REQUEST,
+                    "INVALID_TABLE",
+                    "corrupt data",
+                )
+                .into_response());
             }
         }
     }
-    serde_json::from_slice(&data).map_err(|e| {
-        error!("json decode error: {e}");
-        (StatusCode::BAD_REQUEST, "corrupt data").into_response()
+    Ok(data)
+}
+
+/// read and parse an uploaded table, see [`parse_table`].
+///
+/// The body is decompressed by then, so its size is checked as it is read:
+/// a small compressed body may expand beyond `max_decoded_body_size`.
+async fn decode_request(
+    req: Request<Body>,
+    max_decoded_body_size: usize,
+) -> Result<DecodedTable, Response> {
+    let is_xlsx = req
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with(XLSX_CONTENT_TYPE));
+    let data = read_body(req, max_decoded_body_size).await?;
+    let table = if is_xlsx {
+        tokio::task::block_in_place(|| parse_xlsx(&data)).map(|(routes, skipped_rows)| {
+            DecodedTable {
+                routes,
+                format: TableFormat::Xlsx,
+                skipped_rows,
+            }
+        })
+    } else {
+        parse_table(&data).map(|(routes, format)| DecodedTable {
+            routes,
+            format,
+            skipped_rows: 0,
+        })
+    };
+    table.map_err(|e| {
+        warn!("table decode error: {e}");
+        AdminResponse::error(
+            StatusCode::BAD_REQUEST,
+            "INVALID_TABLE",
+            format!("corrupt data: {e}"),
+        )
+        .into_response()
     })
 }
+
+/// whether reading a body failed on its `DefaultBodyLimit`.
+fn is_length_limit_error(e: &axum::Error) -> bool {
+    std::error::Error::source(e).is_some_and(|e| e.is::<http_body_util::LengthLimitError>())
+}
+
+/// Envelope of admin api json responses:
+/// `{"status": "ok", "ts": ..., "data": ...}` on success,
+/// `{"status": "error", "ts": ..., "code": "BUSY", "message": ...}` on failure.
+///
+/// The status code defaults to 200 for `Ok`, and can be overridden
+/// by returning it in a tuple with the response.
+pub enum AdminResponse<T> {
+    Ok(T),
+    Error {
+        status: StatusCode,
+        /// upper snake case, stable for clients to match on.
+        code: &'static str,
+        message: String,
+    },
+}
+
+impl AdminResponse<()> {
+    pub fn error(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        Self::Error {
+            status,
+            code,
+            message: message.into(),
+        }
+    }
+
+    /// 500 quoting the request id, so users can refer to it in bug reports.
+    pub fn internal_error(msg: &str, request_id: &RequestId) -> Self {
+        Self::error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "INTERNAL_ERROR",
+            format!("{msg} (request id: {request_id})"),
+        )
+    }
+}
+
+#[derive(Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+enum Envelope<'a, T> {
+    Ok {
+        ts: String,
+        data: &'a T,
+    },
+    Error {
+        ts: String,
+        code: &'a str,
+        message: &'a str,
+    },
+}
+
+/// `ts` of the envelope, in seconds and utc.
+fn envelope_ts() -> String {
+    Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+impl<T: Serialize> IntoResponse for AdminResponse<T> {
+    fn into_response(self) -> Response {
+        let ts = envelope_ts();
+        match self {
+            AdminResponse::Ok(data) => Json(Envelope::Ok { ts, data: &data }).into_response(),
+            AdminResponse::Error {
+                status,
+                code,
+                message,
+            } => {
+                let envelope: Envelope<()> = Envelope::Error {
+                    ts,
+                    code,
+                    message: &message,
+                };
+                (status, Json(envelope)).into_response()
+            }
+        }
+    }
+}
+
+/// wrap a json body streamed as is into the `Ok` envelope.
+fn enveloped_body(body: Body) -> Body {
+    let prefix = format!(r#"{{"status":"ok","ts":"{}","data":"#, envelope_ts());
+    let stream = futures::stream::once(std::future::ready(Ok(Bytes::from(prefix))))
+        .chain(body.into_data_stream())
+        .chain(futures::stream::once(std::future::ready(Ok(
+            Bytes::from_static(b"}"),
+        ))));
+    Body::from_stream(stream)
+}
+
+/// malformed `If-Match` header, answered with 400.
+struct InvalidIfMatch;
+
+impl IntoResponse for InvalidIfMatch {
+    fn into_response(self) -> Response {
+        AdminResponse::error(
+            StatusCode::BAD_REQUEST,
+            "INVALID_IF_MATCH",
+            "If-Match must be a table version",
+        )
+        .into_response()
+    }
+}
+
+/// the version of an `If-Match` header, quoted like an etag or not.
+fn if_match(headers: &HeaderMap) -> Result<Option<u64>, InvalidIfMatch> {
+    let Some(value) = headers.get(IF_MATCH) else {
+        return Ok(None);
+    };
+    value
+        .to_str()
+        .ok()
+        .map(|value| value.trim().trim_matches('"'))
+        .and_then(|version| version.parse().ok())
+        .map(Some)
+        .ok_or(InvalidIfMatch)
+}
+
+/// 412 response, the table changed since the client read `expected`.
+fn version_mismatch(expected: u64, current: u64) -> Response {
+    warn!("table version mismatch (if-match={expected}, current={current})");
+    (
+        [(X_TABLE_VERSION, HeaderValue::from(current))],
+        AdminResponse::error(
+            StatusCode::PRECONDITION_FAILED,
+            "VERSION_MISMATCH",
+            format!("table version is {current}, not {expected}"),
+        ),
+    )
+        .into_response()
+}
+
+/// 429 response, the state is locked by another admin operation.
+///
+/// `Retry-After` suggests when the running operation should be done.
+fn busy(api: &'static str, state: &RouterState) -> Response {
+    counter!(BUSY_RESPONSES_TOTAL, "endpoint" => api).increment(1);
+    warn!("{api} busy");
+    let retry_after = state.retry_after();
+    // round up to whole seconds
+    let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+    (
+        [(RETRY_AFTER, HeaderValue::from(secs))],
+        AdminResponse::error(StatusCode::TOO_MANY_REQUESTS, "BUSY", "busy, try again"),
+    )
+        .into_response()
+}
+
+/// 503 response, too many requests in flight.
+pub async fn overloaded(_: BoxError) -> Response {
+    (StatusCode::SERVICE_UNAVAILABLE, "overloaded, try again").into_response()
+}
+
+/// 500 response quoting the request id, so users can refer to it in bug reports.
+pub fn internal_error(msg: &str, request_id: &RequestId) -> Response {
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        format!("{msg} (request id: {request_id})"),
+    )
+        .into_response()
+}
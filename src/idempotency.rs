@@ -0,0 +1,161 @@
+//! `Idempotency-Key` support for table uploads, so that retried requests
+//! are answered from a cache instead of writing the table again.
+use crate::handler::AdminResponse;
+use axum::{
+    body::{to_bytes, Body, Bytes},
+    extract::Request,
+    http::{header::CONTENT_TYPE, HeaderName, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use lru::LruCache;
+use std::{
+    future::Future,
+    num::NonZeroUsize,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+pub const IDEMPOTENCY_KEY: HeaderName = HeaderName::from_static("idempotency-key");
+/// set on responses replayed from the cache.
+pub const IDEMPOTENT_REPLAYED: HeaderName = HeaderName::from_static("idempotent-replayed");
+const MAX_KEY_LENGTH: usize = 255;
+
+/// The last responses by idempotency key.
+pub struct IdempotencyCache {
+    entries: Mutex<LruCache<String, Entry>>,
+    ttl: Duration,
+}
+
+enum Entry {
+    InFlight,
+    Done(CachedResponse),
+}
+
+struct CachedResponse {
+    status: StatusCode,
+    content_type: Option<HeaderValue>,
+    body: Bytes,
+    stored_at: Instant,
+}
+
+impl CachedResponse {
+    fn replay(&self) -> Response {
+        let mut rsp = (self.status, self.body.clone()).into_response();
+        let headers = rsp.headers_mut();
+        if let Some(content_type) = &self.content_type {
+            headers.insert(CONTENT_TYPE, content_type.clone());
+        }
+        headers.insert(IDEMPOTENT_REPLAYED, HeaderValue::from_static("true"));
+        rsp
+    }
+}
+
+/// removes the in-flight marker if the request did not complete,
+/// e.g. the client went away.
+struct InFlightGuard<'a> {
+    cache: &'a IdempotencyCache,
+    key: &'a str,
+}
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        let mut entries = self.cache.entries.lock().expect("poisoned");
+        if matches!(entries.peek(self.key), Some(Entry::InFlight)) {
+            entries.pop(self.key);
+        }
+    }
+}
+
+impl IdempotencyCache {
+    pub fn new(capacity: NonZeroUsize, ttl: Duration) -> Self {
+        Self {
+            entries: Mutex::new(LruCache::new(capacity)),
+            ttl,
+        }
+    }
+
+    /// run `handler` once per key: a completed request is answered from the cache,
+    /// one still in flight with 409. Only successful responses are cached,
+    /// so that failed requests can be retried with the same key.
+    pub async fn run(
+        &self,
+        key: Option<String>,
+        handler: impl Future<Output = Response>,
+    ) -> Response {
+        let Some(key) = key else {
+            return handler.await;
+        };
+        {
+            let mut entries = self.entries.lock().expect("poisoned");
+            match entries.get(&key) {
+                Some(Entry::InFlight) => {
+                    return AdminResponse::error(
+                        StatusCode::CONFLICT,
+                        "IDEMPOTENCY_KEY_IN_USE",
+                        "request with this idempotency key in progress",
+                    )
+                    .into_response()
+                }
+                Some(Entry::Done(cached)) if cached.stored_at.elapsed() < self.ttl => {
+                    return cached.replay()
+                }
+                _ => entries.put(key.clone(), Entry::InFlight),
+            };
+        }
+        let _guard = InFlightGuard {
+            cache: self,
+            key: &key,
+        };
+        let rsp = handler.await;
+        if !rsp.status().is_success() {
+            return rsp;
+        }
+        let (parts, body) = rsp.into_parts();
+        let Ok(body) = to_bytes(body, usize::MAX).await else {
+            return AdminResponse::error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "INTERNAL_ERROR",
+                "failed to read the response",
+            )
+            .into_response();
+        };
+        let cached = CachedResponse {
+            status: parts.status,
+            content_type: parts.headers.get(CONTENT_TYPE).cloned(),
+            body: body.clone(),
+            stored_at: Instant::now(),
+        };
+        self.entries
+            .lock()
+            .expect("poisoned")
+            .put(key.clone(), Entry::Done(cached));
+        Response::from_parts(parts, Body::from(body))
+    }
+}
+
+/// malformed `Idempotency-Key` header, answered with 400.
+pub struct InvalidIdempotencyKey;
+
+impl IntoResponse for InvalidIdempotencyKey {
+    fn into_response(self) -> Response {
+        AdminResponse::error(
+            StatusCode::BAD_REQUEST,
+            "INVALID_IDEMPOTENCY_KEY",
+            "invalid idempotency key",
+        )
+        .into_response()
+    }
+}
+
+/// the idempotency key of a request, scoped by method.
+pub fn idempotency_key(req: &Request) -> Result<Option<String>, InvalidIdempotencyKey> {
+    let Some(key) = req.headers().get(IDEMPOTENCY_KEY) else {
+        return Ok(None);
+    };
+    match key.to_str() {
+        Ok(key) if !key.is_empty() && key.len() <= MAX_KEY_LENGTH => {
+            Ok(Some(format!("{} {key}", req.method())))
+        }
+        _ => Err(InvalidIdempotencyKey),
+    }
+}
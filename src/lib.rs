@@ -0,0 +1,390 @@
+//! Survey redirect server: participants get a personal link,
+//! which redirects them to their survey with their id attached.
+use crate::{admin_auth::AdminAuth, config::Config, server::Surface, state::RouterState};
+use axum::{
+    body::Bytes,
+    error_handling::HandleErrorLayer,
+    extract::DefaultBodyLimit,
+    http::{
+        header::{CACHE_CONTROL, CONTENT_TYPE},
+        HeaderName, HeaderValue, StatusCode,
+    },
+    middleware,
+    response::IntoResponse,
+    routing::{delete, get, patch, post, put},
+    Router,
+};
+use ipnet::IpNet;
+use metrics_exporter_prometheus::PrometheusHandle;
+use std::{path::Path, sync::Arc, time::Duration};
+use tower::{limit::GlobalConcurrencyLimitLayer, ServiceBuilder};
+use tower_http::{
+    compression::CompressionLayer, decompression::RequestDecompressionLayer,
+    set_header::SetResponseHeaderLayer,
+};
+
+pub mod access_log;
+pub mod admin_auth;
+pub mod catch_panic;
+pub mod certs;
+pub mod cli;
+pub mod client_ip;
+pub mod config;
+pub mod error_pages;
+pub mod geoip;
+pub mod handler;
+pub mod idempotency;
+pub mod log_file;
+pub mod monitoring;
+pub mod redirect_page;
+pub mod redirect_policy;
+pub mod request_id;
+pub mod server;
+pub mod sharded_map;
+pub mod state;
+pub mod storage;
+pub mod timeout;
+pub mod trace_context;
+pub mod utility;
+
+pub const EXTERNEL_ID: &str = "externalUserId";
+/// query parameter telling which of `round_robin_urls` was chosen.
+pub const RR_IDX: &str = "_rr_idx";
+/// query parameter telling which of `geo_urls` was chosen.
+pub const GEO: &str = "geo";
+pub const API: &str = "api";
+pub const CODE: &str = "code";
+/// default length of newly generated codes, see `Config::code_length`.
+pub const CODE_LENGTH: usize = 16;
+pub const CONFIG_FILE_NAME: &str = "config.yaml";
+pub const MAX_DESCRIPTION_LENGTH: usize = 1000;
+/// upper bound of the characters of `wave` of routes.
+pub const MAX_WAVE_LENGTH: usize = 64;
+/// upper bound of `request_timeout_secs` of routes.
+pub const MAX_REQUEST_TIMEOUT_SECS: u64 = 600;
+pub const X_ROBOTS_TAG: HeaderName = HeaderName::from_static("x-robots-tag");
+pub const X_API_VERSION: HeaderName = HeaderName::from_static("x-api-version");
+pub const X_SURVEY_TIMEOUT: HeaderName = HeaderName::from_static("x-survey-timeout");
+/// shape of an uploaded table, `array`, `map` or `xlsx`.
+pub const X_TABLE_FORMAT: HeaderName = HeaderName::from_static("x-table-format");
+/// version of the routing table, see `RouterState::table_version`.
+pub const X_TABLE_VERSION: HeaderName = HeaderName::from_static("x-table-version");
+/// number of xlsx rows skipped for having no id.
+pub const X_SKIPPED_ROWS: HeaderName = HeaderName::from_static("x-skipped-rows");
+/// content type of uploaded excel workbooks.
+pub const XLSX_CONTENT_TYPE: &str =
+    "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet";
+/// content type of JSON Merge Patch (RFC 7396) bodies of `PATCH routing_table`.
+pub const MERGE_PATCH_CONTENT_TYPE: &str = "application/merge-patch+json";
+pub const DEPRECATION: HeaderName = HeaderName::from_static("deprecation");
+pub const BODY_LIMIT: usize = 128 * 1024 * 1024;
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// define the listeners: a single one serving everything,
+/// or separate public and admin listeners if `admin_binding` is set,
+/// plus a metrics listener if `metrics_binding` is set.
+pub fn surfaces(
+    server_config: &Config,
+    state: RouterState,
+    metrics: PrometheusHandle,
+) -> Vec<Surface> {
+    // `/metrics` goes behind the admin token, unless it has its own listener
+    let (admin_metrics, metrics_surface) = match server_config.metrics_binding.clone() {
+        None => (Some(metrics), None),
+        Some(metrics_binding) => (
+            None,
+            Some(Surface {
+                name: "metrics (/metrics)",
+                binds: metrics_binding,
+                app: with_common_layers(monitoring::routes(metrics), server_config, state.clone()),
+                admin_tls: false,
+            }),
+        ),
+    };
+    let mut surfaces = match server_config.admin_binding.clone() {
+        None => vec![Surface {
+            name: "server",
+            binds: server_config.server_binding.clone(),
+            app: router_with_metrics(server_config, state, admin_metrics),
+            admin_tls: false,
+        }],
+        Some(admin_binding) => vec![
+            Surface {
+                name: "public api (/api)",
+                binds: server_config.server_binding.clone(),
+                app: with_common_layers(
+                    with_path_prefix(
+                        api_routes(server_config)
+                            .merge(health_routes())
+                            .merge(static_routes(server_config)),
+                        server_config,
+                    ),
+                    server_config,
+                    state.clone(),
+                ),
+                admin_tls: false,
+            },
+            Surface {
+                name: "admin api (/admin)",
+                binds: admin_binding,
+                app: with_common_layers(
+                    with_path_prefix(
+                        versioned_admin_routes(server_config, &state, admin_metrics),
+                        server_config,
+                    ),
+                    server_config,
+                    state,
+                ),
+                admin_tls: true,
+            },
+        ],
+    };
+    surfaces.extend(metrics_surface);
+    surfaces
+}
+
+/// define router serving both the public and the admin api, without `/admin/metrics`.
+pub fn router(server_config: &Config, state: RouterState) -> Router {
+    router_with_metrics(server_config, state, None)
+}
+
+fn router_with_metrics(
+    server_config: &Config,
+    state: RouterState,
+    metrics: Option<PrometheusHandle>,
+) -> Router {
+    let app = api_routes(server_config)
+        .merge(versioned_admin_routes(server_config, &state, metrics))
+        .merge(health_routes())
+        .merge(static_routes(server_config));
+    with_common_layers(with_path_prefix(app, server_config), server_config, state)
+}
+
+/// nest `app` under `path_prefix`, if set.
+fn with_path_prefix(app: Router<RouterState>, server_config: &Config) -> Router<RouterState> {
+    match &server_config.path_prefix {
+        Some(prefix) => Router::new().nest(prefix, app),
+        None => app,
+    }
+}
+
+/// public redirect routes.
+///
+/// `/api/` is accepted as well, since some email gateways append a slash to links.
+/// Paths stay case-sensitive, links are generated in lower case.
+fn api_routes(server_config: &Config) -> Router<RouterState> {
+    let app = Router::new()
+        .route("/api", get(handler::redirect))
+        .route("/api/", get(handler::redirect))
+        .route(
+            "/api/complete",
+            // a replayed completion would count twice
+            get(handler::complete).layer(middleware::from_fn(server::reject_all_early_data)),
+        )
+        .layer(
+            ServiceBuilder::new()
+                .layer(HandleErrorLayer::new(handler::overloaded))
+                .load_shed()
+                .layer(GlobalConcurrencyLimitLayer::new(
+                    server_config.api_concurrency_limit,
+                )),
+        )
+        .route_layer(middleware::from_fn(server::reject_drained));
+    if server_config.robots_noindex {
+        app.layer(SetResponseHeaderLayer::overriding(
+            X_ROBOTS_TAG,
+            HeaderValue::from_static("noindex, nofollow"),
+        ))
+    } else {
+        app
+    }
+}
+
+/// `/robots.txt` and `/favicon.ico`, answered here so that browsers and crawlers
+/// do not show up as 404s. No authentication, not counted as clicks.
+fn static_routes(server_config: &Config) -> Router<RouterState> {
+    let robots_txt = server_config.robots_txt.clone();
+    let favicon = server_config.favicon.as_ref().and_then(|path| {
+        std::fs::read(path)
+            .map(|icon| (favicon_content_type(path), Bytes::from(icon)))
+            .inspect_err(|e| tracing::warn!("failed to read favicon {}: {e}", path.display()))
+            .ok()
+    });
+    Router::new()
+        .route("/robots.txt", get(move || std::future::ready(robots_txt)))
+        .route(
+            "/favicon.ico",
+            get(move || {
+                std::future::ready(match favicon {
+                    Some((content_type, icon)) => (
+                        [
+                            (CONTENT_TYPE, content_type),
+                            (CACHE_CONTROL, "public, max-age=86400"),
+                        ],
+                        icon,
+                    )
+                        .into_response(),
+                    None => StatusCode::NO_CONTENT.into_response(),
+                })
+            }),
+        )
+}
+
+fn favicon_content_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("png") => "image/png",
+        Some("svg") => "image/svg+xml",
+        _ => "image/x-icon",
+    }
+}
+
+/// health checks for load balancers, no authentication
+fn health_routes() -> Router<RouterState> {
+    Router::new()
+        .route("/healthz", get(handler::healthz))
+        .route("/readyz", get(handler::readyz))
+}
+
+/// admin routes at `/{api_version}/admin`, and at the deprecated `/admin`.
+fn versioned_admin_routes(
+    server_config: &Config,
+    state: &RouterState,
+    metrics: Option<PrometheusHandle>,
+) -> Router<RouterState> {
+    let mut admin = admin_routes(server_config, state, metrics).merge(admin_unauthed_routes());
+    if let Some(networks) = &server_config.admin_allowed_networks {
+        admin = admin.layer(middleware::from_fn_with_state(
+            Arc::<[IpNet]>::from(networks.as_slice()),
+            admin_auth::require_allowed_network,
+        ));
+    }
+    let Some(api_version) = &server_config.api_version else {
+        return Router::new().nest("/admin", admin);
+    };
+    Router::new()
+        .nest(&format!("/{api_version}/admin"), admin.clone())
+        .nest(
+            "/admin",
+            admin.layer(SetResponseHeaderLayer::overriding(
+                DEPRECATION,
+                HeaderValue::from_static("true"),
+            )),
+        )
+}
+
+/// admin routes outside of the token check and the concurrency limit,
+/// they must not expose any state.
+fn admin_unauthed_routes() -> Router<RouterState> {
+    Router::new().route("/ping", get(handler::ping))
+}
+
+/// admin routes, behind the admin token
+fn admin_routes(
+    server_config: &Config,
+    state: &RouterState,
+    metrics: Option<PrometheusHandle>,
+) -> Router<RouterState> {
+    // body limits per group of routes, a route layer applies to the
+    // routes added before it and the innermost limit wins.
+    let mut app = Router::new()
+        .route("/routing_table", put(handler::put_routing_table))
+        .route("/routing_table", patch(handler::patch_routing_table))
+        .route_layer(DefaultBodyLimit::max(server_config.max_upload_bytes))
+        .route("/routing_table", delete(handler::delete_routes))
+        .route("/activate_codes", post(handler::activate_codes))
+        .route("/deactivate_codes", post(handler::deactivate_codes))
+        .route("/drain", post(handler::drain))
+        .route("/undrain", post(handler::undrain))
+        .route("/reload", post(handler::reload))
+        .route("/reload_cert", post(handler::reload_cert))
+        .route("/rekey", post(handler::rekey))
+        .route("/purge_orphaned_codes", post(handler::purge_orphaned_codes))
+        .route("/admin_token", patch(handler::rotate_admin_token))
+        .route_layer(DefaultBodyLimit::max(BODY_LIMIT))
+        .route("/get_links", get(handler::get_links))
+        .route("/get_codes", get(handler::get_codes))
+        .route("/route_stats", get(handler::get_route_stats))
+        .route("/route_history", get(handler::route_history))
+        .route("/participant/:id/links", get(handler::participant_links))
+        .route("/completions", get(handler::completions))
+        .route("/storage_stats", get(handler::storage_stats))
+        .route("/runtime_info", get(handler::runtime_info))
+        .route("/metrics.json", get(handler::json_metrics))
+        .route("/search_routes", get(handler::search_routes))
+        .route("/search_codes", get(handler::search_codes))
+        .route("/routing_table", get(handler::get_routing_table))
+        .route("/validate", get(handler::validate))
+        .route("/codes_without_routes", get(handler::codes_without_routes))
+        .route_layer(DefaultBodyLimit::max(0));
+    if let Some(metrics) = metrics {
+        app = app.merge(monitoring::routes(metrics));
+    }
+    let compression = &server_config.compression;
+    let app = app
+        .layer(
+            RequestDecompressionLayer::new()
+                .gzip(compression.gzip)
+                .br(compression.br)
+                .zstd(compression.zstd),
+        )
+        .layer(
+            CompressionLayer::new()
+                .gzip(compression.gzip)
+                .br(compression.br)
+                .zstd(compression.zstd)
+                .quality(compression.level.into()),
+        )
+        .layer(middleware::from_fn_with_state(
+            AdminAuth::new(
+                state.admin_token.clone(),
+                server_config.admin_auth_max_failures,
+                Duration::from_secs(server_config.admin_auth_failure_window_secs),
+            ),
+            admin_auth::require_admin_token,
+        ))
+        // reject excess requests before reading their bodies
+        .layer(
+            ServiceBuilder::new()
+                .layer(HandleErrorLayer::new(handler::overloaded))
+                .load_shed()
+                .layer(GlobalConcurrencyLimitLayer::new(
+                    server_config.admin_concurrency_limit,
+                )),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            state.too_many_requests_total.clone(),
+            monitoring::track_admin,
+        ));
+    match &server_config.api_version {
+        Some(api_version) => app.layer(SetResponseHeaderLayer::overriding(
+            X_API_VERSION,
+            HeaderValue::from_str(api_version).expect("validated api_version"),
+        )),
+        None => app,
+    }
+}
+
+/// layers shared by all listeners
+pub fn with_common_layers(
+    app: Router<RouterState>,
+    server_config: &Config,
+    state: RouterState,
+) -> Router {
+    let trusted_proxies: Arc<[IpNet]> = server_config.trusted_proxies.clone().into();
+    app.layer(middleware::from_fn(server::reject_early_data))
+        .layer(middleware::from_fn(catch_panic::catch_panic))
+        .layer(middleware::from_fn(timeout::timeout))
+        .layer(middleware::from_fn_with_state(
+            server_config.access_log_exclude.clone().into(),
+            access_log::access_log,
+        ))
+        .layer(middleware::from_fn_with_state(
+            trusted_proxies.clone(),
+            request_id::request_id,
+        ))
+        .layer(middleware::from_fn_with_state(
+            trusted_proxies,
+            client_ip::client_ip,
+        ))
+        .with_state(state)
+}
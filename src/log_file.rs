@@ -0,0 +1,110 @@
+//! The application log file, rotated by time or size, see `Config::log_rotation`.
+use crate::config::LogRotation;
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+
+/// open the log file `dir/prefix`, rotated files are named `prefix.<time>`.
+///
+/// At most `keep_files` files are kept, including the current one.
+pub fn open(
+    dir: &Path,
+    prefix: &str,
+    rotation: LogRotation,
+    keep_files: usize,
+) -> io::Result<Box<dyn Write + Send>> {
+    let builder = RollingFileAppender::builder().filename_prefix(prefix);
+    let builder = match rotation {
+        // no pruning, it would remove the only file
+        LogRotation::Never => builder.rotation(Rotation::NEVER),
+        LogRotation::Hourly => builder.rotation(Rotation::HOURLY).max_log_files(keep_files),
+        LogRotation::Daily => builder.rotation(Rotation::DAILY).max_log_files(keep_files),
+        LogRotation::SizeMb(size_mb) => {
+            let file = SizeRollingFile::new(dir, prefix, size_mb * 1024 * 1024, keep_files)?;
+            return Ok(Box::new(file));
+        }
+    };
+    let appender = builder.build(dir).map_err(io::Error::other)?;
+    Ok(Box::new(appender))
+}
+
+/// A log file moved aside to `prefix.<time>` once it exceeds `max_bytes`.
+pub struct SizeRollingFile {
+    dir: PathBuf,
+    prefix: String,
+    max_bytes: u64,
+    keep_files: usize,
+    file: File,
+    written: u64,
+}
+
+impl SizeRollingFile {
+    pub fn new(dir: &Path, prefix: &str, max_bytes: u64, keep_files: usize) -> io::Result<Self> {
+        let path = dir.join(prefix);
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let written = file.metadata()?.len();
+        Ok(Self {
+            dir: dir.to_owned(),
+            prefix: prefix.to_owned(),
+            max_bytes,
+            keep_files,
+            file,
+            written,
+        })
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        let time = chrono::Local::now().format("%Y-%m-%dT%H-%M-%S%.6f");
+        let current = self.dir.join(&self.prefix);
+        std::fs::rename(&current, self.dir.join(format!("{}.{time}", self.prefix)))?;
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&current)?;
+        self.written = 0;
+        self.prune()
+    }
+
+    /// remove the oldest rotated files, keeping `keep_files` including the current one.
+    fn prune(&self) -> io::Result<()> {
+        let rotated_prefix = format!("{}.", self.prefix);
+        let mut rotated = Vec::new();
+        for entry in std::fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_file()
+                && entry
+                    .file_name()
+                    .to_str()
+                    .is_some_and(|name| name.starts_with(&rotated_prefix))
+            {
+                rotated.push(entry.path());
+            }
+        }
+        // timestamps sort chronologically
+        rotated.sort_unstable();
+        let excess = (rotated.len() + 1).saturating_sub(self.keep_files);
+        for path in &rotated[..excess] {
+            std::fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+}
+
+impl Write for SizeRollingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.written > 0 && self.written + buf.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        let n = self.file.write(buf)?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
@@ -1,62 +1,127 @@
-use crate::{certs::cert_provider_from_file, config::Config, state::RouterState};
-use axum::{
-    extract::DefaultBodyLimit,
-    routing::{get, patch, put},
-    Router,
-};
+use clap::Parser;
 use std::{fs::OpenOptions, time::Duration};
-use tower_http::{
-    compression::CompressionLayer, decompression::RequestDecompressionLayer, timeout::TimeoutLayer,
-    validate_request::ValidateRequestHeaderLayer,
+use survey_redirect::{
+    access_log::ACCESS_LOG_TARGET,
+    catch_panic,
+    certs::{cert_provider_from_file, CertRole},
+    cli::{self, Cli},
+    config::Config,
+    handler::CLICK_LOG_TARGET,
+    log_file, monitoring,
+    server::{self, ServerOptions},
+    state::RouterState,
+    surfaces,
 };
-use tracing_subscriber::prelude::*;
-
-pub mod certs;
-pub mod config;
-pub mod handler;
-pub mod server;
-pub mod state;
-pub mod utility;
-
-pub const EXTERNEL_ID: &str = "externalUserId";
-pub const API: &str = "api";
-pub const CODE: &str = "code";
-pub const CODE_LENGTH: usize = 16;
-pub const CONFIG_FILE_NAME: &str = "config.yaml";
-pub const BODY_LIMIT: usize = 128 * 1024 * 1024;
-pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+use tracing_subscriber::{filter::filter_fn, prelude::*};
 
 fn main() {
+    let cli = Cli::parse();
+
+    // validate config and state, without serving
+    if cli.dry_run {
+        let summary = Config::load()
+            .map_err(|e| std::io::Error::other(format!("invalid config: {e}")))
+            .and_then(|config| cli::dry_run(&config));
+        match summary {
+            Ok(summary) => println!(
+                "{}",
+                serde_json::to_string(&summary).expect("serializable summary")
+            ),
+            Err(e) => {
+                eprintln!("error: {e}");
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    // offline commands
+    if let Some(command) = cli.command {
+        if let Err(e) = cli::run(command) {
+            eprintln!("error: {e}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
     // read configuration
     let server_config = Config::load().expect("failed to load config");
 
     // configure log
     let timer = tracing_subscriber::fmt::time::ChronoLocal::rfc_3339();
+    // keep access and click logs out of the application log if they have their own files
+    let separate_access_log = server_config.access_log_file.is_some();
+    let separate_click_log = server_config.click_log_file.is_some();
+    let not_separate_log = move || {
+        filter_fn(move |m| {
+            !(separate_access_log && m.target() == ACCESS_LOG_TARGET
+                || separate_click_log && m.target() == CLICK_LOG_TARGET)
+        })
+    };
     let stdout_log = tracing_subscriber::fmt::layer()
         .pretty()
-        .with_timer(timer.clone());
-    let log_file = OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open(&server_config.log_file)
-        .expect("failed to open log file");
+        .with_timer(timer.clone())
+        .with_filter(not_separate_log());
+    let (log_dir, log_file_prefix) = server_config.log_file_location();
+    let log_file = log_file::open(
+        &log_dir,
+        &log_file_prefix,
+        server_config.log_rotation,
+        server_config.log_keep_files,
+    )
+    .expect("failed to open log file");
+    // flushed when dropped at the end of main
+    let (log_file, _log_guard) = tracing_appender::non_blocking(log_file);
     let log_to_file = tracing_subscriber::fmt::layer()
         .with_ansi(false)
-        .with_timer(timer)
-        .with_writer(log_file);
+        .with_timer(timer.clone())
+        .with_writer(log_file)
+        .with_filter(not_separate_log());
+    let access_log_to_file = server_config.access_log_file.as_ref().map(|path| {
+        let access_log_file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .expect("failed to open access log file");
+        tracing_subscriber::fmt::layer()
+            .with_ansi(false)
+            .with_timer(timer.clone())
+            .with_writer(access_log_file)
+            .with_filter(filter_fn(|m| {
+                m.is_span() || m.target() == ACCESS_LOG_TARGET
+            }))
+    });
+    let click_log_to_file = server_config.click_log_file.as_ref().map(|path| {
+        let click_log_file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .expect("failed to open click log file");
+        tracing_subscriber::fmt::layer()
+            .with_ansi(false)
+            .with_timer(timer)
+            .with_writer(click_log_file)
+            .with_filter(filter_fn(|m| m.target() == CLICK_LOG_TARGET))
+    });
     tracing_subscriber::registry()
         .with(tracing_subscriber::EnvFilter::new(
             std::env::var("RUST_LOG").unwrap_or_else(|_| "survey_redirect=info".into()),
         ))
         .with(stdout_log)
         .with(log_to_file)
+        .with(access_log_to_file)
+        .with(click_log_to_file)
         .init();
+    catch_panic::install_panic_hook();
+
+    // metrics recorder, before anything is recorded
+    let metrics = monitoring::install_recorder(&server_config.metrics_latency_buckets);
 
     // load state from disk
     let state = RouterState::init(&server_config).expect("error initing router table");
 
-    // define router
-    let app = router(&server_config, state);
+    // define routers
+    let surfaces = surfaces(&server_config, state.clone(), metrics.clone());
 
     // init runtime
     let rt = tokio::runtime::Builder::new_multi_thread()
@@ -64,41 +129,84 @@ fn main() {
         .build()
         .expect("failed to start runtime");
 
-    let bind = server_config.server_binding;
-    tracing::info!("server listening at {}", bind);
-
     // watch cert changes
     let tls_cert_provider = cert_provider_from_file(
         server_config.server_tls,
+        CertRole::Server,
         &server_config.watch_cert_changes,
         &rt,
     )
     .expect("failed to watch cert files");
+    let admin_tls_cert_provider = cert_provider_from_file(
+        server_config.admin_tls,
+        CertRole::Admin,
+        &server_config.watch_cert_changes,
+        &rt,
+    )
+    .expect("failed to watch admin cert files");
+    let (tls_cert_provider, server_cert_reload) = tls_cert_provider.unzip();
+    let (admin_tls_cert_provider, admin_cert_reload) = admin_tls_cert_provider.unzip();
+    state.set_cert_reload(
+        server_cert_reload
+            .into_iter()
+            .chain(admin_cert_reload)
+            .collect(),
+    );
+
+    // reread error pages on change, until the end of main
+    let _error_templates_watcher = state
+        .watch_error_templates()
+        .expect("failed to watch error_templates_dir");
+
+    rt.spawn(monitoring::run_upkeep(metrics));
+
+    let server_options = ServerOptions {
+        unix_socket_mode: server_config.unix_socket_mode,
+        tcp_backlog: server_config.tcp_backlog,
+        shutting_down: state.shutting_down.clone(),
+        draining: state.draining.clone(),
+        drain_timeout: Duration::from_secs(server_config.shutdown_timeout_secs),
+        idle_connection_timeout: server_config
+            .idle_connection_timeout_secs
+            .map(Duration::from_secs),
+    };
+
+    // flush hit counts in background, and once more after connections are closed
+    let hit_flusher = rt.spawn(
+        state
+            .clone()
+            .run_hit_flusher(Duration::from_secs(server_config.hit_flush_interval_secs)),
+    );
+    // write snapshots of PATCHes after `snapshot_debounce_secs`, and any pending one at shutdown
+    let snapshot_writer = rt.spawn(state.clone().run_snapshot_writer());
+    let final_flush = async move {
+        hit_flusher.abort();
+        snapshot_writer.abort();
+        // let a snapshot being written finish
+        let _ = snapshot_writer.await;
+        if let Err(e) = state.flush_snapshot().await {
+            tracing::error!("failed to write snapshot: {:?}", e);
+        }
+        if let Err(e) = state.flush_hits().await {
+            tracing::error!("failed to flush hit counts: {:?}", e);
+        }
+        if let Err(e) = state.flush_completions() {
+            tracing::error!("failed to flush completions: {:?}", e);
+        }
+    };
 
     // start server
-    if let Err(e) = rt.block_on(server::run_server(&app, bind, tls_cert_provider)) {
+    if let Err(e) = rt.block_on(server::run_server(
+        surfaces,
+        &server_options,
+        tls_cert_provider,
+        admin_tls_cert_provider,
+        final_flush,
+    )) {
         tracing::error!("failed to run server {}", e);
     }
-}
 
-/// define router
-fn router(server_config: &Config, state: RouterState) -> Router {
-    // define router
-    let api = Router::new().route("/", get(handler::redirect));
-    let admin = Router::new()
-        .route("/get_links", get(handler::get_links))
-        .route("/get_codes", get(handler::get_codes))
-        .route("/routing_table", put(handler::put_routing_table))
-        .route("/routing_table", patch(handler::patch_routing_table))
-        .layer(RequestDecompressionLayer::new().gzip(true))
-        .layer(CompressionLayer::new().gzip(true))
-        .layer(ValidateRequestHeaderLayer::bearer(
-            &server_config.admin_token,
-        ))
-        .layer(DefaultBodyLimit::max(BODY_LIMIT));
-    Router::new()
-        .nest("/api", api)
-        .nest("/admin", admin)
-        .layer(TimeoutLayer::new(DEFAULT_TIMEOUT))
-        .with_state(state)
+    // stop the cert watcher and other background tasks
+    rt.shutdown_timeout(Duration::from_secs(1));
+    tracing::info!("shutdown completed");
 }
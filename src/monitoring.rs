@@ -0,0 +1,247 @@
+//! Prometheus metrics, rendered at `/metrics`.
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::Response,
+    routing::get,
+    Router,
+};
+use metrics::{counter, describe_counter, describe_gauge, describe_histogram, gauge, Unit};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+pub const REDIRECTS_TOTAL: &str = "redirects_total";
+pub const REDIRECT_DURATION_SECONDS: &str = "redirect_duration_seconds";
+/// whole redirect requests, from the handler until the response is built.
+pub const REDIRECT_RESOLUTION_DURATION_SECONDS: &str =
+    "survey_redirect_resolution_duration_seconds";
+/// routing table updates by `operation`, including the writes to storage.
+pub const ADMIN_OPERATION_DURATION_SECONDS: &str = "admin_operation_duration_seconds";
+pub const ADMIN_REQUESTS_TOTAL: &str = "admin_requests_total";
+pub const BUSY_RESPONSES_TOTAL: &str = "busy_responses_total";
+/// admin requests from outside of `admin_allowed_networks`.
+pub const ADMIN_DENIED_TOTAL: &str = "admin_denied_total";
+/// admin requests with a missing or invalid token.
+pub const ADMIN_AUTH_FAILURES_TOTAL: &str = "admin_auth_failures_total";
+/// admin requests answered 429 after too many auth failures.
+pub const ADMIN_AUTH_THROTTLED_TOTAL: &str = "admin_auth_throttled_total";
+pub const TLS_HANDSHAKE_FAILURES_TOTAL: &str = "tls_handshake_failures_total";
+pub const ROUTER_TABLE_SIZE: &str = "router_table_size";
+pub const CODE_TABLE_SIZE: &str = "code_table_size";
+pub const CONNECTIONS_ACTIVE: &str = "connections_active";
+pub const CONNECTIONS_TOTAL: &str = "connections_total";
+pub const PUT_APPLY_DURATION_SECONDS: &str = "put_apply_duration_seconds";
+
+/// connection counters, kept by the server loops.
+pub static SERVER_METRICS: ServerMetrics = ServerMetrics::new();
+
+/// `outcome` labels of `REDIRECTS_TOTAL`.
+pub const REDIRECT_OUTCOMES: [&str; 8] = [
+    "success",
+    "malformed_query",
+    "blocked_referrer",
+    "invalid_code",
+    "deactivated",
+    "expired",
+    "blocked_target",
+    "error",
+];
+
+/// `REDIRECTS_TOTAL` by outcome, kept as plain atomics for `/admin/metrics.json`.
+#[derive(Default)]
+pub struct RedirectOutcomes([AtomicU64; REDIRECT_OUTCOMES.len()]);
+
+impl RedirectOutcomes {
+    /// count a redirect with one of `REDIRECT_OUTCOMES`.
+    pub fn count(&self, outcome: &'static str) {
+        counter!(REDIRECTS_TOTAL, "outcome" => outcome).increment(1);
+        if let Some(i) = REDIRECT_OUTCOMES.iter().position(|o| *o == outcome) {
+            self.0[i].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// counts since startup by outcome, including outcomes that never happened.
+    pub fn totals(&self) -> BTreeMap<&'static str, u64> {
+        REDIRECT_OUTCOMES
+            .into_iter()
+            .zip(&self.0)
+            .map(|(outcome, count)| (outcome, count.load(Ordering::Relaxed)))
+            .collect()
+    }
+}
+
+/// Connection-level counters.
+///
+/// Kept as plain atomics so they can also be reported outside of `/metrics`,
+/// they are published to the recorder whenever `/metrics` is rendered.
+pub struct ServerMetrics {
+    pub connections_active: AtomicU64,
+    pub connections_total: AtomicU64,
+    pub tls_handshake_failures: AtomicU64,
+}
+
+impl ServerMetrics {
+    const fn new() -> Self {
+        Self {
+            connections_active: AtomicU64::new(0),
+            connections_total: AtomicU64::new(0),
+            tls_handshake_failures: AtomicU64::new(0),
+        }
+    }
+
+    pub fn connection_opened(&self) {
+        self.connections_total.fetch_add(1, Ordering::Relaxed);
+        self.connections_active.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_closed(&self) {
+        self.connections_active.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn tls_handshake_failed(&self) {
+        self.tls_handshake_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn publish(&self) {
+        gauge!(CONNECTIONS_ACTIVE).set(self.connections_active.load(Ordering::Relaxed) as f64);
+        counter!(CONNECTIONS_TOTAL).absolute(self.connections_total.load(Ordering::Relaxed));
+        counter!(TLS_HANDSHAKE_FAILURES_TOTAL)
+            .absolute(self.tls_handshake_failures.load(Ordering::Relaxed));
+    }
+}
+
+const UPKEEP_INTERVAL: Duration = Duration::from_secs(5);
+
+/// buckets of the table update histograms.
+const ADMIN_BUCKETS: &[f64] = &[0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0];
+
+/// install the global metrics recorder, with `latency_buckets` for the redirect histograms.
+pub fn install_recorder(latency_buckets: &[f64]) -> PrometheusHandle {
+    let handle = PrometheusBuilder::new()
+        .set_buckets_for_metric(
+            Matcher::Full(REDIRECT_DURATION_SECONDS.to_owned()),
+            latency_buckets,
+        )
+        .expect("non-empty buckets")
+        .set_buckets_for_metric(
+            Matcher::Full(REDIRECT_RESOLUTION_DURATION_SECONDS.to_owned()),
+            latency_buckets,
+        )
+        .expect("non-empty buckets")
+        .set_buckets_for_metric(
+            Matcher::Full(PUT_APPLY_DURATION_SECONDS.to_owned()),
+            ADMIN_BUCKETS,
+        )
+        .expect("non-empty buckets")
+        .set_buckets_for_metric(
+            Matcher::Full(ADMIN_OPERATION_DURATION_SECONDS.to_owned()),
+            ADMIN_BUCKETS,
+        )
+        .expect("non-empty buckets")
+        .install_recorder()
+        .expect("failed to install metrics recorder");
+
+    describe_counter!(REDIRECTS_TOTAL, "redirect requests by outcome");
+    describe_histogram!(
+        REDIRECT_DURATION_SECONDS,
+        Unit::Seconds,
+        "time to resolve a redirect"
+    );
+    describe_histogram!(
+        REDIRECT_RESOLUTION_DURATION_SECONDS,
+        Unit::Seconds,
+        "time to answer a redirect request with a redirect"
+    );
+    describe_counter!(
+        ADMIN_REQUESTS_TOTAL,
+        "admin requests by endpoint and status"
+    );
+    describe_counter!(BUSY_RESPONSES_TOTAL, "429 busy responses by endpoint");
+    describe_counter!(
+        ADMIN_DENIED_TOTAL,
+        "admin requests denied by admin_allowed_networks"
+    );
+    describe_counter!(
+        ADMIN_AUTH_FAILURES_TOTAL,
+        "admin requests with a missing or invalid token"
+    );
+    describe_counter!(
+        ADMIN_AUTH_THROTTLED_TOTAL,
+        "admin requests throttled after too many auth failures"
+    );
+    describe_counter!(
+        TLS_HANDSHAKE_FAILURES_TOTAL,
+        "failed or timed out tls handshakes"
+    );
+    describe_gauge!(ROUTER_TABLE_SIZE, "number of routes");
+    describe_gauge!(CODE_TABLE_SIZE, "number of issued codes");
+    describe_gauge!(CONNECTIONS_ACTIVE, "currently open connections");
+    describe_counter!(CONNECTIONS_TOTAL, "accepted connections");
+    describe_histogram!(
+        PUT_APPLY_DURATION_SECONDS,
+        Unit::Seconds,
+        "time to apply and persist a PUT routing table"
+    );
+    describe_histogram!(
+        ADMIN_OPERATION_DURATION_SECONDS,
+        Unit::Seconds,
+        "time of routing table updates by operation"
+    );
+    handle
+}
+
+/// drain histogram buckets periodically, as the recorder has no background task.
+pub async fn run_upkeep(handle: PrometheusHandle) {
+    let mut interval = tokio::time::interval(UPKEEP_INTERVAL);
+    loop {
+        interval.tick().await;
+        handle.run_upkeep();
+    }
+}
+
+/// the `/metrics` route.
+pub fn routes<S: Clone + Send + Sync + 'static>(handle: PrometheusHandle) -> Router<S> {
+    Router::new().route(
+        "/metrics",
+        get(move || {
+            SERVER_METRICS.publish();
+            std::future::ready(handle.render())
+        }),
+    )
+}
+
+/// middleware counting admin requests by endpoint and status,
+/// and 429 responses in `too_many_requests`.
+///
+/// Must be added with `route_layer`, so that only matched routes are counted.
+pub async fn track_admin(
+    State(too_many_requests): State<Arc<AtomicU64>>,
+    path: MatchedPath,
+    req: Request,
+    next: Next,
+) -> Response {
+    let rsp = next.run(req).await;
+    if rsp.status() == StatusCode::TOO_MANY_REQUESTS {
+        too_many_requests.fetch_add(1, Ordering::Relaxed);
+    }
+    counter!(
+        ADMIN_REQUESTS_TOTAL,
+        "endpoint" => path.as_str().to_owned(),
+        "status" => rsp.status().as_str().to_owned(),
+    )
+    .increment(1);
+    rsp
+}
+
+pub fn set_table_sizes(router_table: usize, code_table: usize) {
+    gauge!(ROUTER_TABLE_SIZE).set(router_table as f64);
+    gauge!(CODE_TABLE_SIZE).set(code_table as f64);
+}
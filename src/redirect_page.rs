@@ -0,0 +1,33 @@
+//! The html page of `redirect_mode: html`, for browsers that do not follow
+//! redirects reliably.
+
+/// replaced by the escaped target url in templates, may occur several times.
+pub const URL_PLACEHOLDER: &str = "{{url}}";
+
+/// built-in template, see `Config::redirect_page_template`.
+pub const DEFAULT_TEMPLATE: &str = "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\">\
+<meta name=\"robots\" content=\"noindex, nofollow\">\
+<meta http-equiv=\"refresh\" content=\"0; url={{url}}\">\
+<title>Redirecting</title></head><body><p>Redirecting to your survey. \
+If nothing happens, <a href=\"{{url}}\">continue here</a>.</p></body></html>\n";
+
+/// `template` with every placeholder replaced by `url`.
+pub fn render(template: &str, url: &str) -> String {
+    template.replace(URL_PLACEHOLDER, &escape_html(url))
+}
+
+/// escape text for html element content and quoted attribute values.
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
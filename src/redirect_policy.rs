@@ -0,0 +1,64 @@
+//! Which redirect targets and referrers are refused.
+use crate::config::Config;
+use regex::RegexSet;
+use std::collections::HashSet;
+use url::Url;
+
+/// Target and referrer checks of redirects.
+///
+/// Held by `RouterState` behind a lock, so that it can be replaced
+/// along with the config.
+pub struct RedirectPolicy {
+    /// host and port of the links, targets below `api_path` on it would loop.
+    own_origin: (Option<String>, Option<u16>),
+    /// path the server is mounted at, `/` unless `base_url` or `path_prefix` have one.
+    mount_path: String,
+    /// lower case.
+    denied_target_hosts: HashSet<String>,
+    blocked_referrers: RegexSet,
+}
+
+impl RedirectPolicy {
+    /// fails if a `blocked_referrers` pattern is invalid.
+    pub fn from_config(config: &Config) -> Result<Self, regex::Error> {
+        let api_url = config.api_url();
+        let mount_path = api_url
+            .path()
+            .rsplit_once('/')
+            .map(|(dir, _)| format!("{dir}/"))
+            .unwrap_or_else(|| "/".to_owned());
+        Ok(Self {
+            own_origin: (
+                api_url.host_str().map(str::to_owned),
+                api_url.port_or_known_default(),
+            ),
+            mount_path,
+            denied_target_hosts: config
+                .denied_target_hosts
+                .iter()
+                .map(|host| host.to_ascii_lowercase())
+                .collect(),
+            blocked_referrers: RegexSet::new(&config.blocked_referrers)?,
+        })
+    }
+
+    /// whether `url` may be redirected to: not back to this server,
+    /// and not to a denied host.
+    pub fn check_target(&self, url: &Url) -> Result<(), String> {
+        let host = url.host_str().map(str::to_ascii_lowercase);
+        if (host.clone(), url.port_or_known_default()) == self.own_origin
+            && url.path().starts_with(&self.mount_path)
+        {
+            return Err(format!("{url} points back at this server"));
+        }
+        if host.is_some_and(|host| self.denied_target_hosts.contains(&host)) {
+            return Err(format!("host of {url} is denied"));
+        }
+        Ok(())
+    }
+
+    /// whether a request with this `Referer` is answered without a redirect.
+    pub fn is_blocked_referrer(&self, referer: &str) -> bool {
+        self.blocked_referrers.is_match(referer)
+    }
+}
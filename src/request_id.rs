@@ -0,0 +1,53 @@
+//! Request id generation and propagation.
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{HeaderName, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use ipnet::IpNet;
+use std::{fmt, net::SocketAddr, sync::Arc};
+use tracing::Instrument;
+
+pub const X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+/// The id of the current request, inserted as a request extension by [`request_id`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RequestId(pub String);
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// middleware assigning an id to every request.
+///
+/// An incoming `X-Request-Id` is only honored from trusted proxies,
+/// otherwise a new ULID is generated. The handler runs in a span
+/// carrying the id, and the id is echoed in the response headers.
+pub async fn request_id(
+    State(trusted_proxies): State<Arc<[IpNet]>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    let trusted = trusted_proxies.iter().any(|net| net.contains(&peer.ip()));
+    let id = req
+        .headers()
+        .get(X_REQUEST_ID)
+        .filter(|_| trusted)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| !v.is_empty() && v.len() <= MAX_REQUEST_ID_LENGTH)
+        .map(str::to_owned)
+        .unwrap_or_else(|| ulid::Ulid::new().to_string());
+    req.extensions_mut().insert(RequestId(id.clone()));
+
+    let span = tracing::info_span!("request", id = %id);
+    let mut rsp = next.run(req).instrument(span).await;
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        rsp.headers_mut().insert(X_REQUEST_ID, value);
+    }
+    rsp
+}
@@ -1,66 +1,284 @@
 //! All server related code
-use crate::DEFAULT_TIMEOUT;
-use axum::Router;
+use crate::{config::Bind, monitoring::SERVER_METRICS, DEFAULT_TIMEOUT};
+use axum::{
+    extract::ConnectInfo,
+    http::{header::CONNECTION, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Router,
+};
+use futures::future::join_all;
 use hyper::{body::Incoming, Request};
 use hyper_util::rt::{TokioExecutor, TokioIo};
-use std::{net::SocketAddr, time::Duration};
+use std::{
+    future::Future,
+    io::{IoSlice, Read as _},
+    net::{Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+#[cfg(unix)]
+use tokio::net::UnixListener;
 use tokio::{
-    net::{TcpListener, TcpStream},
-    time::timeout,
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpSocket, TcpStream},
+    time::{sleep_until, timeout, Instant},
 };
 use tokio_rustls::TlsAcceptor;
+use tokio_util::sync::CancellationToken;
 use tower::Service;
 
-/// run the server loop, handle shudown.
+/// A router served on its own address.
+pub struct Surface {
+    /// used in logs to tell the listeners apart.
+    pub name: &'static str,
+    pub binds: Vec<Bind>,
+    pub app: Router,
+    /// served with the `admin_tls` certificate if it is set.
+    pub admin_tls: bool,
+}
+
+/// how long aborted connection tasks may take to wind down.
+const ABORT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Listener-level options.
+pub struct ServerOptions {
+    /// permission bits of unix domain sockets.
+    pub unix_socket_mode: Option<u32>,
+    /// listen backlog of tcp listeners, see `Config::tcp_backlog`.
+    pub tcp_backlog: u32,
+    /// set once a shutdown signal is received.
+    pub shutting_down: Arc<AtomicBool>,
+    /// how long connections may take to finish at shutdown before being aborted.
+    pub drain_timeout: Duration,
+    /// set while draining, connections accepted meanwhile are marked [`DrainedConnection`].
+    pub draining: Arc<AtomicBool>,
+    /// close connections without in-flight requests for this long,
+    /// e.g. idle keep-alive connections.
+    pub idle_connection_timeout: Option<Duration>,
+}
+
+/// Request extension, the connection was accepted while draining.
+#[derive(Clone, Copy)]
+pub struct DrainedConnection;
+
+/// Request extension, the request may have been sent as tls early data,
+/// which can be replayed. Set on the first request of such connections.
+#[derive(Clone, Copy)]
+pub struct EarlyData;
+
+/// Handed to every connection task: counts open connections,
+/// and tells them to close gracefully, then to abort.
+#[derive(Clone)]
+pub struct ConnControl {
+    close_rx: tokio::sync::watch::Receiver<()>,
+    graceful: CancellationToken,
+    abort: CancellationToken,
+    draining: Arc<AtomicBool>,
+    idle_timeout: Option<Duration>,
+}
+
+impl ConnControl {
+    fn draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+
+/// run the server loops of all surfaces, handle shudown.
+///
+/// `admin_tls_cert_provider` serves the surfaces marked `admin_tls`,
+/// falling back to `tls_cert_provider`.
+///
+/// On shutdown, connections are asked to close gracefully and are aborted
+/// once `drain_timeout` expires, then `on_shutdown` runs.
 pub async fn run_server(
-    app: &Router,
-    bind: SocketAddr,
-    mut tls_cert_provider: Option<tokio::sync::watch::Receiver<TlsAcceptor>>,
+    surfaces: Vec<Surface>,
+    options: &ServerOptions,
+    tls_cert_provider: Option<tokio::sync::watch::Receiver<TlsAcceptor>>,
+    admin_tls_cert_provider: Option<tokio::sync::watch::Receiver<TlsAcceptor>>,
+    on_shutdown: impl Future<Output = ()>,
 ) -> std::io::Result<()> {
-    // attempt to bind to address
-    let tcp_listener = TcpListener::bind(bind).await?;
+    // attempt to bind to all addresses
+    tracing::info!("tcp listen backlog {}", options.tcp_backlog);
+    let mut listeners = Vec::with_capacity(surfaces.len());
+    for surface in surfaces {
+        for addr in &surface.binds {
+            let cert_provider = match &admin_tls_cert_provider {
+                Some(admin_tls) if surface.admin_tls => Some(admin_tls.clone()),
+                _ => tls_cert_provider.clone(),
+            };
+            #[cfg(not(feature = "insecure"))]
+            if cert_provider.is_none() && matches!(addr, Bind::Tcp(_)) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "{} at {addr} has no tls, serving plain http over tcp \
+                         requires building with the `insecure` feature",
+                        surface.name
+                    ),
+                ));
+            }
+            let listener = bind(addr, options).map_err(|e| {
+                std::io::Error::new(e.kind(), format!("failed to bind {addr}: {e}"))
+            })?;
+            tracing::info!("{} listening at {}", surface.name, addr);
+            listeners.push((listener, surface.app.clone(), cert_provider));
+        }
+    }
     // shutdown signal
-    let shutdown_tx = shutdown_signal();
+    let shutdown_tx = shutdown_signal(options.shutting_down.clone());
     // connection counter
     let (close_tx, close_rx) = tokio::sync::watch::channel(());
+    let conns = ConnControl {
+        close_rx,
+        graceful: CancellationToken::new(),
+        abort: CancellationToken::new(),
+        draining: options.draining.clone(),
+        idle_timeout: options.idle_connection_timeout,
+    };
 
-    // main loop
+    // main loops
     tracing::info!("server running");
-    if let Some(tls_cert_provider) = tls_cert_provider.as_mut() {
-        server_loop(
-            &tcp_listener,
-            &shutdown_tx,
-            &close_rx,
-            tls_cert_provider,
-            app,
-        )
-        .await
-    } else {
-        server_loop_notls(&tcp_listener, &shutdown_tx, &close_rx, app).await
-    }
-
-    // graceful shutdown process
+    join_all(listeners.iter().map(|(listener, app, cert_provider)| {
+        serve_listener(listener, &shutdown_tx, &conns, cert_provider.clone(), app)
+    }))
+    .await;
 
+    // graceful shutdown process, `shutting_down` is set already
     // stop accepting new connections during shutdown periods
-    drop(tcp_listener);
-    // shutdown procedure: wait for connections to finish
+    for (listener, ..) in listeners {
+        #[cfg(unix)]
+        if let Listener::Unix(listener, path) = listener {
+            drop(listener);
+            if let Err(e) = std::fs::remove_file(&path) {
+                tracing::warn!("failed to remove socket {}: {}", path.display(), e);
+            }
+        }
+    }
+    // shutdown procedure: close idle connections, finish in-flight requests
+    let ConnControl {
+        close_rx,
+        graceful,
+        abort,
+        ..
+    } = conns;
     drop(close_rx);
+    graceful.cancel();
     // wait for all connections to close
     tracing::info!(
         "waiting for {} task(s) to finish",
         close_tx.receiver_count()
     );
-    if timeout(DEFAULT_TIMEOUT, close_tx.closed()).await.is_err() {
-        tracing::warn!("failed to close all connections");
+    if timeout(options.drain_timeout, close_tx.closed())
+        .await
+        .is_err()
+    {
+        tracing::warn!(
+            "aborting {} connection(s) after {:?}",
+            close_tx.receiver_count(),
+            options.drain_timeout
+        );
+        abort.cancel();
+        if timeout(ABORT_TIMEOUT, close_tx.closed()).await.is_err() {
+            tracing::error!("failed to abort all connections");
+        }
     }
+    on_shutdown.await;
     Ok(())
 }
 
+/// bind a listener, replacing stale unix domain sockets.
+fn bind(bind: &Bind, options: &ServerOptions) -> std::io::Result<Listener> {
+    match bind {
+        Bind::Tcp(addr) => {
+            let socket = match addr {
+                SocketAddr::V4(_) => TcpSocket::new_v4()?,
+                SocketAddr::V6(_) => TcpSocket::new_v6()?,
+            };
+            // as `std::net::TcpListener::bind` does
+            #[cfg(unix)]
+            socket.set_reuseaddr(true)?;
+            socket.bind(*addr)?;
+            Ok(Listener::Tcp(socket.listen(options.tcp_backlog)?))
+        }
+        #[cfg(unix)]
+        Bind::Unix(path) => {
+            use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+            match std::fs::symlink_metadata(path) {
+                Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(path)?,
+                Ok(_) => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::AlreadyExists,
+                        format!("{} exists and is not a socket", path.display()),
+                    ))
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+            let listener = UnixListener::bind(path)?;
+            if let Some(mode) = options.unix_socket_mode {
+                std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+            }
+            Ok(Listener::Unix(listener, path.clone()))
+        }
+        #[cfg(not(unix))]
+        Bind::Unix(_) => Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "unix domain sockets are not supported on this platform",
+        )),
+    }
+}
+
+/// run the server loop of one listener until shutdown.
+async fn serve_listener(
+    listener: &Listener,
+    shutdown_tx: &tokio::sync::watch::Sender<()>,
+    conns: &ConnControl,
+    tls_cert_provider: Option<tokio::sync::watch::Receiver<TlsAcceptor>>,
+    app: &Router,
+) {
+    match (listener, tls_cert_provider) {
+        (Listener::Tcp(tcp_listener), Some(mut tls_cert_provider)) => {
+            server_loop(
+                tcp_listener,
+                shutdown_tx,
+                conns,
+                &mut tls_cert_provider,
+                app,
+            )
+            .await
+        }
+        #[cfg(feature = "insecure")]
+        (Listener::Tcp(tcp_listener), None) => {
+            server_loop_notls(tcp_listener, shutdown_tx, conns, app).await
+        }
+        #[cfg(not(feature = "insecure"))]
+        (Listener::Tcp(_), None) => unreachable!("plain tcp is rejected by run_server"),
+        // unix sockets are served without tls, see `Config::validate`
+        #[cfg(unix)]
+        (Listener::Unix(unix_listener, _), _) => {
+            server_loop_unix(unix_listener, shutdown_tx, conns, app).await
+        }
+    }
+}
+
 /// run the server loop, no tls, handle shudown.
 pub async fn server_loop(
     tcp_listener: &TcpListener,
     shutdown_tx: &tokio::sync::watch::Sender<()>,
-    close_rx: &tokio::sync::watch::Receiver<()>,
+    conns: &ConnControl,
     tls_cert_provider: &mut tokio::sync::watch::Receiver<TlsAcceptor>,
     app: &Router,
 ) {
@@ -88,18 +306,27 @@ pub async fn server_loop(
 
         tracing::debug!("new connection from {}", addr);
 
+        let drained = conns.draining();
         let app = app.clone();
         let tls_acceptor = tls_acceptor.clone();
-        let close_rx = close_rx.clone();
-        tokio::spawn(handle_conn_tls(app, conn, tls_acceptor, close_rx, addr));
+        let conns = conns.clone();
+        tokio::spawn(handle_conn_tls(
+            app,
+            conn,
+            tls_acceptor,
+            conns,
+            addr,
+            drained,
+        ));
     }
 }
 
 /// run the server loop, no tls, handle shudown.
+#[cfg(feature = "insecure")]
 pub async fn server_loop_notls(
     tcp_listener: &TcpListener,
     shutdown_tx: &tokio::sync::watch::Sender<()>,
-    close_rx: &tokio::sync::watch::Receiver<()>,
+    conns: &ConnControl,
     app: &Router,
 ) {
     loop {
@@ -119,9 +346,59 @@ pub async fn server_loop_notls(
 
         tracing::debug!("new connection from {}", addr);
 
+        let drained = conns.draining();
         let app = app.clone();
-        let close_rx = close_rx.clone();
-        tokio::spawn(handle_conn(app, TokioIo::new(conn), close_rx, addr));
+        let conns = conns.clone();
+        tokio::spawn(handle_conn(
+            app,
+            TokioIo::new(conn),
+            conns,
+            addr,
+            drained,
+            false,
+        ));
+    }
+}
+
+/// run the server loop on a unix domain socket, no tls, handle shudown.
+///
+/// unix socket peers have no ip address, they are reported as `127.0.0.1:0`.
+#[cfg(unix)]
+pub async fn server_loop_unix(
+    unix_listener: &UnixListener,
+    shutdown_tx: &tokio::sync::watch::Sender<()>,
+    conns: &ConnControl,
+    app: &Router,
+) {
+    let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+    loop {
+        let new_conn = tokio::select! {
+            biased;
+            conn = unix_listener.accept() => conn,
+            _ = shutdown_tx.closed() => break,
+        };
+
+        let conn = match new_conn {
+            Ok((conn, _)) => conn,
+            Err(err) => {
+                handle_accept_error(err).await;
+                continue;
+            }
+        };
+
+        tracing::debug!("new connection on unix socket");
+
+        let drained = conns.draining();
+        let app = app.clone();
+        let conns = conns.clone();
+        tokio::spawn(handle_conn(
+            app,
+            TokioIo::new(conn),
+            conns,
+            addr,
+            drained,
+            false,
+        ));
     }
 }
 
@@ -130,61 +407,288 @@ async fn handle_conn_tls(
     app: Router,
     con: TcpStream,
     tls_acceptor: TlsAcceptor,
-    close_rx: tokio::sync::watch::Receiver<()>,
+    conns: ConnControl,
     addr: SocketAddr,
+    drained: bool,
 ) {
     // tls handshake timeout
     let tls_stream = tls_acceptor.accept(con);
     let timeout_acceptor = timeout(DEFAULT_TIMEOUT, tls_stream);
-    // tls handshake
-    let Ok(Ok(stream)) = timeout_acceptor.await else {
+    // tls handshake, give up if shutting down
+    let handshake = tokio::select! {
+        handshake = timeout_acceptor => handshake,
+        _ = conns.graceful.cancelled() => return,
+    };
+    let Ok(Ok(mut stream)) = handshake else {
         // quickly ignore all tls handshake failure.
         // deny non-secured connections.
         tracing::debug!("tls handshake failure or timeout for {}", addr);
+        SERVER_METRICS.tls_handshake_failed();
         return;
     };
-    handle_conn(app, TokioIo::new(stream), close_rx, addr).await;
+    // tls 1.3 early data arrives with the handshake, it is read before the rest
+    let mut early_data = Vec::new();
+    if let Some(mut reader) = stream.get_mut().1.early_data() {
+        if let Err(e) = reader.read_to_end(&mut early_data) {
+            tracing::debug!("failed to read early data from {}: {}", addr, e);
+            return;
+        }
+    }
+    let has_early_data = !early_data.is_empty();
+    let stream = EarlyDataStream {
+        early_data,
+        read: 0,
+        inner: stream,
+    };
+    handle_conn(
+        app,
+        TokioIo::new(stream),
+        conns,
+        addr,
+        drained,
+        has_early_data,
+    )
+    .await;
+}
+
+/// A stream yielding the early data received in the tls handshake first.
+struct EarlyDataStream<S> {
+    early_data: Vec<u8>,
+    read: usize,
+    inner: S,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for EarlyDataStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+        let early_data = &this.early_data[this.read..];
+        if early_data.is_empty() {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+        let len = early_data.len().min(buf.remaining());
+        buf.put_slice(&early_data[..len]);
+        this.read += len;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for EarlyDataStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
 }
 
 /// serve an incoming connection.
+///
+/// Requests on `drained` connections carry the [`DrainedConnection`] extension,
+/// the first request of connections with `early_data` carries [`EarlyData`].
 async fn handle_conn<I: hyper::rt::Read + hyper::rt::Write + Unpin + 'static>(
     app: Router,
     stream: I,
-    close_rx: tokio::sync::watch::Receiver<()>,
+    conns: ConnControl,
     addr: SocketAddr,
+    drained: bool,
+    early_data: bool,
 ) {
+    SERVER_METRICS.connection_opened();
+
     // Hyper also has its own `Service` trait and doesn't use tower. We can use
     // `hyper::service::service_fn` to create a hyper `Service` that calls our app through
     // `tower::Service::call`.
-    let hyper_service = hyper::service::service_fn(move |request: Request<Incoming>| {
+    let idle = IdleTracker::default();
+    let tracker = idle.clone();
+    let early_data = AtomicBool::new(early_data);
+    let hyper_service = hyper::service::service_fn(move |mut request: Request<Incoming>| {
+        let activity = tracker.start();
+        // expose peer address to handlers
+        request.extensions_mut().insert(ConnectInfo(addr));
+        if drained {
+            request.extensions_mut().insert(DrainedConnection);
+        }
+        if early_data.swap(false, Ordering::Relaxed) {
+            request.extensions_mut().insert(EarlyData);
+        }
         // We have to clone `app` because hyper's `Service` uses `&self` whereas
         // tower's `Service` requires `&mut self`.
         // We don't need to call `poll_ready` since `Router` is always ready.
         let mut app = app.clone();
-        app.as_service().call(request)
+        let response = app.as_service().call(request);
+        async move {
+            let response = response.await;
+            drop(activity);
+            response
+        }
     });
 
-    if let Err(err) = hyper_util::server::conn::auto::Builder::new(TokioExecutor::new())
-        .http1()
-        .serve_connection(stream, hyper_service)
-        .await
-    {
+    let builder = hyper_util::server::conn::auto::Builder::new(TokioExecutor::new());
+    let conn = builder.serve_connection(stream, hyper_service);
+    tokio::pin!(conn);
+    let result = tokio::select! {
+        result = conn.as_mut() => result,
+        _ = idle.timed_out(conns.idle_timeout) => {
+            tracing::trace!("closed idle connection from {}", addr);
+            conn.as_mut().graceful_shutdown();
+            tokio::select! {
+                result = conn.as_mut() => result,
+                _ = conns.abort.cancelled() => Ok(()),
+            }
+        }
+        _ = conns.graceful.cancelled() => {
+            // finish the in-flight request, if any, then close
+            conn.as_mut().graceful_shutdown();
+            tokio::select! {
+                result = conn.as_mut() => result,
+                _ = conns.abort.cancelled() => {
+                    tracing::debug!("aborted connection from {}", addr);
+                    Ok(())
+                }
+            }
+        }
+    };
+    if let Err(err) = result {
         // skip tls UnexpectedEof:
         // https://docs.rs/rustls/latest/rustls/manual/_03_howto/index.html#unexpected-eof
-        if !err
-            .downcast_ref::<std::io::Error>()
-            .is_some_and(|e| e.kind() == std::io::ErrorKind::UnexpectedEof)
-        {
+        if !matches!(
+            err.downcast_ref::<std::io::Error>(),
+            Some(e) if e.kind() == std::io::ErrorKind::UnexpectedEof
+        ) {
             tracing::debug!("error serving connection from {}: {}", addr, err)
         }
     }
 
     // decrease connection counter
-    drop(close_rx);
+    drop(conns);
+    SERVER_METRICS.connection_closed();
+}
+
+/// When a connection last had a request in flight.
+#[derive(Clone)]
+struct IdleTracker {
+    in_flight: Arc<AtomicUsize>,
+    last_active: Arc<Mutex<Instant>>,
+}
+
+/// a request in flight, the connection is idle again once all are dropped.
+struct Activity(IdleTracker);
+
+impl Default for IdleTracker {
+    fn default() -> Self {
+        Self {
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            last_active: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+}
+
+impl IdleTracker {
+    fn start(&self) -> Activity {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        Activity(self.clone())
+    }
+
+    fn touch(&self) {
+        *self.last_active.lock().expect("poisoned") = Instant::now();
+    }
+
+    /// resolves once no request was in flight for `timeout`, never if `None`.
+    async fn timed_out(&self, timeout: Option<Duration>) {
+        let Some(timeout) = timeout else {
+            return std::future::pending().await;
+        };
+        loop {
+            let deadline = *self.last_active.lock().expect("poisoned") + timeout;
+            if Instant::now() < deadline {
+                sleep_until(deadline).await;
+            } else if self.in_flight.load(Ordering::SeqCst) == 0 {
+                return;
+            } else {
+                // busy connections are checked again one timeout later
+                sleep_until(Instant::now() + timeout).await;
+            }
+        }
+    }
+}
+
+impl Drop for Activity {
+    fn drop(&mut self) {
+        self.0.touch();
+        self.0.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// middleware answering 503 on connections accepted while draining,
+/// and closing them. Connections opened before keep being served.
+pub async fn reject_drained(req: axum::extract::Request, next: Next) -> Response {
+    if req.extensions().get::<DrainedConnection>().is_some() {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(CONNECTION, "close")],
+            "draining",
+        )
+            .into_response();
+    }
+    next.run(req).await
+}
+
+/// middleware answering `425 Too Early` to requests sent as tls early data,
+/// unless they are GET or HEAD, see `TlsConfig::max_early_data_size`
+/// and `reject_all_early_data`.
+pub async fn reject_early_data(req: axum::extract::Request, next: Next) -> Response {
+    if req.extensions().get::<EarlyData>().is_some()
+        && !matches!(*req.method(), Method::GET | Method::HEAD)
+    {
+        return (
+            StatusCode::from_u16(425).expect("valid status"),
+            "too early",
+        )
+            .into_response();
+    }
+    next.run(req).await
+}
+
+/// middleware answering `425 Too Early` to any request sent as tls early
+/// data, for GETs that change state such as `/api/complete`.
+pub async fn reject_all_early_data(req: axum::extract::Request, next: Next) -> Response {
+    if req.extensions().get::<EarlyData>().is_some() {
+        return (
+            StatusCode::from_u16(425).expect("valid status"),
+            "too early",
+        )
+            .into_response();
+    }
+    next.run(req).await
 }
 
-/// listen to shutdown signals, get `sender.closed()` if signaled.
-fn shutdown_signal() -> tokio::sync::watch::Sender<()> {
+/// listen to shutdown signals, set `shutting_down` and get `sender.closed()` if signaled.
+fn shutdown_signal(shutting_down: Arc<AtomicBool>) -> tokio::sync::watch::Sender<()> {
     let (signal_tx, signal_rx) = tokio::sync::watch::channel(());
     tokio::spawn(async move {
         let ctrl_c = async {
@@ -211,6 +715,8 @@ fn shutdown_signal() -> tokio::sync::watch::Sender<()> {
         }
 
         tracing::info!("received graceful shutdown signal. Telling tasks to shutdown");
+        // readiness fails from now on, before any connection is closed
+        shutting_down.store(true, Ordering::Relaxed);
         drop(signal_rx);
     });
     signal_tx
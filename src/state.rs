@@ -1,195 +1,3288 @@
-use crate::{config::Config, utility::*, API, CODE, CODE_LENGTH, EXTERNEL_ID};
+use crate::{
+    admin_auth::{validate_admin_token, AdminToken},
+    certs::{cert_fingerprint, CertReload, CertReloadSender, CertRole},
+    config::{CodeGenMode, Config, MAX_CODE_LENGTH, MIN_CODE_LENGTH},
+    error_pages::ErrorPages,
+    geoip::{self, GeoIp},
+    idempotency::IdempotencyCache,
+    monitoring::{set_table_sizes, RedirectOutcomes, PUT_APPLY_DURATION_SECONDS, SERVER_METRICS},
+    redirect_page,
+    redirect_policy::RedirectPolicy,
+    sharded_map::ShardedMap,
+    storage::Storage,
+    utility::*,
+    CODE, EXTERNEL_ID, GEO, MAX_DESCRIPTION_LENGTH, MAX_REQUEST_TIMEOUT_SECS, MAX_WAVE_LENGTH,
+    RR_IDX, X_TABLE_VERSION,
+};
 use axum::{
+    body::{Body, Bytes},
+    http::{header::CONTENT_TYPE, HeaderValue},
     response::{IntoResponse, Response},
-    Json,
 };
+use calamine::{open_workbook_from_rs, Reader, Xlsx};
+use chrono::{DateTime, Utc};
+use compact_str::CompactString;
+use dashmap::DashMap;
+use metrics::histogram;
+use notify::Watcher as _;
 use rand::{distributions::Alphanumeric, Rng};
-use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, path::PathBuf, sync::Arc};
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    convert::Infallible,
+    fmt,
+    io::Cursor,
+    net::IpAddr,
+    num::NonZeroUsize,
+    ops::{Deref, DerefMut},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, OnceLock,
+    },
+    time::{Duration, Instant},
+};
 use tokio::sync::{Mutex, MutexGuard, RwLock};
-use url::Url;
+use tokio_util::sync::CancellationToken;
+use url::{form_urlencoded, Url};
+
+/// tracing target of changes to participant ids, see `RouterState::rekey`.
+pub const AUDIT_LOG_TARGET: &str = "survey_redirect::audit";
+
+#[derive(Deserialize, Serialize, Clone, Debug, Hash, PartialEq, Eq)]
+pub struct Id(String);
+
+/// Codes of up to 24 bytes (16 by default) are stored inline, without
+/// a heap allocation. Serialized as a plain string.
+#[derive(Deserialize, Serialize, Clone, Debug, Hash, PartialEq, Eq)]
+pub struct Code(CompactString);
+
+impl Code {
+    /// whether this could be a generated code: alphanumeric, of a valid `code_length`.
+    ///
+    /// Codes keep their length when `code_length` changes, so any valid length passes.
+    pub fn is_well_formed(&self) -> bool {
+        (MIN_CODE_LENGTH..=MAX_CODE_LENGTH).contains(&self.0.len())
+            && self.0.bytes().all(|b| b.is_ascii_alphanumeric())
+    }
+
+    /// bytes allocated outside of the code itself, none for short codes.
+    fn heap_size(&self) -> usize {
+        if self.0.is_heap_allocated() {
+            self.0.capacity()
+        } else {
+            0
+        }
+    }
+}
 
-#[derive(Deserialize, Serialize, Clone, Hash, PartialEq, Eq)]
-pub struct Uid(String);
+impl fmt::Display for Id {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
 
-#[derive(Deserialize, Serialize, Clone, Hash, PartialEq, Eq)]
-pub struct Code(String);
+impl fmt::Display for Code {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
 
 #[derive(Deserialize, Serialize)]
 pub struct Route {
-    pub uid: Uid,
+    pub uid: Id,
     pub url: Url,
+    /// alternative url for mobile browsers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mobile_url: Option<Url>,
+    /// distribute participants across these urls in turn, instead of `url`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub round_robin_urls: Option<Vec<Url>>,
+    /// urls by location of the client, keyed by country code (`DE`) or `EU`,
+    /// see `geoip`. Clients elsewhere or not located get the other urls.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub geo_urls: Option<BTreeMap<String, Url>>,
+    /// overrides `redirect_mode` of the config for this route.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub redirect_mode: Option<RedirectMode>,
+    /// seconds the survey may take to load, sent as `X-Survey-Timeout`,
+    /// and the server waits at least as long for the redirect.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_timeout_secs: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub notes: Option<String>,
+    /// the participant whose links include this route, see `participant_links`,
+    /// `uid` if unset. Set for the routes of later waves of a participant,
+    /// requires `wave`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub participant: Option<Id>,
+    /// survey or wave of the route among the links of its participant,
+    /// `DEFAULT_SURVEY` if unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wave: Option<String>,
+    /// the route answers `410 Gone` from then on.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// The router table, from codes to routes.
+///
+/// Updates build a new table from a clone of the current one, the sharded map
+/// keeps that clone cheap for small updates.
+pub type RouterTable = ShardedMap<Code, RouteEntry>;
+
+/// value of the router table.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct RouteEntry {
+    /// shared by the routes of a table with the same url, see `UrlInterner`.
+    pub url: Arc<Url>,
+    /// alternative url for mobile browsers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mobile_url: Option<Url>,
+    /// distribute participants across these urls in turn, instead of `url`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub round_robin_urls: Option<Vec<Url>>,
+    /// urls by location of the client, see `Route::geo_urls`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub geo_urls: Option<BTreeMap<String, Url>>,
+    /// see `Route::redirect_mode`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub redirect_mode: Option<RedirectMode>,
+    /// seconds the survey may take to load, see `Route::request_timeout_secs`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_timeout_secs: Option<u64>,
+    #[serde(default)]
+    pub hit_count: HitCount,
+    /// suspended routes answer `410 Gone` instead of redirecting.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub deactivated: bool,
+    /// admin-facing only, never shown to participants.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// admin-facing only, never shown to participants.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub notes: Option<String>,
+    /// see `Route::participant`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub participant: Option<Id>,
+    /// see `Route::wave`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wave: Option<String>,
+    /// see `Route::expires_at`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<DateTime<Utc>>,
+    /// the redirect target of `url`, built once when the route is stored.
+    /// Redirects build it per request if `None`.
+    #[serde(skip)]
+    pub redirect_url: Option<Arc<Url>>,
+}
+
+impl RouteEntry {
+    /// all redirect targets of the route.
+    pub fn urls(&self) -> impl Iterator<Item = &Url> {
+        std::iter::once(&*self.url)
+            .chain(&self.mobile_url)
+            .chain(self.round_robin_urls.iter().flatten())
+            .chain(self.geo_urls.iter().flat_map(BTreeMap::values))
+    }
+
+    /// whether both store the same route, regardless of hit counts.
+    pub fn same_route(&self, other: &RouteEntry) -> bool {
+        self.url == other.url
+            && self.mobile_url == other.mobile_url
+            && self.round_robin_urls == other.round_robin_urls
+            && self.geo_urls == other.geo_urls
+            && self.redirect_mode == other.redirect_mode
+            && self.request_timeout_secs == other.request_timeout_secs
+            && self.deactivated == other.deactivated
+            && self.description == other.description
+            && self.notes == other.notes
+            && self.participant == other.participant
+            && self.wave == other.wave
+            && self.expires_at == other.expires_at
+    }
+
+    /// whether `expires_at` has passed.
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.is_some_and(|expires_at| now >= expires_at)
+    }
+
+    /// build `redirect_url` for the route of `code`.
+    pub fn precompute_redirect(&mut self, code: &Code) {
+        self.redirect_url = Some(Arc::new(redirect_url(&self.url, code, None)));
+    }
+
+    /// approximate bytes allocated by the entry, shared urls split among their owners.
+    fn heap_size(&self) -> usize {
+        let owned = |url: &Url| std::mem::size_of::<Url>() + url.as_str().len();
+        let shared = |url: &Arc<Url>| owned(url) / Arc::strong_count(url);
+        shared(&self.url)
+            + self.redirect_url.as_ref().map_or(0, shared)
+            + self.mobile_url.as_ref().map_or(0, owned)
+            + self
+                .round_robin_urls
+                .iter()
+                .flatten()
+                .map(owned)
+                .sum::<usize>()
+            + self
+                .geo_urls
+                .iter()
+                .flatten()
+                .map(|(key, geo_url)| key.capacity() + owned(geo_url))
+                .sum::<usize>()
+            + self.description.as_ref().map_or(0, String::capacity)
+            + self.notes.as_ref().map_or(0, String::capacity)
+            + self.participant.as_ref().map_or(0, |id| id.0.capacity())
+            + self.wave.as_ref().map_or(0, String::capacity)
+            + std::mem::size_of::<AtomicU64>()
+    }
+}
+
+/// Shares identical urls of a table while it is built, so that routes to
+/// the same survey hold one `Url`.
+#[derive(Default)]
+pub struct UrlInterner(HashSet<Arc<Url>>);
+
+impl UrlInterner {
+    /// the shared copy of `url`, which becomes the shared copy if there is none.
+    pub fn intern(&mut self, url: Arc<Url>) -> Arc<Url> {
+        if let Some(shared) = self.0.get(&*url) {
+            return shared.clone();
+        }
+        self.0.insert(url.clone());
+        url
+    }
+}
+
+/// `url` with the code as `externalUserId`, and the round robin index if any.
+fn redirect_url(url: &Url, code: &Code, rr_idx: Option<usize>) -> Url {
+    let mut url = url.clone();
+    // exactly one, also for tables stored before uploads were cleaned
+    strip_external_id(&mut url);
+    {
+        let mut query = url.query_pairs_mut();
+        query.append_pair(EXTERNEL_ID, &code.0);
+        if let Some(idx) = rr_idx {
+            query.append_pair(RR_IDX, &idx.to_string());
+        }
+        query.finish();
+    }
+    url
+}
+
+/// whether a query parameter (decoded) is `externalUserId`, ignoring case.
+fn is_external_id(name: &str) -> bool {
+    name.eq_ignore_ascii_case(EXTERNEL_ID)
+}
+
+/// remove `externalUserId` parameters from the query of `url`,
+/// returns whether there were any. Other parameters are kept in order.
+fn strip_external_id(url: &mut Url) -> bool {
+    if !url.query_pairs().any(|(name, _)| is_external_id(&name)) {
+        return false;
+    }
+    let kept: Vec<(String, String)> = url
+        .query_pairs()
+        .filter(|(name, _)| !is_external_id(name))
+        .map(|(name, value)| (name.into_owned(), value.into_owned()))
+        .collect();
+    if kept.is_empty() {
+        url.set_query(None);
+    } else {
+        url.query_pairs_mut().clear().extend_pairs(kept);
+    }
+    true
+}
+
+/// remove `externalUserId` parameters from the urls of uploaded routes,
+/// e.g. of links copied back into the table, logging how many were cleaned.
+fn strip_external_ids(data: &mut [Route]) {
+    let cleaned = data
+        .iter_mut()
+        .map(Route::strip_external_ids)
+        .filter(|&cleaned| cleaned)
+        .count();
+    if cleaned > 0 {
+        tracing::info!("removed {EXTERNEL_ID} from the urls of {cleaned} routes");
+    }
+}
+
+/// Redirect counter (serialized as a plain number).
+///
+/// The counter is shared between clones, so that redirects
+/// counted while a new table is being built are not lost.
+#[derive(Clone, Default)]
+pub struct HitCount(Arc<AtomicU64>);
+
+impl HitCount {
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub fn incr(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// raise the count to at least `hits`.
+    pub fn merge(&self, hits: u64) {
+        self.0.fetch_max(hits, Ordering::Relaxed);
+    }
+}
+
+impl Serialize for HitCount {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.get().serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for HitCount {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(Self(Arc::new(AtomicU64::new(u64::deserialize(
+            deserializer,
+        )?))))
+    }
+}
+
+impl Route {
+    /// a route to `url` without options.
+    pub fn new(uid: Id, url: Url) -> Self {
+        Route {
+            uid,
+            url,
+            mobile_url: None,
+            round_robin_urls: None,
+            geo_urls: None,
+            redirect_mode: None,
+            request_timeout_secs: None,
+            description: None,
+            notes: None,
+            participant: None,
+            wave: None,
+            expires_at: None,
+        }
+    }
+
+    /// all redirect targets of the route.
+    pub fn urls(&self) -> impl Iterator<Item = &Url> {
+        std::iter::once(&self.url)
+            .chain(&self.mobile_url)
+            .chain(self.round_robin_urls.iter().flatten())
+            .chain(self.geo_urls.iter().flat_map(BTreeMap::values))
+    }
+
+    /// `strip_external_id` on all urls, returns whether any had the parameter.
+    fn strip_external_ids(&mut self) -> bool {
+        std::iter::once(&mut self.url)
+            .chain(&mut self.mobile_url)
+            .chain(self.round_robin_urls.iter_mut().flatten())
+            .chain(self.geo_urls.iter_mut().flat_map(BTreeMap::values_mut))
+            .fold(false, |cleaned, url| strip_external_id(url) | cleaned)
+    }
+
+    /// split into id and a router table entry with a new hit counter.
+    fn into_entry(self, urls: &mut UrlInterner) -> (Id, RouteEntry) {
+        let entry = RouteEntry {
+            url: urls.intern(Arc::new(self.url)),
+            mobile_url: self.mobile_url,
+            round_robin_urls: self.round_robin_urls,
+            geo_urls: self.geo_urls,
+            redirect_mode: self.redirect_mode,
+            request_timeout_secs: self.request_timeout_secs,
+            hit_count: HitCount::default(),
+            deactivated: false,
+            description: self.description,
+            notes: self.notes,
+            participant: self.participant,
+            wave: self.wave,
+            expires_at: self.expires_at,
+            redirect_url: None,
+        };
+        (self.uid, entry)
+    }
+}
+
+/// `a, b, c`, for messages.
+pub fn join_ids(ids: &[Id]) -> String {
+    ids.iter()
+        .map(|id| id.0.as_str())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Tables read by `load_tables`.
+struct LoadedTables {
+    /// time of the router table snapshot, `None` if there was none.
+    time: Option<TimeStamp>,
+    /// see `RouterState::table_version`.
+    version: u64,
+    router_table: RouterTable,
+    code_table: HashMap<Id, Code>,
+}
+
+/// What `init` reads from disk, see `load_stored`.
+struct Stored {
+    storage: Storage,
+    tables: LoadedTables,
+    completions: HashMap<Code, Completion>,
+    geoip: Option<Arc<GeoIp>>,
+    redirect_page_template: String,
+    error_pages: ErrorPages,
+}
+
+/// the blocking part of `init`: create `store` if missing, open the storage
+/// and read the stored tables, the completions, the GeoIP database
+/// the redirect page template and the error pages.
+///
+/// Returned as a closure owning its inputs, for `spawn_blocking`.
+fn load_stored(
+    config: &Config,
+    store: PathBuf,
+) -> impl FnOnce() -> Result<Stored, StateError> + Send + 'static {
+    let (backend, write_ahead_log) = (config.storage_backend, config.write_ahead_log);
+    let geoip_database = config.geoip_database.clone();
+    let redirect_page_template = config.redirect_page_template.clone();
+    let error_templates_dir = config.error_templates_dir.clone();
+    move || {
+        std::fs::create_dir_all(&store).map_err(StateError::StoreError)?;
+        let storage =
+            Storage::open(backend, write_ahead_log, &store).map_err(StateError::StoreError)?;
+        let tables = load_tables(&storage).map_err(StateError::StoreError)?;
+        let completions = load_completions(&store)
+            .map_err(StateError::StoreError)?
+            .unwrap_or_default();
+        Ok(Stored {
+            storage,
+            tables,
+            completions,
+            geoip: geoip_database.as_deref().and_then(open_geoip),
+            redirect_page_template: redirect_page_template
+                .as_deref()
+                .and_then(|path| {
+                    std::fs::read_to_string(path)
+                        .inspect_err(|e| tracing::warn!("failed to read {}: {e}", path.display()))
+                        .ok()
+                })
+                .unwrap_or_else(|| redirect_page::DEFAULT_TEMPLATE.to_owned()),
+            error_pages: error_templates_dir
+                .as_deref()
+                .map(ErrorPages::load)
+                .unwrap_or_default(),
+        })
+    }
+}
+
+/// load the stored tables, with urls shared and redirect urls precomputed.
+fn load_tables(storage: &Storage) -> std::io::Result<LoadedTables> {
+    let (time, mut router_table) = match storage.load_latest_router_table()? {
+        Some((time, table)) => {
+            tracing::info!("router table loaded (time={time})");
+            (Some(time), table)
+        }
+        None => {
+            tracing::info!("new router table created");
+            (None, RouterTable::new())
+        }
+    };
+    let mut urls = UrlInterner::default();
+    for (code, entry) in router_table.iter_mut() {
+        entry.url = urls.intern(entry.url.clone());
+        entry.precompute_redirect(code);
+    }
+    let code_table = match storage.load_latest_code_table()? {
+        Some(table) => {
+            tracing::info!("code table loaded");
+            table
+        }
+        None => {
+            tracing::info!("new code table created");
+            HashMap::new()
+        }
+    };
+    let version = storage.load_table_version()?;
+    Ok(LoadedTables {
+        time,
+        version,
+        router_table,
+        code_table,
+    })
+}
+
+/// codes of several ids, sorted by code and then by id.
+fn duplicate_codes(code_table: &HashMap<Id, Code>) -> Vec<(Code, Vec<Id>)> {
+    let mut owners: HashMap<&Code, &Id> = HashMap::with_capacity(code_table.len());
+    let mut duplicates: HashMap<Code, Vec<Id>> = HashMap::new();
+    for (id, code) in code_table.iter() {
+        if let Some(owner) = owners.insert(code, id) {
+            duplicates
+                .entry(code.clone())
+                .or_insert_with(|| vec![owner.clone()])
+                .push(id.clone());
+        }
+    }
+    let mut duplicates: Vec<_> = duplicates.into_iter().collect();
+    duplicates.sort_unstable_by(|(a, _), (b, _)| a.0.cmp(&b.0));
+    for (_, ids) in &mut duplicates {
+        ids.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+    }
+    duplicates
+}
+
+/// check the consistency of the tables, and their routes against `policy`.
+///
+/// The checks of `GET validate` and of `init`, which fails on duplicate codes
+/// unless `repair_duplicate_codes` and warns about the rest.
+fn validate_tables(
+    code_table: &HashMap<Id, Code>,
+    router_table: &RouterTable,
+    policy: &RedirectPolicy,
+) -> ValidationReport {
+    let owners: HashMap<&Code, &Id> = code_table.iter().map(|(id, code)| (code, id)).collect();
+    let mut routes_without_id = Vec::new();
+    let mut invalid_routes = Vec::new();
+    let mut deactivated = 0;
+    for (code, entry) in router_table.iter() {
+        let id = owners.get(code).map(|&id| id.clone());
+        if id.is_none() {
+            routes_without_id.push(code.clone());
+        }
+        if let Err(error) = entry
+            .urls()
+            .try_for_each(|url| check_scheme(url).and_then(|()| policy.check_target(url)))
+        {
+            invalid_routes.push(InvalidRoute {
+                code: code.clone(),
+                id,
+                error,
+            });
+        }
+        deactivated += usize::from(entry.deactivated);
+    }
+    routes_without_id.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+    invalid_routes.sort_unstable_by(|a, b| a.code.0.cmp(&b.code.0));
+    let ids_without_route = ids_without_route(code_table, router_table);
+    let duplicate_codes = duplicate_codes(code_table)
+        .into_iter()
+        .map(|(code, ids)| DuplicateCode { code, ids })
+        .collect();
+    ValidationReport {
+        routes: router_table.len(),
+        codes: code_table.len(),
+        routes_without_id: Findings::new(routes_without_id),
+        ids_without_route: Findings::new(ids_without_route),
+        duplicate_codes: Findings::new(duplicate_codes),
+        invalid_routes: Findings::new(invalid_routes),
+        deactivated,
+    }
+}
+
+/// ids whose codes have no route, e.g. after their routes were removed, sorted.
+fn ids_without_route(code_table: &HashMap<Id, Code>, router_table: &RouterTable) -> Vec<Id> {
+    let mut ids: Vec<Id> = code_table
+        .iter()
+        .filter(|(_, code)| !router_table.contains_key(code))
+        .map(|(id, _)| id.clone())
+        .collect();
+    ids.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+    ids
+}
+
+/// how often a long `put_routing_table` logs its progress.
+const PROGRESS_LOG_INTERVAL: Duration = Duration::from_secs(5);
+/// routes applied between checks of the progress clock.
+const PROGRESS_CHECK_INTERVAL: usize = 4096;
+
+const MIN_RETRY_AFTER: Duration = Duration::from_secs(1);
+const RETRY_AFTER_JITTER: Duration = Duration::from_secs(1);
+
+/// When the running update took the code table lock, and how long the previous one held it.
+#[derive(Default)]
+struct UpdateClock {
+    since: Option<Instant>,
+    last_hold: Duration,
+}
+
+/// code table guard of an update, recording how long it is held.
+struct UpdateGuard<'a> {
+    guard: MutexGuard<'a, HashMap<Id, Code>>,
+    clock: &'a std::sync::Mutex<UpdateClock>,
+}
+
+impl Deref for UpdateGuard<'_> {
+    type Target = HashMap<Id, Code>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.guard
+    }
+}
+
+impl DerefMut for UpdateGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.guard
+    }
+}
+
+impl Drop for UpdateGuard<'_> {
+    fn drop(&mut self) {
+        let mut clock = self.clock.lock().expect("poisoned");
+        if let Some(since) = clock.since.take() {
+            clock.last_hold = since.elapsed();
+        }
+    }
+}
+
+/// Snapshot of PATCHed tables waiting for `snapshot_debounce`,
+/// see `run_snapshot_writer`.
+#[derive(Default)]
+struct PendingSnapshot {
+    /// when to write, pushed back by every PATCH meanwhile.
+    due: std::sync::Mutex<Option<Due>>,
+    changed: tokio::sync::Notify,
+}
+
+/// see `PendingSnapshot::due`.
+#[derive(Clone, Copy)]
+struct Due {
+    /// the first PATCH of the snapshot, it is written `snapshot_max_delay` after at the latest.
+    since: Instant,
+    at: Instant,
+}
+
+/// Entry counts of the live tables.
+#[derive(Default)]
+struct TableSizes {
+    router_table: AtomicUsize,
+    code_table: AtomicUsize,
+}
+
+/// how long the estimates of `json_metrics` are reused.
+const SAMPLED_SIZES_TTL: Duration = Duration::from_secs(10);
+/// entries of each table measured to estimate its heap bytes.
+const HEAP_SAMPLE_ENTRIES: usize = 1000;
+
+/// Estimates of `json_metrics` that are too costly to take on every call.
+#[derive(Clone, Copy)]
+struct SampledSizes {
+    router_table_heap_bytes: u64,
+    code_table_heap_bytes: u64,
+    snapshot_count: usize,
+    snapshot_size_bytes: u64,
+}
+
+/// `len` times the average `size` of the first `HEAP_SAMPLE_ENTRIES` of `entries`.
+fn approx_heap_bytes<T>(
+    entries: impl Iterator<Item = T>,
+    len: usize,
+    size: impl Fn(T) -> usize,
+) -> u64 {
+    let (sampled, bytes) = entries
+        .take(HEAP_SAMPLE_ENTRIES)
+        .fold((0u64, 0u64), |(n, bytes), entry| {
+            (n + 1, bytes + size(entry) as u64)
+        });
+    if sampled == 0 {
+        return 0;
+    }
+    bytes * len as u64 / sampled
 }
 
+/// Shape of an uploaded table.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TableFormat {
+    /// `[{"uid": ..., "url": ...}, ...]`
+    Array,
+    /// `{"<id>": "<url>", ...}`, or `{"<id>": {"url": ...}, ...}`
+    Map,
+    /// first worksheet of an excel workbook, with `id` and `url` columns.
+    Xlsx,
+}
+
+impl TableFormat {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TableFormat::Array => "array",
+            TableFormat::Map => "map",
+            TableFormat::Xlsx => "xlsx",
+        }
+    }
+}
+
+/// value of a map table: a url, or a route without `uid`.
 #[derive(Deserialize)]
+#[serde(untagged)]
+enum MapValue {
+    Url(Url),
+    Route(serde_json::Map<String, serde_json::Value>),
+}
+
+/// entries of a map table in order, rejecting repeated ids that a
+/// `HashMap` would silently overwrite.
+struct MapEntries(Vec<(Id, MapValue)>);
+
+impl<'de> Deserialize<'de> for MapEntries {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct EntriesVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for EntriesVisitor {
+            type Value = MapEntries;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a map from ids to routes")
+            }
+
+            fn visit_map<A: serde::de::MapAccess<'de>>(
+                self,
+                mut map: A,
+            ) -> Result<MapEntries, A::Error> {
+                let mut seen = HashSet::new();
+                let mut entries = Vec::with_capacity(map.size_hint().unwrap_or_default());
+                while let Some((uid, value)) = map.next_entry::<Id, MapValue>()? {
+                    if !seen.insert(uid.clone()) {
+                        return Err(serde::de::Error::custom(format!("duplicate id {}", uid.0)));
+                    }
+                    entries.push((uid, value));
+                }
+                Ok(MapEntries(entries))
+            }
+        }
+
+        deserializer.deserialize_map(EntriesVisitor)
+    }
+}
+
+/// parse an uploaded table, either an array of routes, or a map from ids to routes.
+///
+/// `Err` describes the problem, to be shown to the uploader.
+pub fn parse_table(data: &[u8]) -> Result<(Vec<Route>, TableFormat), String> {
+    match data.iter().find(|b| !b.is_ascii_whitespace()) {
+        Some(b'[') => serde_json::from_slice(data)
+            .map(|routes| (routes, TableFormat::Array))
+            .map_err(|e| format!("invalid array of routes: {e}")),
+        Some(b'{') => {
+            let MapEntries(entries) = serde_json::from_slice(data).map_err(|e| {
+                format!("invalid map of routes, values must be urls or route objects: {e}")
+            })?;
+            let routes = entries
+                .into_iter()
+                .map(|(uid, value)| match value {
+                    MapValue::Url(url) => Ok(Route::new(uid, url)),
+                    MapValue::Route(mut fields) => {
+                        if fields.contains_key("uid") {
+                            return Err(format!("route of {} must not repeat uid", uid.0));
+                        }
+                        fields.insert("uid".to_owned(), uid.0.clone().into());
+                        serde_json::from_value(fields.into())
+                            .map_err(|e| format!("invalid route of {}: {e}", uid.0))
+                    }
+                })
+                .collect::<Result<_, _>>()?;
+            Ok((routes, TableFormat::Map))
+        }
+        _ => Err("expected a json array or object".to_owned()),
+    }
+}
+
+/// number of bad rows quoted in `parse_xlsx` errors.
+const MAX_REPORTED_ROWS: usize = 20;
+
+/// parse the first worksheet of an uploaded workbook into routes.
+///
+/// Columns are found by the headers `id` and `url` in the first row, ignoring case.
+/// Rows with an empty id are skipped and counted.
+/// `Err` describes the problem, to be shown to the uploader.
+///
+/// This is a blocking function, call it in `block_in_place`.
+pub fn parse_xlsx(data: &[u8]) -> Result<(Vec<Route>, usize), String> {
+    let mut workbook: Xlsx<_> =
+        open_workbook_from_rs(Cursor::new(data)).map_err(|e| format!("invalid xlsx: {e}"))?;
+    let sheet = workbook
+        .worksheet_range_at(0)
+        .ok_or("workbook has no worksheet")?
+        .map_err(|e| format!("invalid worksheet: {e}"))?;
+    // row numbers as shown by spreadsheet applications
+    let first_row = sheet.start().map_or(0, |(row, _)| row as usize) + 1;
+    let mut rows = sheet.rows();
+    let header = rows.next().ok_or("worksheet is empty")?;
+    let column = |name: &str| {
+        header
+            .iter()
+            .position(|cell| cell.to_string().trim().eq_ignore_ascii_case(name))
+            .ok_or_else(|| format!("missing column `{name}` in the first row"))
+    };
+    let (id_col, url_col) = (column("id")?, column("url")?);
+    let mut routes = Vec::new();
+    let mut skipped = 0;
+    let mut bad_rows = Vec::new();
+    for (i, row) in rows.enumerate() {
+        let cell = |col: usize| {
+            row.get(col)
+                .map(|cell| cell.to_string())
+                .unwrap_or_default()
+        };
+        let id = cell(id_col);
+        let id = id.trim();
+        if id.is_empty() {
+            skipped += 1;
+            continue;
+        }
+        match Url::parse(cell(url_col).trim()) {
+            Ok(url) => routes.push(Route::new(Id(id.to_owned()), url)),
+            Err(_) => bad_rows.push(first_row + 1 + i),
+        }
+    }
+    if !bad_rows.is_empty() {
+        let mut quoted = bad_rows
+            .iter()
+            .take(MAX_REPORTED_ROWS)
+            .map(|row| row.to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+        if bad_rows.len() > MAX_REPORTED_ROWS {
+            quoted += &format!(" and {} more", bad_rows.len() - MAX_REPORTED_ROWS);
+        }
+        return Err(format!("invalid url in rows {quoted}"));
+    }
+    Ok((routes, skipped))
+}
+
+/// compiled size limit of `search_routes` regexes, rejects pathological patterns.
+const SEARCH_REGEX_SIZE_LIMIT: usize = 1 << 20;
+
+/// open a GeoIP database, logging failures, after which `geo_urls` are ignored.
+/// A missing database is only noted, it may be installed later.
+fn open_geoip(path: &Path) -> Option<Arc<GeoIp>> {
+    if !path.exists() {
+        tracing::info!(
+            "no geoip database at {}, geo_urls are ignored",
+            path.display()
+        );
+        return None;
+    }
+    match GeoIp::open(path) {
+        Ok(geoip) => {
+            tracing::info!("geoip database loaded from {}", path.display());
+            Some(Arc::new(geoip))
+        }
+        Err(e) => {
+            tracing::warn!("failed to load geoip database {}: {e}", path.display());
+            None
+        }
+    }
+}
+
+/// mobile browser heuristic on the `User-Agent` header.
+fn is_mobile(user_agent: &str) -> bool {
+    static MOBILE: OnceLock<Regex> = OnceLock::new();
+    MOBILE
+        .get_or_init(|| Regex::new("Mobile|Android|iPhone|iPad").expect("valid regex"))
+        .is_match(user_agent)
+}
+
+/// check an uploaded route.
+pub fn validate_route(route: &Route) -> Result<(), String> {
+    route
+        .urls()
+        .try_for_each(check_scheme)
+        .map_err(|e| format!("target of {} rejected: {e}", route.uid.0))?;
+    if route.round_robin_urls.as_ref().is_some_and(Vec::is_empty) {
+        return Err(format!("round_robin_urls of {} is empty", route.uid.0));
+    }
+    if let Some(key) = route
+        .geo_urls
+        .iter()
+        .flat_map(BTreeMap::keys)
+        .find(|key| !geoip::is_valid_key(key))
+    {
+        return Err(format!(
+            "geo_urls key {key:?} of {} is not an upper case country code or EU",
+            route.uid.0
+        ));
+    }
+    if route
+        .request_timeout_secs
+        .is_some_and(|secs| secs == 0 || secs > MAX_REQUEST_TIMEOUT_SECS)
+    {
+        return Err(format!(
+            "request_timeout_secs of {} must be between 1 and {MAX_REQUEST_TIMEOUT_SECS}",
+            route.uid.0
+        ));
+    }
+    if let Some(description) = &route.description {
+        if description.chars().count() > MAX_DESCRIPTION_LENGTH {
+            return Err(format!(
+                "description of {} exceeds {MAX_DESCRIPTION_LENGTH} characters",
+                route.uid.0
+            ));
+        }
+    }
+    if let Some(wave) = &route.wave {
+        if wave.is_empty() || wave.chars().count() > MAX_WAVE_LENGTH || wave == DEFAULT_SURVEY {
+            return Err(format!(
+                "wave of {} must have 1 to {MAX_WAVE_LENGTH} characters and not be {DEFAULT_SURVEY}",
+                route.uid.0
+            ));
+        }
+    }
+    if route.participant.is_some() && route.wave.is_none() {
+        return Err(format!(
+            "route of {} has a participant but no wave",
+            route.uid.0
+        ));
+    }
+    Ok(())
+}
+
+/// `Err` if a participant would have two routes of one wave once `routes`
+/// replace the routes of their ids in `router_table`, see `participant_links`.
+///
+/// Only routes with a `wave` can collide: a route without one is keyed by
+/// its own id, and routes with a `participant` always have a wave.
+fn check_waves(
+    code_table: &HashMap<Id, Code>,
+    router_table: &RouterTable,
+    routes: &[&Route],
+) -> Result<(), String> {
+    if routes.iter().all(|route| route.wave.is_none()) {
+        return Ok(());
+    }
+    let replaced: HashSet<&Code> = routes
+        .iter()
+        .filter_map(|route| code_table.get(&route.uid))
+        .collect();
+    let owners: HashMap<&Code, &Id> = code_table.iter().map(|(id, code)| (code, id)).collect();
+    let stored = router_table
+        .iter()
+        .filter(|(code, _)| !replaced.contains(code))
+        .filter_map(|(code, entry)| {
+            let wave = entry.wave.as_ref()?;
+            let participant = entry
+                .participant
+                .as_ref()
+                .or_else(|| owners.get(code).copied())?;
+            Some((participant, wave))
+        });
+    let uploaded = routes.iter().filter_map(|route| {
+        Some((
+            route.participant.as_ref().unwrap_or(&route.uid),
+            route.wave.as_ref()?,
+        ))
+    });
+    let mut seen = HashSet::new();
+    for (participant, wave) in stored.chain(uploaded) {
+        if !seen.insert((participant, wave)) {
+            return Err(format!("{} has two routes of wave {wave}", participant.0));
+        }
+    }
+    Ok(())
+}
+
+/// `Err` unless `url` is http or https: `javascript:` or `data:` targets
+/// would run on this origin from the page of `redirect_mode: html`.
+fn check_scheme(url: &Url) -> Result<(), String> {
+    match url.scheme() {
+        "http" | "https" => Ok(()),
+        scheme => Err(format!("scheme {scheme} is not http or https")),
+    }
+}
+
 pub struct RedirectParams {
     pub code: Code,
 }
 
+impl RedirectParams {
+    /// parse the query of a redirect request, other parameters are ignored.
+    ///
+    /// `Err` explains an empty, missing or repeated `code`.
+    pub fn from_query(query: Option<&str>) -> Result<Self, &'static str> {
+        let mut codes = form_urlencoded::parse(query.unwrap_or_default().as_bytes())
+            .filter(|(key, _)| key == CODE);
+        let code = codes.next().ok_or("missing code")?.1;
+        if codes.next().is_some() {
+            return Err("duplicate code parameter");
+        }
+        if code.is_empty() {
+            return Err("missing code");
+        }
+        Ok(Self {
+            code: Code(code.into()),
+        })
+    }
+}
+
+/// Where a code redirects to.
+pub struct RedirectTarget {
+    pub url: Arc<Url>,
+    pub request_timeout_secs: Option<u64>,
+    pub mode: RedirectMode,
+}
+
+/// How participants are sent to their survey.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RedirectMode {
+    /// `303 See Other`.
+    #[default]
+    Http,
+    /// a page with a meta refresh and a link, see `redirect_page`.
+    Html,
+}
+
+/// What PATCH does with ids that already have a route.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictResolution {
+    #[default]
+    Overwrite,
+    Skip,
+    /// reject the whole request.
+    Error,
+}
+
+/// What PATCH does with ids without a code.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PatchMode {
+    /// give them codes and add their routes.
+    #[default]
+    Upsert,
+    /// reject the whole request, e.g. to catch misspelled ids.
+    UpdateOnly,
+}
+
+#[derive(Deserialize)]
+pub struct PutParams {
+    /// answer with the links of the uploaded ids, see `RouterState::links_of`.
+    #[serde(default)]
+    pub return_links: bool,
+}
+
+#[derive(Deserialize)]
+pub struct PatchParams {
+    #[serde(default)]
+    pub conflict: ConflictResolution,
+    #[serde(default)]
+    pub mode: PatchMode,
+    /// see `PutParams::return_links`.
+    #[serde(default)]
+    pub return_links: bool,
+    /// write the snapshot before responding instead of debouncing it.
+    #[serde(default)]
+    pub durable: bool,
+}
+
+/// Result of a PATCH.
+#[derive(Serialize, Debug, Default)]
+pub struct PatchSummary {
+    pub mode: PatchMode,
+    pub updated: usize,
+    pub overwritten: Vec<Id>,
+    pub skipped: Vec<Id>,
+    pub errors: Vec<String>,
+}
+
+/// Ids with a code but no route, see `RouterState::codes_without_routes`.
+#[derive(Serialize, Debug)]
+pub struct OrphanedCodes {
+    pub orphaned: Vec<Id>,
+    pub count: usize,
+}
+
+impl OrphanedCodes {
+    fn new(orphaned: Vec<Id>) -> Self {
+        Self {
+            count: orphaned.len(),
+            orphaned,
+        }
+    }
+}
+
+/// Result of a JSON Merge Patch, see `RouterState::merge_patch_routing_table`.
+#[derive(Serialize, Debug, Default)]
+pub struct MergePatchSummary {
+    pub updated: usize,
+    pub removed: usize,
+}
+
+/// body of `activate_codes` and `deactivate_codes`.
+#[derive(Deserialize)]
+pub struct BulkIds {
+    pub ids: Vec<Id>,
+}
+
+/// body of `PATCH admin_token`.
+#[derive(Deserialize)]
+pub struct AdminTokenRotation {
+    pub new_token: String,
+}
+
+/// Result of a bulk operation on ids.
+#[derive(Serialize, Debug, Default)]
+pub struct BulkResult {
+    pub succeeded: Vec<Id>,
+    pub not_found: Vec<Id>,
+}
+
+/// a participant id change, see `RouterState::rekey`.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct Rekey {
+    pub old_id: Id,
+    pub new_id: Id,
+}
+
+/// body of `POST rekey`, one change or several applied together.
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum RekeyRequest {
+    One(Rekey),
+    Many(Vec<Rekey>),
+}
+
+impl RekeyRequest {
+    pub fn into_vec(self) -> Vec<Rekey> {
+        match self {
+            RekeyRequest::One(rekey) => vec![rekey],
+            RekeyRequest::Many(rekeys) => rekeys,
+        }
+    }
+}
+
+/// Result of `bulk_remove_routes`.
+#[derive(Serialize, Debug, Default)]
+pub struct BulkRemoveResult {
+    pub removed: Vec<Id>,
+    pub not_found: Vec<Id>,
+}
+
+#[derive(Deserialize)]
+pub struct SearchRoutesParams {
+    pub url_contains: Option<String>,
+    pub url_regex: Option<String>,
+}
+
+/// `search_routes` item.
+#[derive(Serialize)]
+pub struct RouteMatch {
+    pub id: Id,
+    pub url: Url,
+}
+
+#[derive(Deserialize)]
+pub struct SearchCodesParams {
+    pub id_prefix: String,
+    #[serde(default = "default_search_codes_limit")]
+    pub limit: usize,
+}
+
+fn default_search_codes_limit() -> usize {
+    50
+}
+
+/// `search_codes` item.
+#[derive(Serialize)]
+pub struct CodeMatch {
+    pub id: Id,
+    pub code: Code,
+    /// `None` if the id currently has no route.
+    pub redirect_url: Option<Url>,
+}
+
+/// `runtime_info` response, a first-order view of traffic without a metrics stack.
+#[derive(Serialize)]
+pub struct RuntimeInfo {
+    pub version: &'static str,
+    pub draining: bool,
+    pub started_at_iso: String,
+    pub uptime_secs: u64,
+    pub requests_total: u64,
+    /// `None` before the first redirect request.
+    pub last_request_at_iso: Option<String>,
+    pub redirect_errors_total: u64,
+    pub connections_active: u64,
+    pub connections_total: u64,
+    pub tls_handshake_failures: u64,
+    /// SHA-256 of the certificate being served, `None` without tls.
+    pub cert_fingerprint: Option<String>,
+    /// SHA-256 of `admin_tls`, `None` if the admin api shares the certificate.
+    pub admin_cert_fingerprint: Option<String>,
+}
+
+/// Response of `/admin/metrics.json`.
+#[derive(Serialize)]
+pub struct JsonMetrics {
+    pub router_table: TableMetrics,
+    pub code_table: TableMetrics,
+    pub snapshot_count: usize,
+    pub snapshot_size_bytes: u64,
+    /// redirect requests since startup by outcome.
+    pub redirects: BTreeMap<&'static str, u64>,
+    /// admin requests answered 429 since startup, busy or throttled.
+    pub too_many_requests_total: u64,
+}
+
+#[derive(Serialize)]
+pub struct TableMetrics {
+    pub entries: usize,
+    /// extrapolated from a sample of the entries, ignoring the overhead of the map.
+    pub approx_heap_bytes: u64,
+}
+
+/// A survey completion, reported by `/api/complete`.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Completion {
+    /// time of the first completion.
+    pub completed_at: DateTime<Utc>,
+    /// completions, including repeats.
+    pub count: u64,
+}
+
+#[derive(Deserialize)]
+pub struct RoutingTableParams {
+    #[serde(default)]
+    pub format: RoutingTableFormat,
+}
+
+/// Body of `GET routing_table`.
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RoutingTableFormat {
+    /// `[{"uid": ..., "url": ..., ...}, ...]`, as uploaded.
+    #[default]
+    Json,
+    /// `uid,url,mobile_url,description,notes,orphaned` with a header row,
+    /// the columns of the offline import.
+    Csv,
+}
+
+/// `GET routing_table` item, a `Route` as it was uploaded.
+#[derive(Serialize)]
+pub struct TableRoute {
+    /// `None` if the code of the route has no id, which means the store is corrupt.
+    pub uid: Option<Id>,
+    pub url: Url,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mobile_url: Option<Url>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub round_robin_urls: Option<Vec<Url>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub geo_urls: Option<BTreeMap<String, Url>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub redirect_mode: Option<RedirectMode>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_timeout_secs: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub notes: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub participant: Option<Id>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub wave: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<DateTime<Utc>>,
+    /// set for routes without an id.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub orphaned: bool,
+}
+
+impl TableRoute {
+    fn new(uid: Option<Id>, entry: &RouteEntry) -> Self {
+        TableRoute {
+            orphaned: uid.is_none(),
+            uid,
+            url: Url::clone(&entry.url),
+            mobile_url: entry.mobile_url.clone(),
+            round_robin_urls: entry.round_robin_urls.clone(),
+            geo_urls: entry.geo_urls.clone(),
+            redirect_mode: entry.redirect_mode,
+            request_timeout_secs: entry.request_timeout_secs,
+            description: entry.description.clone(),
+            notes: entry.notes.clone(),
+            participant: entry.participant.clone(),
+            wave: entry.wave.clone(),
+            expires_at: entry.expires_at,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct CompletionsParams {
+    #[serde(default)]
+    pub format: CompletionsFormat,
+}
+
+/// Body of `completions`.
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CompletionsFormat {
+    /// `{"<id>": {"completed_at": ..., "count": ...}, ...}`
+    #[default]
+    Json,
+    /// `id,completed_at,count` with a header row.
+    Csv,
+}
+
+/// offenders of a `validate_tables` check: how many, and the first of them.
+#[derive(Serialize, Debug)]
+pub struct Findings<T> {
+    pub count: usize,
+    pub samples: Vec<T>,
+}
+
+impl<T> Findings<T> {
+    /// from all offenders, sorted.
+    fn new(all: Vec<T>) -> Self {
+        Findings {
+            count: all.len(),
+            samples: all.into_iter().take(VALIDATION_SAMPLES).collect(),
+        }
+    }
+}
+
+/// offenders per check reported by `validate_tables`.
+const VALIDATION_SAMPLES: usize = 100;
+
+/// a code of several ids.
+#[derive(Serialize, Debug)]
+pub struct DuplicateCode {
+    pub code: Code,
+    pub ids: Vec<Id>,
+}
+
+/// a stored route with a target rejected by the current `RedirectPolicy`.
+#[derive(Serialize, Debug)]
+pub struct InvalidRoute {
+    pub code: Code,
+    /// `None` for routes without id.
+    pub id: Option<Id>,
+    pub error: String,
+}
+
+/// `validate` response, see `validate_tables`.
+#[derive(Serialize, Debug)]
+pub struct ValidationReport {
+    pub routes: usize,
+    pub codes: usize,
+    /// routes of codes no id has, so no link leads to them.
+    pub routes_without_id: Findings<Code>,
+    /// ids whose code has no route, e.g. after their route was removed.
+    pub ids_without_route: Findings<Id>,
+    pub duplicate_codes: Findings<DuplicateCode>,
+    pub invalid_routes: Findings<InvalidRoute>,
+    /// suspended routes, not an error.
+    pub deactivated: usize,
+}
+
+impl ValidationReport {
+    /// whether no check found offenders.
+    pub fn is_clean(&self) -> bool {
+        self.routes_without_id.count == 0
+            && self.ids_without_route.count == 0
+            && self.duplicate_codes.count == 0
+            && self.invalid_routes.count == 0
+    }
+}
+
+/// `reload` response.
+#[derive(Serialize)]
+pub struct ReloadSummary {
+    /// time of the loaded router table snapshot, `None` if there was none.
+    pub snapshot_ts: Option<String>,
+    pub routes: usize,
+    pub codes: usize,
+    /// whether a GeoIP database is loaded after the reload.
+    pub geoip: bool,
+}
+
+#[derive(Deserialize)]
+pub struct RouteHistoryParams {
+    pub id: Id,
+}
+
+/// `route_history` item, the url of a route in one snapshot.
+#[derive(Serialize)]
+pub struct RouteHistoryEntry {
+    pub ts: String,
+    pub url: Url,
+}
+
+/// Survey key of routes without a `wave` in `participant_links`.
+pub const DEFAULT_SURVEY: &str = "default";
+
+/// `participant_links` entry, the link of a participant to one survey.
+#[derive(Serialize)]
+pub struct ParticipantLink {
+    pub link: Url,
+    /// the survey url the link redirects to.
+    pub url: Url,
+    pub status: LinkStatus,
+    pub hit_count: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub notes: Option<String>,
+}
+
+/// Whether a participant link redirects.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LinkStatus {
+    Active,
+    /// past its `expires_at`, answers `410 Gone`.
+    Expired,
+    /// deactivated by `deactivate_codes`, answers `410 Gone`.
+    Disabled,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct LinksParams {
+    #[serde(default)]
+    pub format: LinksFormat,
+}
+
+/// Body of `get_links`.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LinksFormat {
+    /// `{"<id>": {"link": ..., "description": ..., "notes": ...}, ...}`
+    #[default]
+    Json,
+    /// one `{"id": ..., "url": ..., "description": ..., "notes": ...}` per line.
+    Ndjson,
+}
+
+/// `get_links` entries per streamed chunk.
+const LINKS_CHUNK_SIZE: usize = 1024;
+
+/// `get_links` entry copied out of the tables.
+struct LinkRow {
+    id: Id,
+    code: Code,
+    description: Option<String>,
+    notes: Option<String>,
+}
+
+/// `get_links` line with `format=ndjson`.
+#[derive(Serialize)]
+struct LinkLine<'a> {
+    id: &'a Id,
+    url: Url,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    notes: Option<&'a str>,
+}
+
+/// `get_links` item of an id with `format=json`.
+#[derive(Serialize)]
+struct LinkItem<'a> {
+    link: Url,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    notes: Option<&'a str>,
+}
+
 #[derive(Clone)]
 pub struct RouterState {
+    /// public url of `/api`, see `Config::api_url`.
     pub router_url: Url,
+    /// `storage_root`, for hits files and the readiness probe.
     pub router_table_store: PathBuf,
-    pub router_table: Arc<RwLock<HashMap<Code, Url>>>,
-    pub code_table: Arc<Mutex<HashMap<Uid, Code>>>,
+    /// where the router and code tables are written.
+    pub storage: Storage,
+    pub router_table: Arc<RwLock<RouterTable>>,
+    pub code_table: Arc<Mutex<HashMap<Id, Code>>>,
+    pub code_length: usize,
+    pub code_prefix: String,
+    pub code_gen_mode: CodeGenMode,
+    /// total hits at the last flush, to skip flushing when idle.
+    pub hits_flushed: Arc<AtomicU64>,
+    /// survey completions reported by `/api/complete`, flushed with the hit counts.
+    pub completions: Arc<DashMap<Code, Completion>>,
+    /// set by completions, cleared when they are flushed.
+    pub completions_changed: Arc<AtomicBool>,
+    /// where `/api/complete` redirects, a built-in page is shown if `None`.
+    pub thank_you_url: Option<Url>,
+    /// next index into `round_robin_urls` of each code.
+    pub round_robin_counters: Arc<DashMap<Code, AtomicUsize>>,
+    /// set at graceful shutdown, readiness fails from then on.
+    pub shutting_down: Arc<AtomicBool>,
+    /// set by `/admin/drain`: new public connections get 503, readiness fails.
+    /// Not persisted, a restarted process serves normally.
+    pub draining: Arc<AtomicBool>,
+    pub readiness_probe_storage: bool,
+    pub disable_redirect_caching: bool,
+    /// of routes without their own `redirect_mode`.
+    pub redirect_mode: RedirectMode,
+    /// page of `RedirectMode::Html`, see `redirect_page`.
+    pub redirect_page_template: Arc<str>,
+    /// `error_templates_dir` of the config, see `watch_error_templates`.
+    error_templates_dir: Option<PathBuf>,
+    /// see `error_pages`.
+    error_pages: Arc<std::sync::RwLock<Arc<ErrorPages>>>,
+    /// give ids sharing a code new codes when tables are loaded, instead of failing.
+    pub repair_duplicate_codes: bool,
+    /// redirect requests, valid or not.
+    pub requests_total: Arc<AtomicU64>,
+    /// unix time (seconds) of the last redirect request, 0 if none yet.
+    pub last_request_at: Arc<AtomicU64>,
+    /// redirect requests with an unknown code.
+    pub redirect_errors_total: Arc<AtomicU64>,
+    /// end of `init`, for `uptime_secs`.
+    pub started_at: Instant,
+    pub started_at_utc: DateTime<Utc>,
+    /// denied redirect targets and blocked referrers, see `set_redirect_policy`.
+    pub redirect_policy: Arc<std::sync::RwLock<Arc<RedirectPolicy>>>,
+    /// `geoip_database` of the config, reread by `reload`.
+    geoip_database: Option<PathBuf>,
+    /// see `Config::global_query_params` and `set_global_query_params`.
+    global_query_params: Arc<std::sync::RwLock<Arc<BTreeMap<String, String>>>>,
+    /// locates clients for `geo_urls`, `None` without a (readable) database.
+    geoip: Arc<std::sync::RwLock<Option<Arc<GeoIp>>>>,
+    /// reload triggers of the served certificates, see `set_cert_reload`.
+    cert_reload: Arc<std::sync::RwLock<Vec<CertReloadSender>>>,
+    /// checked by `admin_auth::require_admin_token`, see `rotate_admin_token`.
+    pub admin_token: AdminToken,
+    /// held while rotating, so the stored and the live token agree.
+    admin_token_rotation: Arc<std::sync::Mutex<()>>,
+    /// limit of uploaded tables after decompression.
+    pub max_decoded_body_size: usize,
+    /// responses of uploads by `Idempotency-Key`.
+    pub idempotency: Arc<IdempotencyCache>,
+    /// timing of code table updates, to suggest `Retry-After` on `Busy`.
+    update_clock: Arc<std::sync::Mutex<UpdateClock>>,
+    /// see `table_version`, changed with the code table locked.
+    table_version: Arc<AtomicU64>,
+    /// how long PATCHes wait to write a snapshot, zero writes immediately.
+    snapshot_debounce: Duration,
+    /// bound of the debounce, so that a steady stream of PATCHes is written.
+    snapshot_max_delay: Duration,
+    pending_snapshot: Arc<PendingSnapshot>,
+    /// entry counts of the tables, kept with the `ROUTER_TABLE_SIZE` gauges.
+    table_sizes: Arc<TableSizes>,
+    /// cache of `json_metrics` estimates, with the time they were taken.
+    sampled_sizes: Arc<std::sync::Mutex<Option<(Instant, SampledSizes)>>>,
+    /// redirect requests by outcome, for `json_metrics`.
+    pub redirect_outcomes: Arc<RedirectOutcomes>,
+    /// admin responses with status 429, counted by `monitoring::track_admin`.
+    pub too_many_requests_total: Arc<AtomicU64>,
 }
 
 #[derive(Debug)]
 pub enum StateError {
     Unauthorized,
     InvalidCode,
+    Deactivated,
+    /// the `expires_at` of the route has passed.
+    Expired,
     StoreError(std::io::Error),
+    InvalidRoute(String),
+    InvalidQuery(String),
+    ShuttingDown,
+    Draining,
+    UnknownId,
+    /// PATCH with `conflict=error` hit existing routes.
+    Conflict(PatchSummary),
     Busy,
+    /// the redirect target is denied by the `RedirectPolicy`.
+    BlockedTarget(String),
+    /// a new admin token that is too short or too simple.
+    InvalidToken(String),
+    /// the stored code table maps several ids to one code.
+    DuplicateCode {
+        code: Code,
+        ids: Vec<Id>,
+    },
+    /// the client of an update went away before it was applied.
+    Cancelled,
+    /// PATCH with `mode=update_only` of ids without a code.
+    UnknownIds(Vec<Id>),
+    /// `rekey` of an id without a code.
+    UnknownOldId(Id),
+    /// `rekey` to an id that has a code already.
+    IdTaken(Id),
+    /// `If-Match` of an update names another version than the live one.
+    VersionMismatch {
+        expected: u64,
+        current: u64,
+    },
+    /// `reload` while debounced PATCHes are not written yet.
+    SnapshotPending,
+    /// the sequential counter has no digits left within `code_length`.
+    CodesExhausted,
 }
 
 impl RouterState {
+    /// This is a blocking function, use `init_async` in async contexts.
     pub fn init(config: &Config) -> Result<Self, StateError> {
-        // create store if not exist
-        std::fs::create_dir_all(&config.storage_root).map_err(StateError::StoreError)?;
-        // load stored states
+        Self::init_with_dir(config, config.storage_root.clone())
+    }
+
+    /// like `init`, but storing in `store` instead of `config.storage_root`,
+    /// e.g. a temporary directory in tests.
+    pub fn init_with_dir(config: &Config, store: PathBuf) -> Result<Self, StateError> {
+        let stored = load_stored(config, store.clone())()?;
+        Self::from_stored(config, store, stored)
+    }
+
+    /// like `init`, but reading the stored state in `spawn_blocking`.
+    pub async fn init_async(config: &Config) -> Result<Self, StateError> {
         let store = config.storage_root.clone();
-        let router_table = match load_latest_router_table(&store).map_err(StateError::StoreError)? {
-            Some((time, table)) => {
-                tracing::info!("router table loaded (time={time})");
-                Arc::new(RwLock::new(table))
+        let stored = tokio::task::spawn_blocking(load_stored(config, store.clone()))
+            .await
+            .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()))?;
+        Self::from_stored(config, store, stored)
+    }
+
+    fn from_stored(config: &Config, store: PathBuf, stored: Stored) -> Result<Self, StateError> {
+        let Stored {
+            storage,
+            tables:
+                LoadedTables {
+                    version,
+                    router_table,
+                    code_table,
+                    ..
+                },
+            completions,
+            geoip,
+            redirect_page_template,
+            error_pages,
+        } = stored;
+        let hits_loaded = router_table.values().map(|e| e.hit_count.get()).sum();
+        let table_sizes = TableSizes {
+            router_table: AtomicUsize::new(router_table.len()),
+            code_table: AtomicUsize::new(code_table.len()),
+        };
+        set_table_sizes(router_table.len(), code_table.len());
+        // existing codes are kept as is, only new codes use this length
+        tracing::info!("code length: {}", config.code_length);
+        let code_prefix = config.code_prefix.clone().unwrap_or_default();
+        if !code_prefix.is_empty() {
+            tracing::info!("code prefix: {code_prefix}");
+        }
+        let started_at_utc = Utc::now();
+        tracing::info!("server started at {started_at_utc}");
+        let state = Self {
+            router_url: config.api_url(),
+            router_table_store: store,
+            storage,
+            router_table: Arc::new(RwLock::new(router_table)),
+            code_table: Arc::new(Mutex::new(code_table)),
+            code_length: config.code_length,
+            code_prefix,
+            code_gen_mode: config.code_gen_mode.clone(),
+            hits_flushed: Arc::new(AtomicU64::new(hits_loaded)),
+            completions: Arc::new(completions.into_iter().collect()),
+            completions_changed: Arc::default(),
+            thank_you_url: config.thank_you_url.clone(),
+            round_robin_counters: Arc::new(DashMap::new()),
+            shutting_down: Arc::new(AtomicBool::new(false)),
+            draining: Arc::new(AtomicBool::new(false)),
+            readiness_probe_storage: config.readiness_probe_storage,
+            disable_redirect_caching: config.disable_redirect_caching,
+            redirect_mode: config.redirect_mode,
+            redirect_page_template: redirect_page_template.into(),
+            error_templates_dir: config.error_templates_dir.clone(),
+            error_pages: Arc::new(std::sync::RwLock::new(Arc::new(error_pages))),
+            redirect_policy: Arc::new(std::sync::RwLock::new(Arc::new(
+                RedirectPolicy::from_config(config).expect("validated blocked_referrers"),
+            ))),
+            geoip_database: config.geoip_database.clone(),
+            geoip: Arc::new(std::sync::RwLock::new(geoip)),
+            cert_reload: Arc::default(),
+            global_query_params: Arc::new(std::sync::RwLock::new(Arc::new(
+                config.global_query_params.clone(),
+            ))),
+            admin_token: Arc::new(std::sync::RwLock::new(config.admin_token.clone())),
+            admin_token_rotation: Arc::default(),
+            max_decoded_body_size: config.max_decoded_body_size,
+            requests_total: Arc::default(),
+            last_request_at: Arc::default(),
+            redirect_errors_total: Arc::default(),
+            started_at: Instant::now(),
+            started_at_utc,
+            update_clock: Arc::default(),
+            table_version: Arc::new(AtomicU64::new(version)),
+            snapshot_debounce: Duration::from_secs(config.snapshot_debounce_secs),
+            snapshot_max_delay: Duration::from_secs(config.snapshot_max_delay_secs),
+            pending_snapshot: Arc::default(),
+            table_sizes: Arc::new(table_sizes),
+            sampled_sizes: Arc::default(),
+            redirect_outcomes: Arc::default(),
+            too_many_requests_total: Arc::default(),
+            idempotency: Arc::new(IdempotencyCache::new(
+                NonZeroUsize::new(config.idempotency_cache_size).expect("validated cache size"),
+                Duration::from_secs(config.idempotency_ttl_secs),
+            )),
+            repair_duplicate_codes: config.repair_duplicate_codes,
+        };
+        {
+            let mut code_table = state.code_table.try_lock().expect("not shared yet");
+            let mut router_table = state.router_table.try_write().expect("not shared yet");
+            state.seed_code_counter(&code_table);
+            state.resolve_duplicate_codes(&mut code_table, &mut router_table, version)?;
+            let report = validate_tables(&code_table, &router_table, &state.redirect_policy());
+            if !report.is_clean() {
+                tracing::warn!(
+                    "inconsistent tables: {} routes without id, {} ids without route, \
+                     {} invalid routes, see GET /admin/validate",
+                    report.routes_without_id.count,
+                    report.ids_without_route.count,
+                    report.invalid_routes.count
+                );
+            }
+        }
+        Ok(state)
+    }
+
+    // public API
+
+    /// count a redirect request, called before it is resolved.
+    pub fn count_request(&self) {
+        self.requests_total.fetch_add(1, Ordering::Relaxed);
+        let now = Utc::now().timestamp().max(0) as u64;
+        self.last_request_at.store(now, Ordering::Relaxed);
+    }
+
+    /// get the redirect url, the `geo_urls` entry of the client's location
+    /// if any, otherwise `mobile_url` for mobile user agents,
+    /// otherwise the next of `round_robin_urls` if set.
+    #[tracing::instrument(skip(self, redirect_params, user_agent, client_ip), fields(code = %redirect_params.code))]
+    pub async fn redirect(
+        &self,
+        redirect_params: RedirectParams,
+        user_agent: Option<&str>,
+        client_ip: Option<IpAddr>,
+    ) -> Result<RedirectTarget, StateError> {
+        // cheap rejection of scans, without taking the lock
+        if !redirect_params.code.is_well_formed() {
+            return Err(StateError::InvalidCode);
+        }
+        let code = &redirect_params.code;
+        let router_table_lk = self.router_table.read().await;
+        let entry = router_table_lk.get(code).ok_or(StateError::InvalidCode)?;
+        if entry.deactivated {
+            return Err(StateError::Deactivated);
+        }
+        if entry.is_expired(Utc::now()) {
+            return Err(StateError::Expired);
+        }
+        let geo_url = client_ip.and_then(|ip| self.geo_redirect_url(entry, code, ip));
+        let url = match (geo_url, &entry.mobile_url, &entry.round_robin_urls) {
+            (Some(geo_url), _, _) => Arc::new(geo_url),
+            (None, Some(mobile_url), _) if user_agent.is_some_and(is_mobile) => {
+                Arc::new(redirect_url(mobile_url, code, None))
             }
-            None => {
-                tracing::info!("new router table created");
-                Arc::new(RwLock::new(HashMap::new()))
+            (None, _, Some(urls)) if !urls.is_empty() => {
+                let idx = self.next_round_robin(code) % urls.len();
+                Arc::new(redirect_url(&urls[idx], code, Some(idx)))
             }
+            _ => match &entry.redirect_url {
+                Some(url) => url.clone(),
+                None => Arc::new(redirect_url(&entry.url, code, None)),
+            },
         };
-        let code_table = match load_latest_code_table(&store).map_err(StateError::StoreError)? {
-            Some(table) => {
-                tracing::info!("code table loaded");
-                Arc::new(Mutex::new(table))
-            }
-            None => {
-                tracing::info!("new code table created");
-                Arc::new(Mutex::new(HashMap::new()))
+        let url = self.with_global_query_params(url);
+        // tables stored before a target was denied
+        check_scheme(&url)
+            .and_then(|()| self.redirect_policy().check_target(&url))
+            .map_err(StateError::BlockedTarget)?;
+        entry.hit_count.incr();
+        Ok(RedirectTarget {
+            url,
+            request_timeout_secs: entry.request_timeout_secs,
+            mode: entry.redirect_mode.unwrap_or(self.redirect_mode),
+        })
+    }
+
+    /// record that the participant of a code completed the survey,
+    /// the first completion keeps its time, repeats are counted.
+    ///
+    /// returns `Err(InvalidCode)` if the code has no route.
+    pub async fn complete(&self, params: RedirectParams) -> Result<(), StateError> {
+        if !params.code.is_well_formed()
+            || !self.router_table.read().await.contains_key(&params.code)
+        {
+            return Err(StateError::InvalidCode);
+        }
+        self.completions
+            .entry(params.code)
+            .and_modify(|completion| completion.count += 1)
+            .or_insert_with(|| Completion {
+                completed_at: Utc::now(),
+                count: 1,
+            });
+        self.completions_changed.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// completions by id, ids without a code are left out.
+    ///
+    /// returns `Err(Busy)` if cannot acquire a lock of code_table.
+    pub async fn get_completions(&self) -> Result<Vec<(Id, Completion)>, StateError> {
+        let code_table_lk = self.code_table.try_lock().map_err(|_| StateError::Busy)?;
+        let mut completions: Vec<_> = code_table_lk
+            .iter()
+            .filter_map(|(id, code)| {
+                let completion = self.completions.get(code)?;
+                Some((id.clone(), completion.clone()))
+            })
+            .collect();
+        completions.sort_unstable_by(|(a, _), (b, _)| a.0.cmp(&b.0));
+        Ok(completions)
+    }
+
+    /// the `geo_urls` target of `ip` with `geo=<key>` appended, `None` if the route
+    /// has no `geo_urls`, there is no GeoIP database or no key matches.
+    fn geo_redirect_url(&self, entry: &RouteEntry, code: &Code, ip: IpAddr) -> Option<Url> {
+        let geo_urls = entry.geo_urls.as_ref()?;
+        let location = self.geoip.read().expect("poisoned").clone()?.lookup(ip)?;
+        let (key, url) = location
+            .keys()
+            .find_map(|key| geo_urls.get_key_value(key))?;
+        let mut url = redirect_url(url, code, None);
+        url.query_pairs_mut().append_pair(GEO, key);
+        Some(url)
+    }
+
+    /// `url` with the global query parameters it does not have yet.
+    fn with_global_query_params(&self, url: Arc<Url>) -> Arc<Url> {
+        let params = self.global_query_params.read().expect("poisoned").clone();
+        if params.is_empty() {
+            return url;
+        }
+        let present: HashSet<String> = url
+            .query_pairs()
+            .map(|(name, _)| name.into_owned())
+            .collect();
+        let mut url = Arc::unwrap_or_clone(url);
+        {
+            let mut query = url.query_pairs_mut();
+            for (name, value) in params.iter() {
+                if !present.contains(name) {
+                    query.append_pair(name, value);
+                }
             }
+        }
+        Arc::new(url)
+    }
+
+    /// replace the global query parameters, e.g. after the config changed.
+    pub fn set_global_query_params(&self, params: BTreeMap<String, String>) {
+        *self.global_query_params.write().expect("poisoned") = Arc::new(params);
+    }
+
+    /// set the reload triggers of the certificates, once they are served.
+    pub fn set_cert_reload(&self, senders: Vec<CertReloadSender>) {
+        *self.cert_reload.write().expect("poisoned") = senders;
+    }
+
+    /// reload the served certificates as if their files changed,
+    /// in the background. Returns the number of certificates, 0 without tls.
+    pub fn reload_cert(&self) -> usize {
+        let senders = self.cert_reload.read().expect("poisoned");
+        for sender in senders.iter() {
+            sender.send_replace(CertReload::Forced);
+        }
+        senders.len()
+    }
+
+    /// reread `geoip_database`, `None` if it is missing or fails to load.
+    ///
+    /// This is a blocking function, call it in `block_in_place`.
+    fn read_geoip(&self) -> Option<Arc<GeoIp>> {
+        self.geoip_database.as_deref().and_then(open_geoip)
+    }
+
+    /// replace the loaded GeoIP database, keeping it if `geoip` is `None`.
+    ///
+    /// returns whether a database is loaded.
+    fn replace_geoip(&self, geoip: Option<Arc<GeoIp>>) -> bool {
+        let mut loaded = self.geoip.write().expect("poisoned");
+        if geoip.is_some() {
+            *loaded = geoip;
+        }
+        loaded.is_some()
+    }
+
+    /// responses of failed redirects, see `Config::error_templates_dir`.
+    pub fn error_pages(&self) -> Arc<ErrorPages> {
+        self.error_pages.read().expect("poisoned").clone()
+    }
+
+    /// reread the pages of `error_templates_dir` whenever its files change,
+    /// until the returned watcher is dropped. `None` without a directory.
+    pub fn watch_error_templates(&self) -> std::io::Result<Option<notify::RecommendedWatcher>> {
+        let Some(dir) = self.error_templates_dir.clone() else {
+            return Ok(None);
         };
-        Ok(Self {
-            router_url: config.base_url.clone(),
-            router_table_store: config.storage_root.clone(),
-            router_table,
-            code_table,
+        let error_pages = self.error_pages.clone();
+        let mut watcher = notify::recommended_watcher({
+            let dir = dir.clone();
+            move |event: Result<notify::Event, notify::Error>| {
+                if event.is_ok() {
+                    *error_pages.write().expect("poisoned") = Arc::new(ErrorPages::load(&dir));
+                }
+            }
         })
+        .map_err(|e| std::io::Error::other(format!("failed to init error page watcher {}", e)))?;
+        watcher
+            .watch(&dir, notify::RecursiveMode::NonRecursive)
+            .map_err(|e| std::io::Error::other(format!("failed to watch error pages {}", e)))?;
+        Ok(Some(watcher))
+    }
+
+    /// increment the round robin counter of a code, returning its previous value.
+    fn next_round_robin(&self, code: &Code) -> usize {
+        if let Some(counter) = self.round_robin_counters.get(code) {
+            return counter.fetch_add(1, Ordering::Relaxed);
+        }
+        self.round_robin_counters
+            .entry(code.clone())
+            .or_default()
+            .fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// ready to serve, unless shutting down, draining,
+    /// or (optionally) storage is not writable.
+    pub async fn readiness(&self) -> Result<(), StateError> {
+        if self.shutting_down.load(Ordering::Relaxed) {
+            return Err(StateError::ShuttingDown);
+        }
+        if self.draining.load(Ordering::Relaxed) {
+            return Err(StateError::Draining);
+        }
+        if self.readiness_probe_storage {
+            tokio::task::block_in_place(|| tempfile::tempfile_in(&self.router_table_store))
+                .map_err(StateError::StoreError)?;
+        }
+        Ok(())
+    }
+
+    // admin APIs
+
+    /// start or stop draining, returns the previous state.
+    pub fn set_draining(&self, draining: bool) -> bool {
+        self.draining.swap(draining, Ordering::Relaxed)
+    }
+
+    /// replace the tables with those on disk, e.g. after restoring a backup.
+    ///
+    /// Hit counts of remaining codes are kept if higher than the stored ones.
+    ///
+    /// returns `Err(Busy)` if cannot acquire a lock of code_table,
+    /// `Err(InvalidRoute)` if a route has no id in the stored code table,
+    /// `Err(DuplicateCode)` as in `init`,
+    /// `Err(SnapshotPending)` if PATCHes are not written yet.
+    pub async fn reload(&self) -> Result<ReloadSummary, StateError> {
+        // read before taking the locks, redirects wait for the router table
+        let geoip = tokio::task::block_in_place(|| self.read_geoip());
+        let mut code_table_lk = self.code_table.try_lock().map_err(|_| StateError::Busy)?;
+        // the stored tables would drop them, and their snapshot overwrite the reload
+        if self.snapshot_pending() {
+            return Err(StateError::SnapshotPending);
+        }
+        let LoadedTables {
+            time,
+            version,
+            mut router_table,
+            mut code_table,
+        } = tokio::task::block_in_place(|| load_tables(&self.storage))
+            .map_err(StateError::StoreError)?;
+        let codes: HashSet<&Code> = code_table.values().collect();
+        let orphans = router_table
+            .iter()
+            .filter(|(code, _)| !codes.contains(code))
+            .count();
+        if orphans > 0 {
+            return Err(StateError::InvalidRoute(format!(
+                "{orphans} stored routes have no id in the stored code table"
+            )));
+        }
+        drop(codes);
+        self.seed_code_counter(&code_table);
+        tokio::task::block_in_place(|| {
+            self.resolve_duplicate_codes(&mut code_table, &mut router_table, version)
+        })?;
+        let mut router_table_lk = self.router_table.write().await;
+        for (code, entry) in router_table.iter() {
+            if let Some(old) = router_table_lk.get(code) {
+                entry.hit_count.merge(old.hit_count.get());
+            }
+        }
+        let summary = ReloadSummary {
+            snapshot_ts: time.map(|time| time.to_rfc3339()),
+            routes: router_table.len(),
+            codes: code_table.len(),
+            geoip: self.replace_geoip(geoip),
+        };
+        self.set_table_sizes(router_table.len(), code_table.len());
+        self.hits_flushed.store(
+            router_table.values().map(|e| e.hit_count.get()).sum(),
+            Ordering::Relaxed,
+        );
+        self.round_robin_counters
+            .retain(|code, _| router_table.contains_key(code));
+        *router_table_lk = router_table;
+        *code_table_lk = code_table;
+        self.table_version.store(version, Ordering::SeqCst);
+        Ok(summary)
     }
 
-    // public API
-
-    /// get the redirect url
-    pub async fn redirect(&self, redirect_params: RedirectParams) -> Result<Url, StateError> {
-        let mut url = self
-            .router_table
-            .read()
+    /// replace routing table, keeping hit counts and deactivation of remaining codes.
+    /// Returns the new table version.
+    ///
+    /// returns `Err(Busy)` if cannot acquire a lock of code_table,
+    /// `Err(VersionMismatch)` if `if_match` is not the live table version.
+    pub async fn put_routing_table(
+        &self,
+        data: Vec<Route>,
+        if_match: Option<u64>,
+    ) -> Result<u64, StateError> {
+        self.put_routing_table_cancellable(data, if_match, &CancellationToken::new())
             .await
-            .get(&redirect_params.code)
-            .ok_or(StateError::InvalidCode)?
-            .clone();
-        {
-            let mut query = url.query_pairs_mut();
-            query.append_pair(EXTERNEL_ID, &redirect_params.code.0);
-            query.finish();
-        }
-        Ok(url)
     }
 
-    // admin APIs
-
-    /// replace routing table
+    /// like `put_routing_table`, but nothing is written if `cancelled` is
+    /// cancelled before the new table is applied, e.g. because the client
+    /// disconnected. Once applying, the update is completed regardless.
     ///
-    /// returns `Err(Busy)` if cannot acquire a lock of code_table.
-    pub async fn put_routing_table(&self, data: Vec<Route>) -> Result<(), StateError> {
+    /// Progress is logged every few seconds while applying.
+    ///
+    /// returns `Err(Cancelled)` if cancelled in time.
+    #[tracing::instrument(skip(self, data, cancelled), fields(route_count = data.len()))]
+    pub async fn put_routing_table_cancellable(
+        &self,
+        mut data: Vec<Route>,
+        if_match: Option<u64>,
+        cancelled: &CancellationToken,
+    ) -> Result<u64, StateError> {
+        let start = Instant::now();
+        strip_external_ids(&mut data);
+        self.validate_routes(&data)?;
+        check_waves(
+            &HashMap::new(),
+            &RouterTable::new(),
+            &data.iter().collect::<Vec<_>>(),
+        )
+        .map_err(StateError::InvalidRoute)?;
+        let mut code_table_lk = self.lock_code_table_for_update()?;
+        let version = self.next_table_version(if_match)?;
         let new_router_table = {
-            let mut code_table_lk = self.code_table.try_lock().map_err(|_| StateError::Busy)?;
+            let old_router_table = self.router_table.read().await;
+            if cancelled.is_cancelled() {
+                tracing::info!("put cancelled by the client before applying, nothing written");
+                return Err(StateError::Cancelled);
+            }
             // at most one block_in_place call
             tokio::task::block_in_place(|| {
-                let mut tmp = HashMap::with_capacity(data.len());
-                for route in data {
-                    let code = Self::get_code(&mut code_table_lk, route.uid).clone();
-                    tmp.insert(code, route.url);
+                let total = data.len();
+                let codes_before = code_table_lk.len();
+                let mut last_progress = Instant::now();
+                let mut tmp = RouterTable::new();
+                let mut urls = UrlInterner::default();
+                let mut used_codes = None;
+                for (applied, route) in data.into_iter().enumerate() {
+                    if applied % PROGRESS_CHECK_INTERVAL == 0
+                        && last_progress.elapsed() >= PROGRESS_LOG_INTERVAL
+                    {
+                        tracing::info!(
+                            "put applying: {applied}/{total} routes, {} new codes",
+                            code_table_lk.len() - codes_before
+                        );
+                        last_progress = Instant::now();
+                    }
+                    let (uid, mut entry) = route.into_entry(&mut urls);
+                    let code = self
+                        .get_code(&mut code_table_lk, &mut used_codes, uid)?
+                        .clone();
+                    entry.precompute_redirect(&code);
+                    if let Some(old) = old_router_table.get(&code) {
+                        entry.hit_count = old.hit_count.clone();
+                        entry.deactivated = old.deactivated;
+                    }
+                    tmp.insert(code, entry);
                 }
                 // write tables
-                write_code_table(&code_table_lk, &self.router_table_store)
-                    .map_err(StateError::StoreError)?;
-                write_router_table(&tmp, &self.router_table_store)
+                self.write_tables(&code_table_lk, true, &tmp, version)
                     .map_err(StateError::StoreError)?;
+                self.set_table_sizes(tmp.len(), code_table_lk.len());
                 Ok::<_, StateError>(tmp)
             })?
         };
+        if cancelled.is_cancelled() {
+            tracing::info!("put cancelled by the client while applying, completed anyway");
+        }
+        self.round_robin_counters
+            .retain(|code, _| new_router_table.contains_key(code));
         *self.router_table.write().await = new_router_table;
-        Ok(())
+        self.table_version.store(version, Ordering::SeqCst);
+        drop(code_table_lk);
+        histogram!(PUT_APPLY_DURATION_SECONDS).record(start.elapsed().as_secs_f64());
+        Ok(version)
     }
 
-    /// partially update routing table
+    /// partially update routing table, existing routes are handled according to `conflict`.
+    /// Overwritten routes keep their hit counts and deactivation.
+    /// Nothing is written if every route is already stored as is.
+    /// Returns the table version after the update, too.
     ///
-    /// returns `Err(Busy)` if cannot acquire a lock of code_table.
-    pub async fn patch_routing_table(&self, data: Vec<Route>) -> Result<(), StateError> {
+    /// The snapshot is written after `snapshot_debounce`, coalescing bursts
+    /// of PATCHes, unless `durable`.
+    ///
+    /// returns `Err(Busy)` if cannot acquire a lock of code_table,
+    /// `Err(VersionMismatch)` if `if_match` is not the live table version,
+    /// `Err(Conflict)` if `conflict` is `Error` and any route already exists.
+    #[tracing::instrument(skip(self, data), fields(route_count = data.len()))]
+    pub async fn patch_routing_table(
+        &self,
+        mut data: Vec<Route>,
+        conflict: ConflictResolution,
+        mode: PatchMode,
+        durable: bool,
+        if_match: Option<u64>,
+    ) -> Result<(PatchSummary, u64), StateError> {
+        strip_external_ids(&mut data);
+        self.validate_routes(&data)?;
+        let mut summary = PatchSummary {
+            mode,
+            ..Default::default()
+        };
+        let mut code_table_lk = self.lock_code_table_for_update()?;
+        let version = self.next_table_version(if_match)?;
+        if mode == PatchMode::UpdateOnly {
+            let unknown: Vec<Id> = data
+                .iter()
+                .filter(|route| !code_table_lk.contains_key(&route.uid))
+                .map(|route| route.uid.clone())
+                .collect();
+            if !unknown.is_empty() {
+                return Err(StateError::UnknownIds(unknown));
+            }
+        }
         let new_router_table = {
-            let mut code_table_lk = self.code_table.try_lock().map_err(|_| StateError::Busy)?;
             let mut tmp = self.router_table.read().await.clone();
+            let exists = |uid: &Id| code_table_lk.get(uid).is_some_and(|c| tmp.contains_key(c));
+            if conflict == ConflictResolution::Error {
+                summary.errors = data
+                    .iter()
+                    .filter(|route| exists(&route.uid))
+                    .map(|route| format!("route of {} already exists", route.uid.0))
+                    .collect();
+                if !summary.errors.is_empty() {
+                    return Err(StateError::Conflict(summary));
+                }
+            }
+            let applied: Vec<&Route> = data
+                .iter()
+                .filter(|route| conflict != ConflictResolution::Skip || !exists(&route.uid))
+                .collect();
+            check_waves(&code_table_lk, &tmp, &applied).map_err(StateError::InvalidRoute)?;
             // at most one block_in_place call
             tokio::task::block_in_place(|| {
+                // urls are shared within the patch, the table is not scanned for them
+                let mut urls = UrlInterner::default();
+                let mut used_codes = None;
+                let mut changed = false;
                 for route in data {
-                    let code = Self::get_code(&mut code_table_lk, route.uid).clone();
-                    tmp.insert(code, route.url);
+                    let (uid, mut entry) = route.into_entry(&mut urls);
+                    let code = self
+                        .get_code(&mut code_table_lk, &mut used_codes, uid.clone())?
+                        .clone();
+                    entry.precompute_redirect(&code);
+                    if let Some(old) = tmp.get(&code) {
+                        if conflict == ConflictResolution::Skip {
+                            summary.skipped.push(uid);
+                            continue;
+                        }
+                        entry.hit_count = old.hit_count.clone();
+                        entry.deactivated = old.deactivated;
+                        changed |= !old.same_route(&entry);
+                        summary.overwritten.push(uid);
+                    } else {
+                        changed = true;
+                    }
+                    tmp.insert(code, entry);
+                    summary.updated += 1;
+                }
+                // new ids always add routes, so the code table is unchanged too
+                if !changed {
+                    tracing::trace!("routing table unchanged, skipping snapshot");
+                    return Ok(None);
                 }
                 // write tables
-                write_code_table(&code_table_lk, &self.router_table_store)
-                    .map_err(StateError::StoreError)?;
-                write_router_table(&tmp, &self.router_table_store)
-                    .map_err(StateError::StoreError)?;
-                Ok::<_, StateError>(tmp)
+                if !self.defers_snapshot(durable) {
+                    self.write_tables(&code_table_lk, true, &tmp, version)
+                        .map_err(StateError::StoreError)?;
+                }
+                self.set_table_sizes(tmp.len(), code_table_lk.len());
+                Ok::<_, StateError>(Some(tmp))
             })?
         };
-        *self.router_table.write().await = new_router_table;
+        let version = match new_router_table {
+            Some(new_router_table) => {
+                *self.router_table.write().await = new_router_table;
+                self.table_version.store(version, Ordering::SeqCst);
+                if self.defers_snapshot(durable) {
+                    self.schedule_snapshot();
+                }
+                version
+            }
+            None => self.table_version(),
+        };
+        drop(code_table_lk);
+        Ok((summary, version))
+    }
+
+    /// apply a JSON Merge Patch (RFC 7396) of the table seen as ids to urls:
+    /// a url adds the route of a new id, or replaces the url of an existing
+    /// route keeping its other options, `null` removes the route.
+    ///
+    /// Removed ids keep their codes, as with `bulk_remove_routes`, and
+    /// `null` for ids without route is ignored. The snapshot is debounced
+    /// as with `patch_routing_table`.
+    ///
+    /// returns `Err(Busy)` if cannot acquire a lock of code_table.
+    #[tracing::instrument(skip(self, patch), fields(route_count = patch.len()))]
+    pub async fn merge_patch_routing_table(
+        &self,
+        patch: HashMap<Id, Option<Url>>,
+        durable: bool,
+        if_match: Option<u64>,
+    ) -> Result<(MergePatchSummary, u64), StateError> {
+        let mut upserts = Vec::new();
+        let mut removals = Vec::new();
+        for (uid, url) in patch {
+            match url {
+                Some(url) => upserts.push(Route::new(uid, url)),
+                None => removals.push(uid),
+            }
+        }
+        strip_external_ids(&mut upserts);
+        self.validate_routes(&upserts)?;
+        let mut summary = MergePatchSummary::default();
+        let mut code_table_lk = self.lock_code_table_for_update()?;
+        let version = self.next_table_version(if_match)?;
+        let mut tmp = self.router_table.read().await.clone();
+        let mut urls = UrlInterner::default();
+        let mut used_codes = None;
+        let mut changed = false;
+        for route in upserts {
+            let (uid, mut entry) = route.into_entry(&mut urls);
+            let code = self
+                .get_code(&mut code_table_lk, &mut used_codes, uid)?
+                .clone();
+            match tmp.get_mut(&code) {
+                Some(old) if old.url == entry.url => {}
+                Some(old) => {
+                    old.url = entry.url;
+                    old.precompute_redirect(&code);
+                    changed = true;
+                }
+                None => {
+                    entry.precompute_redirect(&code);
+                    tmp.insert(code, entry);
+                    changed = true;
+                }
+            }
+            summary.updated += 1;
+        }
+        for uid in removals {
+            if code_table_lk
+                .get(&uid)
+                .and_then(|code| tmp.remove(code))
+                .is_some()
+            {
+                summary.removed += 1;
+                changed = true;
+            }
+        }
+        // new ids always add routes, so the code table is unchanged too
+        if !changed {
+            tracing::trace!("routing table unchanged, skipping snapshot");
+            return Ok((summary, self.table_version()));
+        }
+        if self.defers_snapshot(durable) {
+            self.schedule_snapshot();
+        } else {
+            tokio::task::block_in_place(|| self.write_tables(&code_table_lk, true, &tmp, version))
+                .map_err(StateError::StoreError)?;
+        }
+        self.set_table_sizes(tmp.len(), code_table_lk.len());
+        self.round_robin_counters
+            .retain(|code, _| tmp.contains_key(code));
+        *self.router_table.write().await = tmp;
+        self.table_version.store(version, Ordering::SeqCst);
+        drop(code_table_lk);
+        Ok((summary, version))
+    }
+
+    /// whether a PATCH leaves its snapshot to `run_snapshot_writer`.
+    fn defers_snapshot(&self, durable: bool) -> bool {
+        !durable && !self.snapshot_debounce.is_zero()
+    }
+
+    /// (re)start the debounce of the pending snapshot, up to `snapshot_max_delay`
+    /// after its first PATCH.
+    fn schedule_snapshot(&self) {
+        let now = Instant::now();
+        let mut due = self.pending_snapshot.due.lock().expect("poisoned");
+        let since = due.map_or(now, |due| due.since);
+        *due = Some(Due {
+            since,
+            at: (now + self.snapshot_debounce).min(since + self.snapshot_max_delay),
+        });
+        drop(due);
+        self.pending_snapshot.changed.notify_one();
+    }
+
+    /// whether PATCHes wait for `run_snapshot_writer`.
+    fn snapshot_pending(&self) -> bool {
+        self.pending_snapshot
+            .due
+            .lock()
+            .expect("poisoned")
+            .is_some()
+    }
+
+    /// write the tables of an update that is not debounced, which writes the
+    /// pending snapshot too: the code table is written if `code_table_changed`
+    /// or if a PATCH minted codes that are not stored yet, since the stored
+    /// routes must not refer to codes missing in the stored code table.
+    ///
+    /// This is a blocking function, call it in `block_in_place`.
+    fn write_tables(
+        &self,
+        code_table: &HashMap<Id, Code>,
+        code_table_changed: bool,
+        router_table: &RouterTable,
+        version: u64,
+    ) -> std::io::Result<()> {
+        let pending = self.pending_snapshot.due.lock().expect("poisoned").take();
+        let written = if code_table_changed || pending.is_some() {
+            self.storage.write_code_table(code_table)
+        } else {
+            Ok(())
+        }
+        .and_then(|()| self.storage.write_router_table(router_table, version));
+        if written.is_err() && pending.is_some() {
+            self.schedule_snapshot();
+        }
+        written
+    }
+
+    /// write snapshots of PATCHed tables once they were left alone for
+    /// `snapshot_debounce` (never returns).
+    pub async fn run_snapshot_writer(self) {
+        loop {
+            let due = self
+                .pending_snapshot
+                .due
+                .lock()
+                .expect("poisoned")
+                .map(|due| due.at);
+            match due {
+                None => self.pending_snapshot.changed.notified().await,
+                Some(due) if due > Instant::now() => {
+                    // woken early by another PATCH to look at its new due time
+                    tokio::select! {
+                        _ = tokio::time::sleep_until(due.into()) => {}
+                        _ = self.pending_snapshot.changed.notified() => {}
+                    }
+                }
+                Some(_) => {
+                    if let Err(e) = self.flush_snapshot().await {
+                        tracing::error!("failed to write snapshot: {:?}", e);
+                    }
+                }
+            }
+        }
+    }
+
+    /// write the pending snapshot now, if any, e.g. at shutdown.
+    /// Retried by `run_snapshot_writer` if it fails.
+    pub async fn flush_snapshot(&self) -> Result<(), StateError> {
+        let code_table_lk = self.code_table.lock().await;
+        let router_table = self.router_table.read().await.clone();
+        let version = self.table_version();
+        // taken after the last await, so that aborting the writer cannot lose it
+        let pending = self.pending_snapshot.due.lock().expect("poisoned").take();
+        if pending.is_none() {
+            return Ok(());
+        }
+        tokio::task::block_in_place(|| {
+            self.storage.write_code_table(&code_table_lk)?;
+            self.storage.write_router_table(&router_table, version)
+        })
+        .inspect_err(|_| self.schedule_snapshot())
+        .map_err(StateError::StoreError)?;
+        tracing::debug!("snapshot written, version {version}");
+        Ok(())
+    }
+
+    /// replace the admin token, saved in `storage_root` so that it outlives
+    /// a restart. Requests with the old token are rejected from then on.
+    ///
+    /// returns `Err(InvalidToken)` if the new token is too weak.
+    pub fn rotate_admin_token(&self, new_token: String) -> Result<(), StateError> {
+        validate_admin_token(&new_token).map_err(StateError::InvalidToken)?;
+        let _rotation = self.admin_token_rotation.lock().expect("poisoned");
+        // written before locking the token, auth checks do not wait for the fsync
+        tokio::task::block_in_place(|| write_admin_token(&new_token, &self.router_table_store))
+            .map_err(StateError::StoreError)?;
+        *self.admin_token.write().expect("poisoned") = new_token;
         Ok(())
     }
 
-    /// get all links
+    /// suspend or resume the routes of `ids`.
+    /// Nothing is written if every route already has the requested state.
+    ///
+    /// returns `Err(Busy)` if cannot acquire a lock of code_table.
+    pub async fn bulk_set_deactivated(
+        &self,
+        ids: Vec<Id>,
+        deactivated: bool,
+    ) -> Result<BulkResult, StateError> {
+        let mut result = BulkResult::default();
+        let code_table_lk = self.lock_code_table_for_update()?;
+        let version = self.next_table_version(None)?;
+        let mut tmp = self.router_table.read().await.clone();
+        let mut changed = false;
+        for uid in ids {
+            match code_table_lk.get(&uid).and_then(|code| tmp.get_mut(code)) {
+                Some(entry) => {
+                    changed |= entry.deactivated != deactivated;
+                    entry.deactivated = deactivated;
+                    result.succeeded.push(uid);
+                }
+                None => result.not_found.push(uid),
+            }
+        }
+        if changed {
+            tokio::task::block_in_place(|| self.write_tables(&code_table_lk, false, &tmp, version))
+                .map_err(StateError::StoreError)?;
+            *self.router_table.write().await = tmp;
+            self.table_version.store(version, Ordering::SeqCst);
+        }
+        drop(code_table_lk);
+        Ok(result)
+    }
+
+    /// remove the routes of `ids` in one update. The ids keep their codes,
+    /// so routes added for them again have the same links.
+    ///
+    /// returns `Err(Busy)` if cannot acquire a lock of code_table.
+    pub async fn bulk_remove_routes(&self, ids: Vec<Id>) -> Result<BulkRemoveResult, StateError> {
+        let mut result = BulkRemoveResult::default();
+        let code_table_lk = self.lock_code_table_for_update()?;
+        let version = self.next_table_version(None)?;
+        let mut tmp = self.router_table.read().await.clone();
+        for uid in ids {
+            match code_table_lk.get(&uid).and_then(|code| tmp.remove(code)) {
+                Some(_) => result.removed.push(uid),
+                None => result.not_found.push(uid),
+            }
+        }
+        if !result.removed.is_empty() {
+            tokio::task::block_in_place(|| self.write_tables(&code_table_lk, false, &tmp, version))
+                .map_err(StateError::StoreError)?;
+            self.set_table_sizes(tmp.len(), code_table_lk.len());
+            self.round_robin_counters
+                .retain(|code, _| tmp.contains_key(code));
+            *self.router_table.write().await = tmp;
+            self.table_version.store(version, Ordering::SeqCst);
+        }
+        drop(code_table_lk);
+        Ok(result)
+    }
+
+    /// move the codes of `old_id`s to `new_id`s, so the links stay valid
+    /// for the new ids. The router table is keyed by codes and is unchanged.
+    ///
+    /// The changes apply in order and all or none, so chains like `a -> b`,
+    /// `b -> c` work. Each change is logged to `AUDIT_LOG_TARGET`.
+    ///
+    /// Returns the table version after the update.
+    ///
+    /// returns `Err(Busy)` if cannot acquire a lock of code_table,
+    /// `Err(VersionMismatch)` if `if_match` is not the live table version,
+    /// `Err(UnknownOldId)` or `Err(IdTaken)` for the first change that fails.
+    pub async fn rekey(
+        &self,
+        rekeys: Vec<Rekey>,
+        if_match: Option<u64>,
+    ) -> Result<u64, StateError> {
+        let mut code_table_lk = self.lock_code_table_for_update()?;
+        let version = self.next_table_version(if_match)?;
+        let mut tmp = code_table_lk.clone();
+        for Rekey { old_id, new_id } in &rekeys {
+            let code = tmp
+                .remove(old_id)
+                .ok_or_else(|| StateError::UnknownOldId(old_id.clone()))?;
+            if tmp.contains_key(new_id) {
+                return Err(StateError::IdTaken(new_id.clone()));
+            }
+            tmp.insert(new_id.clone(), code);
+        }
+        let router_table_lk = self.router_table.read().await;
+        tokio::task::block_in_place(|| self.write_tables(&tmp, true, &router_table_lk, version))
+            .map_err(StateError::StoreError)?;
+        drop(router_table_lk);
+        *code_table_lk = tmp;
+        self.table_version.store(version, Ordering::SeqCst);
+        drop(code_table_lk);
+        for Rekey { old_id, new_id } in &rekeys {
+            tracing::info!(target: AUDIT_LOG_TARGET, "rekeyed id {old_id} to {new_id}");
+        }
+        Ok(version)
+    }
+
+    /// ids that keep a code but have no route, e.g. after their routes were removed.
     ///
     /// returns `Err(Busy)` if cannot acquire a lock of code_table.
-    pub async fn get_links(&self) -> Result<Response, StateError> {
-        let links = {
+    pub async fn codes_without_routes(&self) -> Result<OrphanedCodes, StateError> {
+        let code_table_lk = self.code_table.try_lock().map_err(|_| StateError::Busy)?;
+        let router_table_lk = self.router_table.read().await;
+        Ok(OrphanedCodes::new(ids_without_route(
+            &code_table_lk,
+            &router_table_lk,
+        )))
+    }
+
+    /// forget the codes of `codes_without_routes`, e.g. before a study ends.
+    /// Their old links stay invalid, and the ids get new codes if they are
+    /// routed again. The purge is logged to `AUDIT_LOG_TARGET`.
+    ///
+    /// Returns the table version after the purge, too.
+    ///
+    /// returns `Err(Busy)` if cannot acquire a lock of code_table.
+    pub async fn purge_orphaned_codes(&self) -> Result<(OrphanedCodes, u64), StateError> {
+        let mut code_table_lk = self.lock_code_table_for_update()?;
+        let router_table_lk = self.router_table.read().await;
+        let orphaned = ids_without_route(&code_table_lk, &router_table_lk);
+        if orphaned.is_empty() {
+            return Ok((OrphanedCodes::new(orphaned), self.table_version()));
+        }
+        let version = self.next_table_version(None)?;
+        let mut tmp = code_table_lk.clone();
+        for id in &orphaned {
+            tmp.remove(id);
+        }
+        tokio::task::block_in_place(|| self.write_tables(&tmp, true, &router_table_lk, version))
+            .map_err(StateError::StoreError)?;
+        drop(router_table_lk);
+        self.set_table_sizes(
+            self.table_sizes.router_table.load(Ordering::Relaxed),
+            tmp.len(),
+        );
+        *code_table_lk = tmp;
+        self.table_version.store(version, Ordering::SeqCst);
+        drop(code_table_lk);
+        tracing::info!(
+            target: AUDIT_LOG_TARGET,
+            "purged the codes of {} ids without route: {}",
+            orphaned.len(),
+            join_ids(&orphaned)
+        );
+        Ok((OrphanedCodes::new(orphaned), version))
+    }
+
+    /// get all links with the description and notes of their routes,
+    /// as a json object keyed by ids, or as json lines.
+    ///
+    /// The tables are copied under the locks, and the response is serialized
+    /// while it is streamed, so slow downloads do not hold the locks.
+    ///
+    /// returns `Err(Busy)` if cannot acquire a lock of code_table.
+    #[tracing::instrument(skip(self))]
+    pub async fn get_links(&self, params: LinksParams) -> Result<Response, StateError> {
+        let (rows, version) = {
             let code_table_lk = self.code_table.try_lock().map_err(|_| StateError::Busy)?;
             let router_table_lk = self.router_table.read().await;
-            let mut links: HashMap<Uid, Url> = HashMap::with_capacity(router_table_lk.len());
+            let mut rows = Vec::with_capacity(router_table_lk.len());
             for (id, code) in code_table_lk.iter() {
-                if router_table_lk.contains_key(code) {
-                    let mut url = self.router_url.clone();
-                    url.set_path(API);
-                    url.query_pairs_mut().append_pair(CODE, &code.0).finish();
-                    links.insert(id.clone(), url);
+                if let Some(entry) = router_table_lk.get(code) {
+                    rows.push(LinkRow {
+                        id: id.clone(),
+                        code: code.clone(),
+                        description: entry.description.clone(),
+                        notes: entry.notes.clone(),
+                    });
                 }
             }
-            links
+            (rows, self.table_version())
         };
-        Ok(Json(links).into_response())
+        let content_type = match params.format {
+            LinksFormat::Json => "application/json",
+            LinksFormat::Ndjson => "application/x-ndjson",
+        };
+        let state = self.clone();
+        let mut rows = rows.into_iter().peekable();
+        let mut first = true;
+        let mut done = false;
+        let chunks = std::iter::from_fn(move || {
+            if done {
+                return None;
+            }
+            let mut buf = Vec::new();
+            if first && params.format == LinksFormat::Json {
+                buf.push(b'{');
+            }
+            for row in rows.by_ref().take(LINKS_CHUNK_SIZE) {
+                state.write_link(&mut buf, &row, &params, first);
+                first = false;
+            }
+            if rows.peek().is_none() {
+                done = true;
+                if params.format == LinksFormat::Json {
+                    buf.push(b'}');
+                }
+            }
+            Some(Ok::<_, Infallible>(Bytes::from(buf)))
+        });
+        Ok((
+            [
+                (CONTENT_TYPE, HeaderValue::from_static(content_type)),
+                (X_TABLE_VERSION, HeaderValue::from(version)),
+            ],
+            Body::from_stream(futures::stream::iter(chunks)),
+        )
+            .into_response())
     }
 
-    /// get all uid-codes mapping
+    /// append one `get_links` entry to `buf`.
+    fn write_link(&self, buf: &mut Vec<u8>, row: &LinkRow, params: &LinksParams, first: bool) {
+        const SERIALIZABLE: &str = "links are serializable";
+        let link = self.link(&row.code);
+        match params.format {
+            LinksFormat::Json => {
+                if !first {
+                    buf.push(b',');
+                }
+                serde_json::to_writer(&mut *buf, &row.id).expect(SERIALIZABLE);
+                buf.push(b':');
+                let item = LinkItem {
+                    link,
+                    description: row.description.as_deref(),
+                    notes: row.notes.as_deref(),
+                };
+                serde_json::to_writer(&mut *buf, &item).expect(SERIALIZABLE);
+            }
+            LinksFormat::Ndjson => {
+                let line = LinkLine {
+                    id: &row.id,
+                    url: link,
+                    description: row.description.as_deref(),
+                    notes: row.notes.as_deref(),
+                };
+                serde_json::to_writer(&mut *buf, &line).expect(SERIALIZABLE);
+                buf.push(b'\n');
+            }
+        }
+    }
+
+    /// all links, sorted by id.
+    ///
+    /// returns `Err(Busy)` if cannot acquire a lock of code_table.
+    pub async fn links(&self) -> Result<Vec<(Id, Url)>, StateError> {
+        let code_table_lk = self.code_table.try_lock().map_err(|_| StateError::Busy)?;
+        let router_table_lk = self.router_table.read().await;
+        let mut links: Vec<(Id, Url)> = code_table_lk
+            .iter()
+            .filter(|(_, code)| router_table_lk.contains_key(code))
+            .map(|(id, code)| (id.clone(), self.link(code)))
+            .collect();
+        links.sort_unstable_by(|(a, _), (b, _)| a.0.cmp(&b.0));
+        Ok(links)
+    }
+
+    /// check the tables as `init` does, see `validate_tables`.
+    ///
+    /// The tables are copied under the locks and checked after.
+    ///
+    /// returns `Err(Busy)` if cannot acquire a lock of code_table.
+    pub async fn validate(&self) -> Result<ValidationReport, StateError> {
+        let (code_table, router_table) = {
+            let code_table_lk = self.code_table.try_lock().map_err(|_| StateError::Busy)?;
+            let router_table_lk = self.router_table.read().await;
+            (code_table_lk.clone(), router_table_lk.clone())
+        };
+        let policy = self.redirect_policy();
+        Ok(tokio::task::block_in_place(|| {
+            validate_tables(&code_table, &router_table, &policy)
+        }))
+    }
+
+    /// the links of `ids` that have routes, e.g. of an upload right after it.
+    ///
+    /// Waits for running updates instead of failing with `Busy`, since it
+    /// answers an update that succeeded.
+    pub async fn links_of(&self, ids: Vec<Id>) -> HashMap<Id, Url> {
+        let code_table_lk = self.code_table.lock().await;
+        let router_table_lk = self.router_table.read().await;
+        ids.into_iter()
+            .filter_map(|id| {
+                let code = code_table_lk.get(&id)?;
+                router_table_lk
+                    .contains_key(code)
+                    .then(|| (id, self.link(code)))
+            })
+            .collect()
+    }
+
+    /// request counters, and connection counters of the server loops.
+    pub fn runtime_info(&self) -> RuntimeInfo {
+        let last_request_at = self.last_request_at.load(Ordering::Relaxed);
+        RuntimeInfo {
+            version: env!("CARGO_PKG_VERSION"),
+            draining: self.draining.load(Ordering::Relaxed),
+            started_at_iso: self.started_at_utc.to_rfc3339(),
+            uptime_secs: self.started_at.elapsed().as_secs(),
+            requests_total: self.requests_total.load(Ordering::Relaxed),
+            last_request_at_iso: (last_request_at > 0)
+                .then(|| DateTime::from_timestamp(last_request_at as i64, 0))
+                .flatten()
+                .map(|time| time.to_rfc3339()),
+            redirect_errors_total: self.redirect_errors_total.load(Ordering::Relaxed),
+            connections_active: SERVER_METRICS.connections_active.load(Ordering::Relaxed),
+            connections_total: SERVER_METRICS.connections_total.load(Ordering::Relaxed),
+            tls_handshake_failures: SERVER_METRICS
+                .tls_handshake_failures
+                .load(Ordering::Relaxed),
+            cert_fingerprint: cert_fingerprint(CertRole::Server),
+            admin_cert_fingerprint: cert_fingerprint(CertRole::Admin),
+        }
+    }
+
+    /// table sizes, snapshots and counters for simple monitors.
+    /// Heap bytes and snapshots are estimated at most every `SAMPLED_SIZES_TTL`.
+    ///
+    /// returns `Err(Busy)` if cannot acquire a lock of code_table to
+    /// estimate its size, unless an estimate is cached.
+    pub async fn json_metrics(&self) -> Result<JsonMetrics, StateError> {
+        let cached = *self.sampled_sizes.lock().expect("poisoned");
+        let sampled = match cached {
+            Some((at, sampled)) if at.elapsed() < SAMPLED_SIZES_TTL => sampled,
+            _ => match self.sample_sizes().await {
+                Ok(sampled) => {
+                    *self.sampled_sizes.lock().expect("poisoned") = Some((Instant::now(), sampled));
+                    sampled
+                }
+                // a stale estimate is better than none while tables are updated
+                Err(StateError::Busy) => cached.ok_or(StateError::Busy)?.1,
+                Err(e) => return Err(e),
+            },
+        };
+        Ok(JsonMetrics {
+            router_table: TableMetrics {
+                entries: self.table_sizes.router_table.load(Ordering::Relaxed),
+                approx_heap_bytes: sampled.router_table_heap_bytes,
+            },
+            code_table: TableMetrics {
+                entries: self.table_sizes.code_table.load(Ordering::Relaxed),
+                approx_heap_bytes: sampled.code_table_heap_bytes,
+            },
+            snapshot_count: sampled.snapshot_count,
+            snapshot_size_bytes: sampled.snapshot_size_bytes,
+            redirects: self.redirect_outcomes.totals(),
+            too_many_requests_total: self.too_many_requests_total.load(Ordering::Relaxed),
+        })
+    }
+
+    async fn sample_sizes(&self) -> Result<SampledSizes, StateError> {
+        let code_table_heap_bytes = {
+            let code_table_lk = self.code_table.try_lock().map_err(|_| StateError::Busy)?;
+            approx_heap_bytes(code_table_lk.iter(), code_table_lk.len(), |(id, code)| {
+                std::mem::size_of::<(Id, Code)>() + id.0.capacity() + code.heap_size()
+            })
+        };
+        let router_table_heap_bytes = {
+            let router_table_lk = self.router_table.read().await;
+            approx_heap_bytes(
+                router_table_lk.iter(),
+                router_table_lk.len(),
+                |(code, entry)| {
+                    std::mem::size_of::<(Code, RouteEntry)>() + code.heap_size() + entry.heap_size()
+                },
+            )
+        };
+        let stats = tokio::task::block_in_place(|| {
+            storage_statistics(&self.router_table_store, self.table_version())
+        })
+        .map_err(StateError::StoreError)?;
+        Ok(SampledSizes {
+            router_table_heap_bytes,
+            code_table_heap_bytes,
+            snapshot_count: stats.snapshot_count,
+            snapshot_size_bytes: stats.snapshot_size_bytes,
+        })
+    }
+
+    /// update the entry counts of the tables, and their gauges.
+    fn set_table_sizes(&self, router_table: usize, code_table: usize) {
+        self.table_sizes
+            .router_table
+            .store(router_table, Ordering::Relaxed);
+        self.table_sizes
+            .code_table
+            .store(code_table, Ordering::Relaxed);
+        set_table_sizes(router_table, code_table);
+    }
+
+    /// the url of the route of `id` in every stored snapshot, newest first.
+    ///
+    /// returns `Err(Busy)` if cannot acquire a lock of code_table,
+    /// `Err(UnknownId)` if `id` never had a code.
+    pub async fn route_history(&self, id: &Id) -> Result<Vec<RouteHistoryEntry>, StateError> {
+        let code = {
+            let code_table_lk = self.code_table.try_lock().map_err(|_| StateError::Busy)?;
+            code_table_lk
+                .get(id)
+                .cloned()
+                .ok_or(StateError::UnknownId)?
+        };
+        let snapshots =
+            tokio::task::block_in_place(|| self.storage.load_all_router_table_snapshots())
+                .map_err(StateError::StoreError)?;
+        Ok(snapshots
+            .into_iter()
+            .filter_map(|(time, mut urls)| {
+                urls.remove(&code).map(|url| RouteHistoryEntry {
+                    ts: time.to_rfc3339(),
+                    url,
+                })
+            })
+            .collect())
+    }
+
+    /// the links of `id` keyed by survey, with route metadata and status:
+    /// the route of `id` itself, and the routes with `id` as `participant`,
+    /// each under its `wave` or `DEFAULT_SURVEY`.
+    ///
+    /// The map is empty if the id has a code but no routes. Other routes
+    /// are found by a scan of the router table.
+    ///
+    /// returns `Err(Busy)` if cannot acquire a lock of code_table,
+    /// `Err(UnknownId)` if `id` never had a code and is no participant.
+    pub async fn participant_links(
+        &self,
+        id: &Id,
+    ) -> Result<BTreeMap<String, ParticipantLink>, StateError> {
+        let own_code = {
+            let code_table_lk = self.code_table.try_lock().map_err(|_| StateError::Busy)?;
+            code_table_lk.get(id).cloned()
+        };
+        let now = Utc::now();
+        let router_table_lk = self.router_table.read().await;
+        let links: BTreeMap<String, ParticipantLink> = router_table_lk
+            .iter()
+            .filter(|(code, entry)| {
+                own_code.as_ref() == Some(*code) || entry.participant.as_ref() == Some(id)
+            })
+            .map(|(code, entry)| {
+                let status = if entry.deactivated {
+                    LinkStatus::Disabled
+                } else if entry.is_expired(now) {
+                    LinkStatus::Expired
+                } else {
+                    LinkStatus::Active
+                };
+                let link = ParticipantLink {
+                    link: self.link(code),
+                    url: Url::clone(&entry.url),
+                    status,
+                    hit_count: entry.hit_count.get(),
+                    expires_at: entry.expires_at,
+                    description: entry.description.clone(),
+                    notes: entry.notes.clone(),
+                };
+                let wave = entry.wave.as_deref().unwrap_or(DEFAULT_SURVEY);
+                (wave.to_owned(), link)
+            })
+            .collect();
+        if own_code.is_none() && links.is_empty() {
+            return Err(StateError::UnknownId);
+        }
+        Ok(links)
+    }
+
+    /// the public link of a code.
+    fn link(&self, code: &Code) -> Url {
+        let mut url = self.router_url.clone();
+        url.query_pairs_mut().append_pair(CODE, &code.0).finish();
+        url
+    }
+
+    /// get hit counts of all routes
     ///
     /// returns `Err(Busy)` if cannot acquire a lock of code_table.
-    pub async fn get_codes(&self) -> Result<Response, StateError> {
-        let codes = {
+    pub async fn get_route_stats(&self) -> Result<HashMap<Id, u64>, StateError> {
+        let stats = {
             let code_table_lk = self.code_table.try_lock().map_err(|_| StateError::Busy)?;
-            code_table_lk.clone()
+            let router_table_lk = self.router_table.read().await;
+            let mut stats: HashMap<Id, u64> = HashMap::with_capacity(router_table_lk.len());
+            for (id, code) in code_table_lk.iter() {
+                if let Some(entry) = router_table_lk.get(code) {
+                    stats.insert(id.clone(), entry.hit_count.get());
+                }
+            }
+            stats
+        };
+        Ok(stats)
+    }
+
+    /// periodically flush hit counts to disk (never returns).
+    pub async fn run_hit_flusher(self, interval: std::time::Duration) {
+        let mut interval = tokio::time::interval(interval);
+        // the first tick completes immediately
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.flush_hits().await {
+                tracing::error!("failed to flush hit counts: {:?}", e);
+            }
+            if let Err(e) = self.flush_completions() {
+                tracing::error!("failed to flush completions: {:?}", e);
+            }
+        }
+    }
+
+    /// write completions to disk, unless none were recorded since the last flush.
+    pub fn flush_completions(&self) -> Result<(), StateError> {
+        if !self.completions_changed.swap(false, Ordering::Relaxed) {
+            return Ok(());
+        }
+        let completions: HashMap<Code, Completion> = self
+            .completions
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect();
+        tokio::task::block_in_place(|| write_completions(&completions, &self.router_table_store))
+            .inspect_err(|_| self.completions_changed.store(true, Ordering::Relaxed))
+            .map_err(StateError::StoreError)?;
+        tracing::debug!("completions flushed");
+        Ok(())
+    }
+
+    /// write current hit counts to disk, unless nothing changed since the last flush.
+    ///
+    /// The counts are copied out, updates are not held up by the write.
+    pub async fn flush_hits(&self) -> Result<(), StateError> {
+        let hits: HashMap<Code, u64> = self
+            .router_table
+            .read()
+            .await
+            .iter()
+            .map(|(code, entry)| (code.clone(), entry.hit_count.get()))
+            .collect();
+        let total = hits.values().sum();
+        if total == self.hits_flushed.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        tokio::task::block_in_place(|| write_hits_table(&hits, &self.router_table_store))
+            .map_err(StateError::StoreError)?;
+        self.hits_flushed.store(total, Ordering::Relaxed);
+        tracing::debug!("hit counts flushed");
+        Ok(())
+    }
+
+    /// find routes with any target url containing `url_contains`, or matching `url_regex`.
+    ///
+    /// returns `Err(Busy)` if cannot acquire a lock of code_table.
+    pub async fn search_routes(
+        &self,
+        params: SearchRoutesParams,
+    ) -> Result<Vec<RouteMatch>, StateError> {
+        let regex = match (params.url_contains, params.url_regex) {
+            (Some(substring), None) => Regex::new(&regex::escape(&substring)),
+            (None, Some(pattern)) => RegexBuilder::new(&pattern)
+                .size_limit(SEARCH_REGEX_SIZE_LIMIT)
+                .build(),
+            _ => {
+                return Err(StateError::InvalidQuery(
+                    "exactly one of url_contains and url_regex is required".to_owned(),
+                ))
+            }
+        }
+        .map_err(|e| StateError::InvalidQuery(format!("invalid url_regex: {e}")))?;
+        let code_table_lk = self.code_table.try_lock().map_err(|_| StateError::Busy)?;
+        let router_table_lk = self.router_table.read().await;
+        let mut matches = Vec::new();
+        for (id, code) in code_table_lk.iter() {
+            let Some(entry) = router_table_lk.get(code) else {
+                continue;
+            };
+            if entry.urls().any(|url| regex.is_match(url.as_str())) {
+                matches.push(RouteMatch {
+                    id: id.clone(),
+                    url: Url::clone(&entry.url),
+                });
+            }
+        }
+        Ok(matches)
+    }
+
+    /// ids starting with `id_prefix`, sorted, at most `limit` of them.
+    ///
+    /// This is a linear scan of the code table, a trie-based index
+    /// could be added if it grows beyond ~100k entries.
+    ///
+    /// returns `Err(Busy)` if cannot acquire a lock of code_table.
+    pub async fn search_codes(
+        &self,
+        params: SearchCodesParams,
+    ) -> Result<Vec<CodeMatch>, StateError> {
+        let code_table_lk = self.code_table.try_lock().map_err(|_| StateError::Busy)?;
+        let router_table_lk = self.router_table.read().await;
+        let mut ids: Vec<(&Id, &Code)> = code_table_lk
+            .iter()
+            .filter(|(id, _)| id.0.starts_with(&params.id_prefix))
+            .collect();
+        ids.sort_unstable_by(|(a, _), (b, _)| a.0.cmp(&b.0));
+        Ok(ids
+            .into_iter()
+            .take(params.limit)
+            .map(|(id, code)| CodeMatch {
+                id: id.clone(),
+                code: code.clone(),
+                redirect_url: router_table_lk
+                    .get(code)
+                    .map(|entry| Url::clone(&entry.url)),
+            })
+            .collect())
+    }
+
+    /// the routes by id, as uploaded, followed by routes whose code has no id.
+    ///
+    /// returns `Err(Busy)` if cannot acquire a lock of code_table.
+    pub async fn get_routing_table(&self) -> Result<(Vec<TableRoute>, u64), StateError> {
+        let code_table_lk = self.code_table.try_lock().map_err(|_| StateError::Busy)?;
+        let router_table_lk = self.router_table.read().await;
+        let version = self.table_version();
+        let mut routes: Vec<_> = code_table_lk
+            .iter()
+            .filter_map(|(id, code)| {
+                let entry = router_table_lk.get(code)?;
+                Some(TableRoute::new(Some(id.clone()), entry))
+            })
+            .collect();
+        routes.sort_unstable_by(|a, b| {
+            a.uid
+                .as_ref()
+                .map(|id| &id.0)
+                .cmp(&b.uid.as_ref().map(|id| &id.0))
+        });
+        let codes: HashSet<&Code> = code_table_lk.values().collect();
+        let mut orphans: Vec<_> = router_table_lk
+            .iter()
+            .filter(|(code, _)| !codes.contains(code))
+            .collect();
+        if !orphans.is_empty() {
+            tracing::warn!("{} routes have no id in the code table", orphans.len());
+        }
+        orphans.sort_unstable_by(|(a, _), (b, _)| a.0.cmp(&b.0));
+        routes.extend(
+            orphans
+                .into_iter()
+                .map(|(_, entry)| TableRoute::new(None, entry)),
+        );
+        Ok((routes, version))
+    }
+
+    /// get all uid-codes mapping
+    ///
+    /// returns `Err(Busy)` if cannot acquire a lock of code_table.
+    pub async fn get_codes(&self) -> Result<HashMap<Id, Code>, StateError> {
+        let code_table_lk = self.code_table.try_lock().map_err(|_| StateError::Busy)?;
+        Ok(code_table_lk.clone())
+    }
+
+    /// `validate_route`, and every target url against the redirect policy.
+    fn validate_routes(&self, data: &[Route]) -> Result<(), StateError> {
+        let policy = self.redirect_policy();
+        data.iter()
+            .try_for_each(|route| {
+                validate_route(route)?;
+                route
+                    .urls()
+                    .try_for_each(|url| policy.check_target(url))
+                    .map_err(|e| format!("target of {} rejected: {e}", route.uid.0))
+            })
+            .map_err(StateError::InvalidRoute)
+    }
+
+    /// the current redirect policy.
+    pub fn redirect_policy(&self) -> Arc<RedirectPolicy> {
+        self.redirect_policy.read().expect("poisoned").clone()
+    }
+
+    /// replace the denied targets and blocked referrers, e.g. after the
+    /// config changed. Stored routes are checked again when they redirect.
+    pub fn set_redirect_policy(&self, policy: RedirectPolicy) {
+        *self.redirect_policy.write().expect("poisoned") = Arc::new(policy);
+    }
+
+    /// the version of the live routing table, raised by every update
+    /// and persisted with the table.
+    pub fn table_version(&self) -> u64 {
+        self.table_version.load(Ordering::SeqCst)
+    }
+
+    /// the version an update will store, call with the code table locked.
+    ///
+    /// returns `Err(VersionMismatch)` if `if_match` is not the live table version.
+    fn next_table_version(&self, if_match: Option<u64>) -> Result<u64, StateError> {
+        let current = self.table_version();
+        match if_match {
+            Some(expected) if expected != current => {
+                Err(StateError::VersionMismatch { expected, current })
+            }
+            _ => Ok(current + 1),
+        }
+    }
+
+    /// lock the code table for an update, timing how long it is held.
+    fn lock_code_table_for_update(&self) -> Result<UpdateGuard<'_>, StateError> {
+        let guard = self.code_table.try_lock().map_err(|_| StateError::Busy)?;
+        self.update_clock.lock().expect("poisoned").since = Some(Instant::now());
+        Ok(UpdateGuard {
+            guard,
+            clock: &self.update_clock,
+        })
+    }
+
+    /// when to retry after `Busy`: the time the running update has left,
+    /// assuming it takes as long as the previous one, at least 1 s, plus up to 1 s jitter.
+    pub fn retry_after(&self) -> Duration {
+        let remaining = {
+            let clock = self.update_clock.lock().expect("poisoned");
+            let held = clock.since.map(|since| since.elapsed()).unwrap_or_default();
+            clock.last_hold.saturating_sub(held)
         };
-        Ok(Json(codes).into_response())
+        remaining.max(MIN_RETRY_AFTER)
+            + rand::thread_rng().gen_range(Duration::ZERO..RETRY_AFTER_JITTER)
+    }
+
+    /// find codes of several ids in loaded tables. The smallest id keeps
+    /// the code, the others get new codes if `repair_duplicate_codes`, which
+    /// are persisted and logged so that their links can be sent again.
+    /// The route of the shared code is copied to the new codes, since it
+    /// was the route of all these ids.
+    ///
+    /// returns `Err(DuplicateCode)` with the ids of the first such code otherwise.
+    fn resolve_duplicate_codes(
+        &self,
+        code_table: &mut HashMap<Id, Code>,
+        router_table: &mut RouterTable,
+        version: u64,
+    ) -> Result<(), StateError> {
+        let mut duplicates = duplicate_codes(code_table);
+        if duplicates.is_empty() {
+            return Ok(());
+        }
+        if !self.repair_duplicate_codes {
+            let (code, ids) = duplicates.swap_remove(0);
+            tracing::error!("code {code} is used by ids {}", join_ids(&ids));
+            return Err(StateError::DuplicateCode { code, ids });
+        }
+        let mut codes: HashSet<Code> = code_table.values().cloned().collect();
+        for (code, ids) in duplicates {
+            for id in ids.into_iter().skip(1) {
+                let new_code = loop {
+                    let new_code = self.gen_code()?;
+                    if codes.insert(new_code.clone()) {
+                        break new_code;
+                    }
+                };
+                tracing::warn!("re-keyed id {id} from duplicate code {code} to {new_code}");
+                if let Some(entry) = router_table.get(&code) {
+                    let mut entry = RouteEntry {
+                        hit_count: HitCount::default(),
+                        ..entry.clone()
+                    };
+                    entry.precompute_redirect(&new_code);
+                    router_table.insert(new_code.clone(), entry);
+                }
+                code_table.insert(id, new_code);
+            }
+        }
+        self.storage
+            .write_code_table(code_table)
+            .and_then(|()| self.storage.write_router_table(router_table, version))
+            .map_err(StateError::StoreError)
     }
 
-    /// lookup or gen code.
+    /// lookup or gen code. New codes are checked against the codes in use,
+    /// collected into `used_codes` on the first code generated.
+    ///
+    /// returns `Err(CodesExhausted)` as `gen_code`.
     #[inline]
-    fn get_code<'a>(code_table: &'a mut MutexGuard<HashMap<Uid, Code>>, id: Uid) -> &'a Code {
-        code_table.entry(id).or_insert_with(|| {
-            Code(
+    fn get_code<'a>(
+        &self,
+        code_table: &'a mut HashMap<Id, Code>,
+        used_codes: &mut Option<HashSet<Code>>,
+        id: Id,
+    ) -> Result<&'a Code, StateError> {
+        if !code_table.contains_key(&id) {
+            let used_codes =
+                used_codes.get_or_insert_with(|| code_table.values().cloned().collect());
+            let code = loop {
+                let code = self.gen_code()?;
+                if used_codes.insert(code.clone()) {
+                    break code;
+                }
+            };
+            code_table.insert(id.clone(), code);
+        }
+        Ok(&code_table[&id])
+    }
+
+    /// continue the sequential counter after the largest sequential code
+    /// stored, so that a restart does not hand out codes in use.
+    fn seed_code_counter(&self, code_table: &HashMap<Id, Code>) {
+        let CodeGenMode::Sequential(counter) = &self.code_gen_mode else {
+            return;
+        };
+        let last = code_table
+            .values()
+            .filter_map(|code| code.0.strip_prefix(self.code_prefix.as_str()))
+            .filter(|suffix| suffix.bytes().all(|b| b.is_ascii_digit()))
+            .filter_map(|suffix| suffix.parse::<u64>().ok())
+            .max()
+            .unwrap_or(0);
+        counter.fetch_max(last, Ordering::Relaxed);
+    }
+
+    /// generate a new code: `code_prefix` followed by random characters
+    /// (or a zero-padded counter), `code_length` in total.
+    ///
+    /// returns `Err(CodesExhausted)` if the counter outgrew `code_length`.
+    fn gen_code(&self) -> Result<Code, StateError> {
+        let mut code = CompactString::new(&self.code_prefix);
+        let suffix_length = self.code_length - self.code_prefix.len();
+        match &self.code_gen_mode {
+            CodeGenMode::Random => code.extend(
                 rand::thread_rng()
                     .sample_iter(Alphanumeric)
-                    .take(CODE_LENGTH)
-                    .map(char::from)
-                    .collect::<String>(),
-            )
-        })
+                    .take(suffix_length)
+                    .map(char::from),
+            ),
+            CodeGenMode::Sequential(counter) => {
+                let n = counter.fetch_add(1, Ordering::Relaxed) + 1;
+                let digits = n.to_string();
+                if digits.len() > suffix_length {
+                    tracing::error!("sequential codes of length {} exhausted", self.code_length);
+                    return Err(StateError::CodesExhausted);
+                }
+                code.push_str(&format!("{digits:0>suffix_length$}"));
+            }
+        }
+        Ok(Code(code))
     }
 }
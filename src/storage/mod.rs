@@ -0,0 +1,141 @@
+//! Where the router and code tables are stored, see `Config::storage_backend`.
+//!
+//! Hit counts are written to files in `storage_root` with either backend.
+//! All functions in this module are blocking functions!
+use crate::{
+    config::StorageBackend,
+    state::{Code, Id, RouterTable},
+    utility::{self, TimeStamp},
+};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+use url::Url;
+
+#[cfg(feature = "sled-storage")]
+mod sled;
+#[cfg(feature = "sled-storage")]
+pub use self::sled::SledStorage;
+
+/// directory of the sled database in `storage_root`.
+#[cfg(feature = "sled-storage")]
+const SLED_DIR: &str = "sled";
+
+#[derive(Clone)]
+pub enum Storage {
+    /// json snapshot files in `storage_root` (default).
+    File {
+        dir: PathBuf,
+        /// see `Config::write_ahead_log`.
+        wal: bool,
+    },
+    #[cfg(feature = "sled-storage")]
+    Sled {
+        /// `storage_root`, for the hits files.
+        dir: PathBuf,
+        db: SledStorage,
+    },
+}
+
+impl Storage {
+    /// open `backend` in `storage_root`, which must exist.
+    pub fn open(
+        backend: StorageBackend,
+        write_ahead_log: bool,
+        storage_root: &Path,
+    ) -> std::io::Result<Self> {
+        match backend {
+            StorageBackend::File => Ok(Storage::File {
+                dir: storage_root.to_owned(),
+                wal: write_ahead_log,
+            }),
+            #[cfg(feature = "sled-storage")]
+            StorageBackend::Sled => Ok(Storage::Sled {
+                dir: storage_root.to_owned(),
+                db: SledStorage::open(storage_root.join(SLED_DIR))?,
+            }),
+            #[cfg(not(feature = "sled-storage"))]
+            StorageBackend::Sled => Err(std::io::Error::other(
+                "storage_backend sled requires the sled-storage feature",
+            )),
+        }
+    }
+
+    /// write `router_table`, stored with its `version`.
+    pub fn write_router_table(
+        &self,
+        router_table: &RouterTable,
+        version: u64,
+    ) -> std::io::Result<()> {
+        match self {
+            Storage::File { dir, wal } => {
+                utility::write_router_table(router_table, version, dir, *wal)
+            }
+            #[cfg(feature = "sled-storage")]
+            Storage::Sled { db, .. } => db.write_router_table(router_table, version),
+        }
+    }
+
+    pub fn write_code_table(&self, code_table: &HashMap<Id, Code>) -> std::io::Result<()> {
+        match self {
+            Storage::File { dir, .. } => utility::write_code_table(code_table, dir),
+            #[cfg(feature = "sled-storage")]
+            Storage::Sled { db, .. } => db.write_code_table(code_table),
+        }
+    }
+
+    /// load the latest router table, with hit counts from the latest hits file merged.
+    pub fn load_latest_router_table(&self) -> std::io::Result<Option<(TimeStamp, RouterTable)>> {
+        match self {
+            Storage::File { dir, .. } => utility::load_latest_router_table(dir),
+            #[cfg(feature = "sled-storage")]
+            Storage::Sled { dir, db } => {
+                let latest = db.load_latest_router_table()?;
+                if let Some((_, router_table)) = &latest {
+                    utility::merge_latest_hits(router_table, dir)?;
+                }
+                Ok(latest)
+            }
+        }
+    }
+
+    /// the version stored with the latest router table, 0 if there is none.
+    pub fn load_table_version(&self) -> std::io::Result<u64> {
+        match self {
+            Storage::File { dir, .. } => utility::load_table_version(dir),
+            #[cfg(feature = "sled-storage")]
+            Storage::Sled { db, .. } => db.load_table_version(),
+        }
+    }
+
+    pub fn load_latest_code_table(&self) -> std::io::Result<Option<HashMap<Id, Code>>> {
+        match self {
+            Storage::File { dir, .. } => utility::load_latest_code_table(dir),
+            #[cfg(feature = "sled-storage")]
+            Storage::Sled { db, .. } => db.load_latest_code_table(),
+        }
+    }
+
+    /// the url of every route in every stored router table, newest first.
+    /// Sled keeps only the current table.
+    pub fn load_all_router_table_snapshots(
+        &self,
+    ) -> std::io::Result<Vec<(TimeStamp, HashMap<Code, Url>)>> {
+        match self {
+            Storage::File { dir, .. } => utility::load_all_router_table_snapshots(dir),
+            #[cfg(feature = "sled-storage")]
+            Storage::Sled { db, .. } => Ok(db
+                .load_latest_router_table()?
+                .map(|(time, router_table)| {
+                    let urls = router_table
+                        .iter()
+                        .map(|(code, entry)| (code.clone(), Url::clone(&entry.url)))
+                        .collect();
+                    (time, urls)
+                })
+                .into_iter()
+                .collect()),
+        }
+    }
+}
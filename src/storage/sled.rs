@@ -0,0 +1,161 @@
+//! Tables stored in a sled database, one tree per table.
+//!
+//! Keys and values are json, like in the snapshot files. A write updates
+//! only the keys that changed, in one transaction.
+//! All functions in this file are blocking functions!
+use crate::{
+    state::{Code, Id, RouteEntry, RouterTable},
+    utility::TimeStamp,
+};
+use ::sled::{transaction::TransactionError, Batch, Db, Transactional, Tree};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{collections::HashMap, path::Path};
+
+const ROUTER_TREE: &str = "router_table";
+const CODE_TREE: &str = "code_table";
+const META_TREE: &str = "meta";
+/// time of the last router table write, rfc3339.
+const ROUTER_TABLE_WRITTEN_AT: &str = "router_table_written_at";
+/// version of the router table, a json number.
+const ROUTER_TABLE_VERSION: &str = "router_table_version";
+
+#[derive(Clone)]
+pub struct SledStorage {
+    db: Db,
+    router_tree: Tree,
+    code_tree: Tree,
+    meta_tree: Tree,
+}
+
+impl SledStorage {
+    /// open or create the database at `path`.
+    pub fn open<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        let db = ::sled::open(path)?;
+        Ok(Self {
+            router_tree: db.open_tree(ROUTER_TREE)?,
+            code_tree: db.open_tree(CODE_TREE)?,
+            meta_tree: db.open_tree(META_TREE)?,
+            db,
+        })
+    }
+
+    pub fn write_router_table(
+        &self,
+        router_table: &RouterTable,
+        version: u64,
+    ) -> std::io::Result<()> {
+        let batch = diff(&self.router_tree, router_table.iter())?;
+        let written_at = chrono::Local::now().to_rfc3339();
+        let version = to_json(&version)?;
+        (&self.router_tree, &self.meta_tree)
+            .transaction(|(router_tree, meta_tree)| {
+                router_tree.apply_batch(&batch)?;
+                meta_tree.insert(ROUTER_TABLE_WRITTEN_AT, written_at.as_bytes())?;
+                meta_tree.insert(ROUTER_TABLE_VERSION, version.as_slice())?;
+                Ok(())
+            })
+            .map_err(transaction_error)?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    pub fn write_code_table(&self, code_table: &HashMap<Id, Code>) -> std::io::Result<()> {
+        let batch = diff(&self.code_tree, code_table.iter())?;
+        self.code_tree
+            .transaction(|code_tree| {
+                code_tree.apply_batch(&batch)?;
+                Ok(())
+            })
+            .map_err(transaction_error)?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// the router table and the time it was written, without hit counts
+    /// of the hits files.
+    pub fn load_latest_router_table(&self) -> std::io::Result<Option<(TimeStamp, RouterTable)>> {
+        let Some(written_at) = self.meta_tree.get(ROUTER_TABLE_WRITTEN_AT)? else {
+            return Ok(None);
+        };
+        let written_at = std::str::from_utf8(&written_at)
+            .ok()
+            .and_then(|time| TimeStamp::parse_from_rfc3339(time).ok())
+            .ok_or_else(|| std::io::Error::other("invalid router table time"))?;
+        let router_table = load_tree::<Code, RouteEntry>(&self.router_tree)?
+            .into_iter()
+            .collect();
+        Ok(Some((written_at, router_table)))
+    }
+
+    /// the version written with the router table. Databases written before
+    /// versions were kept start at 1 if they have a table.
+    pub fn load_table_version(&self) -> std::io::Result<u64> {
+        match self.meta_tree.get(ROUTER_TABLE_VERSION)? {
+            Some(version) => from_json(&version),
+            None => Ok(self.meta_tree.contains_key(ROUTER_TABLE_WRITTEN_AT)? as u64),
+        }
+    }
+
+    pub fn load_latest_code_table(&self) -> std::io::Result<Option<HashMap<Id, Code>>> {
+        if self.code_tree.is_empty() {
+            return Ok(None);
+        }
+        load_tree(&self.code_tree).map(Some)
+    }
+}
+
+/// the batch turning `tree` into `table`.
+fn diff<'a, K, V>(
+    tree: &Tree,
+    table: impl Iterator<Item = (&'a K, &'a V)>,
+) -> std::io::Result<Batch>
+where
+    K: Serialize + 'a,
+    V: Serialize + 'a,
+{
+    let mut table = table
+        .map(|(key, value)| Ok((to_json(key)?, to_json(value)?)))
+        .collect::<std::io::Result<HashMap<_, _>>>()?;
+    let mut batch = Batch::default();
+    for kv in tree.iter() {
+        let (key, old) = kv?;
+        match table.remove(key.as_ref()) {
+            Some(new) if new == old.as_ref() => {}
+            Some(new) => batch.insert(key, new),
+            None => batch.remove(key),
+        }
+    }
+    for (key, value) in table {
+        batch.insert(key, value);
+    }
+    Ok(batch)
+}
+
+fn load_tree<K, V>(tree: &Tree) -> std::io::Result<HashMap<K, V>>
+where
+    K: DeserializeOwned + std::hash::Hash + Eq,
+    V: DeserializeOwned,
+{
+    tree.iter()
+        .map(|kv| {
+            let (key, value) = kv?;
+            Ok((from_json(&key)?, from_json(&value)?))
+        })
+        .collect()
+}
+
+fn to_json<T: Serialize>(data: &T) -> std::io::Result<Vec<u8>> {
+    serde_json::to_vec(data)
+        .map_err(|e| std::io::Error::other(format!("json serialization error: {e}")))
+}
+
+fn from_json<T: DeserializeOwned>(data: &[u8]) -> std::io::Result<T> {
+    serde_json::from_slice(data)
+        .map_err(|e| std::io::Error::other(format!("json deserialization error: {e}")))
+}
+
+fn transaction_error(e: TransactionError) -> std::io::Error {
+    match e {
+        TransactionError::Storage(e) | TransactionError::Abort(e) => e.into(),
+    }
+}
@@ -0,0 +1,55 @@
+//! Request timeout, which handlers may extend for their own request.
+use crate::DEFAULT_TIMEOUT;
+use axum::{
+    extract::Request,
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::time::Instant;
+
+/// The timeout of the current request in milliseconds,
+/// inserted as a request extension by [`timeout`].
+#[derive(Clone)]
+pub struct RequestTimeout(Arc<AtomicU64>);
+
+impl RequestTimeout {
+    fn new(timeout: Duration) -> Self {
+        Self(Arc::new(AtomicU64::new(timeout.as_millis() as u64)))
+    }
+
+    pub fn get(&self) -> Duration {
+        Duration::from_millis(self.0.load(Ordering::Relaxed))
+    }
+
+    /// raise the timeout to at least `timeout`.
+    pub fn extend(&self, timeout: Duration) {
+        self.0
+            .fetch_max(timeout.as_millis() as u64, Ordering::Relaxed);
+    }
+}
+
+/// middleware answering 408 once the request takes longer than its timeout,
+/// `DEFAULT_TIMEOUT` unless extended through [`RequestTimeout`].
+pub async fn timeout(mut req: Request, next: Next) -> Response {
+    let start = Instant::now();
+    let timeout = RequestTimeout::new(DEFAULT_TIMEOUT);
+    req.extensions_mut().insert(timeout.clone());
+    let rsp = next.run(req);
+    tokio::pin!(rsp);
+    loop {
+        match tokio::time::timeout_at(start + timeout.get(), rsp.as_mut()).await {
+            Ok(rsp) => return rsp,
+            // extended meanwhile
+            Err(_) if start + timeout.get() > Instant::now() => continue,
+            Err(_) => return StatusCode::REQUEST_TIMEOUT.into_response(),
+        }
+    }
+}
@@ -0,0 +1,40 @@
+//! W3C Trace Context of redirect requests, logged so that survey platforms
+//! can correlate their logs with ours.
+use axum::http::HeaderMap;
+
+const TRACEPARENT: &str = "traceparent";
+
+/// the `traceparent` header, if it is well formed:
+/// `<version>-<trace id>-<parent id>-<flags>` in lower case hex,
+/// with non-zero ids. Version `ff` is invalid.
+pub fn traceparent(headers: &HeaderMap) -> Option<&str> {
+    let value = headers.get(TRACEPARENT)?.to_str().ok()?;
+    let mut fields = value.split('-');
+    let version = fields.next()?;
+    let trace_id = fields.next()?;
+    let parent_id = fields.next()?;
+    let flags = fields.next()?;
+    // later versions may append fields
+    if version == "00" && fields.next().is_some() {
+        return None;
+    }
+    let valid = is_hex(version, 2)
+        && version != "ff"
+        && is_hex(trace_id, 32)
+        && is_hex(parent_id, 16)
+        && is_hex(flags, 2)
+        && !is_zero(trace_id)
+        && !is_zero(parent_id);
+    valid.then_some(value)
+}
+
+fn is_hex(field: &str, len: usize) -> bool {
+    field.len() == len
+        && field
+            .bytes()
+            .all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+fn is_zero(field: &str) -> bool {
+    field.bytes().all(|b| b == b'0')
+}
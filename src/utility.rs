@@ -1,30 +1,188 @@
 //! All functions in this file are blocking functions!
 //! Must call within `spawn_blocking`.
-use crate::state::{Code, Uid};
-use chrono::{DateTime, FixedOffset};
-use serde::{de::DeserializeOwned, Serialize};
+use crate::state::{Code, Completion, Id, RouteEntry, RouterTable};
+use chrono::{DateTime, FixedOffset, Utc};
+use serde::{
+    de::{DeserializeOwned, IgnoredAny},
+    Deserialize, Serialize,
+};
 use std::fs::DirEntry;
 use std::{
     collections::HashMap,
     io::{Read, Write},
-    path::Path,
+    path::{Path, PathBuf},
+    sync::Arc,
 };
 use url::Url;
 
 const JSON_EXT: &str = "json";
 const CODE_TABLE: &str = "code";
+/// completions of `/api/complete`, by code.
+const COMPLETIONS: &str = "completions";
+/// admin token set by `rotate_admin_token`, preferred over `admin_token` of the config.
+const ADMIN_TOKEN_FILE: &str = "admin_token.secret";
+const HITS_PREFIX: &str = "hits-";
+/// latest router table not yet persisted as a snapshot, see `write_router_table`.
+const WAL_FILE: &str = "wal.log";
+/// version of the latest router table, see `RouterState::table_version`.
+const TABLE_VERSION: &str = "table_version";
+
+pub type TimeStamp = DateTime<FixedOffset>;
+
+/// `storage_stats` response.
+#[derive(Serialize)]
+pub struct StorageStats {
+    pub storage_root: PathBuf,
+    /// router table snapshots.
+    pub snapshot_count: usize,
+    /// of the router table snapshots.
+    pub snapshot_size_bytes: u64,
+    /// all files under `storage_root`, including hits files, the code table and sled.
+    pub total_size_bytes: u64,
+    pub latest_snapshot_ts: Option<DateTime<Utc>>,
+    pub oldest_snapshot_ts: Option<DateTime<Utc>>,
+    /// version of the live router table, see `RouterState::table_version`.
+    pub table_version: u64,
+}
+
+/// router table value on disk, older snapshots store bare urls.
+// no larger than the `RouteEntry` each one becomes
+#[allow(clippy::large_enum_variant)]
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredRouteEntry {
+    Entry(RouteEntry),
+    Url(Url),
+}
 
-type TimeStamp = DateTime<FixedOffset>;
+impl From<StoredRouteEntry> for RouteEntry {
+    fn from(entry: StoredRouteEntry) -> Self {
+        match entry {
+            StoredRouteEntry::Entry(entry) => entry,
+            StoredRouteEntry::Url(url) => RouteEntry {
+                url: Arc::new(url),
+                mobile_url: None,
+                round_robin_urls: None,
+                geo_urls: None,
+                redirect_mode: None,
+                request_timeout_secs: None,
+                hit_count: Default::default(),
+                deactivated: false,
+                description: None,
+                notes: None,
+                participant: None,
+                wave: None,
+                expires_at: None,
+                redirect_url: None,
+            },
+        }
+    }
+}
 
+/// write a router table snapshot of `version`.
+///
+/// The version is written first: after a crash in between, the stored version
+/// is ahead of the stored table, and stale `If-Match` headers still fail.
+///
+/// With `wal`, the table is also appended to `wal.log` before the snapshot
+/// is persisted, and the log is truncated after, so that a crash in between
+/// is recovered by `load_latest_router_table`.
 pub fn write_router_table<P: AsRef<Path>>(
-    router_table: &HashMap<Code, Url>,
+    router_table: &RouterTable,
+    version: u64,
+    router_directory: P,
+    wal: bool,
+) -> std::io::Result<()> {
+    write_data(router_directory.as_ref().join(TABLE_VERSION), &version)?;
+    if !wal {
+        return write_data_with_timestamp_ext(router_table, router_directory, "", JSON_EXT);
+    }
+    let timestamp = chrono::Local::now().to_rfc3339();
+    let data = serde_json::to_string(router_table)
+        .map_err(|e| std::io::Error::other(format!("json serialization error: {e}")))?;
+    let temp = write_temp(&data)?;
+    let wal_file = router_directory.as_ref().join(WAL_FILE);
+    append_wal(&wal_file, &timestamp, &data)?;
+    temp.persist(snapshot_path(&router_directory, &timestamp))
+        .map_err(|e| e.error)?;
+    std::fs::File::create(wal_file)?;
+    Ok(())
+}
+
+/// append `<timestamp>\t<json>\n` to the log and sync it.
+fn append_wal(wal_file: &Path, timestamp: &str, data: &str) -> std::io::Result<()> {
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(wal_file)?;
+    file.write_all(format!("{timestamp}\t{data}\n").as_bytes())?;
+    file.sync_data()
+}
+
+/// the timestamp and table of the last complete entry of `wal.log`,
+/// if it is newer than the latest snapshot.
+fn newer_wal_entry<P: AsRef<Path>>(
+    router_directory: P,
+) -> std::io::Result<Option<(String, String)>> {
+    let wal_file = router_directory.as_ref().join(WAL_FILE);
+    let log = match std::fs::read_to_string(&wal_file) {
+        Ok(log) => log,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    // an unterminated last line is an interrupted append
+    let complete = log.rfind('\n').map_or("", |end| &log[..end]);
+    let entry = complete
+        .lines()
+        .rev()
+        .filter_map(|line| line.split_once('\t'))
+        .find_map(|(time, data)| Some((TimeStamp::parse_from_rfc3339(time).ok()?, time, data)));
+    let Some((time, timestamp, data)) = entry else {
+        return Ok(None);
+    };
+    let newer = match get_latest_file_with_ext(&router_directory, "", JSON_EXT)? {
+        Some((latest, _)) => time > latest,
+        None => true,
+    };
+    Ok(newer.then(|| (timestamp.to_owned(), data.to_owned())))
+}
+
+/// persist the last complete entry of `wal.log` as a snapshot
+/// if it is newer than the latest snapshot, then truncate the log.
+fn replay_wal<P: AsRef<Path>>(router_directory: P) -> std::io::Result<()> {
+    let wal_file = router_directory.as_ref().join(WAL_FILE);
+    if !wal_file.is_file() {
+        return Ok(());
+    }
+    if let Some((timestamp, data)) = newer_wal_entry(&router_directory)? {
+        write_temp(&data)?
+            .persist(snapshot_path(&router_directory, &timestamp))
+            .map_err(|e| e.error)?;
+        tracing::warn!("router table of {timestamp} recovered from {WAL_FILE}");
+    }
+    std::fs::File::create(wal_file)?;
+    Ok(())
+}
+
+fn snapshot_path<P: AsRef<Path>>(dir: P, timestamp: &str) -> std::path::PathBuf {
+    dir.as_ref().join(format!("{timestamp}.{JSON_EXT}"))
+}
+
+/// write hit counts to `hits-<timestamp>.json`, and remove older hits files.
+pub fn write_hits_table<P: AsRef<Path>>(
+    hits_table: &HashMap<Code, u64>,
     router_directory: P,
 ) -> std::io::Result<()> {
-    write_data_with_timestamp_ext(router_table, router_directory, JSON_EXT)
+    let old_files = timestamped_files(&router_directory, HITS_PREFIX, JSON_EXT)?;
+    write_data_with_timestamp_ext(hits_table, &router_directory, HITS_PREFIX, JSON_EXT)?;
+    for (_, entry) in old_files {
+        std::fs::remove_file(entry.path())?;
+    }
+    Ok(())
 }
 
 pub fn write_code_table<P: AsRef<Path>>(
-    code_table: &HashMap<Uid, Code>,
+    code_table: &HashMap<Id, Code>,
     router_directory: P,
 ) -> std::io::Result<()> {
     let file = {
@@ -35,15 +193,38 @@ pub fn write_code_table<P: AsRef<Path>>(
     write_data(file, code_table)
 }
 
+pub fn write_completions<P: AsRef<Path>>(
+    completions: &HashMap<Code, Completion>,
+    router_directory: P,
+) -> std::io::Result<()> {
+    write_data(router_directory.as_ref().join(COMPLETIONS), completions)
+}
+
+/// save the admin token, readable and writable by the owner only.
+pub fn write_admin_token<P: AsRef<Path>>(token: &str, router_directory: P) -> std::io::Result<()> {
+    let temp = write_temp(token)?;
+    temp.as_file().sync_all()?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        temp.as_file()
+            .set_permissions(std::fs::Permissions::from_mode(0o600))?;
+    }
+    temp.persist(router_directory.as_ref().join(ADMIN_TOKEN_FILE))
+        .map_err(|e| e.error)?;
+    Ok(())
+}
+
 fn write_data_with_timestamp_ext<P: AsRef<Path>, T: Serialize>(
     data: &T,
     dir: P,
+    prefix: &str,
     ext: &str,
 ) -> std::io::Result<()> {
     let timestamp = chrono::Local::now().to_rfc3339();
     let file = {
         let mut dst = dir.as_ref().to_owned();
-        dst.push(format!("{}.{}", timestamp, ext));
+        dst.push(format!("{}{}.{}", prefix, timestamp, ext));
         dst
     };
     write_data(file, data)
@@ -54,42 +235,114 @@ fn write_data<P: AsRef<Path> + Send + 'static, T: Serialize>(
     data: &T,
 ) -> std::io::Result<()> {
     // serialize data
-    let data = serde_json::to_string(data).map_err(|e| {
-        std::io::Error::new(
-            std::io::ErrorKind::Other,
-            format!("json serialization error: {e}"),
-        )
-    })?;
+    let data = serde_json::to_string(data)
+        .map_err(|e| std::io::Error::other(format!("json serialization error: {e}")))?;
+    // persist file
+    write_temp(&data)?.persist(file_path).map_err(|e| e.error)?;
+    Ok(())
+}
+
+/// write `data` to a temp file, to be persisted.
+fn write_temp(data: &str) -> std::io::Result<tempfile::NamedTempFile> {
     // create a temp file
     let temp = tempfile::NamedTempFile::new()?;
     // write to temp file
     let (mut temp_file, temp_path) = temp.into_parts();
     temp_file.write_all(data.as_ref())?;
-    let temp = tempfile::NamedTempFile::from_parts(temp_file, temp_path);
-    // persist file
-    temp.persist(file_path).map_err(|e| e.error)?;
-    Ok(())
+    Ok(tempfile::NamedTempFile::from_parts(temp_file, temp_path))
 }
 
 //
 // Load-related functions are async.
 //
 
+/// load the latest router table, with hit counts from the latest hits file merged.
+///
+/// A table left in `wal.log` by an interrupted write is persisted first.
 pub fn load_latest_router_table<P: AsRef<Path>>(
     router_directory: P,
-) -> std::io::Result<Option<(TimeStamp, HashMap<Code, Url>)>> {
-    let latest = get_latest_file_with_ext(router_directory, JSON_EXT)?;
+) -> std::io::Result<Option<(TimeStamp, RouterTable)>> {
+    replay_wal(&router_directory)?;
+    let latest = get_latest_file_with_ext(&router_directory, "", JSON_EXT)?;
     // load data
-    if let Some((time, entry)) = latest {
-        Ok(Some((time, load_data(entry.path())?)))
+    let Some((time, entry)) = latest else {
+        return Ok(None);
+    };
+    let router_table: HashMap<Code, StoredRouteEntry> = load_data(entry.path())?;
+    let router_table: RouterTable = router_table
+        .into_iter()
+        .map(|(code, entry)| (code, entry.into()))
+        .collect();
+    merge_latest_hits(&router_table, &router_directory)?;
+    Ok(Some((time, router_table)))
+}
+
+/// entry counts of the latest router table and of the code table, read
+/// without changing the store: a table left in `wal.log` is counted, not
+/// persisted.
+pub fn stored_table_sizes<P: AsRef<Path>>(router_directory: P) -> std::io::Result<(usize, usize)> {
+    let routes = match newer_wal_entry(&router_directory)? {
+        Some((_, data)) => serde_json::from_str::<HashMap<Code, IgnoredAny>>(&data)
+            .map_err(|e| std::io::Error::other(format!("json deserialization error: {e}")))?
+            .len(),
+        None => match get_latest_file_with_ext(&router_directory, "", JSON_EXT)? {
+            Some((_, entry)) => load_data::<_, HashMap<Code, IgnoredAny>>(entry.path())?.len(),
+            None => 0,
+        },
+    };
+    let codes = load_latest_code_table(router_directory)?.map_or(0, |table| table.len());
+    Ok((routes, codes))
+}
+
+/// the version of the latest router table. Stores written before versions
+/// were kept count one version per snapshot.
+pub fn load_table_version<P: AsRef<Path>>(router_directory: P) -> std::io::Result<u64> {
+    let file = router_directory.as_ref().join(TABLE_VERSION);
+    if file.is_file() {
+        load_data(file)
     } else {
-        Ok(None)
+        Ok(timestamped_files(router_directory, "", JSON_EXT)?.len() as u64)
+    }
+}
+
+/// raise the hit counts of `router_table` to those of the latest hits file.
+pub fn merge_latest_hits<P: AsRef<Path>>(
+    router_table: &RouterTable,
+    router_directory: P,
+) -> std::io::Result<()> {
+    if let Some((_, entry)) = get_latest_file_with_ext(&router_directory, HITS_PREFIX, JSON_EXT)? {
+        let hits_table: HashMap<Code, u64> = load_data(entry.path())?;
+        for (code, hits) in hits_table {
+            if let Some(route) = router_table.get(&code) {
+                route.hit_count.merge(hits);
+            }
+        }
     }
+    Ok(())
+}
+
+/// load the url of every route in every router table snapshot, newest first.
+pub fn load_all_router_table_snapshots(
+    dir: &Path,
+) -> std::io::Result<Vec<(TimeStamp, HashMap<Code, Url>)>> {
+    let mut files = timestamped_files(dir, "", JSON_EXT)?;
+    files.sort_unstable_by(|(a, _), (b, _)| b.cmp(a));
+    files
+        .into_iter()
+        .map(|(time, entry)| {
+            let table: HashMap<Code, StoredRouteEntry> = load_data(entry.path())?;
+            let urls = table
+                .into_iter()
+                .map(|(code, entry)| (code, Arc::unwrap_or_clone(RouteEntry::from(entry).url)))
+                .collect();
+            Ok((time, urls))
+        })
+        .collect()
 }
 
 pub fn load_latest_code_table<P: AsRef<Path>>(
     router_directory: P,
-) -> std::io::Result<Option<HashMap<Uid, Code>>> {
+) -> std::io::Result<Option<HashMap<Id, Code>>> {
     let latest = {
         let mut dst = router_directory.as_ref().to_owned();
         dst.push(CODE_TABLE);
@@ -103,12 +356,79 @@ pub fn load_latest_code_table<P: AsRef<Path>>(
     }
 }
 
-/// get latest file with extension
+pub fn load_completions<P: AsRef<Path>>(
+    router_directory: P,
+) -> std::io::Result<Option<HashMap<Code, Completion>>> {
+    let file = router_directory.as_ref().join(COMPLETIONS);
+    if file.is_file() {
+        Ok(Some(load_data(file)?))
+    } else {
+        Ok(None)
+    }
+}
+
+/// the admin token saved by `write_admin_token`, if any.
+pub fn load_admin_token<P: AsRef<Path>>(router_directory: P) -> std::io::Result<Option<String>> {
+    let file = router_directory.as_ref().join(ADMIN_TOKEN_FILE);
+    if file.is_file() {
+        Ok(Some(std::fs::read_to_string(file)?.trim().to_owned()))
+    } else {
+        Ok(None)
+    }
+}
+
+/// count router table snapshots and add up the file sizes in `dir`.
+pub fn storage_statistics(dir: &Path, table_version: u64) -> std::io::Result<StorageStats> {
+    let snapshots = timestamped_files(dir, "", JSON_EXT)?;
+    let times = snapshots.iter().map(|(time, _)| time.with_timezone(&Utc));
+    let mut snapshot_size_bytes = 0;
+    for (_, entry) in &snapshots {
+        snapshot_size_bytes += entry.metadata()?.len();
+    }
+    Ok(StorageStats {
+        storage_root: dir.to_owned(),
+        snapshot_count: snapshots.len(),
+        snapshot_size_bytes,
+        total_size_bytes: dir_size(dir)?,
+        latest_snapshot_ts: times.clone().max(),
+        oldest_snapshot_ts: times.min(),
+        table_version,
+    })
+}
+
+/// size of the files in `dir` and its sub directories, symlinks are not followed.
+fn dir_size(dir: &Path) -> std::io::Result<u64> {
+    let mut size = 0;
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            size += dir_size(&entry.path())?;
+        } else if file_type.is_file() {
+            size += entry.metadata()?.len();
+        }
+    }
+    Ok(size)
+}
+
+/// get latest file with prefix and extension
 fn get_latest_file_with_ext<P: AsRef<Path>>(
     dir: P,
+    prefix: &str,
     ext: &str,
 ) -> std::io::Result<Option<(TimeStamp, DirEntry)>> {
-    let mut latest = None;
+    Ok(timestamped_files(dir, prefix, ext)?
+        .into_iter()
+        .max_by_key(|(time, _)| *time))
+}
+
+/// list files named `<prefix><rfc3339 time>.<ext>`
+fn timestamped_files<P: AsRef<Path>>(
+    dir: P,
+    prefix: &str,
+    ext: &str,
+) -> std::io::Result<Vec<(TimeStamp, DirEntry)>> {
+    let mut files = Vec::new();
     for entry in std::fs::read_dir(dir)? {
         let entry = entry?;
         // skip folder and symlinks
@@ -116,35 +436,21 @@ fn get_latest_file_with_ext<P: AsRef<Path>>(
             continue;
         }
 
-        // extract time from time.json
-        let this_time = {
-            let path = entry.path();
-            if Some(ext) == path.extension().and_then(|ext| ext.to_str()) {
-                if let Some(Ok(this_time)) = path
-                    .file_stem()
-                    .and_then(|name| name.to_str())
-                    .map(chrono::DateTime::parse_from_rfc3339)
-                {
-                    this_time
-                } else {
-                    continue;
-                }
-            } else {
-                continue;
-            }
-        };
-
-        // update latest entry
-        if let Some((time, e)) = latest.as_mut() {
-            if *time < this_time {
-                *time = this_time;
-                *e = entry;
-            }
-        } else {
-            latest = Some((this_time, entry));
+        // extract time from <prefix>time.json
+        let path = entry.path();
+        if Some(ext) != path.extension().and_then(|ext| ext.to_str()) {
+            continue;
+        }
+        if let Some(Ok(this_time)) = path
+            .file_stem()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix(prefix))
+            .map(chrono::DateTime::parse_from_rfc3339)
+        {
+            files.push((this_time, entry));
         }
     }
-    Ok(latest)
+    Ok(files)
 }
 
 /// load data
@@ -153,10 +459,6 @@ fn load_data<P: AsRef<Path> + Send + 'static, T: DeserializeOwned>(
 ) -> std::io::Result<T> {
     let mut buf = Vec::new();
     std::fs::File::open(file_path)?.read_to_end(&mut buf)?;
-    serde_json::from_slice::<T>(&buf).map_err(|e| {
-        std::io::Error::new(
-            std::io::ErrorKind::Other,
-            format!("json deserialization error: {e}"),
-        )
-    })
+    serde_json::from_slice::<T>(&buf)
+        .map_err(|e| std::io::Error::other(format!("json deserialization error: {e}")))
 }
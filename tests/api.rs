@@ -0,0 +1,2591 @@
+mod common;
+
+use axum::{
+    body::Body,
+    http::{
+        header::{
+            ACCEPT, AUTHORIZATION, CONNECTION, CONTENT_ENCODING, CONTENT_TYPE, LOCATION, REFERER,
+            RETRY_AFTER,
+        },
+        Request, StatusCode,
+    },
+};
+use common::{admin, admin_data, body_string, links_data, TestApp};
+use std::{collections::HashMap, io::Write, time::Duration};
+use survey_redirect::{
+    certs::CertReload,
+    redirect_policy::RedirectPolicy,
+    server::{DrainedConnection, EarlyData},
+    state::{RouterState, StateError},
+};
+use url::Url;
+
+const TABLE: &str = r#"[
+    {"uid": "alice", "url": "https://survey.example/a?wave=1"},
+    {"uid": "bob", "url": "https://survey.example/b"}
+]"#;
+
+async fn get_links(app: &TestApp) -> HashMap<String, Url> {
+    let rsp = app
+        .send(
+            admin("GET", "/admin/get_links")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+    links_data(rsp).await
+}
+
+async fn follow(app: &TestApp, link: &Url) -> Url {
+    follow_path(app, "/api", link).await
+}
+
+/// follow `link` with its path replaced.
+async fn follow_path(app: &TestApp, path: &str, link: &Url) -> Url {
+    let req = Request::get(format!("{path}?{}", link.query().unwrap()))
+        .body(Body::empty())
+        .unwrap();
+    let rsp = app.send(req).await;
+    assert_eq!(rsp.status(), StatusCode::SEE_OTHER);
+    Url::parse(rsp.headers()[LOCATION].to_str().unwrap()).unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn put_table_then_redirect() {
+    let app = TestApp::new();
+    let rsp = app
+        .send(
+            admin("PUT", "/admin/routing_table")
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(TABLE))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+
+    let links = get_links(&app).await;
+    assert_eq!(links.len(), 2);
+    let link = &links["alice"];
+    assert!(link.as_str().starts_with(common::BASE_URL));
+    let code = link
+        .query_pairs()
+        .find(|(k, _)| k == "code")
+        .map(|(_, v)| v.into_owned())
+        .unwrap();
+
+    let target = follow(&app, link).await;
+    assert_eq!(target.host_str(), Some("survey.example"));
+    assert_eq!(target.path(), "/a");
+    let query: HashMap<_, _> = target.query_pairs().into_owned().collect();
+    assert_eq!(query["wave"], "1");
+    assert_eq!(query["externalUserId"], code);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn unknown_code_is_not_found() {
+    let app = TestApp::new();
+    let rsp = app
+        .send(Request::get("/api?code=nope").body(Body::empty()).unwrap())
+        .await;
+    assert_eq!(rsp.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn admin_requires_token() {
+    let app = TestApp::new();
+    let rsp = app
+        .send(
+            Request::get("/admin/get_links")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn put_brotli_table() {
+    let app = TestApp::new();
+    let mut compressed = Vec::new();
+    {
+        let mut encoder = brotli::CompressorWriter::new(&mut compressed, 4096, 5, 22);
+        encoder.write_all(TABLE.as_bytes()).unwrap();
+    }
+    let rsp = app
+        .send(
+            admin("PUT", "/admin/routing_table")
+                .header(CONTENT_TYPE, "application/json")
+                .header(CONTENT_ENCODING, "br")
+                .body(Body::from(compressed))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+
+    let links = get_links(&app).await;
+    assert_eq!(links.len(), 2);
+    assert_eq!(follow(&app, &links["bob"]).await.path(), "/b");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn trailing_slash_is_accepted() {
+    let app = TestApp::new();
+    let rsp = app
+        .send(
+            admin("PUT", "/admin/routing_table")
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(TABLE))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+    let links = get_links(&app).await;
+    let target = follow_path(&app, "/api/", &links["bob"]).await;
+    assert_eq!(target.path(), "/b");
+
+    // paths are case-sensitive
+    let req = Request::get(format!("/API?{}", links["bob"].query().unwrap()))
+        .body(Body::empty())
+        .unwrap();
+    assert_eq!(app.send(req).await.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn admin_is_versioned() {
+    let app = TestApp::new();
+    let rsp = app
+        .send(
+            admin("GET", "/v1/admin/get_links")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+    assert_eq!(rsp.headers()["x-api-version"], "v1");
+    assert!(!rsp.headers().contains_key("deprecation"));
+
+    let rsp = app
+        .send(
+            admin("GET", "/admin/get_links")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+    assert_eq!(rsp.headers()["x-api-version"], "v1");
+    assert_eq!(rsp.headers()["deprecation"], "true");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn drained_connections_get_503() {
+    let app = TestApp::new();
+    let drained_request = || {
+        let mut req = Request::get("/api?code=nope").body(Body::empty()).unwrap();
+        req.extensions_mut().insert(DrainedConnection);
+        req
+    };
+    let ready = || Request::get("/readyz").body(Body::empty()).unwrap();
+
+    // not draining yet: connections are never marked
+    assert_eq!(app.send(ready()).await.status(), StatusCode::OK);
+
+    let rsp = app
+        .send(
+            admin("POST", "/v1/admin/drain")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+    let rsp = app.send(ready()).await;
+    assert_eq!(rsp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(body_string(rsp).await, "draining");
+    let rsp = app.send(drained_request()).await;
+    assert_eq!(rsp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(rsp.headers()[CONNECTION], "close");
+    // connections opened before draining are still served
+    let rsp = app
+        .send(Request::get("/api?code=nope").body(Body::empty()).unwrap())
+        .await;
+    assert_eq!(rsp.status(), StatusCode::NOT_FOUND);
+
+    let rsp = app
+        .send(
+            admin("POST", "/v1/admin/undrain")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+    assert_eq!(app.send(ready()).await.status(), StatusCode::OK);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn early_data_is_only_for_safe_methods() {
+    let app = TestApp::new();
+    let early = |builder: axum::http::request::Builder, body: &str| {
+        let mut req = builder.body(Body::from(body.to_owned())).unwrap();
+        req.extensions_mut().insert(EarlyData);
+        req
+    };
+
+    let rsp = app
+        .send(early(
+            admin("PUT", "/v1/admin/routing_table").header(CONTENT_TYPE, "application/json"),
+            TABLE,
+        ))
+        .await;
+    assert_eq!(rsp.status().as_u16(), 425);
+    assert_eq!(body_string(rsp).await, "too early");
+    assert!(get_links(&app).await.is_empty());
+
+    // reads are served as usual
+    let rsp = app.send(early(Request::get("/api?code=nope"), "")).await;
+    assert_eq!(rsp.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn early_data_completions_are_rejected() {
+    let app = TestApp::new();
+    let rsp = app
+        .send(
+            admin("PUT", "/admin/routing_table")
+                .body(Body::from(TABLE))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+    let links = get_links(&app).await;
+    let complete = || {
+        Request::get(format!("/api/complete?{}", links["alice"].query().unwrap()))
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    let mut req = complete();
+    req.extensions_mut().insert(EarlyData);
+    let rsp = app.send(req).await;
+    assert_eq!(rsp.status().as_u16(), 425);
+    assert_eq!(body_string(rsp).await, "too early");
+
+    // accepted after the handshake
+    let rsp = app.send(complete()).await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn robots_and_favicon() {
+    let app = TestApp::new();
+    let rsp = app
+        .send(Request::get("/robots.txt").body(Body::empty()).unwrap())
+        .await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+    assert_eq!(body_string(rsp).await, "User-agent: *\nDisallow: /\n");
+    let rsp = app
+        .send(Request::get("/favicon.ico").body(Body::empty()).unwrap())
+        .await;
+    assert_eq!(rsp.status(), StatusCode::NO_CONTENT);
+
+    let icon_dir = tempfile::tempdir().unwrap();
+    let icon = icon_dir.path().join("favicon.png");
+    std::fs::write(&icon, b"not really a png").unwrap();
+    let app = TestApp::with_config(&format!("favicon: {}\n", icon.display()));
+    let rsp = app
+        .send(Request::get("/favicon.ico").body(Body::empty()).unwrap())
+        .await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+    assert_eq!(rsp.headers()[CONTENT_TYPE], "image/png");
+    assert_eq!(body_string(rsp).await, "not really a png");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn per_route_timeout_header() {
+    let app = TestApp::new();
+    let put = |table: &'static str| {
+        admin("PUT", "/admin/routing_table")
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(table))
+            .unwrap()
+    };
+    let rsp = app
+        .send(put(
+            r#"[{"uid": "slow", "url": "https://survey.example/s", "request_timeout_secs": 0}]"#,
+        ))
+        .await;
+    assert_eq!(rsp.status(), StatusCode::BAD_REQUEST);
+    let rsp = app
+        .send(put(
+            r#"[{"uid": "slow", "url": "https://survey.example/s", "request_timeout_secs": 90}]"#,
+        ))
+        .await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+
+    let link = &get_links(&app).await["slow"];
+    let req = Request::get(format!("/api?{}", link.query().unwrap()))
+        .body(Body::empty())
+        .unwrap();
+    let rsp = app.send(req).await;
+    assert_eq!(rsp.status(), StatusCode::SEE_OTHER);
+    assert_eq!(rsp.headers()["x-survey-timeout"], "90");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn busy_suggests_retry_after() {
+    let app = TestApp::new();
+    let _lock = app.state.code_table.lock().await;
+    let rsp = app
+        .send(
+            admin("GET", "/admin/get_codes")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status(), StatusCode::TOO_MANY_REQUESTS);
+    let retry_after: u64 = rsp.headers()[RETRY_AFTER]
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    // at least 1 s, plus up to 1 s jitter
+    assert!((1..=2).contains(&retry_after), "{retry_after}");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn idempotent_put_is_written_once() {
+    let app = TestApp::new();
+    let put = || {
+        admin("PUT", "/admin/routing_table")
+            .header(CONTENT_TYPE, "application/json")
+            .header("idempotency-key", "8d2c0b8e-1f4e-4d55-9a43-51c0a4a5b2f1")
+            .body(Body::from(TABLE))
+            .unwrap()
+    };
+    let snapshots = || {
+        std::fs::read_dir(app.dir.path().join("db"))
+            .unwrap()
+            .filter(|entry| {
+                let name = entry.as_ref().unwrap().file_name();
+                let name = name.to_str().unwrap();
+                name.ends_with(".json") && !name.starts_with("hits-")
+            })
+            .count()
+    };
+
+    let rsp = app.send(put()).await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+    assert!(!rsp.headers().contains_key("idempotent-replayed"));
+    assert_eq!(snapshots(), 1);
+
+    let rsp = app.send(put()).await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+    assert_eq!(rsp.headers()["idempotent-replayed"], "true");
+    assert_eq!(admin_data::<()>(rsp).await, ());
+    assert_eq!(snapshots(), 1);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn route_history_lists_snapshots() {
+    let app = TestApp::new();
+    for url in ["https://survey.example/v1", "https://survey.example/v2"] {
+        let table = format!(r#"[{{"uid": "alice", "url": "{url}"}}]"#);
+        let rsp = app
+            .send(
+                admin("PUT", "/admin/routing_table")
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Body::from(table))
+                    .unwrap(),
+            )
+            .await;
+        assert_eq!(rsp.status(), StatusCode::OK);
+    }
+
+    let rsp = app
+        .send(
+            admin("GET", "/admin/route_history?id=alice")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+    let history: Vec<HashMap<String, String>> = admin_data(rsp).await;
+    let urls: Vec<_> = history.iter().map(|entry| entry["url"].as_str()).collect();
+    assert_eq!(
+        urls,
+        ["https://survey.example/v2", "https://survey.example/v1"]
+    );
+
+    let rsp = app
+        .send(
+            admin("GET", "/admin/route_history?id=mallory")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn put_table_as_map() {
+    let app = TestApp::new();
+    let put = |table: &'static str| {
+        admin("PUT", "/admin/routing_table")
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(table))
+            .unwrap()
+    };
+    let rsp = app
+        .send(put(r#"{
+            "alice": "https://survey.example/a",
+            "bob": {"url": "https://survey.example/b", "notes": "pilot"}
+        }"#))
+        .await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+    assert_eq!(rsp.headers()["x-table-format"], "map");
+    let links = get_links(&app).await;
+    assert_eq!(follow(&app, &links["alice"]).await.path(), "/a");
+    assert_eq!(follow(&app, &links["bob"]).await.path(), "/b");
+
+    let rsp = app.send(put(TABLE)).await;
+    assert_eq!(rsp.headers()["x-table-format"], "array");
+
+    for (table, problem) in [
+        (r#"{"alice": 1}"#, "invalid map of routes"),
+        (
+            r#"{"alice": {"uid": "bob", "url": "https://survey.example/a"}}"#,
+            "must not repeat uid",
+        ),
+        (
+            r#"{"alice": "https://survey.example/a", "alice": "https://survey.example/b"}"#,
+            "duplicate id alice",
+        ),
+        (
+            r#""https://survey.example/a""#,
+            "expected a json array or object",
+        ),
+    ] {
+        let rsp = app.send(put(table)).await;
+        assert_eq!(rsp.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = serde_json::from_str(&body_string(rsp).await).unwrap();
+        assert_eq!(body["code"], "INVALID_TABLE");
+        assert!(
+            body["message"].as_str().unwrap().contains(problem),
+            "{table}"
+        );
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn runtime_info_counts_requests() {
+    let app = TestApp::new();
+    let runtime_info = || async {
+        let rsp = app
+            .send(
+                admin("GET", "/admin/runtime_info")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await;
+        assert_eq!(rsp.status(), StatusCode::OK);
+        admin_data::<serde_json::Value>(rsp).await
+    };
+    let info = runtime_info().await;
+    assert_eq!(info["requests_total"], 0);
+    assert!(info["last_request_at_iso"].is_null());
+
+    let rsp = app
+        .send(
+            admin("PUT", "/admin/routing_table")
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(TABLE))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+    let links = get_links(&app).await;
+    follow(&app, &links["alice"]).await;
+    let rsp = app
+        .send(
+            Request::get("/api?code=0000000000000000")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status(), StatusCode::NOT_FOUND);
+
+    let info = runtime_info().await;
+    assert_eq!(info["requests_total"], 2);
+    assert_eq!(info["redirect_errors_total"], 1);
+    let time = |field: &str| {
+        chrono::DateTime::parse_from_rfc3339(info[field].as_str().unwrap())
+            .unwrap()
+            .timestamp()
+    };
+    assert!(time("started_at_iso") <= time("last_request_at_iso"));
+    assert_eq!(info["draining"], false);
+    assert!(info["uptime_secs"].is_u64());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn json_metrics_counts_tables_and_redirects() {
+    let app = TestApp::new();
+    let metrics = || async {
+        let rsp = app
+            .send(
+                admin("GET", "/admin/metrics.json")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await;
+        assert_eq!(rsp.status(), StatusCode::OK);
+        admin_data::<serde_json::Value>(rsp).await
+    };
+    let put = |table: &'static str| {
+        admin("PUT", "/admin/routing_table")
+            .body(Body::from(table))
+            .unwrap()
+    };
+    assert_eq!(app.send(put(TABLE)).await.status(), StatusCode::OK);
+    let links = get_links(&app).await;
+    follow(&app, &links["alice"]).await;
+    let rsp = app
+        .send(
+            Request::get("/api?code=0000000000000000")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status(), StatusCode::NOT_FOUND);
+    {
+        let _updating = app.state.code_table.lock().await;
+        let rsp = app
+            .send(
+                admin("GET", "/admin/get_codes")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await;
+        assert_eq!(rsp.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    let m = metrics().await;
+    assert_eq!(m["router_table"]["entries"], 2);
+    assert_eq!(m["code_table"]["entries"], 2);
+    assert!(m["router_table"]["approx_heap_bytes"].as_u64().unwrap() > 0);
+    assert!(m["code_table"]["approx_heap_bytes"].as_u64().unwrap() > 0);
+    let snapshots = common::snapshots(&app.config.storage_root);
+    let bytes: u64 = snapshots
+        .iter()
+        .map(|path| std::fs::metadata(path).unwrap().len())
+        .sum();
+    assert_eq!(m["snapshot_count"], 1);
+    assert_eq!(m["snapshot_size_bytes"], bytes);
+    assert_eq!(m["redirects"]["success"], 1);
+    assert_eq!(m["redirects"]["invalid_code"], 1);
+    assert_eq!(m["redirects"]["deactivated"], 0);
+    assert_eq!(m["too_many_requests_total"], 1);
+
+    // entry counts are live, estimates cached, also while the code table is locked
+    let three = r#"[
+        {"uid": "alice", "url": "https://survey.example/a"},
+        {"uid": "bob", "url": "https://survey.example/b"},
+        {"uid": "carol", "url": "https://survey.example/c"}
+    ]"#;
+    assert_eq!(app.send(put(three)).await.status(), StatusCode::OK);
+    let _updating = app.state.code_table.lock().await;
+    let m = metrics().await;
+    assert_eq!(m["router_table"]["entries"], 3);
+    assert_eq!(m["code_table"]["entries"], 3);
+    assert_eq!(m["snapshot_count"], 1);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn malformed_redirect_queries() {
+    let app = TestApp::new();
+    let get = |query: &str| {
+        Request::get(format!("/api{query}"))
+            .body(Body::empty())
+            .unwrap()
+    };
+    for (query, problem) in [
+        ("", "missing code"),
+        ("?other=1", "missing code"),
+        ("?code=&other=1", "missing code"),
+        (
+            "?code=0000000000000000&code=0000000000000001",
+            "duplicate code",
+        ),
+        ("?code=&code=0000000000000000", "duplicate code"),
+    ] {
+        let rsp = app.send(get(query)).await;
+        assert_eq!(rsp.status(), StatusCode::BAD_REQUEST, "{query}");
+        assert!(body_string(rsp).await.contains(problem), "{query}");
+    }
+
+    // rejected without waiting for the router table
+    let _lock = app.state.router_table.write().await;
+    for query in [
+        "?code=short",
+        "?code=00000000000000000000000000000000000000000000000000000000000000000",
+        "?code=0000000000000%2F00",
+        "?code=00000000000000%C3%A9",
+    ] {
+        let rsp = tokio::time::timeout(Duration::from_secs(5), app.send(get(query)))
+            .await
+            .expect("took the router table lock");
+        assert_eq!(rsp.status(), StatusCode::NOT_FOUND, "{query}");
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn get_links_is_streamed() {
+    let app = &TestApp::new();
+    let links = |query: &'static str| async move {
+        let rsp = app
+            .send(
+                admin("GET", &format!("/admin/get_links{query}"))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await;
+        assert_eq!(rsp.status(), StatusCode::OK);
+        rsp
+    };
+    let empty = links_data(links("").await).await;
+    assert!(empty.is_empty());
+
+    // more than one chunk
+    let table: Vec<_> = (0..2500)
+        .map(|i| {
+            serde_json::json!({
+                "uid": format!("p{i}"),
+                "url": format!("https://survey.example/{i}"),
+                "notes": "pilot",
+            })
+        })
+        .collect();
+    let rsp = app
+        .send(
+            admin("PUT", "/admin/routing_table")
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(serde_json::to_vec(&table).unwrap()))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+
+    // the tables are not locked while the body is sent
+    let rsp = links("").await;
+    assert_eq!(rsp.headers()[CONTENT_TYPE], "application/json");
+    assert!(app.state.code_table.try_lock().is_ok());
+    let json: HashMap<String, serde_json::Value> = admin_data(rsp).await;
+    assert_eq!(json.len(), 2500);
+    assert_eq!(json["p7"]["notes"], "pilot");
+    assert!(json["p7"]["link"].is_string());
+    assert!(json["p7"].get("description").is_none());
+
+    let rsp = links("?format=ndjson").await;
+    assert_eq!(rsp.headers()[CONTENT_TYPE], "application/x-ndjson");
+    let body = body_string(rsp).await;
+    let lines: Vec<serde_json::Value> = body
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(lines.len(), 2500);
+    for line in lines {
+        let url = Url::parse(line["url"].as_str().unwrap()).unwrap();
+        assert_eq!(url.as_str(), json[line["id"].as_str().unwrap()]["link"]);
+        assert_eq!(line["notes"], "pilot");
+    }
+    let rsp = app
+        .send(
+            admin("GET", "/admin/get_links")
+                .header(
+                    ACCEPT,
+                    "application/x-ndjson; q=1.0, application/json; q=0.5",
+                )
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.headers()[CONTENT_TYPE], "application/x-ndjson");
+    assert_eq!(body_string(rsp).await.lines().count(), 2500);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn redirect_urls_are_precomputed() {
+    let app = TestApp::new();
+    let rsp = app
+        .send(
+            admin("PUT", "/admin/routing_table")
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(TABLE))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+    let link = get_links(&app).await["alice"].clone();
+    let target = follow(&app, &link).await;
+
+    // also when the table is loaded at startup
+    let state = RouterState::init(&app.config).unwrap();
+    for state in [&app.state, &state] {
+        let router_table = state.router_table.read().await;
+        assert!(router_table.values().all(|e| e.redirect_url.is_some()));
+    }
+    let restarted = survey_redirect::router(&app.config, state);
+    let req = Request::get(format!("/api?{}", link.query().unwrap()))
+        .body(Body::empty())
+        .unwrap();
+    let rsp = common::send(&restarted, req).await;
+    assert_eq!(rsp.headers()[LOCATION], target.as_str());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn admin_ping_needs_no_token() {
+    let app = TestApp::new();
+    for path in ["/v1/admin/ping", "/admin/ping"] {
+        let rsp = app
+            .send(Request::get(path).body(Body::empty()).unwrap())
+            .await;
+        assert_eq!(rsp.status(), StatusCode::OK, "{path}");
+        assert_eq!(admin_data::<String>(rsp).await, "pong");
+    }
+    let rsp = app
+        .send(Request::get("/api/ping").body(Body::empty()).unwrap())
+        .await;
+    assert_ne!(rsp.status(), StatusCode::OK);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn identical_urls_are_shared() {
+    let app = TestApp::new();
+    let table = r#"[
+        {"uid": "alice", "url": "https://survey.example/s?wave=1"},
+        {"uid": "bob", "url": "https://survey.example/s?wave=1"},
+        {"uid": "carol", "url": "https://survey.example/s?wave=2"}
+    ]"#;
+    let rsp = app
+        .send(
+            admin("PUT", "/admin/routing_table")
+                .body(Body::from(table))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+
+    // also when the table is loaded at startup
+    let state = RouterState::init(&app.config).unwrap();
+    for state in [&app.state, &state] {
+        let router_table = state.router_table.read().await;
+        let mut urls: Vec<_> = router_table.values().map(|e| e.url.clone()).collect();
+        urls.sort_unstable();
+        assert!(std::sync::Arc::ptr_eq(&urls[0], &urls[1]));
+        assert!(!std::sync::Arc::ptr_eq(&urls[1], &urls[2]));
+    }
+
+    // codes and urls are still plain strings in the snapshot
+    let snapshot = std::fs::read_dir(&app.config.storage_root)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .unwrap();
+    let snapshot: HashMap<String, serde_json::Value> =
+        serde_json::from_slice(&std::fs::read(snapshot).unwrap()).unwrap();
+    assert_eq!(snapshot.len(), 3);
+    assert!(snapshot.values().all(|entry| entry["url"].is_string()));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn reload_restored_snapshot() {
+    let app = TestApp::new();
+    let put = |table: &'static str| {
+        admin("PUT", "/admin/routing_table")
+            .body(Body::from(table))
+            .unwrap()
+    };
+    let reload = || {
+        admin("POST", "/v1/admin/reload")
+            .body(Body::empty())
+            .unwrap()
+    };
+    assert_eq!(app.send(put(TABLE)).await.status(), StatusCode::OK);
+    let before = get_links(&app).await;
+    let table = r#"[{"uid": "carol", "url": "https://survey.example/c"}]"#;
+    assert_eq!(app.send(put(table)).await.status(), StatusCode::OK);
+
+    // restore the first snapshot by removing the newer one
+    let store = &app.config.storage_root;
+    let mut snapshots: Vec<_> = std::fs::read_dir(store)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .collect();
+    snapshots.sort_unstable();
+    std::fs::remove_file(snapshots.pop().unwrap()).unwrap();
+
+    let rsp = app.send(reload()).await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+    let summary: serde_json::Value = admin_data(rsp).await;
+    assert_eq!(summary["routes"], 2);
+    assert_eq!(summary["codes"], 3);
+    assert!(summary["snapshot_ts"].is_string());
+    let links = get_links(&app).await;
+    assert_eq!(links, before);
+    assert_eq!(follow(&app, &links["bob"]).await.path(), "/b");
+
+    // refused while a table update holds the code table
+    {
+        let _update = app.state.code_table.lock().await;
+        assert_eq!(app.send(reload()).await.status(), StatusCode::CONFLICT);
+    }
+
+    // routes without ids are not swapped in
+    std::fs::write(store.join("code"), "{}").unwrap();
+    let rsp = app.send(reload()).await;
+    assert_eq!(rsp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(get_links(&app).await, before);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn storage_stats_counts_snapshots() {
+    let app = TestApp::new();
+    let stats = || {
+        admin("GET", "/v1/admin/storage_stats")
+            .body(Body::empty())
+            .unwrap()
+    };
+    let rsp = app.send(stats()).await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+    let empty: serde_json::Value = admin_data(rsp).await;
+    assert_eq!(empty["snapshot_count"], 0);
+    assert!(empty["latest_snapshot_ts"].is_null());
+    assert_eq!(
+        empty["storage_root"],
+        app.config.storage_root.to_str().unwrap()
+    );
+
+    for _ in 0..2 {
+        let rsp = app
+            .send(
+                admin("PUT", "/admin/routing_table")
+                    .body(Body::from(TABLE))
+                    .unwrap(),
+            )
+            .await;
+        assert_eq!(rsp.status(), StatusCode::OK);
+    }
+    let rsp = app.send(stats()).await;
+    let stats: serde_json::Value = admin_data(rsp).await;
+    assert_eq!(stats["snapshot_count"], 2);
+    let ts =
+        |key: &str| chrono::DateTime::parse_from_rfc3339(stats[key].as_str().unwrap()).unwrap();
+    assert!(ts("oldest_snapshot_ts") < ts("latest_snapshot_ts"));
+    // two snapshots and the code table
+    let files: u64 = std::fs::read_dir(&app.config.storage_root)
+        .unwrap()
+        .map(|entry| entry.unwrap().metadata().unwrap().len())
+        .sum();
+    assert_eq!(stats["total_size_bytes"], files);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn unchanged_patch_writes_no_snapshot() {
+    let app = TestApp::new();
+    let patch = |table: &'static str| {
+        admin("PATCH", "/admin/routing_table")
+            .body(Body::from(table))
+            .unwrap()
+    };
+    let store = &app.config.storage_root;
+    assert_eq!(app.send(patch(TABLE)).await.status(), StatusCode::OK);
+    assert_eq!(common::snapshots(store).len(), 1);
+    let rsp = app.send(patch(TABLE)).await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+    let summary: serde_json::Value = admin_data(rsp).await;
+    assert_eq!(summary["updated"], 2);
+    assert_eq!(common::snapshots(store).len(), 1);
+
+    let changed = r#"[{"uid": "bob", "url": "https://survey.example/b", "notes": "wave 2"}]"#;
+    assert_eq!(app.send(patch(changed)).await.status(), StatusCode::OK);
+    assert_eq!(common::snapshots(store).len(), 2);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn patch_snapshots_are_debounced() {
+    let app = TestApp::with_config("snapshot_debounce_secs: 60\n");
+    let patch =
+        |uri: &str, table: &'static str| admin("PATCH", uri).body(Body::from(table)).unwrap();
+    let store = &app.config.storage_root;
+    let rsp = app.send(patch("/admin/routing_table", TABLE)).await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+    let rsp = app
+        .send(patch(
+            "/admin/routing_table",
+            r#"[{"uid": "carol", "url": "https://survey.example/c"}]"#,
+        ))
+        .await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+    // applied, but not written yet
+    assert_eq!(get_links(&app).await.len(), 3);
+    assert_eq!(common::snapshots(store).len(), 0);
+
+    // one snapshot of both
+    app.state.flush_snapshot().await.unwrap();
+    assert_eq!(common::snapshots(store).len(), 1);
+    app.state.flush_snapshot().await.unwrap();
+    assert_eq!(common::snapshots(store).len(), 1);
+    let reloaded = RouterState::init(&app.config).unwrap();
+    assert_eq!(reloaded.router_table.read().await.len(), 3);
+    assert_eq!(reloaded.table_version(), app.state.table_version());
+
+    let rsp = app
+        .send(patch(
+            "/admin/routing_table?durable=true",
+            r#"[{"uid": "dave", "url": "https://survey.example/d"}]"#,
+        ))
+        .await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+    assert_eq!(common::snapshots(store).len(), 2);
+    let reloaded = RouterState::init(&app.config).unwrap();
+    assert_eq!(reloaded.router_table.read().await.len(), 4);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn updates_write_the_pending_snapshot() {
+    let app = TestApp::with_config("snapshot_debounce_secs: 60\n");
+    let rsp = app
+        .send(
+            admin("PUT", "/admin/routing_table")
+                .body(Body::from(TABLE))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+    let rsp = app
+        .send(
+            admin("PATCH", "/admin/routing_table")
+                .body(Body::from(
+                    r#"[{"uid": "carol", "url": "https://survey.example/c"}]"#,
+                ))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+    let links = get_links(&app).await;
+
+    // written with carol's new code, before the debounce
+    let rsp = app
+        .send(
+            admin("DELETE", "/admin/routing_table")
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(r#"{"ids": ["bob"]}"#))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+    let state = RouterState::init_async(&app.config).await.unwrap();
+    assert_eq!(
+        state.get_codes().await.unwrap(),
+        app.state.get_codes().await.unwrap()
+    );
+    assert_eq!(state.router_table.read().await.len(), 2);
+    let app = TestApp {
+        app: survey_redirect::router(&app.config, state.clone()),
+        state,
+        ..app
+    };
+    assert_eq!(follow(&app, &links["carol"]).await.path(), "/c");
+    // nothing left to write
+    app.state.flush_snapshot().await.unwrap();
+    assert_eq!(common::snapshots(&app.config.storage_root).len(), 2);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn reload_waits_for_the_pending_snapshot() {
+    let app = TestApp::with_config("snapshot_debounce_secs: 60\n");
+    let reload = || admin("POST", "/admin/reload").body(Body::empty()).unwrap();
+    let rsp = app
+        .send(
+            admin("PATCH", "/admin/routing_table")
+                .body(Body::from(TABLE))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+    let rsp = app.send(reload()).await;
+    assert_eq!(rsp.status(), StatusCode::CONFLICT);
+    let body: serde_json::Value = serde_json::from_str(&body_string(rsp).await).unwrap();
+    assert_eq!(body["code"], "SNAPSHOT_PENDING");
+
+    app.state.flush_snapshot().await.unwrap();
+    let rsp = app.send(reload()).await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+    assert_eq!(get_links(&app).await.len(), 2);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn purges_write_the_pending_snapshot() {
+    let app = TestApp::with_config("snapshot_debounce_secs: 60\n");
+    let rsp = app
+        .send(
+            admin("PUT", "/admin/routing_table")
+                .body(Body::from(TABLE))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+    let rsp = app
+        .send(
+            admin("PATCH", "/admin/routing_table")
+                .header(CONTENT_TYPE, "application/merge-patch+json")
+                .body(Body::from(r#"{"bob": null}"#))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+    let rsp = app
+        .send(
+            admin("POST", "/admin/purge_orphaned_codes")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+
+    // the stored route of bob went with his code
+    let state = RouterState::init_async(&app.config).await.unwrap();
+    assert_eq!(
+        state.get_codes().await.unwrap(),
+        app.state.get_codes().await.unwrap()
+    );
+    assert_eq!(state.router_table.read().await.len(), 1);
+    let rsp = app
+        .send(admin("POST", "/admin/reload").body(Body::empty()).unwrap())
+        .await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn patch_snapshots_have_a_max_delay() {
+    let app = TestApp::with_config("snapshot_debounce_secs: 60\nsnapshot_max_delay_secs: 1\n");
+    let writer = tokio::spawn(app.state.clone().run_snapshot_writer());
+    let store = &app.config.storage_root;
+    let start = std::time::Instant::now();
+    for uid in ["alice", "bob", "carol", "dave", "erin", "frank"] {
+        let table = format!(r#"[{{"uid": "{uid}", "url": "https://survey.example/{uid}"}}]"#);
+        let rsp = app
+            .send(
+                admin("PATCH", "/admin/routing_table")
+                    .body(Body::from(table))
+                    .unwrap(),
+            )
+            .await;
+        assert_eq!(rsp.status(), StatusCode::OK);
+        if !common::snapshots(store).is_empty() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(400)).await;
+    }
+    while common::snapshots(store).is_empty() {
+        assert!(start.elapsed() < Duration::from_secs(5), "not written");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    writer.abort();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn update_only_patch_rejects_unknown_ids() {
+    let app = TestApp::new();
+    let patch =
+        |uri: &str, table: &'static str| admin("PATCH", uri).body(Body::from(table)).unwrap();
+    let rsp = app.send(patch("/admin/routing_table", TABLE)).await;
+    let summary: serde_json::Value = admin_data(rsp).await;
+    assert_eq!(summary["mode"], "upsert");
+    assert_eq!(summary["updated"], 2);
+
+    let misspelled = r#"[
+        {"uid": "bob", "url": "https://survey.example/b2"},
+        {"uid": "alcie", "url": "https://survey.example/a2"},
+        {"uid": "carol", "url": "https://survey.example/c"}
+    ]"#;
+    let rsp = app
+        .send(patch("/admin/routing_table?mode=update_only", misspelled))
+        .await;
+    assert_eq!(rsp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let body: serde_json::Value = serde_json::from_str(&body_string(rsp).await).unwrap();
+    assert_eq!(body["code"], "UNKNOWN_IDS");
+    assert!(body["message"].as_str().unwrap().ends_with("alcie, carol"));
+    assert_eq!(common::snapshots(&app.config.storage_root).len(), 1);
+    assert_eq!(get_links(&app).await.len(), 2);
+
+    let rsp = app
+        .send(patch(
+            "/admin/routing_table?mode=update_only",
+            r#"[{"uid": "bob", "url": "https://survey.example/b2"}]"#,
+        ))
+        .await;
+    let summary: serde_json::Value = admin_data(rsp).await;
+    assert_eq!(summary["mode"], "update_only");
+    assert_eq!(summary["overwritten"], serde_json::json!(["bob"]));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn merge_patch_upserts_and_removes() {
+    let app = TestApp::new();
+    let rsp = app
+        .send(
+            admin("PUT", "/admin/routing_table")
+                .body(Body::from(TABLE))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+    let links = get_links(&app).await;
+    let merge_patch = |patch: &'static str| {
+        admin("PATCH", "/admin/routing_table")
+            .header(CONTENT_TYPE, "application/merge-patch+json")
+            .body(Body::from(patch))
+            .unwrap()
+    };
+
+    let rsp = app
+        .send(merge_patch(
+            r#"{"alice": "https://survey.example/a2", "bob": null,
+                "carol": "https://survey.example/c", "dave": null}"#,
+        ))
+        .await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+    assert_eq!(rsp.headers()["x-table-version"], "2");
+    let summary: serde_json::Value = admin_data(rsp).await;
+    assert_eq!(summary["updated"], 2);
+    assert_eq!(summary["removed"], 1);
+    let patched = get_links(&app).await;
+    assert_eq!(patched["alice"], links["alice"]);
+    assert!(!patched.contains_key("bob"));
+    assert_eq!(follow(&app, &links["alice"]).await.path(), "/a2");
+    assert_eq!(follow(&app, &patched["carol"]).await.path(), "/c");
+
+    // unchanged, nothing written
+    let rsp = app
+        .send(merge_patch(
+            r#"{"alice": "https://survey.example/a2", "bob": null}"#,
+        ))
+        .await;
+    assert_eq!(rsp.headers()["x-table-version"], "2");
+    assert_eq!(common::snapshots(&app.config.storage_root).len(), 2);
+    let rsp = app.send(merge_patch(r#"{"alice": "not a url"}"#)).await;
+    assert_eq!(rsp.status(), StatusCode::BAD_REQUEST);
+
+    // patch options do not apply
+    for query in ["mode=update_only", "conflict=error", "conflict=skip"] {
+        let rsp = app
+            .send(
+                admin("PATCH", &format!("/admin/routing_table?{query}"))
+                    .header(CONTENT_TYPE, "application/merge-patch+json")
+                    .body(Body::from(r#"{"erin": "https://survey.example/e"}"#))
+                    .unwrap(),
+            )
+            .await;
+        assert_eq!(rsp.status(), StatusCode::BAD_REQUEST, "{query}");
+    }
+    assert!(!get_links(&app).await.contains_key("erin"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn uploads_return_links() {
+    let app = TestApp::new();
+    let rsp = app
+        .send(
+            admin("PUT", "/admin/routing_table?return_links=true")
+                .body(Body::from(TABLE))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+    let data: serde_json::Value = admin_data(rsp).await;
+    let links: HashMap<String, Url> = serde_json::from_value(data["links"].clone()).unwrap();
+    assert_eq!(links, get_links(&app).await);
+
+    let rsp = app
+        .send(
+            admin("PATCH", "/admin/routing_table?return_links=true")
+                .body(Body::from(
+                    r#"[{"uid": "carol", "url": "https://survey.example/c"}]"#,
+                ))
+                .unwrap(),
+        )
+        .await;
+    let data: serde_json::Value = admin_data(rsp).await;
+    assert_eq!(data["updated"], 1);
+    let links: HashMap<String, Url> = serde_json::from_value(data["links"].clone()).unwrap();
+    assert_eq!(links.keys().collect::<Vec<_>>(), ["carol"]);
+    assert_eq!(follow(&app, &links["carol"]).await.path(), "/c");
+
+    let rsp = app
+        .send(
+            admin("PATCH", "/admin/routing_table?return_links=true")
+                .header(CONTENT_TYPE, "application/merge-patch+json")
+                .body(Body::from(
+                    r#"{"carol": null, "dave": "https://survey.example/d"}"#,
+                ))
+                .unwrap(),
+        )
+        .await;
+    let data: serde_json::Value = admin_data(rsp).await;
+    assert_eq!(data["removed"], 1);
+    assert_eq!(data["links"].as_object().unwrap().len(), 1);
+    assert!(data["links"]["dave"].is_string());
+
+    // the minimal summary by default
+    let rsp = app
+        .send(
+            admin("PATCH", "/admin/routing_table")
+                .body(Body::from(TABLE))
+                .unwrap(),
+        )
+        .await;
+    let data: serde_json::Value = admin_data(rsp).await;
+    assert!(data.get("links").is_none());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn completions_are_recorded() {
+    let app = TestApp::with_config("thank_you_url: https://survey.example/thanks\n");
+    let rsp = app
+        .send(
+            admin("PUT", "/admin/routing_table")
+                .body(Body::from(TABLE))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+    let links = get_links(&app).await;
+    let complete = |link: &Url| {
+        Request::get(format!("/api/complete?{}", link.query().unwrap()))
+            .body(Body::empty())
+            .unwrap()
+    };
+    for _ in 0..2 {
+        let rsp = app.send(complete(&links["alice"])).await;
+        assert_eq!(rsp.status(), StatusCode::SEE_OTHER);
+        assert_eq!(rsp.headers()[LOCATION], "https://survey.example/thanks");
+    }
+    let rsp = app
+        .send(
+            Request::get("/api/complete?code=nope")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status(), StatusCode::NOT_FOUND);
+
+    let rsp = app
+        .send(
+            admin("GET", "/v1/admin/completions")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    let completions: serde_json::Value = admin_data(rsp).await;
+    assert_eq!(completions.as_object().unwrap().len(), 1);
+    assert_eq!(completions["alice"]["count"], 2);
+    let completed_at = completions["alice"]["completed_at"].as_str().unwrap();
+
+    let rsp = app
+        .send(
+            admin("GET", "/v1/admin/completions?format=csv")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.headers()[CONTENT_TYPE], "text/csv");
+    let csv = body_string(rsp).await;
+    let mut rows = csv.lines();
+    assert_eq!(rows.next(), Some("id,completed_at,count"));
+    let row: Vec<_> = rows.next().unwrap().split(',').collect();
+    assert_eq!(row[0], "alice");
+    assert_eq!(
+        chrono::DateTime::parse_from_rfc3339(row[1]).unwrap(),
+        chrono::DateTime::parse_from_rfc3339(completed_at).unwrap()
+    );
+    assert_eq!(row[2], "2");
+
+    // kept across restarts, and the built-in page without thank_you_url
+    app.state.flush_completions().unwrap();
+    let config = common::config(&app.dir, "");
+    let restarted = survey_redirect::router(&config, RouterState::init(&config).unwrap());
+    let rsp = common::send(&restarted, complete(&links["bob"])).await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+    assert!(body_string(rsp).await.contains("Thank you"));
+    let rsp = common::send(
+        &restarted,
+        admin("GET", "/v1/admin/completions")
+            .body(Body::empty())
+            .unwrap(),
+    )
+    .await;
+    let completions: serde_json::Value = admin_data(rsp).await;
+    assert_eq!(completions["alice"]["count"], 2);
+    assert_eq!(completions["alice"]["completed_at"], completed_at);
+    assert_eq!(completions["bob"]["count"], 1);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn participant_links_have_status() {
+    let app = TestApp::new();
+    let rsp = app
+        .send(
+            admin("PUT", "/admin/routing_table")
+                .body(Body::from(TABLE))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+    let rsp = app
+        .send(
+            admin("POST", "/admin/deactivate_codes")
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(r#"{"ids": ["bob"]}"#))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+    let links = get_links(&app).await;
+
+    let participant_links = |id: &str| {
+        admin("GET", &format!("/v1/admin/participant/{id}/links"))
+            .body(Body::empty())
+            .unwrap()
+    };
+    let rsp = app.send(participant_links("alice")).await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+    let alice: serde_json::Value = admin_data(rsp).await;
+    assert_eq!(alice.as_object().unwrap().len(), 1);
+    assert_eq!(alice["default"]["link"], links["alice"].as_str());
+    assert_eq!(alice["default"]["url"], "https://survey.example/a?wave=1");
+    assert_eq!(alice["default"]["status"], "active");
+
+    let rsp = app.send(participant_links("bob")).await;
+    let bob: serde_json::Value = admin_data(rsp).await;
+    assert_eq!(bob["default"]["status"], "disabled");
+
+    let rsp = app.send(participant_links("mallory")).await;
+    assert_eq!(rsp.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn repeated_deactivation_writes_nothing() {
+    let app = TestApp::new();
+    let rsp = app
+        .send(
+            admin("PUT", "/admin/routing_table")
+                .body(Body::from(TABLE))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+    let deactivate = |body: &'static str| {
+        admin("POST", "/admin/deactivate_codes")
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(body))
+            .unwrap()
+    };
+    app.send(deactivate(r#"{"ids": ["bob"]}"#)).await;
+    assert_eq!(common::snapshots(&app.config.storage_root).len(), 2);
+    assert_eq!(app.state.table_version(), 2);
+
+    // already deactivated, or without route
+    app.send(deactivate(r#"{"ids": ["bob", "mallory"]}"#)).await;
+    app.send(deactivate(r#"{"ids": ["mallory"]}"#)).await;
+    assert_eq!(common::snapshots(&app.config.storage_root).len(), 2);
+    assert_eq!(app.state.table_version(), 2);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn uploads_keep_deactivation() {
+    let app = TestApp::new();
+    let put = || {
+        admin("PUT", "/admin/routing_table")
+            .body(Body::from(TABLE))
+            .unwrap()
+    };
+    assert_eq!(app.send(put()).await.status(), StatusCode::OK);
+    let rsp = app
+        .send(
+            admin("POST", "/admin/deactivate_codes")
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(r#"{"ids": ["bob"]}"#))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+    let bob = get_links(&app).await["bob"].clone();
+    let redirect = || {
+        Request::get(format!("/api?{}", bob.query().unwrap()))
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    let rsp = app
+        .send(
+            admin("PATCH", "/admin/routing_table")
+                .body(Body::from(
+                    r#"[{"uid": "bob", "url": "https://survey.example/b2"}]"#,
+                ))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+    assert_eq!(app.send(redirect()).await.status(), StatusCode::GONE);
+    assert_eq!(app.send(put()).await.status(), StatusCode::OK);
+    assert_eq!(app.send(redirect()).await.status(), StatusCode::GONE);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn participant_links_span_waves() {
+    const WAVES: &str = r#"[
+        {"uid": "alice", "url": "https://survey.example/a"},
+        {"uid": "alice-2", "url": "https://survey.example/b", "participant": "alice", "wave": "w2"},
+        {"uid": "alice-3", "url": "https://survey.example/c", "participant": "alice", "wave": "w3",
+         "expires_at": "2000-01-01T00:00:00Z"},
+        {"uid": "carol-2", "url": "https://survey.example/b", "participant": "carol", "wave": "w2"}
+    ]"#;
+    let app = TestApp::new();
+    let rsp = app
+        .send(
+            admin("PUT", "/admin/routing_table")
+                .body(Body::from(WAVES))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+    let links = get_links(&app).await;
+    let participant_links = |id: &str| {
+        admin("GET", &format!("/v1/admin/participant/{id}/links"))
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    let rsp = app.send(participant_links("alice")).await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+    let alice: serde_json::Value = admin_data(rsp).await;
+    assert_eq!(alice.as_object().unwrap().len(), 3);
+    assert_eq!(alice["default"]["link"], links["alice"].as_str());
+    assert_eq!(alice["default"]["status"], "active");
+    assert_eq!(alice["w2"]["link"], links["alice-2"].as_str());
+    assert_eq!(alice["w2"]["status"], "active");
+    assert_eq!(alice["w3"]["status"], "expired");
+    assert_eq!(alice["w3"]["expires_at"], "2000-01-01T00:00:00Z");
+
+    // participants without a route of their own
+    let rsp = app.send(participant_links("carol")).await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+    let carol: serde_json::Value = admin_data(rsp).await;
+    assert_eq!(carol["w2"]["link"], links["carol-2"].as_str());
+
+    let expired = &links["alice-3"];
+    let req = Request::get(format!("/api?{}", expired.query().unwrap()))
+        .body(Body::empty())
+        .unwrap();
+    assert_eq!(app.send(req).await.status(), StatusCode::GONE);
+
+    for patch in [
+        r#"[{"uid": "alice-4", "url": "https://survey.example/d", "participant": "alice", "wave": "w2"}]"#,
+        r#"[{"uid": "alice", "url": "https://survey.example/a", "wave": "w3"}]"#,
+        r#"[{"uid": "alice-4", "url": "https://survey.example/d", "participant": "alice"}]"#,
+        r#"[{"uid": "alice-4", "url": "https://survey.example/d", "wave": "default"}]"#,
+    ] {
+        let rsp = app
+            .send(
+                admin("PATCH", "/admin/routing_table")
+                    .body(Body::from(patch))
+                    .unwrap(),
+            )
+            .await;
+        assert_eq!(rsp.status(), StatusCode::BAD_REQUEST, "{patch}");
+    }
+    // moving a route to a free wave
+    let rsp = app
+        .send(
+            admin("PATCH", "/admin/routing_table")
+                .body(Body::from(
+                    r#"[{"uid": "alice-3", "url": "https://survey.example/c", "participant": "alice", "wave": "w4"}]"#,
+                ))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn admin_token_rotation() {
+    const NEW_TOKEN: &str = "Xk3v9QpL2mRt7Wz4Yb8Nc1Hd6Jf5Gs0A";
+    let app = TestApp::new();
+    let rotate = |token: &str| {
+        admin("PATCH", "/v1/admin/admin_token")
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(format!(r#"{{"new_token": "{token}"}}"#)))
+            .unwrap()
+    };
+    let runtime_info = |token: &str| {
+        Request::get("/v1/admin/runtime_info")
+            .header(AUTHORIZATION, format!("Bearer {token}"))
+            .body(Body::empty())
+            .unwrap()
+    };
+    for weak in ["short", &"a".repeat(40)] {
+        let rsp = app.send(rotate(weak)).await;
+        assert_eq!(rsp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+    let rsp = app.send(runtime_info(common::ADMIN_TOKEN)).await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+
+    let rsp = app.send(rotate(NEW_TOKEN)).await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+    let rsp = app.send(runtime_info(common::ADMIN_TOKEN)).await;
+    assert_eq!(rsp.status(), StatusCode::UNAUTHORIZED);
+    let rsp = app.send(runtime_info(NEW_TOKEN)).await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let secret = app.config.storage_root.join("admin_token.secret");
+        let mode = std::fs::metadata(secret).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+    // preferred over the config file after a restart
+    let config = common::config(&app.dir, "");
+    assert_eq!(config.admin_token, NEW_TOKEN);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn served_under_path_prefix() {
+    let app = TestApp::with_config("path_prefix: /redirect\n");
+    let rsp = app
+        .send(
+            admin("PUT", "/redirect/v1/admin/routing_table")
+                .body(Body::from(TABLE))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+    let rsp = app
+        .send(
+            admin("GET", "/redirect/v1/admin/get_links")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    let links = links_data(rsp).await;
+    let alice = &links["alice"];
+    assert_eq!(alice.path(), "/redirect/api");
+    let target = follow_path(&app, "/redirect/api", alice).await;
+    assert_eq!(target.path(), "/a");
+    let rsp = app
+        .send(
+            Request::get(format!("/api?{}", alice.query().unwrap()))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status(), StatusCode::NOT_FOUND);
+
+    // the path of base_url is kept
+    let mut config = app.config;
+    config.base_url = Url::parse("https://www.example.org/proxy/").unwrap();
+    assert_eq!(
+        config.api_url().as_str(),
+        "https://www.example.org/proxy/redirect/api"
+    );
+    config.path_prefix = None;
+    assert_eq!(
+        config.api_url().as_str(),
+        "https://www.example.org/proxy/api"
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn loops_and_denied_targets_are_rejected() {
+    let app = TestApp::with_config(
+        "denied_target_hosts: [evil.example]\nblocked_referrers: ['^https://preview\\.example/']\n",
+    );
+    let put = |table: String| {
+        admin("PUT", "/admin/routing_table")
+            .body(Body::from(table))
+            .unwrap()
+    };
+    for url in [
+        "https://redirect.example/api?code=x",
+        "https://EVIL.example/survey",
+    ] {
+        let rsp = app
+            .send(put(format!(r#"[{{"uid": "alice", "url": "{url}"}}]"#)))
+            .await;
+        assert_eq!(rsp.status(), StatusCode::BAD_REQUEST, "{url}");
+    }
+    let rsp = app.send(put(TABLE.to_owned())).await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+    let links = get_links(&app).await;
+
+    let rsp = app
+        .send(
+            Request::get(format!("/api?{}", links["alice"].query().unwrap()))
+                .header(REFERER, "https://preview.example/card")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status(), StatusCode::NO_CONTENT);
+    let rsp = app
+        .send(
+            admin("GET", "/admin/route_stats")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    let stats: HashMap<String, u64> = admin_data(rsp).await;
+    assert_eq!(stats["alice"], 0);
+
+    // routes stored before their host was denied
+    let mut config = common::config(&app.dir, "");
+    config.denied_target_hosts = vec!["survey.example".to_owned()];
+    app.state
+        .set_redirect_policy(RedirectPolicy::from_config(&config).unwrap());
+    let rsp = app
+        .send(
+            Request::get(format!("/api?{}", links["bob"].query().unwrap()))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status(), StatusCode::FORBIDDEN);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn admin_errors_are_enveloped() {
+    let app = TestApp::new();
+    let error = |rsp| async {
+        let envelope: serde_json::Value = serde_json::from_str(&body_string(rsp).await).unwrap();
+        assert_eq!(envelope["status"], "error");
+        assert!(envelope["ts"].as_str().unwrap().ends_with('Z'));
+        assert!(envelope["message"].is_string());
+        envelope["code"].as_str().unwrap().to_owned()
+    };
+    let rsp = app
+        .send(
+            admin("GET", "/v1/admin/route_history?id=mallory")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status(), StatusCode::NOT_FOUND);
+    assert_eq!(error(rsp).await, "UNKNOWN_ID");
+
+    let rsp = app
+        .send(
+            admin("PUT", "/v1/admin/routing_table")
+                .body(Body::from("not a table"))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(error(rsp).await, "INVALID_TABLE");
+
+    let rsp = app
+        .send(
+            Request::get("/v1/admin/runtime_info")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(error(rsp).await, "UNAUTHORIZED");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn init_async_loads_stored_tables() {
+    let app = TestApp::new();
+    let rsp = app
+        .send(
+            admin("PUT", "/admin/routing_table")
+                .body(Body::from(TABLE))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+
+    let state = RouterState::init_async(&app.config).await.unwrap();
+    assert_eq!(
+        state.get_codes().await.unwrap(),
+        app.state.get_codes().await.unwrap()
+    );
+    assert_eq!(state.router_table.read().await.len(), 2);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn html_redirect_mode() {
+    let template = tempfile::NamedTempFile::new().unwrap();
+    std::fs::write(template.path(), "<a href=\"{{url}}\">go</a>").unwrap();
+    let app = TestApp::with_config(&format!(
+        "redirect_mode: html\nredirect_page_template: {}\n",
+        template.path().display()
+    ));
+    let rsp = app
+        .send(
+            admin("PUT", "/admin/routing_table")
+                .body(Body::from(
+                    r#"[
+                        {"uid": "alice", "url": "https://survey.example/a?wave=1"},
+                        {"uid": "bob", "url": "https://survey.example/b", "redirect_mode": "http"}
+                    ]"#,
+                ))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+    let links = get_links(&app).await;
+
+    let rsp = app
+        .send(
+            Request::get(format!("/api?{}", links["alice"].query().unwrap()))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+    assert!(rsp.headers()[CONTENT_TYPE]
+        .to_str()
+        .unwrap()
+        .starts_with("text/html"));
+    let code = links["alice"].query_pairs().next().unwrap().1.into_owned();
+    assert_eq!(
+        body_string(rsp).await,
+        format!("<a href=\"https://survey.example/a?wave=1&amp;externalUserId={code}\">go</a>")
+    );
+    assert_eq!(follow(&app, &links["bob"]).await.path(), "/b");
+
+    let rsp = app
+        .send(
+            admin("GET", "/admin/route_stats")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    let stats: HashMap<String, u64> = admin_data(rsp).await;
+    assert_eq!(stats["alice"], 1);
+    assert_eq!(stats["bob"], 1);
+}
+
+#[test]
+fn redirect_page_template_needs_placeholder() {
+    let template = tempfile::NamedTempFile::new().unwrap();
+    std::fs::write(template.path(), "<p>no link</p>").unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let yaml = format!(
+        "server_binding: 127.0.0.1:0\nbase_url: {}\nadmin_token: \"{}\"\nstorage_root: {}\nlog_file: {}\nredirect_page_template: {}\n",
+        common::BASE_URL,
+        common::ADMIN_TOKEN,
+        dir.path().join("db").display(),
+        dir.path().join("log").display(),
+        template.path().display(),
+    );
+    assert!(survey_redirect::config::Config::from_yaml(&yaml).is_err());
+}
+
+#[test]
+fn code_prefix_leaves_enough_random_characters() {
+    let dir = tempfile::tempdir().unwrap();
+    let yaml = |extra: &str| {
+        format!(
+            "server_binding: 127.0.0.1:0\nbase_url: {}\nadmin_token: \"{}\"\nstorage_root: {}\nlog_file: {}\n{extra}",
+            common::BASE_URL,
+            common::ADMIN_TOKEN,
+            dir.path().join("db").display(),
+            dir.path().join("log").display(),
+        )
+    };
+    let err =
+        survey_redirect::config::Config::from_yaml(&yaml("code_length: 8\ncode_prefix: abcdefg\n"))
+            .err()
+            .unwrap();
+    assert!(err.to_string().contains("less than 8 random characters"));
+    assert!(survey_redirect::config::Config::from_yaml(&yaml(
+        "code_length: 15\ncode_prefix: abcdefgh\n"
+    ))
+    .is_err());
+    assert!(survey_redirect::config::Config::from_yaml(&yaml(
+        "code_length: 16\ncode_prefix: abcdefgh\n"
+    ))
+    .is_ok());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn error_pages_are_reloaded() {
+    let templates = tempfile::tempdir().unwrap();
+    let app = TestApp::with_config(&format!(
+        "error_templates_dir: {}\n",
+        templates.path().display()
+    ));
+    let _watcher = app.state.watch_error_templates().unwrap().unwrap();
+    let invalid = || Request::get("/api?code=nope").body(Body::empty()).unwrap();
+
+    // plain text without the page
+    let rsp = app.send(invalid()).await;
+    assert_eq!(rsp.status(), StatusCode::NOT_FOUND);
+    assert_eq!(body_string(rsp).await, "invalid code");
+
+    std::fs::write(templates.path().join("404.html"), "<p>unknown link</p>").unwrap();
+    let start = std::time::Instant::now();
+    // the page may be read between its creation and the write
+    loop {
+        let rsp = app.send(invalid()).await;
+        assert_eq!(rsp.status(), StatusCode::NOT_FOUND);
+        if body_string(rsp).await == "<p>unknown link</p>" {
+            break;
+        }
+        assert!(start.elapsed() < Duration::from_secs(10), "not reloaded");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    let rsp = app.send(invalid()).await;
+    assert!(rsp.headers()[CONTENT_TYPE]
+        .to_str()
+        .unwrap()
+        .starts_with("text/html"));
+
+    // loaded at startup too
+    let state = RouterState::init_async(&app.config).await.unwrap();
+    let rsp = state.error_pages().not_found();
+    assert_eq!(body_string(rsp).await, "<p>unknown link</p>");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn external_id_is_not_duplicated() {
+    let app = TestApp::new();
+    let rsp = app
+        .send(
+            admin("PUT", "/admin/routing_table")
+                .body(Body::from(
+                    r#"[
+                        {"uid": "alice", "url": "https://survey.example/a?externalUserId=old&wave=1"},
+                        {"uid": "bob", "url": "https://survey.example/b?EXTERNALUSERID=x"},
+                        {"uid": "carol", "url": "https://survey.example/c?wave=2&external%55serId=y&externalUserId=z"}
+                    ]"#,
+                ))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+    let links = get_links(&app).await;
+    for (id, query) in [
+        ("alice", vec![("wave", "1")]),
+        ("bob", vec![]),
+        ("carol", vec![("wave", "2")]),
+    ] {
+        let url = follow(&app, &links[id]).await;
+        let code = links[id].query_pairs().next().unwrap().1.into_owned();
+        let pairs: Vec<(String, String)> = url.query_pairs().into_owned().collect();
+        let mut expected: Vec<(String, String)> = query
+            .into_iter()
+            .map(|(k, v)| (k.to_owned(), v.to_owned()))
+            .collect();
+        expected.push(("externalUserId".to_owned(), code));
+        assert_eq!(pairs, expected, "{id}");
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn global_query_params_are_appended() {
+    let app =
+        TestApp::with_config("global_query_params: {utm_source: survey-redirect, wave: \"9\"}\n");
+    let rsp = app
+        .send(
+            admin("PUT", "/admin/routing_table")
+                .body(Body::from(TABLE))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+    let links = get_links(&app).await;
+    let query = |url: Url| -> Vec<(String, String)> {
+        url.query_pairs()
+            .into_owned()
+            .map(|(name, value)| match name.as_str() {
+                "externalUserId" => (name, "<code>".to_owned()),
+                _ => (name, value),
+            })
+            .collect()
+    };
+    let pairs = |pairs: &[(&str, &str)]| -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    };
+
+    // `wave` of the target wins
+    assert_eq!(
+        query(follow(&app, &links["alice"]).await),
+        pairs(&[
+            ("wave", "1"),
+            ("externalUserId", "<code>"),
+            ("utm_source", "survey-redirect")
+        ])
+    );
+    assert_eq!(
+        query(follow(&app, &links["bob"]).await),
+        pairs(&[
+            ("externalUserId", "<code>"),
+            ("utm_source", "survey-redirect"),
+            ("wave", "9")
+        ])
+    );
+
+    let dir = tempfile::tempdir().unwrap();
+    let config = common::config(&dir, "global_append_params: {source: survey-redirect}\n");
+    assert_eq!(config.global_query_params["source"], "survey-redirect");
+
+    app.state.set_global_query_params(Default::default());
+    assert_eq!(
+        query(follow(&app, &links["bob"]).await),
+        pairs(&[("externalUserId", "<code>")])
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn get_routing_table_as_uploaded() {
+    let app = TestApp::new();
+    let rsp = app
+        .send(
+            admin("PUT", "/admin/routing_table")
+                .body(Body::from(
+                    r#"[
+                        {"uid": "bob", "url": "https://survey.example/b", "notes": "n, 1"},
+                        {"uid": "alice", "url": "https://survey.example/a?wave=1", "mobile_url": "https://m.example/a"}
+                    ]"#,
+                ))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+    let get = |query: &'static str| {
+        admin("GET", &format!("/admin/routing_table{query}"))
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    let rsp = app.send(get("")).await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+    let table: serde_json::Value = admin_data(rsp).await;
+    assert_eq!(
+        table,
+        serde_json::json!([
+            {"uid": "alice", "url": "https://survey.example/a?wave=1", "mobile_url": "https://m.example/a"},
+            {"uid": "bob", "url": "https://survey.example/b", "notes": "n, 1"}
+        ])
+    );
+
+    // a route whose id went missing
+    app.state
+        .code_table
+        .lock()
+        .await
+        .retain(|id, _| id.to_string() != "bob");
+    let rsp = app.send(get("?format=csv")).await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+    assert_eq!(rsp.headers()[CONTENT_TYPE], "text/csv");
+    assert_eq!(
+        body_string(rsp).await,
+        "uid,url,mobile_url,description,notes,orphaned\n\
+         alice,https://survey.example/a?wave=1,https://m.example/a,,,false\n\
+         ,https://survey.example/b,,,\"n, 1\",true\n"
+    );
+    let rsp = app.send(get("")).await;
+    let table: serde_json::Value = admin_data(rsp).await;
+    assert_eq!(table[1]["uid"], serde_json::Value::Null);
+    assert_eq!(table[1]["orphaned"], true);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn if_match_rejects_stale_versions() {
+    let app = TestApp::new();
+    let version = |rsp: &axum::response::Response| -> u64 {
+        rsp.headers()["x-table-version"]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap()
+    };
+    let put = |if_match: Option<&str>| {
+        let req = admin("PUT", "/admin/routing_table");
+        match if_match {
+            Some(if_match) => req.header("if-match", if_match),
+            None => req,
+        }
+        .body(Body::from(TABLE))
+        .unwrap()
+    };
+    let rsp = app.send(put(None)).await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+    assert_eq!(version(&rsp), 1);
+    let rsp = app
+        .send(
+            admin("GET", "/admin/routing_table")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(version(&rsp), 1);
+    let rsp = app
+        .send(
+            admin("GET", "/admin/get_links")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(version(&rsp), 1);
+
+    let rsp = app.send(put(Some(r#""1""#))).await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+    assert_eq!(version(&rsp), 2);
+    // the other coordinator still has version 1
+    let rsp = app.send(put(Some("1"))).await;
+    assert_eq!(rsp.status(), StatusCode::PRECONDITION_FAILED);
+    assert_eq!(version(&rsp), 2);
+    let rsp = app
+        .send(
+            admin("PATCH", "/admin/routing_table")
+                .header("if-match", "1")
+                .body(Body::from(TABLE))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status(), StatusCode::PRECONDITION_FAILED);
+    assert_eq!(
+        app.send(put(Some("latest"))).await.status(),
+        StatusCode::BAD_REQUEST
+    );
+    assert_eq!(common::snapshots(&app.config.storage_root).len(), 2);
+
+    let rsp = app
+        .send(
+            admin("GET", "/admin/storage_stats")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    let stats: serde_json::Value = admin_data(rsp).await;
+    assert_eq!(stats["table_version"], 2);
+    // survives a restart
+    let state = RouterState::init_async(&app.config).await.unwrap();
+    assert_eq!(state.table_version(), 2);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn delete_routes_in_bulk() {
+    let app = TestApp::new();
+    let rsp = app
+        .send(
+            admin("PUT", "/admin/routing_table")
+                .body(Body::from(TABLE))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+    let links = get_links(&app).await;
+    let delete = |body: &'static str| {
+        admin("DELETE", "/admin/routing_table")
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(body))
+            .unwrap()
+    };
+
+    let rsp = app.send(delete(r#"{"ids": ["alice", "mallory"]}"#)).await;
+    assert_eq!(rsp.status(), StatusCode::MULTI_STATUS);
+    let result: serde_json::Value = admin_data(rsp).await;
+    assert_eq!(result["removed"], serde_json::json!(["alice"]));
+    assert_eq!(result["not_found"], serde_json::json!(["mallory"]));
+    let rsp = app
+        .send(
+            Request::get(format!("/api?{}", links["alice"].query().unwrap()))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status(), StatusCode::NOT_FOUND);
+    assert_eq!(follow(&app, &links["bob"]).await.path(), "/b");
+    assert_eq!(common::snapshots(&app.config.storage_root).len(), 2);
+
+    // nothing removed, nothing written
+    let rsp = app.send(delete(r#"{"ids": ["alice"]}"#)).await;
+    assert_eq!(rsp.status(), StatusCode::MULTI_STATUS);
+    assert_eq!(common::snapshots(&app.config.storage_root).len(), 2);
+    let rsp = app.send(delete(r#"{"ids": ["bob"]}"#)).await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+    assert!(get_links(&app).await.is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn admin_allowed_networks() {
+    let app = TestApp::with_config(
+        "trusted_proxies: [127.0.0.1/32]\nadmin_allowed_networks: [10.0.0.0/8]\n",
+    );
+    let from = |ip: Option<&str>, path: &str| {
+        let req = admin("GET", path);
+        match ip {
+            Some(ip) => req.header("x-forwarded-for", ip),
+            None => req,
+        }
+        .body(Body::empty())
+        .unwrap()
+    };
+    // the proxy itself is outside of the allowed networks
+    let rsp = app.send(from(None, "/admin/get_codes")).await;
+    assert_eq!(rsp.status(), StatusCode::FORBIDDEN);
+    let rsp = app.send(from(Some("192.0.2.1"), "/admin/ping")).await;
+    assert_eq!(rsp.status(), StatusCode::FORBIDDEN);
+    // checked before the token
+    let rsp = app
+        .send(
+            Request::get("/admin/get_codes")
+                .header("x-forwarded-for", "192.0.2.1")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status(), StatusCode::FORBIDDEN);
+
+    for ip in ["10.1.2.3", "::ffff:10.1.2.3"] {
+        let rsp = app.send(from(Some(ip), "/admin/get_codes")).await;
+        assert_eq!(rsp.status(), StatusCode::OK, "{ip}");
+    }
+    // the public api is not restricted
+    let rsp = app
+        .send(Request::get("/healthz").body(Body::empty()).unwrap())
+        .await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn admin_token_guesses_are_throttled() {
+    let app = TestApp::with_config("admin_auth_max_failures: 3\n");
+    let guess = || {
+        Request::get("/admin/get_codes")
+            .header(AUTHORIZATION, "Bearer guess")
+            .body(Body::empty())
+            .unwrap()
+    };
+    let valid = || {
+        admin("GET", "/admin/get_codes")
+            .body(Body::empty())
+            .unwrap()
+    };
+    // a valid token resets the failures
+    for _ in 0..2 {
+        assert_eq!(app.send(guess()).await.status(), StatusCode::UNAUTHORIZED);
+    }
+    assert_eq!(app.send(valid()).await.status(), StatusCode::OK);
+    for _ in 0..3 {
+        assert_eq!(app.send(guess()).await.status(), StatusCode::UNAUTHORIZED);
+    }
+    // throttled, even with the valid token
+    let rsp = app.send(valid()).await;
+    assert_eq!(rsp.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(rsp.headers()[RETRY_AFTER], "1");
+    // unauthenticated routes are not throttled
+    let rsp = app
+        .send(Request::get("/admin/ping").body(Body::empty()).unwrap())
+        .await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    assert_eq!(app.send(guess()).await.status(), StatusCode::UNAUTHORIZED);
+    // the backoff doubles
+    let rsp = app.send(valid()).await;
+    assert_eq!(rsp.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(rsp.headers()[RETRY_AFTER], "2");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn ipv6_token_guesses_are_throttled_by_network() {
+    let app = TestApp::with_config("admin_auth_max_failures: 3\ntrusted_proxies: [127.0.0.1/32]\n");
+    let guess = |ip: &str| {
+        Request::get("/admin/get_codes")
+            .header(AUTHORIZATION, "Bearer guess")
+            .header("x-forwarded-for", ip)
+            .body(Body::empty())
+            .unwrap()
+    };
+    let valid = |ip: &str| {
+        admin("GET", "/admin/get_codes")
+            .header("x-forwarded-for", ip)
+            .body(Body::empty())
+            .unwrap()
+    };
+    // every guess from a new address of one /64
+    for ip in ["2001:db8::1", "2001:db8::2", "2001:db8::3"] {
+        assert_eq!(app.send(guess(ip)).await.status(), StatusCode::UNAUTHORIZED);
+    }
+    let rsp = app.send(valid("2001:db8::ffff:4")).await;
+    assert_eq!(rsp.status(), StatusCode::TOO_MANY_REQUESTS);
+    let rsp = app.send(valid("2001:db8:0:1::1")).await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn cancelled_put_writes_nothing() {
+    let app = TestApp::new();
+    let cancelled = tokio_util::sync::CancellationToken::new();
+    cancelled.cancel();
+    let result = app
+        .state
+        .put_routing_table_cancellable(serde_json::from_str(TABLE).unwrap(), None, &cancelled)
+        .await;
+    assert!(matches!(result, Err(StateError::Cancelled)));
+    assert!(app.state.get_codes().await.unwrap().is_empty());
+    assert!(common::snapshots(&app.config.storage_root).is_empty());
+    assert_eq!(app.state.table_version(), 0);
+
+    // the lock is released
+    let result = app
+        .state
+        .put_routing_table(serde_json::from_str(TABLE).unwrap(), None)
+        .await;
+    assert_eq!(result.unwrap(), 1);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn reload_cert_signals_the_cert_watchers() {
+    let app = TestApp::new();
+    let reload_cert = || {
+        admin("POST", "/admin/reload_cert")
+            .body(Body::empty())
+            .unwrap()
+    };
+    let rsp = app.send(reload_cert()).await;
+    assert_eq!(rsp.status(), StatusCode::CONFLICT);
+
+    let (tx, mut rx) = tokio::sync::watch::channel(CertReload::FileChange);
+    app.state.set_cert_reload(vec![tx]);
+    let rsp = app.send(reload_cert()).await;
+    assert_eq!(rsp.status(), StatusCode::ACCEPTED);
+    assert!(rx.has_changed().unwrap());
+    assert_eq!(*rx.borrow_and_update(), CertReload::Forced);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn rekey_keeps_the_links() {
+    let app = TestApp::new();
+    let rsp = app
+        .send(
+            admin("PUT", "/admin/routing_table")
+                .body(Body::from(TABLE))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+    let links = get_links(&app).await;
+    let rekey = |body: &'static str| {
+        admin("POST", "/admin/rekey")
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(body))
+            .unwrap()
+    };
+
+    let rsp = app
+        .send(rekey(r#"{"old_id": "alice", "new_id": "carol"}"#))
+        .await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+    assert_eq!(rsp.headers()["x-table-version"], "2");
+    let rekeyed = get_links(&app).await;
+    assert_eq!(rekeyed["carol"], links["alice"]);
+    assert!(!rekeyed.contains_key("alice"));
+    assert_eq!(follow(&app, &links["alice"]).await.path(), "/a");
+    // the version is stored with the routes
+    assert_eq!(common::snapshots(&app.config.storage_root).len(), 2);
+
+    // a version from before the rekey is stale
+    let rsp = app
+        .send(
+            admin("POST", "/admin/rekey")
+                .header(CONTENT_TYPE, "application/json")
+                .header("if-match", "1")
+                .body(Body::from(r#"{"old_id": "carol", "new_id": "alice"}"#))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status(), StatusCode::PRECONDITION_FAILED);
+    assert_eq!(get_links(&app).await, rekeyed);
+
+    let rsp = app
+        .send(rekey(r#"{"old_id": "alice", "new_id": "dave"}"#))
+        .await;
+    assert_eq!(rsp.status(), StatusCode::NOT_FOUND);
+    // all or nothing, in order
+    let rsp = app
+        .send(rekey(
+            r#"[{"old_id": "carol", "new_id": "dave"}, {"old_id": "bob", "new_id": "dave"}]"#,
+        ))
+        .await;
+    assert_eq!(rsp.status(), StatusCode::CONFLICT);
+    assert_eq!(get_links(&app).await, rekeyed);
+    let rsp = app
+        .send(rekey(
+            r#"[{"old_id": "carol", "new_id": "dave"}, {"old_id": "bob", "new_id": "carol"}]"#,
+        ))
+        .await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+    let rekeyed = get_links(&app).await;
+    assert_eq!(rekeyed["dave"], links["alice"]);
+    assert_eq!(rekeyed["carol"], links["bob"]);
+
+    // persisted
+    let state = RouterState::init(&app.config).unwrap();
+    let links: HashMap<String, Url> = state
+        .links()
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|(id, url)| (id.to_string(), url))
+        .collect();
+    assert_eq!(links, rekeyed);
+    assert_eq!(state.table_version(), 3);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn validate_reports_inconsistencies() {
+    let app = TestApp::new();
+    let validate = || async {
+        let rsp = app
+            .send(admin("GET", "/admin/validate").body(Body::empty()).unwrap())
+            .await;
+        admin_data::<serde_json::Value>(rsp).await
+    };
+    let rsp = app
+        .send(
+            admin("PUT", "/admin/routing_table")
+                .body(Body::from(TABLE))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+    let report = validate().await;
+    assert_eq!(report["routes"], 2);
+    assert_eq!(report["codes"], 2);
+    for check in [
+        "routes_without_id",
+        "ids_without_route",
+        "duplicate_codes",
+        "invalid_routes",
+    ] {
+        assert_eq!(report[check]["count"], 0, "{check}");
+    }
+
+    let rsp = app
+        .send(
+            admin("DELETE", "/admin/routing_table")
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(r#"{"ids": ["bob"]}"#))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+    let alice_code = app
+        .state
+        .code_table
+        .lock()
+        .await
+        .remove(&serde_json::from_str("\"alice\"").unwrap())
+        .unwrap();
+    let mut config = common::config(&app.dir, "");
+    config.denied_target_hosts = vec!["survey.example".to_owned()];
+    app.state
+        .set_redirect_policy(RedirectPolicy::from_config(&config).unwrap());
+
+    let report = validate().await;
+    assert_eq!(
+        report["ids_without_route"]["samples"],
+        serde_json::json!(["bob"])
+    );
+    assert_eq!(
+        report["routes_without_id"]["samples"],
+        serde_json::json!([alice_code])
+    );
+    assert_eq!(report["invalid_routes"]["count"], 1);
+    assert_eq!(
+        report["invalid_routes"]["samples"][0]["id"],
+        serde_json::Value::Null
+    );
+    assert!(report["invalid_routes"]["samples"][0]["error"]
+        .as_str()
+        .unwrap()
+        .contains("denied"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn non_http_targets_are_rejected() {
+    let app = TestApp::with_config("redirect_mode: html\n");
+    for table in [
+        r#"[{"uid": "alice", "url": "javascript:alert(1)"}]"#,
+        r#"[{"uid": "alice", "url": "https://survey.example/a", "mobile_url": "data:text/html,<script>alert(1)</script>"}]"#,
+    ] {
+        let rsp = app
+            .send(
+                admin("PUT", "/admin/routing_table")
+                    .body(Body::from(table))
+                    .unwrap(),
+            )
+            .await;
+        assert_eq!(rsp.status(), StatusCode::BAD_REQUEST, "{table}");
+        assert!(body_string(rsp).await.contains("is not http or https"));
+    }
+
+    // stored before the check
+    let code: survey_redirect::state::Code = serde_json::from_str("\"AAAAAAAAAAAAAAAA\"").unwrap();
+    let entry = serde_json::from_value(serde_json::json!({"url": "javascript:alert(1)"})).unwrap();
+    app.state.router_table.write().await.insert(code, entry);
+    app.state.code_table.lock().await.insert(
+        serde_json::from_str("\"alice\"").unwrap(),
+        serde_json::from_str("\"AAAAAAAAAAAAAAAA\"").unwrap(),
+    );
+    let rsp = app
+        .send(admin("GET", "/admin/validate").body(Body::empty()).unwrap())
+        .await;
+    let report: serde_json::Value = admin_data(rsp).await;
+    assert_eq!(report["invalid_routes"]["count"], 1);
+    assert_eq!(report["invalid_routes"]["samples"][0]["id"], "alice");
+    let rsp = app
+        .send(
+            Request::get("/api?code=AAAAAAAAAAAAAAAA")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status(), StatusCode::FORBIDDEN);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn orphaned_codes_are_listed_and_purged() {
+    let app = TestApp::new();
+    let orphaned = || async {
+        let rsp = app
+            .send(
+                admin("GET", "/admin/codes_without_routes")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await;
+        assert_eq!(rsp.status(), StatusCode::OK);
+        admin_data::<serde_json::Value>(rsp).await
+    };
+    let put = || {
+        admin("PUT", "/admin/routing_table")
+            .body(Body::from(TABLE))
+            .unwrap()
+    };
+    assert_eq!(app.send(put()).await.status(), StatusCode::OK);
+    let links = get_links(&app).await;
+    assert_eq!(orphaned().await["count"], 0);
+
+    let rsp = app
+        .send(
+            admin("DELETE", "/admin/routing_table")
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(r#"{"ids": ["bob"]}"#))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+    assert_eq!(
+        orphaned().await,
+        serde_json::json!({"orphaned": ["bob"], "count": 1})
+    );
+
+    let purge = || {
+        admin("POST", "/admin/purge_orphaned_codes")
+            .body(Body::empty())
+            .unwrap()
+    };
+    let rsp = app.send(purge()).await;
+    assert_eq!(rsp.headers()["x-table-version"], "3");
+    let purged: serde_json::Value = admin_data(rsp).await;
+    assert_eq!(purged, serde_json::json!({"orphaned": ["bob"], "count": 1}));
+    assert_eq!(orphaned().await["count"], 0);
+    // nothing purged, the version stays
+    let rsp = app.send(purge()).await;
+    assert_eq!(rsp.headers()["x-table-version"], "3");
+    let purged: serde_json::Value = admin_data(rsp).await;
+    assert_eq!(purged["count"], 0);
+    let reloaded = RouterState::init(&app.config).unwrap();
+    assert_eq!(reloaded.code_table.lock().await.len(), 1);
+    assert_eq!(reloaded.table_version(), 3);
+
+    // a new code, the old link stays invalid
+    assert_eq!(app.send(put()).await.status(), StatusCode::OK);
+    let relinked = get_links(&app).await;
+    assert_eq!(relinked["alice"], links["alice"]);
+    assert_ne!(relinked["bob"], links["bob"]);
+    let rsp = app
+        .send(
+            Request::get(format!("/api?{}", links["bob"].query().unwrap()))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn sequential_codes_continue_after_restart() {
+    let app =
+        TestApp::with_config("code_gen_mode: sequential\ncode_length: 8\ncode_prefix: abcdef\n");
+    let patch = |table: String| {
+        admin("PATCH", "/admin/routing_table")
+            .body(Body::from(table))
+            .unwrap()
+    };
+    let route = |uid: &str| format!(r#"{{"uid": "{uid}", "url": "https://survey.example/{uid}"}}"#);
+    let rsp = app
+        .send(patch(format!("[{}, {}]", route("a"), route("b"))))
+        .await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+    let mut codes: Vec<String> = app
+        .state
+        .get_codes()
+        .await
+        .unwrap()
+        .into_values()
+        .map(|code| code.to_string())
+        .collect();
+    codes.sort();
+    assert_eq!(codes, ["abcdef01", "abcdef02"]);
+
+    // a new counter, as after a restart
+    let config = common::config(
+        &app.dir,
+        "code_gen_mode: sequential\ncode_length: 8\ncode_prefix: abcdef\n",
+    );
+    let state = RouterState::init_async(&config).await.unwrap();
+    let app = TestApp {
+        app: survey_redirect::router(&config, state.clone()),
+        state,
+        config,
+        ..app
+    };
+    let rsp = app.send(patch(format!("[{}]", route("c")))).await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+    let codes = app.state.get_codes().await.unwrap();
+    assert_eq!(
+        codes[&serde_json::from_str("\"c\"").unwrap()].to_string(),
+        "abcdef03"
+    );
+
+    // two digits only
+    let table: Vec<String> = (0..96).map(|i| route(&format!("p{i}"))).collect();
+    let rsp = app.send(patch(format!("[{}]", table.join(", ")))).await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+    let rsp = app.send(patch(format!("[{}]", route("q")))).await;
+    assert_eq!(rsp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(app.state.get_codes().await.unwrap().len(), 99);
+}
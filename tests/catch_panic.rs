@@ -0,0 +1,61 @@
+mod common;
+
+use axum::{
+    body::Body,
+    http::{Request, StatusCode},
+    routing::get,
+    Router,
+};
+use common::{body_string, send};
+use std::{
+    io::Write,
+    sync::{Arc, Mutex},
+};
+use survey_redirect::{
+    catch_panic::install_panic_hook, request_id::X_REQUEST_ID, state::RouterState,
+    with_common_layers,
+};
+
+/// log output collected in memory.
+#[derive(Clone, Default)]
+struct Captured(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Captured {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+async fn boom() -> &'static str {
+    panic!("deliberate test panic")
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn panic_becomes_500_and_is_logged() {
+    let captured = Captured::default();
+    let writer = captured.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_ansi(false)
+        .with_writer(move || writer.clone())
+        .finish();
+    let _guard = tracing::subscriber::set_default(subscriber);
+    install_panic_hook();
+
+    let dir = tempfile::tempdir().unwrap();
+    let config = common::config(&dir, "");
+    let state = RouterState::init(&config).unwrap();
+    let app = with_common_layers(Router::new().route("/boom", get(boom)), &config, state);
+
+    let rsp = send(&app, Request::get("/boom").body(Body::empty()).unwrap()).await;
+    assert_eq!(rsp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    let request_id = rsp.headers()[X_REQUEST_ID].to_str().unwrap().to_owned();
+    assert!(body_string(rsp).await.contains(&request_id));
+
+    let logs = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+    assert!(logs.contains("deliberate test panic"), "{logs}");
+    assert!(logs.contains(&request_id), "{logs}");
+}
@@ -0,0 +1,118 @@
+use ring::digest::{digest, SHA256};
+use std::{
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+use survey_redirect::{
+    certs::{cert_fingerprint, cert_provider_from_file, CertRole},
+    config::{Config, TlsConfig},
+};
+
+/// write a new self-signed cert, return the fingerprint it should have.
+fn write_cert(tls_config: &TlsConfig) -> String {
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+    std::fs::write(&tls_config.key, cert.key_pair.serialize_pem()).unwrap();
+    std::fs::write(&tls_config.cert, cert.cert.pem()).unwrap();
+    digest(&SHA256, cert.cert.der())
+        .as_ref()
+        .iter()
+        .map(|b| format!("{b:02X}"))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+#[test]
+fn fingerprint_follows_cert_reload() {
+    let dir = tempfile::tempdir().unwrap();
+    let tls_config = TlsConfig {
+        key: dir.path().join("key.pem"),
+        cert: dir.path().join("cert.pem"),
+        session_tickets: true,
+        ticket_rotation_secs: 3600,
+        max_early_data_size: 0,
+    };
+    let first = write_cert(&tls_config);
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let _acceptor = cert_provider_from_file(
+        Some(tls_config.clone()),
+        CertRole::Server,
+        &None::<PathBuf>,
+        &rt,
+    )
+    .unwrap()
+    .unwrap();
+    assert_eq!(
+        cert_fingerprint(CertRole::Server).as_deref(),
+        Some(first.as_str())
+    );
+    assert_eq!(cert_fingerprint(CertRole::Admin), None);
+    assert_eq!(first.len(), 32 * 3 - 1);
+
+    let second = write_cert(&tls_config);
+    let start = Instant::now();
+    while cert_fingerprint(CertRole::Server).as_deref() != Some(second.as_str()) {
+        assert!(start.elapsed() < Duration::from_secs(10), "not reloaded");
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[test]
+fn admin_tls_needs_admin_binding() {
+    let yaml = |extra: &str| {
+        format!(
+            "server_binding: 127.0.0.1:0\n\
+             base_url: https://redirect.example\n\
+             admin_token: \"00000000000000000000\"\n\
+             storage_root: ./db\n\
+             log_file: ./survey_redirect.log\n\
+             admin_tls:\n  key: key.pem\n  cert: cert.pem\n\
+             {extra}"
+        )
+    };
+    let err = Config::from_yaml(&yaml("")).err().unwrap();
+    assert!(err.to_string().contains("admin_tls requires admin_binding"));
+    let err = Config::from_yaml(&yaml("admin_binding: unix:/tmp/admin.sock\n"))
+        .err()
+        .unwrap();
+    assert!(err.to_string().contains("not supported on unix"));
+    assert!(Config::from_yaml(&yaml("admin_binding: 127.0.0.1:0\n")).is_ok());
+}
+
+#[test]
+fn server_tls_with_unix_admin_socket() {
+    let yaml = |server_binding: &str| {
+        format!(
+            "server_binding: {server_binding}\n\
+             admin_binding: unix:/tmp/admin.sock\n\
+             metrics_binding: unix:/tmp/metrics.sock\n\
+             base_url: https://redirect.example\n\
+             admin_token: \"00000000000000000000\"\n\
+             storage_root: ./db\n\
+             log_file: ./survey_redirect.log\n\
+             server_tls:\n  key: key.pem\n  cert: cert.pem\n"
+        )
+    };
+    // the unix sockets are served without tls
+    assert!(Config::from_yaml(&yaml("127.0.0.1:0")).is_ok());
+    let err = Config::from_yaml(&yaml("unix:/tmp/server.sock"))
+        .err()
+        .unwrap();
+    assert!(err.to_string().contains("not supported on unix"));
+}
+
+#[test]
+fn ticket_rotation_must_be_positive() {
+    let yaml = "server_binding: 127.0.0.1:0\n\
+         base_url: https://redirect.example\n\
+         admin_token: \"00000000000000000000\"\n\
+         storage_root: ./db\n\
+         log_file: ./survey_redirect.log\n\
+         server_tls:\n  key: key.pem\n  cert: cert.pem\n  ticket_rotation_secs: 0\n";
+    let err = Config::from_yaml(yaml).err().unwrap();
+    assert!(err
+        .to_string()
+        .contains("ticket_rotation_secs must be positive"));
+}
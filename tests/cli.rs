@@ -0,0 +1,93 @@
+mod common;
+
+use axum::{
+    body::Body,
+    http::{header::LOCATION, Request, StatusCode},
+};
+use survey_redirect::{
+    cli::{self, Command},
+    router,
+    state::RouterState,
+};
+use url::Url;
+
+/// a store prepared offline is served unchanged.
+#[tokio::test(flavor = "multi_thread")]
+async fn imported_store_is_served() {
+    let dir = tempfile::tempdir().unwrap();
+    let csv = dir.path().join("participants.csv");
+    let out = dir.path().join("links.csv");
+    std::fs::write(&csv, "uid,url\np1,https://survey.example/a\n").unwrap();
+    let config = common::config(&dir, "");
+
+    let command = Command::Import {
+        csv,
+        store: config.storage_root.clone(),
+        base_url: config.base_url.clone(),
+        out: Some(out.clone()),
+        code_length: None,
+        code_prefix: None,
+    };
+    tokio::task::spawn_blocking(move || cli::run(command))
+        .await
+        .unwrap()
+        .unwrap();
+
+    let links = std::fs::read_to_string(out).unwrap();
+    let mut rows = links.lines();
+    assert_eq!(rows.next(), Some("id,link"));
+    let (id, link) = rows.next().unwrap().split_once(',').unwrap();
+    assert_eq!(id, "p1");
+    let link = Url::parse(link).unwrap();
+
+    let state = RouterState::init(&config).unwrap();
+    let app = router(&config, state);
+    let req = Request::get(format!("/api?{}", link.query().unwrap()))
+        .body(Body::empty())
+        .unwrap();
+    let rsp = common::send(&app, req).await;
+    assert_eq!(rsp.status(), StatusCode::SEE_OTHER);
+    assert!(rsp.headers()[LOCATION]
+        .to_str()
+        .unwrap()
+        .starts_with("https://survey.example/a?"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn dry_run_summarizes_store() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = common::config(&dir, "");
+    let state = RouterState::init(&config).unwrap();
+    state
+        .put_routing_table(
+            serde_json::from_str(r#"[{"uid": "p1", "url": "https://survey.example/a"}]"#).unwrap(),
+            None,
+        )
+        .await
+        .unwrap();
+    drop(state);
+
+    let summary = tokio::task::spawn_blocking(move || cli::dry_run(&config))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(summary.routes, Some(1));
+    assert_eq!(summary.codes, Some(1));
+    assert_eq!(summary.api_url.as_str(), "https://redirect.example/api");
+}
+
+#[tokio::test]
+async fn dry_run_does_not_create_store() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = common::config(&dir, "");
+    let root = config.storage_root.clone();
+    assert!(!root.exists());
+
+    let summary = tokio::task::spawn_blocking(move || cli::dry_run(&config))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(summary.routes, Some(0));
+    assert_eq!(summary.codes, Some(0));
+    assert!(!root.exists());
+}
@@ -0,0 +1,119 @@
+//! Shared helpers of the integration tests.
+#![allow(dead_code)]
+use axum::{
+    body::{to_bytes, Body},
+    extract::ConnectInfo,
+    http::{header::AUTHORIZATION, Request},
+    response::Response,
+    Router,
+};
+use serde::de::DeserializeOwned;
+use std::{collections::HashMap, net::SocketAddr};
+use survey_redirect::{config::Config, router, state::RouterState};
+use tempfile::TempDir;
+use tower::ServiceExt;
+use url::Url;
+
+pub const ADMIN_TOKEN: &str = "00000000000000000000";
+pub const BASE_URL: &str = "https://redirect.example";
+
+/// a router over a fresh temporary storage directory.
+pub struct TestApp {
+    pub app: Router,
+    pub state: RouterState,
+    pub config: Config,
+    pub dir: TempDir,
+}
+
+impl TestApp {
+    pub fn new() -> Self {
+        Self::with_config("")
+    }
+
+    /// `extra` yaml is appended to the minimal config.
+    pub fn with_config(extra: &str) -> Self {
+        let dir = tempfile::tempdir().expect("failed to create temp dir");
+        let config = config(&dir, extra);
+        let state = RouterState::init(&config).expect("failed to init state");
+        let app = router(&config, state.clone());
+        Self {
+            app,
+            state,
+            config,
+            dir,
+        }
+    }
+
+    /// send a request from 127.0.0.1.
+    pub async fn send(&self, req: Request<Body>) -> Response {
+        send(&self.app, req).await
+    }
+}
+
+pub fn config(dir: &TempDir, extra: &str) -> Config {
+    let yaml = format!(
+        "server_binding: 127.0.0.1:0\n\
+         base_url: {BASE_URL}\n\
+         admin_token: \"{ADMIN_TOKEN}\"\n\
+         storage_root: {}\n\
+         log_file: {}\n\
+         snapshot_debounce_secs: 0\n\
+         {extra}",
+        dir.path().join("db").display(),
+        dir.path().join("survey_redirect.log").display(),
+    );
+    Config::from_yaml(&yaml).expect("invalid test config")
+}
+
+/// send a request from 127.0.0.1 through `app`.
+pub async fn send(app: &Router, mut req: Request<Body>) -> Response {
+    let peer: SocketAddr = ([127, 0, 0, 1], 40000).into();
+    req.extensions_mut().insert(ConnectInfo(peer));
+    app.clone().oneshot(req).await.expect("infallible")
+}
+
+/// an admin request with the bearer token.
+pub fn admin(method: &str, uri: &str) -> axum::http::request::Builder {
+    Request::builder()
+        .method(method)
+        .uri(uri)
+        .header(AUTHORIZATION, format!("Bearer {ADMIN_TOKEN}"))
+}
+
+pub async fn body_string(rsp: Response) -> String {
+    let bytes = to_bytes(rsp.into_body(), usize::MAX)
+        .await
+        .expect("failed to read body");
+    String::from_utf8(bytes.to_vec()).expect("body is not utf-8")
+}
+
+/// `data` of an admin response envelope, which must be `ok`.
+pub async fn admin_data<T: DeserializeOwned>(rsp: Response) -> T {
+    let envelope: serde_json::Value =
+        serde_json::from_str(&body_string(rsp).await).expect("body is not json");
+    assert_eq!(envelope["status"], "ok", "{envelope}");
+    serde_json::from_value(envelope["data"].clone()).expect("unexpected data")
+}
+
+/// the links of a `get_links` response, without the route metadata.
+pub async fn links_data(rsp: Response) -> HashMap<String, Url> {
+    let items: HashMap<String, serde_json::Value> = admin_data(rsp).await;
+    items
+        .into_iter()
+        .map(|(id, item)| {
+            let link = item["link"].as_str().expect("link is not a string");
+            (id, Url::parse(link).expect("link is not a url"))
+        })
+        .collect()
+}
+
+/// router table snapshots in `store`, oldest first.
+pub fn snapshots(store: &std::path::Path) -> Vec<std::path::PathBuf> {
+    let mut snapshots: Vec<_> = std::fs::read_dir(store)
+        .expect("failed to read store")
+        .map(|entry| entry.expect("failed to read store").path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .collect();
+    snapshots.sort_unstable();
+    snapshots
+}
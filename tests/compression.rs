@@ -0,0 +1,152 @@
+mod common;
+
+use axum::{
+    body::{to_bytes, Body},
+    http::{
+        header::{ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_TYPE},
+        StatusCode,
+    },
+};
+use common::{admin, TestApp};
+use flate2::{write::GzEncoder, Compression};
+use std::io::Write;
+use survey_redirect::BODY_LIMIT;
+
+const TABLE: &str = r#"[{"uid": "alice", "url": "https://survey.example/a"}]"#;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn zstd_upload_and_download() {
+    let app = TestApp::new();
+    let rsp = app
+        .send(
+            admin("PUT", "/admin/routing_table")
+                .header(CONTENT_TYPE, "application/json")
+                .header(CONTENT_ENCODING, "zstd")
+                .body(Body::from(zstd::encode_all(TABLE.as_bytes(), 3).unwrap()))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+
+    let rsp = app
+        .send(
+            admin("GET", "/admin/get_links")
+                .header(ACCEPT_ENCODING, "zstd")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+    assert_eq!(rsp.headers()[CONTENT_ENCODING], "zstd");
+    let body = to_bytes(rsp.into_body(), usize::MAX).await.unwrap();
+    let envelope: serde_json::Value =
+        serde_json::from_slice(&zstd::decode_all(&body[..]).unwrap()).unwrap();
+    assert!(envelope["data"]["alice"]["link"].is_string());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn disabled_encoding_is_rejected() {
+    let app = TestApp::with_config("compression:\n  zstd: false\n");
+    let rsp = app
+        .send(
+            admin("PUT", "/admin/routing_table")
+                .header(CONTENT_TYPE, "application/json")
+                .header(CONTENT_ENCODING, "zstd")
+                .body(Body::from(zstd::encode_all(TABLE.as_bytes(), 3).unwrap()))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+}
+
+/// a small gzip body expanding beyond `BODY_LIMIT` is rejected,
+/// although it would decode to a valid (empty) table.
+#[tokio::test(flavor = "multi_thread")]
+async fn gzip_bomb_is_rejected() {
+    let app = TestApp::new();
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+    let spaces = vec![b' '; 1024 * 1024];
+    encoder.write_all(b"[").unwrap();
+    for _ in 0..=BODY_LIMIT / spaces.len() {
+        encoder.write_all(&spaces).unwrap();
+    }
+    encoder.write_all(b"]").unwrap();
+    let bomb = encoder.finish().unwrap();
+    assert!(bomb.len() < 1024 * 1024);
+
+    let rsp = app
+        .send(
+            admin("PUT", "/admin/routing_table")
+                .header(CONTENT_TYPE, "application/json")
+                .header(CONTENT_ENCODING, "gzip")
+                .body(Body::from(bomb))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn decoded_size_is_limited() {
+    let app = TestApp::with_config("max_decoded_body_size: 4096\n");
+    let table = format!(
+        r#"[{{"uid": "alice", "url": "https://survey.example/a", "notes": "{}"}}]"#,
+        "x".repeat(8192)
+    );
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(table.as_bytes()).unwrap();
+    let compressed = encoder.finish().unwrap();
+    assert!(compressed.len() < 4096);
+
+    let rsp = app
+        .send(
+            admin("PUT", "/admin/routing_table")
+                .header(CONTENT_TYPE, "application/json")
+                .header(CONTENT_ENCODING, "gzip")
+                .body(Body::from(compressed))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+}
+
+/// `max_upload_bytes` limits table uploads only, other admin bodies keep theirs.
+#[tokio::test(flavor = "multi_thread")]
+async fn upload_size_is_limited() {
+    let app = TestApp::with_config("max_upload_bytes: 4096\n");
+    let table = format!(
+        r#"[{{"uid": "alice", "url": "https://survey.example/a", "notes": "{}"}}]"#,
+        "x".repeat(8192)
+    );
+    for method in ["PUT", "PATCH"] {
+        let rsp = app
+            .send(
+                admin(method, "/admin/routing_table")
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Body::from(table.clone()))
+                    .unwrap(),
+            )
+            .await;
+        assert_eq!(rsp.status(), StatusCode::PAYLOAD_TOO_LARGE, "{method}");
+    }
+    let rsp = app
+        .send(
+            admin("PUT", "/admin/routing_table")
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(TABLE))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+
+    let ids = (0..1000).map(|i| format!("\"id{i}\"")).collect::<Vec<_>>();
+    let rsp = app
+        .send(
+            admin("POST", "/admin/deactivate_codes")
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(format!(r#"{{"ids": [{}]}}"#, ids.join(","))))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status(), StatusCode::MULTI_STATUS);
+}
@@ -0,0 +1,114 @@
+mod common;
+
+use axum::{
+    body::Body,
+    http::{header::LOCATION, Request, StatusCode},
+};
+use common::admin;
+use std::collections::HashMap;
+use survey_redirect::{
+    router,
+    state::{RouterState, StateError},
+};
+use tempfile::TempDir;
+
+const SHARED: &str = "AAAAAAAAAAAAAAAA";
+
+/// a store whose code table gives alice and bob the same code.
+fn store(extra: &str) -> (TempDir, survey_redirect::config::Config) {
+    let dir = tempfile::tempdir().unwrap();
+    let config = common::config(&dir, extra);
+    std::fs::create_dir_all(&config.storage_root).unwrap();
+    let codes = serde_json::json!({
+        "alice": SHARED,
+        "bob": SHARED,
+        "carol": "CCCCCCCCCCCCCCCC",
+    });
+    std::fs::write(config.storage_root.join("code"), codes.to_string()).unwrap();
+    (dir, config)
+}
+
+fn stored_codes(config: &survey_redirect::config::Config) -> HashMap<String, String> {
+    serde_json::from_slice(&std::fs::read(config.storage_root.join("code")).unwrap()).unwrap()
+}
+
+#[test]
+fn duplicate_codes_fail_startup() {
+    let (_dir, config) = store("");
+    match RouterState::init(&config) {
+        Err(StateError::DuplicateCode { code, ids }) => {
+            assert_eq!(code.to_string(), SHARED);
+            let ids: Vec<_> = ids.iter().map(ToString::to_string).collect();
+            assert_eq!(ids, ["alice", "bob"]);
+        }
+        Err(e) => panic!("unexpected error {e:?}"),
+        Ok(_) => panic!("duplicate codes were loaded"),
+    }
+    // nothing is rewritten
+    assert_eq!(stored_codes(&config)["bob"], SHARED);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn duplicate_codes_are_repaired() {
+    let (_dir, config) = store("repair_duplicate_codes: true\n");
+    let state = RouterState::init(&config).unwrap();
+    let stored = stored_codes(&config);
+    assert_eq!(stored["alice"], SHARED);
+    assert_eq!(stored["carol"], "CCCCCCCCCCCCCCCC");
+    assert_ne!(stored["bob"], SHARED);
+    assert_ne!(stored["bob"], stored["carol"]);
+    assert_eq!(stored["bob"].len(), config.code_length);
+
+    let loaded: HashMap<String, String> = state
+        .code_table
+        .lock()
+        .await
+        .iter()
+        .map(|(id, code)| (id.to_string(), code.to_string()))
+        .collect();
+    assert_eq!(loaded, stored);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn repaired_ids_keep_their_route() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = common::config(&dir, "repair_duplicate_codes: true\n");
+    let state = RouterState::init(&config).unwrap();
+    let rsp = common::send(
+        &router(&config, state),
+        admin("PUT", "/admin/routing_table")
+            .body(Body::from(
+                r#"[{"uid": "alice", "url": "https://survey.example/a"}]"#,
+            ))
+            .unwrap(),
+    )
+    .await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+    // bob got the code of alice, e.g. by a botched import
+    let mut codes = stored_codes(&config);
+    codes.insert("bob".to_owned(), codes["alice"].clone());
+    std::fs::write(
+        config.storage_root.join("code"),
+        serde_json::to_string(&codes).unwrap(),
+    )
+    .unwrap();
+
+    RouterState::init(&config).unwrap();
+    let codes = stored_codes(&config);
+    assert_ne!(codes["bob"], codes["alice"]);
+    // both routes are stored, with the links of their own codes
+    let app = router(&config, RouterState::init(&config).unwrap());
+    for id in ["alice", "bob"] {
+        let rsp = common::send(
+            &app,
+            Request::get(format!("/api?code={}", codes[id]))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(rsp.status(), StatusCode::SEE_OTHER, "{id}");
+        let location = rsp.headers()[LOCATION].to_str().unwrap();
+        assert!(location.starts_with("https://survey.example/a?"), "{id}");
+        assert!(location.contains(&codes[id]), "{id}");
+    }
+}
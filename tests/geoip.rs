@@ -0,0 +1,222 @@
+mod common;
+
+use axum::{
+    body::Body,
+    http::{header::LOCATION, Request, StatusCode},
+};
+use common::{admin, admin_data, links_data, TestApp};
+use std::collections::HashMap;
+use url::Url;
+
+const TABLE: &str = r#"[
+    {"uid": "alice", "url": "https://us.example/s", "geo_urls": {"EU": "https://eu.example/s"}},
+    {"uid": "bob", "url": "https://us.example/s", "geo_urls": {"DE": "https://de.example/s", "EU": "https://eu.example/s"}},
+    {"uid": "carol", "url": "https://us.example/s"}
+]"#;
+
+/// MaxMind DB control byte with a data type and a size (or value) below 29.
+fn control(data_type: u8, size: usize, out: &mut Vec<u8>) {
+    assert!(size < 29);
+    if data_type <= 7 {
+        out.push(data_type << 5 | size as u8);
+    } else {
+        out.extend([size as u8, data_type - 7]);
+    }
+}
+
+fn string(s: &str, out: &mut Vec<u8>) {
+    control(2, s.len(), out);
+    out.extend(s.as_bytes());
+}
+
+fn uint(data_type: u8, value: u8, out: &mut Vec<u8>) {
+    control(data_type, 1, out);
+    out.push(value);
+}
+
+/// an ipv4 country database locating `10.0.0.0/8` in Germany, and nothing else.
+fn mmdb() -> Vec<u8> {
+    const NODES: u32 = 8;
+    const PREFIX: u8 = 10;
+    let mut db = Vec::new();
+    // one node per bit of the prefix, 24 bit records
+    for bit in 0..8 {
+        let next = if bit == 7 { NODES + 16 } else { bit + 1 };
+        let records = match PREFIX >> (7 - bit) & 1 {
+            0 => [next, NODES],
+            _ => [NODES, next],
+        };
+        for record in records {
+            db.extend(&record.to_be_bytes()[1..]);
+        }
+    }
+    db.extend([0; 16]);
+    // {"country": {"iso_code": "DE", "is_in_european_union": true}}
+    control(7, 1, &mut db);
+    string("country", &mut db);
+    control(7, 2, &mut db);
+    string("iso_code", &mut db);
+    string("DE", &mut db);
+    string("is_in_european_union", &mut db);
+    control(14, 1, &mut db);
+
+    db.extend(b"\xAB\xCD\xEFMaxMind.com");
+    control(7, 9, &mut db);
+    string("node_count", &mut db);
+    uint(6, NODES as u8, &mut db);
+    string("record_size", &mut db);
+    uint(5, 24, &mut db);
+    string("ip_version", &mut db);
+    uint(5, 4, &mut db);
+    string("database_type", &mut db);
+    string("Test-Country", &mut db);
+    string("languages", &mut db);
+    control(11, 1, &mut db);
+    string("en", &mut db);
+    string("binary_format_major_version", &mut db);
+    uint(5, 2, &mut db);
+    string("binary_format_minor_version", &mut db);
+    control(5, 0, &mut db);
+    string("build_epoch", &mut db);
+    control(9, 0, &mut db);
+    string("description", &mut db);
+    control(7, 1, &mut db);
+    string("en", &mut db);
+    string("test", &mut db);
+    db
+}
+
+/// follow the link of `id` for a client at `ip`, behind the trusted proxy.
+async fn follow_from(app: &TestApp, links: &HashMap<String, Url>, id: &str, ip: &str) -> Url {
+    let req = Request::get(format!("/api?{}", links[id].query().unwrap()))
+        .header("x-forwarded-for", ip)
+        .body(Body::empty())
+        .unwrap();
+    let rsp = app.send(req).await;
+    assert_eq!(rsp.status(), StatusCode::SEE_OTHER);
+    Url::parse(rsp.headers()[LOCATION].to_str().unwrap()).unwrap()
+}
+
+async fn put_table(app: &TestApp) -> HashMap<String, Url> {
+    let rsp = app
+        .send(
+            admin("PUT", "/admin/routing_table")
+                .body(Body::from(TABLE))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+    let rsp = app
+        .send(
+            admin("GET", "/admin/get_links")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    links_data(rsp).await
+}
+
+fn geo(url: &Url) -> Option<String> {
+    url.query_pairs()
+        .find(|(key, _)| key == "geo")
+        .map(|(_, value)| value.into_owned())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn geo_urls_follow_client_location() {
+    let db_dir = tempfile::tempdir().unwrap();
+    let db = db_dir.path().join("country.mmdb");
+    std::fs::write(&db, mmdb()).unwrap();
+    let app = TestApp::with_config(&format!(
+        "trusted_proxies: [127.0.0.1/32]\ngeoip_database: {}\n",
+        db.display()
+    ));
+    let links = put_table(&app).await;
+
+    let url = follow_from(&app, &links, "alice", "10.1.2.3").await;
+    assert_eq!(url.host_str(), Some("eu.example"));
+    assert_eq!(geo(&url).as_deref(), Some("EU"));
+    assert!(url.query_pairs().any(|(key, _)| key == "externalUserId"));
+    // the country wins over the region
+    let url = follow_from(&app, &links, "bob", "10.1.2.3").await;
+    assert_eq!(url.host_str(), Some("de.example"));
+    assert_eq!(geo(&url).as_deref(), Some("DE"));
+    // not in the database, or no geo_urls
+    for (id, ip) in [
+        ("alice", "192.0.2.1"),
+        ("bob", "2001:db8::1"),
+        ("carol", "10.1.2.3"),
+    ] {
+        let url = follow_from(&app, &links, id, ip).await;
+        assert_eq!(url.host_str(), Some("us.example"), "{id} from {ip}");
+        assert_eq!(geo(&url), None);
+    }
+
+    // a broken database on reload keeps the loaded one
+    std::fs::write(&db, b"not a database").unwrap();
+    let rsp = app
+        .send(admin("POST", "/admin/reload").body(Body::empty()).unwrap())
+        .await;
+    let summary: serde_json::Value = admin_data(rsp).await;
+    assert_eq!(summary["geoip"], true);
+    let url = follow_from(&app, &links, "alice", "10.1.2.3").await;
+    assert_eq!(url.host_str(), Some("eu.example"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn geo_urls_without_database_use_default() {
+    let app = TestApp::with_config("trusted_proxies: [127.0.0.1/32]\n");
+    let links = put_table(&app).await;
+    let url = follow_from(&app, &links, "bob", "10.1.2.3").await;
+    assert_eq!(url.host_str(), Some("us.example"));
+    assert_eq!(geo(&url), None);
+
+    let rsp = app
+        .send(
+            admin("PUT", "/admin/routing_table")
+                .body(Body::from(
+                    r#"[{"uid": "alice", "url": "https://us.example/s", "geo_urls": {"de": "https://de.example/s"}}]"#,
+                ))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn missing_database_is_loaded_on_reload() {
+    let db_dir = tempfile::tempdir().unwrap();
+    let db = db_dir.path().join("country.mmdb");
+    let app = TestApp::with_config(&format!(
+        "trusted_proxies: [127.0.0.1/32]\ngeoip_database: {}\n",
+        db.display()
+    ));
+    let links = put_table(&app).await;
+    let url = follow_from(&app, &links, "alice", "10.1.2.3").await;
+    assert_eq!(url.host_str(), Some("us.example"));
+
+    std::fs::write(&db, mmdb()).unwrap();
+    let rsp = app
+        .send(admin("POST", "/admin/reload").body(Body::empty()).unwrap())
+        .await;
+    let summary: serde_json::Value = admin_data(rsp).await;
+    assert_eq!(summary["geoip"], true);
+    let url = follow_from(&app, &links, "alice", "10.1.2.3").await;
+    assert_eq!(url.host_str(), Some("eu.example"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn search_routes_finds_geo_urls() {
+    let app = TestApp::new();
+    put_table(&app).await;
+    let rsp = app
+        .send(
+            admin("GET", "/admin/search_routes?url_contains=de.example")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    let matches: Vec<serde_json::Value> = admin_data(rsp).await;
+    assert_eq!(matches.len(), 1);
+    assert_eq!(matches[0]["id"], "bob");
+}
@@ -0,0 +1,96 @@
+//! Idle keep-alive connections, served on a unix socket to run without tls.
+#![cfg(unix)]
+use axum::{routing::get, Router};
+use std::{
+    path::Path,
+    sync::{atomic::AtomicBool, Arc},
+    time::Duration,
+};
+use survey_redirect::{
+    config::Bind,
+    server::{run_server, ServerOptions, Surface},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::UnixStream,
+    task::JoinHandle,
+};
+
+const IDLE_TIMEOUT: Duration = Duration::from_secs(1);
+const REQUEST: &[u8] = b"GET /slow HTTP/1.1\r\nHost: localhost\r\n\r\n";
+
+/// serve `/slow`, answering after 1.5 idle timeouts.
+async fn serve(socket: &Path) -> JoinHandle<()> {
+    let app = Router::new().route(
+        "/slow",
+        get(|| async {
+            tokio::time::sleep(IDLE_TIMEOUT * 3 / 2).await;
+            "done"
+        }),
+    );
+    let surface = Surface {
+        name: "test",
+        binds: vec![Bind::Unix(socket.to_owned())],
+        app,
+        admin_tls: false,
+    };
+    let server = tokio::spawn(async move {
+        let options = ServerOptions {
+            unix_socket_mode: None,
+            tcp_backlog: 16,
+            shutting_down: Arc::new(AtomicBool::new(false)),
+            drain_timeout: Duration::from_secs(1),
+            draining: Arc::new(AtomicBool::new(false)),
+            idle_connection_timeout: Some(IDLE_TIMEOUT),
+        };
+        run_server(vec![surface], &options, None, None, async {})
+            .await
+            .unwrap();
+    });
+    while !socket.exists() {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    server
+}
+
+/// read a response, which ends with the body `done`.
+async fn read_response(stream: &mut UnixStream) -> String {
+    let mut response = Vec::new();
+    let mut buf = [0; 1024];
+    while !response.ends_with(b"done") {
+        let n = stream.read(&mut buf).await.unwrap();
+        assert!(n > 0, "connection closed before the response");
+        response.extend_from_slice(&buf[..n]);
+    }
+    String::from_utf8(response).unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn idle_connection_is_closed() {
+    let dir = tempfile::tempdir().unwrap();
+    let socket = dir.path().join("server.sock");
+    let server = serve(&socket).await;
+    let mut stream = UnixStream::connect(&socket).await.unwrap();
+    stream.write_all(REQUEST).await.unwrap();
+    assert!(read_response(&mut stream).await.starts_with("HTTP/1.1 200"));
+    // kept alive, then closed once idle for the timeout
+    let mut buf = [0; 1];
+    let closed = tokio::time::timeout(IDLE_TIMEOUT * 3, stream.read(&mut buf)).await;
+    assert_eq!(closed.expect("idle connection kept open").unwrap(), 0);
+    server.abort();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn busy_connection_is_kept() {
+    let dir = tempfile::tempdir().unwrap();
+    let socket = dir.path().join("server.sock");
+    let server = serve(&socket).await;
+    let mut stream = UnixStream::connect(&socket).await.unwrap();
+    // the request is in flight for longer than the idle timeout
+    stream.write_all(REQUEST).await.unwrap();
+    assert!(read_response(&mut stream).await.starts_with("HTTP/1.1 200"));
+    // and the connection still serves the next one
+    stream.write_all(REQUEST).await.unwrap();
+    assert!(read_response(&mut stream).await.starts_with("HTTP/1.1 200"));
+    server.abort();
+}
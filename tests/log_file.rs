@@ -0,0 +1,36 @@
+use std::io::Write;
+use survey_redirect::{config::LogRotation, log_file};
+
+#[test]
+fn size_rotation_keeps_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut file = log_file::SizeRollingFile::new(dir.path(), "server.log", 100, 3).unwrap();
+    for i in 0..10 {
+        // one write per line, as written by the logger
+        file.write_all(format!("{i:059}\n").as_bytes()).unwrap();
+    }
+    file.flush().unwrap();
+
+    let mut names: Vec<_> = std::fs::read_dir(dir.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    names.sort();
+    assert_eq!(names.len(), 3, "{names:?}");
+    assert!(names.iter().all(|name| name.starts_with("server.log")));
+    // one line per file, the current file has the last line
+    let current = std::fs::read_to_string(dir.path().join("server.log")).unwrap();
+    assert_eq!(current, format!("{:059}\n", 9));
+}
+
+#[test]
+fn no_rotation_appends() {
+    let dir = tempfile::tempdir().unwrap();
+    for line in ["first\n", "second\n"] {
+        let mut file = log_file::open(dir.path(), "server.log", LogRotation::Never, 1).unwrap();
+        file.write_all(line.as_bytes()).unwrap();
+        file.flush().unwrap();
+    }
+    let log = std::fs::read_to_string(dir.path().join("server.log")).unwrap();
+    assert_eq!(log, "first\nsecond\n");
+}
@@ -0,0 +1,31 @@
+//! The participant id type is `state::Id`, make sure the old `Uid` name does not come back.
+use std::path::Path;
+
+fn check_dir(dir: &Path, offenders: &mut Vec<String>) {
+    for entry in std::fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            check_dir(&path, offenders);
+        } else if path.extension().is_some_and(|ext| ext == "rs") {
+            let source = std::fs::read_to_string(&path).unwrap();
+            for (n, line) in source.lines().enumerate() {
+                let uses_uid = line
+                    .split(|c: char| !c.is_alphanumeric() && c != '_')
+                    .any(|word| word == "Uid");
+                if uses_uid {
+                    offenders.push(format!("{}:{}", path.display(), n + 1));
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn no_uid_type_references() {
+    let mut offenders = Vec::new();
+    check_dir(
+        &Path::new(env!("CARGO_MANIFEST_DIR")).join("src"),
+        &mut offenders,
+    );
+    assert!(offenders.is_empty(), "`Uid` referenced at {offenders:?}");
+}
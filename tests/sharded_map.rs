@@ -0,0 +1,37 @@
+use std::collections::HashMap;
+use survey_redirect::sharded_map::ShardedMap;
+
+#[test]
+fn clones_are_independent() {
+    let map: ShardedMap<String, u32> = (0..5000).map(|i| (format!("k{i}"), i)).collect();
+    assert_eq!(map.len(), 5000);
+
+    let mut copy = map.clone();
+    *copy.get_mut("k1").unwrap() = 100;
+    assert_eq!(copy.insert("k2".to_owned(), 200), Some(2));
+    assert_eq!(copy.insert("new".to_owned(), 300), None);
+    assert_eq!(copy.remove("k3"), Some(3));
+    assert_eq!(copy.remove("k3"), None);
+    assert!(copy.get_mut("missing").is_none());
+    assert_eq!(copy.len(), 5000);
+
+    assert_eq!(map.get("k1"), Some(&1));
+    assert_eq!(map.get("k2"), Some(&2));
+    assert!(!map.contains_key("new"));
+    assert!(map.contains_key("k3"));
+    assert_eq!(copy.get("k1"), Some(&100));
+
+    for (_, value) in copy.iter_mut() {
+        *value += 1;
+    }
+    assert_eq!(map.values().sum::<u32>(), (0..5000).sum::<u32>());
+}
+
+#[test]
+fn serializes_like_a_hash_map() {
+    let map: ShardedMap<String, u32> = (0..100).map(|i| (format!("k{i}"), i)).collect();
+    let json = serde_json::to_string(&map).unwrap();
+    let parsed: HashMap<String, u32> = serde_json::from_str(&json).unwrap();
+    assert_eq!(parsed.len(), 100);
+    assert_eq!(parsed["k42"], 42);
+}
@@ -0,0 +1,106 @@
+mod common;
+
+#[cfg(not(feature = "sled-storage"))]
+#[test]
+fn sled_backend_needs_feature() {
+    let yaml = "server_binding: 127.0.0.1:0\n\
+                base_url: https://redirect.example\n\
+                admin_token: \"00000000000000000000\"\n\
+                storage_root: ./db\n\
+                log_file: ./survey_redirect.log\n\
+                storage_backend: sled\n";
+    let err = survey_redirect::config::Config::from_yaml(yaml)
+        .err()
+        .unwrap();
+    assert!(err
+        .to_string()
+        .contains("requires the sled-storage feature"));
+}
+
+#[cfg(feature = "sled-storage")]
+mod sled_storage {
+    use super::common::{admin, admin_data, links_data, TestApp};
+    use axum::{body::Body, http::StatusCode};
+    use std::collections::HashMap;
+    use survey_redirect::{router, state::RouterState};
+    use url::Url;
+
+    async fn links(app: &TestApp) -> HashMap<String, Url> {
+        let rsp = app
+            .send(
+                admin("GET", "/admin/get_links")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await;
+        assert_eq!(rsp.status(), StatusCode::OK);
+        links_data(rsp).await
+    }
+
+    async fn upload(app: &TestApp, method: &str, body: serde_json::Value) {
+        let rsp = app
+            .send(
+                admin(method, "/admin/routing_table")
+                    .body(Body::from(body.to_string()))
+                    .unwrap(),
+            )
+            .await;
+        assert_eq!(rsp.status(), StatusCode::OK);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn sled_backend_keeps_tables_across_restarts() {
+        let app = TestApp::with_config("storage_backend: sled\n");
+        upload(
+            &app,
+            "PUT",
+            serde_json::json!([
+                {"uid": "alice", "url": "https://survey.example/a"},
+                {"uid": "bob", "url": "https://survey.example/b"},
+            ]),
+        )
+        .await;
+        upload(
+            &app,
+            "PATCH",
+            serde_json::json!([{"uid": "bob", "url": "https://survey.example/b2"}]),
+        )
+        .await;
+        upload(
+            &app,
+            "PUT",
+            serde_json::json!([
+                {"uid": "bob", "url": "https://survey.example/b2"},
+                {"uid": "carol", "url": "https://survey.example/c"},
+            ]),
+        )
+        .await;
+        let before = links(&app).await;
+        assert_eq!(before.len(), 2);
+        assert!(app.config.storage_root.join("sled").is_dir());
+
+        // the database is locked until the state is dropped
+        let TestApp {
+            app: old_app,
+            state,
+            config,
+            dir,
+        } = app;
+        drop((old_app, state));
+        let state = RouterState::init(&config).unwrap();
+        let app = TestApp {
+            app: router(&config, state.clone()),
+            state,
+            config,
+            dir,
+        };
+        assert_eq!(links(&app).await, before);
+        let table = app.state.router_table.read().await;
+        let mut urls: Vec<_> = table.values().map(|e| e.url.as_str()).collect();
+        urls.sort_unstable();
+        assert_eq!(
+            urls,
+            ["https://survey.example/b2", "https://survey.example/c"]
+        );
+    }
+}
@@ -0,0 +1,99 @@
+//! Handshakes against the served certificates, in their own process since
+//! the fingerprints of `certs` are global.
+use std::{
+    io::BufReader,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use survey_redirect::{
+    certs::{cert_fingerprint, cert_provider_from_file, CertRole},
+    config::TlsConfig,
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_rustls::{
+    rustls::{pki_types::ServerName, ClientConfig, HandshakeKind, RootCertStore},
+    TlsAcceptor, TlsConnector,
+};
+
+fn write_cert(tls_config: &TlsConfig) {
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+    std::fs::write(&tls_config.key, cert.key_pair.serialize_pem()).unwrap();
+    std::fs::write(&tls_config.cert, cert.cert.pem()).unwrap();
+}
+
+/// a client trusting the certificate currently in `tls_config.cert` only.
+fn connector(tls_config: &TlsConfig) -> TlsConnector {
+    let pem = std::fs::read(&tls_config.cert).unwrap();
+    let mut roots = RootCertStore::empty();
+    for cert in rustls_pemfile::certs(&mut BufReader::new(&pem[..])) {
+        roots.add(cert.unwrap()).unwrap();
+    }
+    let config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    TlsConnector::from(Arc::new(config))
+}
+
+/// connect over an in-memory stream, and read a byte so that the client
+/// receives the session tickets.
+async fn handshake(acceptor: TlsAcceptor, connector: &TlsConnector) -> Option<HandshakeKind> {
+    let (client_io, server_io) = tokio::io::duplex(64 * 1024);
+    let server = tokio::spawn(async move {
+        let mut stream = acceptor.accept(server_io).await.unwrap();
+        stream.write_all(b"x").await.unwrap();
+        stream.flush().await.unwrap();
+    });
+    let name = ServerName::try_from("localhost").unwrap();
+    let mut stream = connector.connect(name, client_io).await.unwrap();
+    let mut byte = [0];
+    stream.read_exact(&mut byte).await.unwrap();
+    server.await.unwrap();
+    stream.get_ref().1.handshake_kind()
+}
+
+#[test]
+fn sessions_resume_across_cert_reloads() {
+    for session_tickets in [true, false] {
+        let dir = tempfile::tempdir().unwrap();
+        let tls_config = TlsConfig {
+            key: dir.path().join("key.pem"),
+            cert: dir.path().join("cert.pem"),
+            session_tickets,
+            ticket_rotation_secs: 3600,
+            max_early_data_size: 0,
+        };
+        write_cert(&tls_config);
+        let connector = connector(&tls_config);
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let (mut acceptor, _reload) = cert_provider_from_file(
+            Some(tls_config.clone()),
+            CertRole::Server,
+            &None::<PathBuf>,
+            &rt,
+        )
+        .unwrap()
+        .unwrap();
+        rt.block_on(async {
+            let first = handshake(acceptor.borrow().clone(), &connector).await;
+            assert_eq!(first, Some(HandshakeKind::Full));
+            let second = handshake(acceptor.borrow().clone(), &connector).await;
+            assert_eq!(second, Some(HandshakeKind::Resumed), "{session_tickets}");
+
+            // the client does not trust the new cert, it can only resume
+            let old = cert_fingerprint(CertRole::Server);
+            write_cert(&tls_config);
+            let start = Instant::now();
+            while cert_fingerprint(CertRole::Server) == old || !acceptor.has_changed().unwrap() {
+                assert!(start.elapsed() < Duration::from_secs(10), "not reloaded");
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+            let reloaded = acceptor.borrow_and_update().clone();
+            let third = handshake(reloaded, &connector).await;
+            assert_eq!(third, Some(HandshakeKind::Resumed), "{session_tickets}");
+        });
+    }
+}
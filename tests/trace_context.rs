@@ -0,0 +1,30 @@
+use axum::http::{HeaderMap, HeaderValue};
+use survey_redirect::trace_context::traceparent;
+
+fn parse(value: &'static str) -> Option<String> {
+    let mut headers = HeaderMap::new();
+    headers.insert("traceparent", HeaderValue::from_static(value));
+    traceparent(&headers).map(str::to_owned)
+}
+
+#[test]
+fn traceparent_must_be_well_formed() {
+    const VALID: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+    assert_eq!(parse(VALID).as_deref(), Some(VALID));
+    assert_eq!(traceparent(&HeaderMap::new()), None);
+    // later versions may have more fields
+    let future = "01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra";
+    assert_eq!(parse(future).as_deref(), Some(future));
+    for invalid in [
+        "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra",
+        "00-4BF92F3577B34DA6A3CE929D0E0E4736-00F067AA0BA902B7-01",
+        "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+        "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+        "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+        "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+        "00-4bf92f3577b34da6a3ce929d0e0e47-00f067aa0ba902b7-01",
+        "not a traceparent",
+    ] {
+        assert_eq!(parse(invalid), None, "{invalid}");
+    }
+}
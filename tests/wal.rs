@@ -0,0 +1,47 @@
+mod common;
+
+use axum::{body::Body, http::StatusCode};
+use common::{admin, snapshots, TestApp};
+use survey_redirect::state::RouterState;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn interrupted_write_is_replayed() {
+    let app = TestApp::with_config("write_ahead_log: true\n");
+    let rsp = app
+        .send(
+            admin("PUT", "/admin/routing_table")
+                .body(Body::from(
+                    r#"[{"uid": "alice", "url": "https://survey.example/a"}]"#,
+                ))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+    let store = &app.config.storage_root;
+    let wal = store.join("wal.log");
+    assert_eq!(std::fs::read_to_string(&wal).unwrap(), "");
+    let written = snapshots(store);
+    assert_eq!(written.len(), 1);
+
+    // a crash after the log entry, before the snapshot was persisted
+    let table = std::fs::read_to_string(&written[0]).unwrap().replace(
+        "https://survey.example/a",
+        "https://survey.example/recovered",
+    );
+    let time = chrono::Local::now().to_rfc3339();
+    std::fs::write(&wal, format!("{time}\t{table}\n{time}\t{{\"partial")).unwrap();
+
+    let state = RouterState::init(&app.config).unwrap();
+    let router_table = state.router_table.read().await;
+    let urls: Vec<_> = router_table.values().map(|e| e.url.as_str()).collect();
+    assert_eq!(urls, ["https://survey.example/recovered"]);
+    assert_eq!(snapshots(store).len(), 2);
+    assert_eq!(std::fs::read_to_string(&wal).unwrap(), "");
+
+    // entries older than the latest snapshot are dropped
+    std::fs::write(&wal, format!("2000-01-01T00:00:00+00:00\t{table}\n")).unwrap();
+    drop(router_table);
+    RouterState::init(&app.config).unwrap();
+    assert_eq!(snapshots(store).len(), 2);
+    assert_eq!(std::fs::read_to_string(&wal).unwrap(), "");
+}
@@ -0,0 +1,83 @@
+mod common;
+
+use axum::{
+    body::Body,
+    http::{header::CONTENT_TYPE, Request, StatusCode},
+};
+use common::{admin, body_string, links_data, TestApp};
+use rust_xlsxwriter::Workbook;
+use survey_redirect::XLSX_CONTENT_TYPE;
+
+/// a workbook with `rows` in its first worksheet, empty strings are left blank.
+fn workbook(rows: &[&[&str]]) -> Vec<u8> {
+    let mut workbook = Workbook::new();
+    let sheet = workbook.add_worksheet();
+    for (r, row) in rows.iter().enumerate() {
+        for (c, cell) in row.iter().enumerate() {
+            if !cell.is_empty() {
+                sheet.write(r as u32, c as u16, *cell).unwrap();
+            }
+        }
+    }
+    workbook.save_to_buffer().unwrap()
+}
+
+fn put(data: Vec<u8>) -> Request<Body> {
+    admin("PUT", "/admin/routing_table")
+        .header(CONTENT_TYPE, XLSX_CONTENT_TYPE)
+        .body(Body::from(data))
+        .unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn put_table_as_xlsx() {
+    let app = TestApp::new();
+    let data = workbook(&[
+        &["Notes", "URL", "Id"],
+        &["", "https://survey.example/a", "alice"],
+        &["no id", "https://survey.example/x", ""],
+        &["", " https://survey.example/b ", "bob"],
+    ]);
+    let rsp = app.send(put(data)).await;
+    assert_eq!(rsp.status(), StatusCode::OK);
+    assert_eq!(rsp.headers()["x-table-format"], "xlsx");
+    assert_eq!(rsp.headers()["x-skipped-rows"], "1");
+
+    let rsp = app
+        .send(
+            admin("GET", "/admin/get_links")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    let links = links_data(rsp).await;
+    let mut ids: Vec<_> = links.keys().map(String::as_str).collect();
+    ids.sort_unstable();
+    assert_eq!(ids, ["alice", "bob"]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn xlsx_with_bad_rows_is_rejected() {
+    let app = TestApp::new();
+    for (data, problem) in [
+        (
+            workbook(&[
+                &["id", "url"],
+                &["alice", "https://survey.example/a"],
+                &["bob", "not a url"],
+                &["carol", ""],
+            ]),
+            "invalid url in rows 3, 4",
+        ),
+        (
+            workbook(&[&["id", "link"], &["alice", "https://survey.example/a"]]),
+            "missing column `url`",
+        ),
+        (b"not a workbook".to_vec(), "invalid xlsx"),
+    ] {
+        let rsp = app.send(put(data)).await;
+        assert_eq!(rsp.status(), StatusCode::BAD_REQUEST);
+        let body = body_string(rsp).await;
+        assert!(body.contains(problem), "{body}");
+    }
+}